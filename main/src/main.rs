@@ -1,12 +1,21 @@
-//! Displays a shaded sphere to the user.
+//! Displays a flat voxel world to the user, generated around the camera.
 
 extern crate amethyst;
 extern crate morass_voxel;
 
-use morass_voxel::{MorassVoxel, MorassChunk, VoxelCoord};
+use morass_voxel::{chunks_in_box, MorassVoxel, VoxelCoord};
+use morass_voxel::asset::SnapshotFormat;
+use morass_voxel::delta::{ChunkDeltaSystem, ChunkDeltas};
+use morass_voxel::edit::Selection;
+use morass_voxel::generate::{ChunkAnchor, ChunkGenerationSystem, ChunkStreamingSystem};
+use morass_voxel::patterns::Superflat;
+use morass_voxel::snapshot::Snapshot;
+use morass_voxel::structure::{MergePolicy, Rotation, Structure};
+use morass_voxel::tracker::ChunkTracker;
 
 use std::time::Duration;
 
+use amethyst::assets::{AssetStorage, Handle, Loader, Processor};
 use amethyst::core::cgmath::Deg;
 use amethyst::core::transform::GlobalTransform;
 use amethyst::ecs::prelude::World;
@@ -21,8 +30,14 @@ const BACKGROUND_COLOUR: [f32; 4] = [0.0, 0.0, 0.0, 0.0]; // black
 const LIGHT_POSITION: [f32; 3] = [20.0, 20.0, -20.0];
 const LIGHT_RADIUS: f32 = 50.0;
 const LIGHT_INTENSITY: f32 = 3.0;
+/// Where the tower (see `load_tower`) goes: on the grass, in front of the camera.
+const TOWER_ORIGIN: (i16, i16, i16) = (2, -3, -10);
 
-struct Example;
+#[derive(Default)]
+struct Example {
+    /// The tower, until it's been stamped into the world.
+    tower: Option<Handle<Structure<MorassVoxel>>>,
+}
 
 impl<'a, 'b> State<GameData<'a, 'b>> for Example {
     fn on_start(&mut self, data: StateData<GameData>) {
@@ -30,6 +45,7 @@ impl<'a, 'b> State<GameData<'a, 'b>> for Example {
         initialise_lights(data.world);
         initialise_camera(data.world);
         initialize_voxels(data.world);
+        self.tower = Some(load_tower(data.world));
     }
 
     fn handle_event(&mut self, _: StateData<GameData>, event: Event) -> Trans<GameData<'a, 'b>> {
@@ -51,6 +67,9 @@ impl<'a, 'b> State<GameData<'a, 'b>> for Example {
 
     fn update(&mut self, data: StateData<GameData>) -> Trans<GameData<'a, 'b>> {
         data.data.update(&data.world);
+        if self.tower.as_ref().map_or(false, |tower| place_tower(data.world, tower)) {
+            self.tower = None;
+        }
         Trans::None
     }
 }
@@ -74,9 +93,19 @@ fn run() -> Result<(), amethyst::Error> {
 
     let game_data = GameDataBuilder::default()
         .with_bundle(RenderBundle::new(pipe, Some(config)))?
-        .with(morass_voxel::tracker::ChunkTrackerSystem::<MorassVoxel>::new(), "chunk_tracker", &[])
-        .with(morass_voxel::mesh::ChunkMesherSystem::<MorassVoxel>::new(Duration::from_millis(3)), "chunk_mesher", &[]);
-    let mut game = Application::new(resources, Example, game_data)?;
+        .with(morass_voxel::budget::HeadroomSystem, "headroom", &[])
+        // (snapshots and structures load from `resources`, like meshes; see `morass_voxel::asset`)
+        .with(Processor::<Snapshot<MorassVoxel>>::new(), "snapshot_processor", &[])
+        .with(Processor::<Structure<MorassVoxel>>::new(), "structure_processor", &[])
+        .with(ChunkStreamingSystem::<MorassVoxel>::new(8), "chunk_streaming", &[])
+        .with(ChunkGenerationSystem::with_workers(world_generator(), 2, Duration::from_millis(2)), "chunk_generation", &["chunk_streaming", "headroom"])
+        .with(morass_voxel::tracker::ChunkTrackerSystem::<MorassVoxel>::new(), "chunk_tracker", &["chunk_generation"])
+        .with(ChunkDeltaSystem::<MorassVoxel>::new(), "chunk_deltas", &["chunk_tracker"])
+        .with(morass_voxel::light::LightingSystem::<MorassVoxel>::new(Duration::from_millis(2)), "lighting", &["chunk_deltas", "headroom"])
+        .with(morass_voxel::light::DayNightSystem::new(600.0, 0.5), "day_night", &[])
+        .with(morass_voxel::mesh::ChunkMesherSystem::<MorassVoxel>::new(Duration::from_millis(3)), "chunk_mesher", &["headroom", "lighting", "day_night"])
+        .with(morass_voxel::mesh::SelectionMeshSystem::default(), "selection_mesh", &[]);
+    let mut game = Application::new(resources, Example::default(), game_data)?;
     game.run();
     Ok(())
 }
@@ -88,22 +117,44 @@ fn main() {
     }
 }
 
+/// Stone, with a layer of grass on top, just below the camera.
+fn world_generator() -> Superflat<MorassVoxel> {
+    Superflat::new(-8, vec![(MorassVoxel::Stone, 4), (MorassVoxel::Grass, 1)])
+}
+
+/// Chunks are generated around the camera (see `initialise_camera`), so all this needs is the tracker.
 fn initialize_voxels(world: &mut World) {
     world.add_resource(morass_voxel::tracker::ChunkTracker::new());
-    let mut chunk = MorassChunk::empty(VoxelCoord::new(0,0,0));
-    chunk.voxels[0][0][0] = MorassVoxel::Grass;
-    chunk.voxels[0][0][2] = MorassVoxel::Grass;
-    chunk.voxels[0][0][1] = MorassVoxel::Grass;
-    chunk.voxels[0][0][0] = MorassVoxel::Grass;
-    chunk.voxels[0][3][3] = MorassVoxel::Grass;
-    chunk.voxels[5][0][5] = MorassVoxel::Grass;
-    chunk.voxels[0][5][0] = MorassVoxel::Grass;
-    use amethyst::core::cgmath::Matrix4;
-    world.create_entity()
-         .with(chunk)
-         .with(GlobalTransform(Matrix4::from_translation([0.0, 0.0, 0.0].into())))
-         .build();
+}
+
+/// Start loading the tower, a snapshot saved with `Snapshot::save`, as a structure to stamp.
+fn load_tower(world: &mut World) -> Handle<Structure<MorassVoxel>> {
+    let loader = world.read_resource::<Loader>();
+    loader.load("tower.snapshot", SnapshotFormat, (), (), &world.read_resource())
+}
 
+/// Stamp the tower at `TOWER_ORIGIN`, once it's loaded and so are the chunks it goes in, and select it (which
+/// `SelectionMeshSystem` outlines); returns whether it was.
+fn place_tower(world: &mut World, tower: &Handle<Structure<MorassVoxel>>) -> bool {
+    let origin = VoxelCoord::new(TOWER_ORIGIN.0, TOWER_ORIGIN.1, TOWER_ORIGIN.2);
+    let max = {
+        let storage = world.read_resource::<AssetStorage<Structure<MorassVoxel>>>();
+        let structure = match storage.get(tower) {
+            Some(structure) => structure,
+            None => return false,
+        };
+        let tracker = world.read_resource::<ChunkTracker>();
+        let max = origin + structure.size() - VoxelCoord::new(1, 1, 1);
+        if !chunks_in_box(origin, max).all(|chunk| tracker.get_chunk_ent(chunk).is_some()) {
+            return false;
+        }
+        world
+            .read_resource::<ChunkDeltas<MorassVoxel>>()
+            .defer_stamp(origin, structure, Rotation::None, MergePolicy::ReplaceAll);
+        max
+    };
+    world.add_resource(Selection::new(origin, max));
+    true
 }
 
 /// This function adds an ambient light and a point light to the world.
@@ -132,5 +183,6 @@ fn initialise_camera(world: &mut World) {
         .create_entity()
         .with(Camera::from(Projection::perspective(1.3, Deg(60.0))))
         .with(GlobalTransform(transform.into()))
+        .with(ChunkAnchor { radius: 4 })
         .build();
 }
\ No newline at end of file
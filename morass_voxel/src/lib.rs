@@ -1,3 +1,6 @@
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
 extern crate voxel;
 
 pub use voxel::*;
@@ -5,6 +8,7 @@ pub use voxel::*;
 pub type MorassChunk = Chunk<MorassVoxel>;
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum MorassVoxel {
     Air,
     Grass,
@@ -28,4 +32,35 @@ impl Voxel for MorassVoxel {
             MorassVoxel::Wood => [92.0/255.0,44.0/255.0,29.0/255.0, 0.0],
         }
     }
+}
+impl VoxelId for MorassVoxel {
+    fn id(&self) -> u16 {
+        *self as u16
+    }
+    fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(MorassVoxel::Air),
+            1 => Some(MorassVoxel::Grass),
+            2 => Some(MorassVoxel::Stone),
+            3 => Some(MorassVoxel::Wood),
+            _ => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match *self {
+            MorassVoxel::Air => "air",
+            MorassVoxel::Grass => "grass",
+            MorassVoxel::Stone => "stone",
+            MorassVoxel::Wood => "wood",
+        }
+    }
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "air" => Some(MorassVoxel::Air),
+            "grass" => Some(MorassVoxel::Grass),
+            "stone" => Some(MorassVoxel::Stone),
+            "wood" => Some(MorassVoxel::Wood),
+            _ => None,
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,71 @@
+//! Where `TimeLimiter`s get the time from.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A source of time for a `TimeLimiter`.
+pub trait Clock {
+    /// The time since some fixed starting point. Must never go backwards.
+    fn now(&self) -> Duration;
+}
+
+/// The system's monotonic clock; what `TimeLimiter`s use by default.
+#[derive(Clone, Copy, Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+impl SystemClock {
+    pub fn new() -> Self {
+        SystemClock { start: Instant::now() }
+    }
+}
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock::new()
+    }
+}
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+/// A clock that only moves when it's told to, for tests and deterministic (e.g. lockstep) simulations.
+///
+/// Clones share the same time, so keep one to drive the clock and give the others to `TimeLimiter`s:
+///
+/// ```
+/// # extern crate soft_time_limit;
+/// # use soft_time_limit::{ManualClock, TimeLimiter};
+/// # use std::time::Duration;
+/// let clock = ManualClock::new();
+/// let mut limit = TimeLimiter::new().with_clock(clock.clone());
+/// let mut tasks = 0;
+/// limit.repeat_with_budget(Duration::from_millis(10), || {
+///     // each task takes 3ms of simulated time
+///     clock.advance(Duration::from_millis(3));
+///     tasks += 1;
+///     true
+/// });
+/// assert_eq!(tasks, 4);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ManualClock {
+    now: Arc<Mutex<Duration>>,
+}
+impl ManualClock {
+    /// A clock starting at zero.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Move the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+}
+impl Clock for ManualClock {
+    fn now(&self) -> Duration {
+        *self.now.lock().unwrap()
+    }
+}
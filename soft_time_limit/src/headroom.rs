@@ -0,0 +1,107 @@
+//! Scaling budgets up and down with how much of the frame is left over.
+
+use super::{to_duration, to_float};
+use std::time::Duration;
+
+/// Scales time budgets by how far under (or over) a target frame time the game is running,
+/// so background work backs off when the game is struggling and speeds up when it's idle.
+///
+/// Call `update` once a frame with the time the last frame took, and pass budgets through `budget`:
+///
+/// ```
+/// # extern crate soft_time_limit;
+/// # use soft_time_limit::{Headroom, TimeLimiter};
+/// # use std::time::Duration;
+/// let mut headroom = Headroom::new(60.0);
+/// let mut limit = TimeLimiter::new();
+/// # let last_frame_time = Duration::from_millis(20);
+/// headroom.update(last_frame_time);
+/// limit.repeat_with_budget(headroom.budget(Duration::from_millis(3)), || {
+///     // ... do something expensive ...
+///     # false
+/// });
+/// ```
+#[derive(Clone, Debug)]
+pub struct Headroom {
+    /// The frame time we're aiming for.
+    pub target: Duration,
+    /// How quickly the scale responds to frame times: each update, it's multiplied by
+    /// `1 + rate * (target - frame_time) / target`.
+    pub rate: f64,
+    /// The smallest the scale can get.
+    pub min_scale: f64,
+    /// The largest the scale can get.
+    pub max_scale: f64,
+    scale: f64,
+}
+impl Headroom {
+    /// Aim for `target_fps` frames per second, with the default rate (0.1) and scales (0.25 to 4).
+    pub fn new(target_fps: f64) -> Self {
+        assert!(target_fps > 0.0, "target_fps must be positive");
+        Headroom {
+            target: to_duration(1.0 / target_fps),
+            rate: 0.1,
+            min_scale: 0.25,
+            max_scale: 4.0,
+            scale: 1.0,
+        }
+    }
+
+    /// Adjust the scale, given the time the last frame took.
+    pub fn update(&mut self, frame_time: Duration) {
+        let target = to_float(self.target);
+        // frames that took much longer than the target shouldn't drop the scale to nothing all at once
+        let headroom = ((target - to_float(frame_time)) / target).max(-1.0);
+        self.scale = (self.scale * (1.0 + self.rate * headroom))
+            .max(self.min_scale)
+            .min(self.max_scale);
+    }
+
+    /// The current scale; 1 until the first `update`.
+    pub fn scale(&self) -> f64 {
+        self.scale
+    }
+
+    /// `budget`, scaled.
+    pub fn budget(&self, budget: Duration) -> Duration {
+        to_duration(to_float(budget) * self.scale)
+    }
+}
+
+impl Default for Headroom {
+    fn default() -> Self {
+        Headroom::new(60.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scaling() {
+        let mut headroom = Headroom::new(50.0);
+        assert_eq!(headroom.budget(Duration::from_millis(4)), Duration::from_millis(4));
+
+        // idle frames scale up, to the limit
+        headroom.update(Duration::from_millis(10));
+        assert!((headroom.scale() - 1.05).abs() < 1e-9);
+        for _ in 0..1000 {
+            headroom.update(Duration::from_millis(10));
+        }
+        assert_eq!(headroom.scale(), 4.0);
+
+        // slow frames scale down
+        headroom.update(Duration::from_millis(1000));
+        assert!((headroom.scale() - 3.6).abs() < 1e-9);
+        for _ in 0..1000 {
+            headroom.update(Duration::from_millis(30));
+        }
+        assert_eq!(headroom.budget(Duration::from_millis(4)), Duration::from_millis(1));
+
+        // frames right on target leave it alone
+        let scale = headroom.scale();
+        headroom.update(Duration::from_millis(20));
+        assert_eq!(headroom.scale(), scale);
+    }
+}
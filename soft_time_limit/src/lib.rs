@@ -54,6 +54,8 @@
 //!
 //! If you have multiple tasks you want to limit, you should create a *separate* `TimeLimiter` for each task.
 //! That is, you should never call two different closures with the same `TimeLimiter`.
+//! To split one per-frame budget between several of them, see `BudgetScheduler`;
+//! to give them more or less time depending on how fast the game is running, see `Headroom`.
 //!
 //! You can also explicitly manage the time frame you have available:
 //!
@@ -78,16 +80,37 @@
 //! ```
 //!
 //! Note: This crate is very low-overhead and never invokes thread sleeps;
-//! it just chooses whether to call your function or not based on the system time.
+//! it just chooses whether to call your function or not based on the system time
+//! (or another `Clock`, like a `ManualClock` for tests; see `TimeLimiter::with_clock`).
 //! However, it won't magically make your tasks faster.
 //! You'll still need to make sure they complete in a reasonable amount of time 😉
 
-use std::time::{Duration, Instant};
+extern crate fnv;
+
+use fnv::FnvHasher;
+use std::cell::Cell;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{Hash, Hasher};
+use std::fmt;
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::Duration;
+
+mod clock;
+mod headroom;
+mod scheduler;
+
+pub use clock::{Clock, ManualClock, SystemClock};
+pub use headroom::Headroom;
+pub use scheduler::{BudgetId, BudgetScheduler};
 
 /// Keeps track of the time taken by some task.
+///
+/// Time comes from a `Clock`: the system clock by default, or another one given with `with_clock`.
 #[derive(Clone, Debug)]
-pub struct TimeLimiter {
-    /// A running average of the time taken by the task in the past.
+pub struct TimeLimiter<C = SystemClock> {
+    /// A running average of the time taken by the task in the past
+    /// (or a percentile of recent times; see `with_percentile`).
     /// In units of seconds.
     pub time_estimate: f64,
     /// The proportion used in the running average:
@@ -102,6 +125,24 @@ pub struct TimeLimiter {
     /// if the time estimate went over the per-frame time budget.
     /// This way the system is guaranteed to at least run one task every few frames.
     pub decay: f64,
+    /// The most time a frame can bank for later frames when it runs out of work before it runs out of
+    /// budget; zero (the default) to turn carrying over off.
+    ///
+    /// The next frame gets the unused time (up to this cap) on top of its own budget. This suits
+    /// workloads like saving or generation that come in bursts and can catch up in quiet frames.
+    /// Note that a frame can then take up to `carry_over` longer than its budget.
+    pub carry_over: Duration,
+    /// Counters for the current (or most recent) frame.
+    stats: FrameStats,
+    /// Whether the current (or most recent) frame ran out of time.
+    ran_out: bool,
+    /// Separate running averages for each kind of task, by hash of their key; see `frame_keyed`.
+    keyed: HashMap<u64, f64>,
+    /// Recent task times, if we're estimating with a percentile instead of an average.
+    percentile: Option<Percentile>,
+    /// Called when a task goes too far over budget; see `on_overshoot`.
+    overshoot_hook: Option<OvershootHook>,
+    clock: C,
 }
 impl TimeLimiter {
     /// Create a TimeLimiter with the default averaging rates (0.1 smoothing, 0.99 decay)
@@ -120,32 +161,207 @@ impl TimeLimiter {
             smoothing,
             decay,
             time_estimate: 0.0,
+            carry_over: Duration::new(0, 0),
+            stats: FrameStats::default(),
+            ran_out: false,
+            keyed: HashMap::new(),
+            percentile: None,
+            overshoot_hook: None,
+            clock: SystemClock::new(),
+        }
+    }
+
+    /// Create a TimeLimiter that estimates the time a task will take as the given `percentile` (from 0 to 1)
+    /// of the times taken by the last `window` tasks, rather than their average.
+    ///
+    /// With a high percentile (say, 0.9) this is conservative about occasional slow tasks, which would
+    /// otherwise blow the budget every time they came up. Estimates still decay every frame, as with `decay`.
+    /// Estimates for keyed frames are still running averages.
+    pub fn with_percentile(percentile: f64, window: usize) -> TimeLimiter {
+        assert!((0.0..=1.0).contains(&percentile), "percentile must be between 0 and 1");
+        assert!(window > 0, "window must not be empty");
+        TimeLimiter {
+            percentile: Some(Percentile {
+                percentile,
+                window,
+                samples: VecDeque::with_capacity(window),
+            }),
+            ..TimeLimiter::new()
+        }
+    }
+
+    /// Create a TimeLimiter with the default averaging rates, starting from estimates saved with `state`.
+    pub fn from_state(state: &TimeLimiterState) -> TimeLimiter {
+        let mut limiter = TimeLimiter::new();
+        limiter.restore(state);
+        limiter
+    }
+
+    /// This TimeLimiter, getting the time from `clock` instead of the system clock;
+    /// e.g. `TimeLimiter::new().with_clock(ManualClock::new())`.
+    pub fn with_clock<C: Clock>(self, clock: C) -> TimeLimiter<C> {
+        TimeLimiter {
+            time_estimate: self.time_estimate,
+            smoothing: self.smoothing,
+            decay: self.decay,
+            carry_over: self.carry_over,
+            stats: self.stats,
+            ran_out: self.ran_out,
+            keyed: self.keyed,
+            percentile: self.percentile,
+            overshoot_hook: self.overshoot_hook,
+            clock,
         }
     }
+}
+
+impl<C: Clock> TimeLimiter<C> {
 
     /// Repeatedly calls a function until either:
     /// 1. The estimated time to complete the task goes over the time budget, OR
     /// 2. The function returns false.
-    pub fn repeat_with_budget<F: FnMut() -> bool>(&mut self, budget: Duration, mut f: F) {
-        let mut frame = self.frame(budget);
+    ///
+    /// Returns how many times it was called, and which of those happened.
+    pub fn repeat_with_budget<F: FnMut() -> bool>(&mut self, budget: Duration, mut f: F) -> Repeated {
+        let start = self.clock.now();
+        let mut tasks = 0;
+        let mut finished = false;
+        {
+            let mut frame = self.frame(budget);
 
-        while frame.have_time() {
-            let _task = frame.time_task();
-            let should_continue = f();
-            if !should_continue {
-                break;
+            while frame.have_time() {
+                let _task = frame.time_task();
+                tasks += 1;
+                let should_continue = f();
+                if !should_continue {
+                    finished = true;
+                    break;
+                }
             }
         }
+        Repeated {
+            tasks,
+            elapsed: self.clock.now() - start,
+            finished,
+        }
     }
 
     /// Manually start timing a single frame.
-    pub fn frame(&mut self, budget: Duration) -> Frame {
+    pub fn frame(&mut self, budget: Duration) -> Frame<'_, C> {
         self.time_estimate *= self.decay;
+        for estimate in self.keyed.values_mut() {
+            *estimate *= self.decay;
+        }
+        if let Some(ref mut percentile) = self.percentile {
+            for sample in &mut percentile.samples {
+                *sample *= self.decay;
+            }
+        }
+        // whatever the last frame didn't use because it ran out of work, up to the cap
+        let unused = if self.ran_out {
+            0.0
+        } else {
+            to_float(self.stats.budget) - to_float(self.stats.spent)
+        };
+        let carried = to_duration(unused.max(0.0).min(to_float(self.carry_over)));
+        let budget = budget + carried;
+
+        self.stats = FrameStats {
+            budget,
+            carried,
+            ..FrameStats::default()
+        };
+        self.ran_out = false;
         Frame {
+            deadline: self.clock.now() + budget,
+            ran_out: Cell::new(false),
             limiter: self,
-            deadline: Instant::now() + budget,
         }
     }
+
+    /// Manually start timing a single frame of tasks that take different amounts of time depending on
+    /// their kind (say, small chunks and huge chunks), keeping a separate time estimate for each kind.
+    ///
+    /// Kinds are identified by keys of type `K`; the limiter remembers an estimate for every key
+    /// it's seen, so use a handful of kinds, not one per task.
+    pub fn frame_keyed<K: Hash>(&mut self, budget: Duration) -> KeyedFrame<'_, K, C> {
+        KeyedFrame {
+            frame: self.frame(budget),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Update the time estimate with the time a task took, in seconds. Times that aren't finite numbers are
+    /// ignored, rather than spoiling the estimate.
+    fn record(&mut self, duration: f64) {
+        if !duration.is_finite() {
+            return;
+        }
+        match self.percentile {
+            Some(ref mut percentile) => self.time_estimate = percentile.record(duration),
+            None => {
+                self.time_estimate = self.time_estimate * (1.0 - self.smoothing) + duration * self.smoothing;
+            }
+        }
+    }
+
+    /// What happened in the current frame, or the most recent one if we're between frames;
+    /// e.g. for reporting budget health, or tuning `smoothing` and `decay`.
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// The running average of the time taken by tasks of kind `key`, in seconds,
+    /// if any have been timed with `frame_keyed`.
+    pub fn estimate_for<K: Hash>(&self, key: &K) -> Option<f64> {
+        self.keyed.get(&hash_key(key)).cloned()
+    }
+
+    /// The estimates this limiter has learned so far, e.g. to save at exit, so the next session can start
+    /// with `from_state` or `restore` instead of mispredicting for a while after startup.
+    pub fn state(&self) -> TimeLimiterState {
+        let mut keyed: Vec<(u64, f64)> = self.keyed.iter().map(|(&key, &estimate)| (key, estimate)).collect();
+        keyed.sort_by_key(|&(key, _)| key);
+        TimeLimiterState {
+            time_estimate: self.time_estimate,
+            keyed,
+            samples: self
+                .percentile
+                .as_ref()
+                .map_or(Vec::new(), |percentile| percentile.samples.iter().cloned().collect()),
+        }
+    }
+
+    /// Replace this limiter's estimates with ones saved with `state`, keeping its rates and clock.
+    /// Saved samples are only used if this limiter estimates with a percentile, and only as many as fit
+    /// in its window.
+    pub fn restore(&mut self, state: &TimeLimiterState) {
+        self.time_estimate = state.time_estimate;
+        self.keyed = state.keyed.iter().cloned().collect();
+        if let Some(ref mut percentile) = self.percentile {
+            let skip = state.samples.len().saturating_sub(percentile.window);
+            percentile.samples = state.samples[skip..]
+                .iter()
+                .cloned()
+                .filter(|sample| sample.is_finite())
+                .collect();
+        }
+    }
+
+    /// Call `hook` whenever a task finishes more than `threshold` after the frame's deadline,
+    /// e.g. to log what was slow, or to switch to a cheaper way of doing things.
+    /// Replaces any earlier hook.
+    pub fn on_overshoot<F: Fn(&Overshoot) + Send + Sync + 'static>(&mut self, threshold: Duration, hook: F) {
+        self.overshoot_hook = Some(OvershootHook {
+            threshold,
+            hook: Arc::new(hook),
+        });
+    }
+
+    /// The clock we get the time from.
+    pub fn clock(&self) -> &C {
+        &self.clock
+    }
 }
 
 impl Default for TimeLimiter {
@@ -154,45 +370,333 @@ impl Default for TimeLimiter {
     }
 }
 
+/// What happened in a call to `TimeLimiter::repeat_with_budget`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Repeated {
+    /// The number of times the function was called.
+    pub tasks: u32,
+    /// The time taken by the whole call.
+    pub elapsed: Duration,
+    /// Whether the function returned false; if not, we stopped because we ran out of time.
+    pub finished: bool,
+}
+
+/// The estimates learned by a `TimeLimiter`; see `TimeLimiter::state`.
+///
+/// This is plain data, so it's easy to save however you save everything else, or it can be saved as bytes with
+/// `to_bytes`. Keyed estimates are stored by an FNV hash of their key, which doesn't change between runs or
+/// versions of Rust, as long as the key's `Hash` impl doesn't; if it does, the worst that happens is that they're
+/// ignored.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TimeLimiterState {
+    /// See `TimeLimiter::time_estimate`.
+    pub time_estimate: f64,
+    /// The estimates for each kind of task, by hash of their key; see `TimeLimiter::frame_keyed`.
+    pub keyed: Vec<(u64, f64)>,
+    /// Recent task times, oldest first, if the limiter estimates with a percentile.
+    pub samples: Vec<f64>,
+}
+impl TimeLimiterState {
+    /// The state as bytes, to read back with `from_bytes`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![STATE_VERSION];
+        put_u64(&mut bytes, self.time_estimate.to_bits());
+        put_u64(&mut bytes, self.keyed.len() as u64);
+        for &(key, estimate) in &self.keyed {
+            put_u64(&mut bytes, key);
+            put_u64(&mut bytes, estimate.to_bits());
+        }
+        put_u64(&mut bytes, self.samples.len() as u64);
+        for &sample in &self.samples {
+            put_u64(&mut bytes, sample.to_bits());
+        }
+        bytes
+    }
+
+    /// Read a state written by `to_bytes`; None if the bytes are truncated, too long, from an unknown version, or
+    /// hold an estimate or sample that isn't a finite number.
+    pub fn from_bytes(bytes: &[u8]) -> Option<TimeLimiterState> {
+        if bytes.first() != Some(&STATE_VERSION) {
+            return None;
+        }
+        let mut words = bytes[1..].chunks(8).map(|word| {
+            if word.len() == 8 {
+                Some(word.iter().rev().fold(0, |value, &byte| value << 8 | u64::from(byte)))
+            } else {
+                None
+            }
+        });
+        let mut next = || words.next().and_then(|word| word);
+        let mut state = TimeLimiterState {
+            time_estimate: finite(next()?)?,
+            ..TimeLimiterState::default()
+        };
+        for _ in 0..next()? {
+            let key = next()?;
+            state.keyed.push((key, finite(next()?)?));
+        }
+        for _ in 0..next()? {
+            state.samples.push(finite(next()?)?);
+        }
+        match next() {
+            None => Some(state),
+            Some(_) => None,
+        }
+    }
+}
+
+/// The version of `TimeLimiterState::to_bytes`' format.
+const STATE_VERSION: u8 = 1;
+
+/// The f64 with these bits, if it's a finite number.
+fn finite(bits: u64) -> Option<f64> {
+    Some(f64::from_bits(bits)).filter(|value| value.is_finite())
+}
+
+fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    for i in 0..8 {
+        bytes.push((value >> (8 * i)) as u8);
+    }
+}
+
+/// A task that went over budget; see `TimeLimiter::on_overshoot`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Overshoot {
+    /// The time the task took.
+    pub duration: Duration,
+    /// The time we expected it to take, in seconds.
+    pub estimate: f64,
+    /// How long after the deadline it finished.
+    pub over: Duration,
+}
+
+#[derive(Clone)]
+struct OvershootHook {
+    threshold: Duration,
+    hook: Arc<dyn Fn(&Overshoot) + Send + Sync>,
+}
+impl fmt::Debug for OvershootHook {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("OvershootHook")
+            .field("threshold", &self.threshold)
+            .finish()
+    }
+}
+
+/// A sliding window of task times; see `TimeLimiter::with_percentile`.
+#[derive(Clone, Debug)]
+struct Percentile {
+    percentile: f64,
+    window: usize,
+    samples: VecDeque<f64>,
+}
+impl Percentile {
+    /// Add a sample, and return the new estimate.
+    fn record(&mut self, duration: f64) -> f64 {
+        if self.samples.len() == self.window {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(duration);
+
+        let mut sorted: Vec<f64> = self.samples.iter().cloned().collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        // nearest rank
+        let rank = (self.percentile * sorted.len() as f64).ceil() as usize;
+        sorted[rank.max(1) - 1]
+    }
+}
+
+/// Counters for a single frame; see `TimeLimiter::stats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct FrameStats {
+    /// The frame's time budget, including time carried over.
+    pub budget: Duration,
+    /// Time carried over from earlier frames; see `TimeLimiter::carry_over`.
+    pub carried: Duration,
+    /// The number of tasks timed.
+    pub tasks: u32,
+    /// The total time taken by those tasks.
+    pub spent: Duration,
+    /// The total difference between the time we expected each task to take and the time it took.
+    pub prediction_error: Duration,
+    /// The number of tasks that finished after the deadline.
+    pub overshoots: u32,
+}
+impl FrameStats {
+    /// The average difference between the time we expected a task to take and the time it took.
+    pub fn mean_prediction_error(&self) -> Duration {
+        if self.tasks == 0 {
+            Duration::new(0, 0)
+        } else {
+            self.prediction_error / self.tasks
+        }
+    }
+}
+
 /// A lock representing a single frame.
-pub struct Frame<'a> {
-    limiter: &'a mut TimeLimiter,
-    deadline: Instant,
+pub struct Frame<'a, C: 'a = SystemClock> {
+    limiter: &'a mut TimeLimiter<C>,
+    /// In the time of the limiter's clock.
+    deadline: Duration,
+    /// Whether `have_time` has said no; copied to the limiter at the end of the frame.
+    ran_out: Cell<bool>,
+}
+
+impl<'a, C: 'a> Drop for Frame<'a, C> {
+    fn drop(&mut self) {
+        self.limiter.ran_out = self.ran_out.get();
+    }
 }
 
-impl<'a> Frame<'a> {
+impl<'a, C: 'a + Clock> Frame<'a, C> {
     /// Whether or not there's enough time available to perform one of our tasks.
     pub fn have_time(&self) -> bool {
-        let result = Instant::now() + to_duration(self.limiter.time_estimate) < self.deadline;
-        result
+        let estimate = self.limiter.time_estimate;
+        self.have_time_with(estimate)
     }
 
     /// Create a Task; when it is dropped, we'll compute the elapsed time and update
     /// our time estimates.
-    pub fn time_task<'b>(&'b mut self) -> Task<'b, 'a> {
-        Task {
-            frame: self,
-            start: Instant::now(),
+    pub fn time_task<'b>(&'b mut self) -> Task<'b, 'a, C> {
+        let predicted = self.limiter.time_estimate;
+        Task::new(self, None, predicted)
+    }
+
+    fn have_time_with(&self, estimate: f64) -> bool {
+        let result = self.limiter.clock.now() + to_duration(estimate) < self.deadline;
+        if !result {
+            self.ran_out.set(true);
         }
+        result
+    }
+}
+
+/// A lock representing a single frame of tasks of different kinds; see `TimeLimiter::frame_keyed`.
+pub struct KeyedFrame<'a, K, C: 'a = SystemClock> {
+    frame: Frame<'a, C>,
+    _phantom: PhantomData<K>,
+}
+
+impl<'a, K: Hash, C: 'a + Clock> KeyedFrame<'a, K, C> {
+    /// Whether or not there's enough time available to perform a task of kind `key`.
+    /// For a kind we haven't timed yet, this uses the estimate for all tasks together.
+    pub fn have_time_for(&self, key: &K) -> bool {
+        self.frame.have_time_with(self.estimate_for(key))
+    }
+
+    /// Whether or not there's enough time available to perform a typical task of any kind.
+    pub fn have_time(&self) -> bool {
+        self.frame.have_time()
+    }
+
+    /// Create a Task of kind `key`; when it is dropped, we'll update the time estimate for that kind,
+    /// as well as the one for all tasks together.
+    pub fn time_task<'b>(&'b mut self, key: &K) -> Task<'b, 'a, C> {
+        let predicted = self.estimate_for(key);
+        Task::new(&mut self.frame, Some(hash_key(key)), predicted)
+    }
+
+    fn estimate_for(&self, key: &K) -> f64 {
+        let limiter = &self.frame.limiter;
+        limiter.keyed.get(&hash_key(key)).cloned().unwrap_or(limiter.time_estimate)
     }
 }
 
 /// A lock representing a single task within a frame.
-pub struct Task<'b, 'a: 'b> {
-    frame: &'b mut Frame<'a>,
-    start: Instant,
+pub struct Task<'b, 'a: 'b, C: 'a + Clock = SystemClock> {
+    frame: &'b mut Frame<'a, C>,
+    start: Duration,
+    /// The hashed key of the task's kind, for keyed frames.
+    key: Option<u64>,
+    /// The time we expected the task to take, in seconds.
+    predicted: f64,
+    /// When the last checkpoint (or the task) started.
+    step_start: Duration,
+    /// The number of checkpoints so far.
+    steps: u32,
+    /// The average time between checkpoints, in seconds.
+    step_estimate: f64,
+}
+
+impl<'b, 'a: 'b, C: 'a + Clock> Task<'b, 'a, C> {
+    fn new(frame: &'b mut Frame<'a, C>, key: Option<u64>, predicted: f64) -> Self {
+        let start = frame.limiter.clock.now();
+        Task {
+            frame,
+            start,
+            key,
+            predicted,
+            step_start: start,
+            steps: 0,
+            step_estimate: 0.0,
+        }
+    }
+
+    /// Mark the end of a step of a task that can be split up (say, propagating light one layer at a time),
+    /// returning the time the step took. Together with `should_yield`, this lets a long task stop partway
+    /// through when the frame runs out of time, and pick up where it left off next frame.
+    pub fn checkpoint(&mut self) -> Duration {
+        let now = self.frame.limiter.clock.now();
+        let step = now - self.step_start;
+        self.steps += 1;
+        self.step_estimate += (to_float(step) - self.step_estimate) / self.steps as f64;
+        self.step_start = now;
+        step
+    }
+
+    /// Whether there isn't enough time left in the frame for another step like the ones so far;
+    /// if so, the task should save its progress and stop.
+    ///
+    /// The time a task takes is recorded as usual when it's dropped, whether it finished or not,
+    /// so tasks that often stop partway will have low time estimates.
+    pub fn should_yield(&self) -> bool {
+        !self.frame.have_time_with(self.step_estimate)
+    }
 }
 
-impl<'b, 'a: 'b> Drop for Task<'b, 'a> {
+impl<'b, 'a: 'b, C: 'a + Clock> Drop for Task<'b, 'a, C> {
     fn drop(&mut self) {
-        let duration = Instant::now() - self.start;
+        let end = self.frame.limiter.clock.now();
+        let duration = to_float(end - self.start);
+        let overshot = end > self.frame.deadline;
         let limiter = &mut self.frame.limiter;
 
-        limiter.time_estimate = limiter.time_estimate * (1.0 - limiter.smoothing)
-            + to_float(duration) * limiter.smoothing;
+        limiter.record(duration);
+
+        limiter.stats.tasks += 1;
+        limiter.stats.spent += end - self.start;
+        limiter.stats.prediction_error += to_duration((duration - self.predicted).abs());
+        if overshot {
+            limiter.stats.overshoots += 1;
+            let over = end - self.frame.deadline;
+            match limiter.overshoot_hook {
+                Some(ref hook) if over > hook.threshold => (hook.hook)(&Overshoot {
+                    duration: end - self.start,
+                    estimate: self.predicted,
+                    over,
+                }),
+                _ => (),
+            }
+        }
+
+        if let Some(key) = self.key {
+            let smoothing = limiter.smoothing;
+            // the first task of a kind is all we know about it
+            limiter
+                .keyed
+                .entry(key)
+                .and_modify(|estimate| *estimate = *estimate * (1.0 - smoothing) + duration * smoothing)
+                .or_insert(duration);
+        }
     }
 }
 
+fn hash_key<K: Hash>(key: &K) -> u64 {
+    let mut hasher = FnvHasher::default();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
 fn to_float(duration: Duration) -> f64 {
     duration.as_secs() as f64 + 0.000_000_001 * duration.subsec_nanos() as f64
 }
@@ -205,7 +709,8 @@ fn to_duration(duration: f64) -> Duration {
 
 #[cfg(test)]
 mod tests {
-    use super::TimeLimiter;
+    use super::{to_float, ManualClock, TimeLimiter, TimeLimiterState};
+    use std::sync::{Arc, Mutex};
     use std::thread::sleep;
     use std::time::Duration;
 
@@ -258,4 +763,228 @@ mod tests {
         );
     }
 
+    #[test]
+    fn stats() {
+        let clock = ManualClock::new();
+        let mut limit = TimeLimiter::new().with_clock(clock.clone());
+        {
+            let mut frame = limit.frame(Duration::from_millis(1));
+            let _task = frame.time_task();
+            clock.advance(Duration::from_millis(3));
+        }
+        let stats = limit.stats();
+        assert_eq!((stats.tasks, stats.overshoots), (1, 1));
+        // the first task was expected to take no time at all
+        assert_eq!(stats.spent, Duration::from_millis(3));
+        assert!((to_float(stats.prediction_error) - 0.003).abs() < 1e-6);
+
+        let repeated = limit.repeat_with_budget(Duration::from_millis(10), || false);
+        assert_eq!((repeated.tasks, repeated.finished), (1, true));
+        assert_eq!(limit.stats().tasks, 1);
+        assert_eq!(limit.stats().overshoots, 0);
+    }
+
+    #[test]
+    fn carry_over() {
+        let mut limit = TimeLimiter::new();
+        limit.frame(Duration::from_millis(3));
+        limit.frame(Duration::from_millis(3));
+        assert_eq!(limit.stats().budget, Duration::from_millis(3));
+
+        // idle frames bank their budget, up to the cap
+        limit.carry_over = Duration::from_millis(5);
+        limit.frame(Duration::from_millis(3));
+        assert_eq!(limit.stats().carried, Duration::from_millis(3));
+        assert_eq!(limit.stats().budget, Duration::from_millis(6));
+        limit.frame(Duration::from_millis(3));
+        assert_eq!(limit.stats().carried, Duration::from_millis(5));
+        assert_eq!(limit.stats().budget, Duration::from_millis(8));
+
+        // busy frames don't
+        let repeated = limit.repeat_with_budget(Duration::from_millis(3), || true);
+        assert!(!repeated.finished);
+        assert!(repeated.tasks > 0 && repeated.elapsed >= Duration::from_millis(7));
+        limit.frame(Duration::from_millis(3));
+        assert!(limit.stats().carried < Duration::from_millis(1));
+    }
+
+    #[test]
+    fn percentile() {
+        let mut average = TimeLimiter::new();
+        let mut p90 = TimeLimiter::with_percentile(0.9, 10);
+
+        // one slow task in five
+        for i in 0..20 {
+            let duration = if i % 5 == 0 { 0.010 } else { 0.001 };
+            average.record(duration);
+            p90.record(duration);
+        }
+        assert!(average.time_estimate < 0.005);
+        assert_eq!(p90.time_estimate, 0.010);
+
+        // the slow tasks slide out of the window
+        for _ in 0..10 {
+            p90.record(0.001);
+        }
+        assert_eq!(p90.time_estimate, 0.001);
+
+        // and estimates still decay, so a run of very slow tasks can't lock us up
+        for _ in 0..10 {
+            p90.record(1.0);
+        }
+        assert!(!p90.frame(Duration::from_millis(1)).have_time());
+        for _ in 0..1000 {
+            p90.frame(Duration::from_millis(1));
+        }
+        assert!(p90.frame(Duration::from_millis(1)).have_time());
+    }
+
+    #[test]
+    fn checkpoints() {
+        let clock = ManualClock::new();
+        let mut limit = TimeLimiter::new().with_clock(clock.clone());
+        let mut steps = 0;
+        {
+            let mut frame = limit.frame(Duration::from_millis(10));
+            let mut task = frame.time_task();
+            while !task.should_yield() {
+                clock.advance(Duration::from_millis(2));
+                assert_eq!(task.checkpoint(), Duration::from_millis(2));
+                steps += 1;
+            }
+        }
+        // a fifth step would end exactly on the deadline
+        assert_eq!(steps, 4);
+        assert_eq!(limit.stats().overshoots, 0);
+    }
+
+    #[test]
+    fn overshoot_hook() {
+        let clock = ManualClock::new();
+        let mut limit = TimeLimiter::new().with_clock(clock.clone());
+        let overshoots = Arc::new(Mutex::new(Vec::new()));
+        {
+            let overshoots = overshoots.clone();
+            limit.on_overshoot(Duration::from_millis(2), move |overshoot| {
+                overshoots.lock().unwrap().push(*overshoot);
+            });
+        }
+
+        // the fourth task takes twice as long as the others, and finishes 5ms late
+        let mut frame = limit.frame(Duration::from_millis(10));
+        for &millis in &[3, 3, 3, 6] {
+            assert!(frame.have_time());
+            let _task = frame.time_task();
+            clock.advance(Duration::from_millis(millis));
+        }
+        let overshoots = overshoots.lock().unwrap();
+        assert_eq!(overshoots.len(), 1);
+        assert_eq!(overshoots[0].duration, Duration::from_millis(6));
+        assert_eq!(overshoots[0].over, Duration::from_millis(5));
+        assert!(overshoots[0].estimate > 0.0 && overshoots[0].estimate < 0.003);
+    }
+
+    #[test]
+    fn state() {
+        let clock = ManualClock::new();
+        let mut limit = TimeLimiter::with_percentile(0.5, 2).with_clock(clock.clone());
+        {
+            let mut frame = limit.frame_keyed(Duration::from_millis(100));
+            for &(key, millis) in &[("small", 1), ("huge", 20), ("small", 1)] {
+                let _task = frame.time_task(&key);
+                clock.advance(Duration::from_millis(millis));
+            }
+        }
+        let state = limit.state();
+        assert_eq!(state.samples.len(), 2);
+        assert_eq!(state.keyed.len(), 2);
+
+        // a new session picks up where the old one left off
+        let restored = TimeLimiter::from_state(&state);
+        assert_eq!(restored.time_estimate, limit.time_estimate);
+        assert_eq!(restored.estimate_for(&"huge"), limit.estimate_for(&"huge"));
+        assert!(restored.state().samples.is_empty());
+
+        let mut restored = TimeLimiter::with_percentile(0.5, 1);
+        restored.restore(&state);
+        assert_eq!(restored.state().samples, vec![state.samples[1]]);
+
+        let bytes = state.to_bytes();
+        assert_eq!(TimeLimiterState::from_bytes(&bytes), Some(state.clone()));
+        assert_eq!(TimeLimiterState::from_bytes(&bytes[..bytes.len() - 1]), None);
+        assert_eq!(TimeLimiterState::from_bytes(&[]), None);
+        let mut longer = bytes.clone();
+        longer.extend_from_slice(&[0; 8]);
+        assert_eq!(TimeLimiterState::from_bytes(&longer), None);
+    }
+
+    #[test]
+    fn corrupt_state() {
+        // a saved NaN or infinity is turned away, rather than crashing the limiter that's given it
+        let good = TimeLimiterState {
+            time_estimate: 0.001,
+            keyed: vec![(3, 0.002)],
+            samples: vec![0.001, 0.003],
+        };
+        assert_eq!(TimeLimiterState::from_bytes(&good.to_bytes()), Some(good.clone()));
+        for &bad in &[f64::NAN, f64::INFINITY] {
+            let mut state = good.clone();
+            state.samples[1] = bad;
+            assert_eq!(TimeLimiterState::from_bytes(&state.to_bytes()), None);
+            let mut state = good.clone();
+            state.keyed[0].1 = bad;
+            assert_eq!(TimeLimiterState::from_bytes(&state.to_bytes()), None);
+            let mut state = good.clone();
+            state.time_estimate = bad;
+            assert_eq!(TimeLimiterState::from_bytes(&state.to_bytes()), None);
+        }
+
+        // and one that gets in some other way is dropped, as are bad task times
+        let mut state = good.clone();
+        state.samples.push(f64::NAN);
+        let mut limit = TimeLimiter::with_percentile(0.5, 3);
+        limit.restore(&state);
+        assert_eq!(limit.state().samples, good.samples);
+        limit.record(f64::NAN);
+        limit.record(0.002);
+        assert_eq!(limit.state().samples, vec![0.001, 0.003, 0.002]);
+        assert_eq!(limit.time_estimate, 0.002);
+    }
+
+    #[test]
+    fn timing_keyed() {
+        let clock = ManualClock::new();
+        let mut limit = TimeLimiter::new().with_clock(clock.clone());
+
+        for _ in 0..3 {
+            let mut frame = limit.frame_keyed(Duration::from_millis(50));
+            for &kind in &["small", "huge", "small"] {
+                if frame.have_time_for(&kind) {
+                    let _task = frame.time_task(&kind);
+                    if kind == "huge" {
+                        clock.advance(Duration::from_millis(5));
+                    }
+                }
+            }
+        }
+
+        let small = limit.estimate_for(&"small").unwrap();
+        let huge = limit.estimate_for(&"huge").unwrap();
+        assert_eq!(small, 0.0);
+        assert!((huge - 0.005).abs() < 1e-4, "huge: {}", huge);
+        assert!(limit.estimate_for(&"medium").is_none());
+
+        // each kind was predicted well, once we'd seen one
+        let stats = limit.stats();
+        assert_eq!((stats.tasks, stats.overshoots), (3, 0));
+        assert_eq!(stats.budget, Duration::from_millis(50));
+        assert_eq!(stats.spent, Duration::from_millis(5));
+        assert!(stats.mean_prediction_error() < Duration::from_millis(1) / 10);
+
+        // with huge tasks estimated at 5ms, a 3ms budget only has room for small ones
+        let frame = limit.frame_keyed(Duration::from_millis(3));
+        assert!(frame.have_time_for(&"small"));
+        assert!(!frame.have_time_for(&"huge"));
+    }
+
 }
@@ -0,0 +1,254 @@
+//! Sharing one per-frame time budget between several `TimeLimiter`s.
+
+use super::{to_duration, to_float, Clock, Frame, Repeated, SystemClock, TimeLimiter};
+use std::time::Duration;
+
+/// Identifies a `TimeLimiter` registered with a `BudgetScheduler`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BudgetId(usize);
+
+/// Splits a total per-frame time budget between several budgeted tasks (meshing, lighting, saving, ...),
+/// so they don't each have to guess at their own slice of the frame.
+///
+/// Every frame, call `plan` once, then run each task with `frame` or `repeat_with_budget`.
+/// Each task's fair share of the total is proportional to its weight. Tasks that didn't need all of
+/// their share last frame get a bit more than they used; the time left over goes to the tasks that
+/// ran out of time last frame, split by weight between those with the highest priority.
+///
+/// ```
+/// # extern crate soft_time_limit;
+/// # use soft_time_limit::{BudgetScheduler, TimeLimiter};
+/// # use std::time::Duration;
+/// # let mut chunks_to_mesh = vec![1, 2, 3];
+/// # let mut game_over = false;
+/// let mut scheduler = BudgetScheduler::new(Duration::from_millis(8));
+/// let meshing = scheduler.register(TimeLimiter::new(), 2.0, 1);
+/// let saving = scheduler.register(TimeLimiter::new(), 1.0, 0);
+///
+/// while !game_over {
+///     scheduler.plan();
+///     scheduler.repeat_with_budget(meshing, || {
+///         chunks_to_mesh.pop();
+///         // ... mesh the chunk ...
+///         !chunks_to_mesh.is_empty()
+///     });
+///     let mut frame = scheduler.frame(saving);
+///     while frame.have_time() {
+///         let _task = frame.time_task();
+///         // ... save something ...
+///         # break;
+///     }
+///     # game_over = true;
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct BudgetScheduler<C = SystemClock> {
+    total: Duration,
+    entries: Vec<Entry<C>>,
+}
+
+#[derive(Clone, Debug)]
+struct Entry<C> {
+    limiter: TimeLimiter<C>,
+    weight: f64,
+    priority: i32,
+    /// This frame's budget, in seconds.
+    budget: f64,
+    /// Whether the task has run since the last `plan`.
+    ran: bool,
+}
+
+impl<C: Clock> BudgetScheduler<C> {
+    /// A scheduler splitting `total` between its tasks every frame.
+    pub fn new(total: Duration) -> Self {
+        BudgetScheduler {
+            total,
+            entries: Vec::new(),
+        }
+    }
+
+    /// The total per-frame budget.
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    /// Change the total per-frame budget; takes effect at the next `plan`.
+    pub fn set_total(&mut self, total: Duration) {
+        self.total = total;
+    }
+
+    /// Add a task, timed by `limiter`. Its fair share of the budget is proportional to `weight`;
+    /// spare time goes to tasks with a higher `priority` first.
+    /// Until it's run once, a task gets its fair share.
+    pub fn register(&mut self, limiter: TimeLimiter<C>, weight: f64, priority: i32) -> BudgetId {
+        assert!(weight > 0.0, "weight must be positive");
+        self.entries.push(Entry {
+            limiter,
+            weight,
+            priority,
+            budget: 0.0,
+            ran: false,
+        });
+        let total = to_float(self.total);
+        let weights = self.weights();
+        for entry in &mut self.entries {
+            if !entry.ran {
+                entry.budget = total * entry.weight / weights;
+            }
+        }
+        BudgetId(self.entries.len() - 1)
+    }
+
+    /// Decide this frame's budgets, based on how each task did last frame.
+    /// Call this once per frame, before running any of the tasks.
+    pub fn plan(&mut self) {
+        let total = to_float(self.total);
+        let weights = self.weights();
+
+        // everyone gets their fair share, or enough to do what they did last frame and one more task,
+        // whichever is less
+        let mut top_priority = None;
+        for entry in &mut self.entries {
+            let fair = total * entry.weight / weights;
+            let hungry = entry.ran && entry.limiter.ran_out;
+            entry.budget = if entry.ran && !hungry {
+                fair.min(to_float(entry.limiter.stats.spent) + entry.limiter.time_estimate)
+            } else {
+                fair
+            };
+            let top = match top_priority {
+                Some(top) => entry.priority > top,
+                None => true,
+            };
+            if hungry && top {
+                top_priority = Some(entry.priority);
+            }
+            entry.ran = false;
+        }
+
+        // the rest goes to the most important tasks that ran out of time
+        let top_priority = match top_priority {
+            Some(top) => top,
+            None => return,
+        };
+        let slack = total - self.entries.iter().map(|entry| entry.budget).sum::<f64>();
+        let is_top = |entry: &Entry<C>| entry.limiter.ran_out && entry.priority == top_priority;
+        let top_weights: f64 = self.entries.iter().filter(|e| is_top(e)).map(|e| e.weight).sum();
+        for entry in &mut self.entries {
+            if is_top(entry) {
+                entry.budget += slack.max(0.0) * entry.weight / top_weights;
+            }
+        }
+    }
+
+    /// The budget of task `id` for this frame.
+    pub fn budget(&self, id: BudgetId) -> Duration {
+        to_duration(self.entries[id.0].budget)
+    }
+
+    /// The `TimeLimiter` timing task `id`.
+    pub fn limiter(&self, id: BudgetId) -> &TimeLimiter<C> {
+        &self.entries[id.0].limiter
+    }
+
+    /// Start timing task `id` for this frame, with the budget from the last `plan`.
+    pub fn frame(&mut self, id: BudgetId) -> Frame<'_, C> {
+        let entry = &mut self.entries[id.0];
+        entry.ran = true;
+        entry.limiter.frame(to_duration(entry.budget))
+    }
+
+    /// Call `f` repeatedly, as with `TimeLimiter::repeat_with_budget`, with task `id`'s budget for this frame.
+    pub fn repeat_with_budget<F: FnMut() -> bool>(&mut self, id: BudgetId, f: F) -> Repeated {
+        let entry = &mut self.entries[id.0];
+        entry.ran = true;
+        entry.limiter.repeat_with_budget(to_duration(entry.budget), f)
+    }
+
+    fn weights(&self) -> f64 {
+        self.entries.iter().map(|entry| entry.weight).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ManualClock;
+
+    /// Pretend task `id` ran last frame, spending `spent` seconds with a per-task estimate of `estimate`.
+    fn ran<C: Clock>(scheduler: &mut BudgetScheduler<C>, id: BudgetId, spent: f64, estimate: f64, ran_out: bool) {
+        let entry = &mut scheduler.entries[id.0];
+        entry.ran = true;
+        entry.limiter.stats.spent = to_duration(spent);
+        entry.limiter.time_estimate = estimate;
+        entry.limiter.ran_out = ran_out;
+    }
+
+    fn millis(duration: Duration) -> f64 {
+        to_float(duration) * 1000.0
+    }
+
+    #[test]
+    fn planning() {
+        let mut scheduler = BudgetScheduler::new(Duration::from_millis(10));
+        let meshing = scheduler.register(TimeLimiter::new(), 2.0, 1);
+        let lighting = scheduler.register(TimeLimiter::new(), 1.0, 1);
+        let saving = scheduler.register(TimeLimiter::new(), 2.0, 0);
+
+        // fair shares to start with
+        assert!((millis(scheduler.budget(meshing)) - 4.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(lighting)) - 2.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(saving)) - 4.0).abs() < 1e-3);
+
+        // saving had little to do, so its spare time goes to meshing and lighting, which ran out
+        ran(&mut scheduler, meshing, 0.004, 0.001, true);
+        ran(&mut scheduler, lighting, 0.002, 0.0005, true);
+        ran(&mut scheduler, saving, 0.0005, 0.0005, false);
+        scheduler.plan();
+        assert!((millis(scheduler.budget(saving)) - 1.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(meshing)) - 6.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(lighting)) - 3.0).abs() < 1e-3);
+
+        // only higher priority tasks get spare time; tasks that didn't run keep their fair share
+        ran(&mut scheduler, meshing, 0.0, 0.001, false);
+        ran(&mut scheduler, saving, 0.004, 0.001, true);
+        scheduler.plan();
+        assert!((millis(scheduler.budget(meshing)) - 1.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(lighting)) - 2.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(saving)) - 7.0).abs() < 1e-3);
+
+        // a higher priority task that's out of time gets it all
+        ran(&mut scheduler, meshing, 0.0, 0.001, false);
+        ran(&mut scheduler, lighting, 0.002, 0.001, true);
+        ran(&mut scheduler, saving, 0.004, 0.001, true);
+        scheduler.plan();
+        assert!((millis(scheduler.budget(lighting)) - 5.0).abs() < 1e-3);
+        assert!((millis(scheduler.budget(saving)) - 4.0).abs() < 1e-3);
+
+        let total: f64 = [meshing, lighting, saving].iter().map(|&id| millis(scheduler.budget(id))).sum();
+        assert!(total <= 10.0 + 1e-3);
+    }
+
+    #[test]
+    fn running() {
+        let clock = ManualClock::new();
+        let mut scheduler = BudgetScheduler::new(Duration::from_millis(10));
+        let busy = scheduler.register(TimeLimiter::new().with_clock(clock.clone()), 1.0, 0);
+        let idle = scheduler.register(TimeLimiter::new().with_clock(clock.clone()), 1.0, 0);
+
+        scheduler.plan();
+        assert!(scheduler.repeat_with_budget(idle, || false).finished);
+        let busy_run = scheduler.repeat_with_budget(busy, || {
+            clock.advance(Duration::from_millis(1));
+            true
+        });
+        assert_eq!((busy_run.tasks, busy_run.finished), (5, false));
+        assert!(scheduler.limiter(busy).ran_out);
+        assert!(!scheduler.limiter(idle).ran_out);
+
+        // the idle task didn't need any of its share
+        scheduler.plan();
+        assert_eq!(scheduler.budget(idle), Duration::from_millis(0));
+        assert_eq!(scheduler.budget(busy), Duration::from_millis(10));
+    }
+}
@@ -5,17 +5,15 @@ extern crate voxel;
 
 use criterion::Criterion;
 
-use voxel::mesh::{mesh_layer, Direction, InProgress};
+use voxel::mesh::{mesh_layer, Direction, InProgress, MeshShading};
+use voxel::persist::{decode_chunk, encode_chunk, Codec};
 use voxel::raycast::raycast;
 use voxel::{Chunk, Coord, TestVoxel, VoxelCoord, CHUNK_SIZE};
 
 fn mesh(chunk: &Chunk<TestVoxel>) {
 
-    let mut in_progress = InProgress {
-        color: vec![],
-        position: vec![],
-        normal: vec![],
-    };
+    let mut in_progress = InProgress::new();
+    let shading = MeshShading::default();
 
     let directions = [
         (0, CHUNK_SIZE as i16 - 1, 1, Direction::East),
@@ -30,7 +28,7 @@ fn mesh(chunk: &Chunk<TestVoxel>) {
     for (start, end, sub, direction) in directions.into_iter() {
         //println!("{} {} {} {:?}", start, end, sub, normal);
         for i in *start..*end {
-            mesh_layer(&chunk, i, &chunk, i + sub, *direction, &mut in_progress)
+            mesh_layer(&chunk, i, None, &chunk, i + sub, None, *direction, &shading, &mut in_progress)
         }
     }
 }
@@ -43,10 +41,24 @@ fn raycast_simple_16() {
         Coord::new(0.37, 0.299, 0.936),
         VoxelCoord::new(-16, -16, -16),
         VoxelCoord::new(16, 16, 16),
+        std::f32::INFINITY,
         |_| false,
     ));
 }
 
+/// Rolling ground, for benchmarking chunk encoding.
+fn terrain() -> Chunk<TestVoxel> {
+    let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+    for x in 0..CHUNK_SIZE as i16 {
+        for z in 0..CHUNK_SIZE as i16 {
+            let height = (x * 7 + z * 3) % 11;
+            chunk.fill_box(VoxelCoord::new(x, 0, z), VoxelCoord::new(x, height, z), TestVoxel::Rock);
+            chunk[VoxelCoord::new(x, height + 1, z)] = TestVoxel::Grass;
+        }
+    }
+    chunk
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("mesh 1", |b| {
         let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
@@ -60,6 +72,16 @@ fn criterion_benchmark(c: &mut Criterion) {
         b.iter(|| mesh(&chunk))
     });
     c.bench_function("raycast_simple_16 1", |b| b.iter(|| raycast_simple_16()));
+    for &codec in Codec::ALL.iter().filter(|codec| codec.is_available()) {
+        let chunk = terrain();
+        c.bench_function(&format!("encode {:?}", codec), move |b| {
+            b.iter(|| encode_chunk(&chunk, codec).unwrap())
+        });
+        let bytes = encode_chunk(&terrain(), codec).unwrap();
+        c.bench_function(&format!("decode {:?}", codec), move |b| {
+            b.iter(|| decode_chunk::<TestVoxel>(VoxelCoord::new(0, 0, 0), &bytes).unwrap())
+        });
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -0,0 +1,54 @@
+//! Loading snapshots and structures through Amethyst's asset `Loader`, from the same place as meshes and textures,
+//! with its progress counters and hot-reloading:
+//!
+//! ```ignore
+//! let arena = loader.load("arena.snapshot", SnapshotFormat, (), &mut progress, &snapshot_storage);
+//! ```
+//!
+//! with a `Processor<Snapshot<V>>` (or `Processor<Structure<V>>`) system to finish loading them. Files are written
+//! by `Snapshot::save`. Loaded as a `Structure`, a snapshot is flattened into one box (see `Snapshot::to_structure`),
+//! so a build saved from one place can be stamped anywhere else.
+
+use super::VoxelId;
+use snapshot::Snapshot;
+use structure::Structure;
+
+use amethyst::assets::{Asset, Handle, Result, ResultExt, SimpleFormat};
+use specs::VecStorage;
+
+impl<V: VoxelId> Asset for Snapshot<V> {
+    const NAME: &'static str = "voxel::Snapshot";
+    type Data = Self;
+    type HandleStorage = VecStorage<Handle<Self>>;
+}
+
+impl<V: VoxelId> Asset for Structure<V> {
+    const NAME: &'static str = "voxel::Structure";
+    type Data = Self;
+    type HandleStorage = VecStorage<Handle<Self>>;
+}
+
+/// Snapshot files, as written by `Snapshot::save`; loads both `Snapshot`s and `Structure`s.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SnapshotFormat;
+
+impl<V: VoxelId> SimpleFormat<Snapshot<V>> for SnapshotFormat {
+    const NAME: &'static str = "VOXEL_SNAPSHOT";
+    type Options = ();
+
+    fn import(&self, bytes: Vec<u8>, _: ()) -> Result<Snapshot<V>> {
+        Snapshot::read(&mut &bytes[..]).chain_err(|| "failed to read voxel snapshot")
+    }
+}
+
+impl<V: VoxelId> SimpleFormat<Structure<V>> for SnapshotFormat {
+    const NAME: &'static str = "VOXEL_SNAPSHOT";
+    type Options = ();
+
+    fn import(&self, bytes: Vec<u8>, options: ()) -> Result<Structure<V>> {
+        let snapshot = SimpleFormat::<Snapshot<V>>::import(self, bytes, options)?;
+        snapshot
+            .to_structure()
+            .ok_or_else(|| format!("voxel snapshot {:?} is empty or too big to flatten", snapshot.name()).into())
+    }
+}
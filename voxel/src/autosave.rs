@@ -0,0 +1,373 @@
+//! Saving modified chunks to a `WorldStore` while the game runs.
+//!
+//! Add a `WorldIo` resource (with its `WorldIoSystem`) and an `AutosaveSystem`, after everything that edits chunks
+//! (e.g. `ChunkDeltaSystem`). Chunks are marked dirty whenever they're mutably borrowed from their storage (as with
+//! meshing; see `ChunkMesherSystem`), and every `Autosave::interval` the dirty ones are copied off to the IO thread
+//! to be saved, as many each frame as fit in the time budget. Unload chunks with `Autosave::unload`, which saves
+//! them first if they're dirty, rather than deleting them. When the game quits, call `save_all` to save whatever's
+//! left; dropping the `WorldIo` then flushes it.
+//!
+//! If there's a `DeltaJournal`, it's compacted after each round of saving, once the saved chunks are on the disk,
+//! dropping the edits from before the round started that they hold.
+
+use super::{canonicalize_chunk, Chunk, ChunkTracker, Voxel, VoxelCoord, VoxelId};
+use budget::Headroom;
+use io_thread::{IoResponse, WorldIo};
+use journal::{DeltaJournal, JournalMark};
+
+use fnv::FnvHashSet;
+use soft_time_limit::TimeLimiter;
+use specs::prelude::*;
+use std::io;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+/// A resource: which chunks need saving, and how often to save them.
+pub struct Autosave {
+    /// How long to wait between rounds of saving. 30 seconds by default.
+    pub interval: Duration,
+    dirty: FnvHashSet<VoxelCoord>,
+    save_soon: bool,
+    /// The chunks to unload next frame.
+    unload: FnvHashSet<VoxelCoord>,
+}
+impl Default for Autosave {
+    fn default() -> Self {
+        Autosave::new(Duration::from_secs(30))
+    }
+}
+impl Autosave {
+    pub fn new(interval: Duration) -> Self {
+        Autosave {
+            interval,
+            dirty: FnvHashSet::default(),
+            save_soon: false,
+            unload: FnvHashSet::default(),
+        }
+    }
+
+    /// Mark the chunk containing `coord` as needing saving. Chunks edited through their storage are marked
+    /// automatically.
+    pub fn mark_dirty(&mut self, coord: VoxelCoord) {
+        self.dirty.insert(canonicalize_chunk(coord));
+    }
+
+    /// Whether the chunk containing `coord` has changed since it was last saved.
+    pub fn is_dirty(&self, coord: VoxelCoord) -> bool {
+        self.dirty.contains(&canonicalize_chunk(coord))
+    }
+
+    /// How many chunks have changed since they were last saved.
+    pub fn dirty_count(&self) -> usize {
+        self.dirty.len()
+    }
+
+    /// Start a round of saving next frame, rather than waiting for the interval.
+    pub fn save_soon(&mut self) {
+        self.save_soon = true;
+    }
+
+    /// Unload the chunk containing `coord` next frame (deleting its entity), saving it first if it's dirty.
+    pub fn unload(&mut self, coord: VoxelCoord) {
+        self.unload.insert(canonicalize_chunk(coord));
+    }
+}
+
+/// Saves dirty chunks through the `WorldIo`, and unloads chunks; see the module docs. It takes the `Saved` and
+/// `Flushed` responses.
+///
+/// Saving a chunk that's been unloaded isn't possible, so chunks deleted other than through `Autosave::unload`
+/// lose the edits that haven't been saved yet.
+pub struct AutosaveSystem<V: Voxel> {
+    time_limiter: TimeLimiter,
+    time_limit: Duration,
+    modified_id: Option<ReaderId<ModifiedFlag>>,
+    modified: BitSet,
+    /// The chunks left to save this round.
+    queue: Vec<VoxelCoord>,
+    /// Whether this round's chunks have all been sent, and it's waiting for the flush.
+    flushing: bool,
+    /// The chunks saved so far this round.
+    saved: FnvHashSet<VoxelCoord>,
+    /// Where the journal was when this round started.
+    mark: Option<JournalMark>,
+    last_round: Instant,
+    _phantom: PhantomData<V>,
+}
+impl<V: VoxelId> AutosaveSystem<V> {
+    /// An autosave system that spends up to `time_limit` saving each frame (scaled by the `Headroom`, if any).
+    pub fn new(time_limit: Duration) -> Self {
+        AutosaveSystem {
+            time_limiter: TimeLimiter::new(),
+            time_limit,
+            modified_id: None,
+            modified: BitSet::new(),
+            queue: Vec::new(),
+            flushing: false,
+            saved: FnvHashSet::default(),
+            mark: None,
+            last_round: Instant::now(),
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a, V: VoxelId> System<'a> for AutosaveSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        WriteExpect<'a, WorldIo<V>>,
+        Write<'a, Autosave>,
+        Option<Read<'a, Headroom>>,
+        Option<Write<'a, DeltaJournal>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        let mut chunks = WriteStorage::<Chunk<V>>::fetch(resources);
+        self.modified_id = Some(chunks.track_modified());
+    }
+
+    fn run(&mut self, (entities, tracker, chunks, mut io, mut autosave, headroom, mut journal): Self::SystemData) {
+        self.modified.clear();
+        chunks.populate_modified(self.modified_id.as_mut().unwrap(), &mut self.modified);
+        for (chunk, _) in (&chunks, &self.modified).join() {
+            autosave.dirty.insert(chunk.coord);
+        }
+
+        let mut unloaded_dirty = false;
+        let unload: Vec<_> = autosave.unload.drain().collect();
+        for coord in unload {
+            let entity = match tracker.get_chunk_ent(coord) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            if autosave.dirty.remove(&coord) {
+                io.save(chunks.get(entity).expect("tracked chunk entity without a chunk"));
+                self.queue.retain(|&queued| queued != coord);
+                unloaded_dirty = true;
+            }
+            if let Err(e) = entities.delete(entity) {
+                error!("failed to unload chunk {:?}: {}", coord, e);
+            }
+        }
+        // (flush them now, unless a round of saving is going to anyway)
+        if unloaded_dirty && !self.flushing && self.queue.is_empty() {
+            io.flush();
+            self.flushing = true;
+        }
+
+        let mut flushed = None;
+        for response in io.take(is_autosave_response) {
+            match response {
+                IoResponse::Saved(coord, Ok(())) => {
+                    self.saved.insert(coord);
+                }
+                IoResponse::Saved(coord, Err(e)) => {
+                    // (try again next round)
+                    error!("failed to save chunk {:?}: {}", coord, e);
+                    autosave.dirty.insert(coord);
+                }
+                IoResponse::Flushed(result) => flushed = Some(result),
+                _ => unreachable!(),
+            }
+        }
+        if let Some(result) = flushed {
+            self.flushing = false;
+            if let Err(e) = result {
+                error!("failed to flush saved chunks: {}", e);
+            }
+            if let (Some(journal), Some(mark)) = (journal.as_mut(), self.mark.take()) {
+                let saved = &self.saved;
+                let dirty = &autosave.dirty;
+                let is_saved = |coord| {
+                    let chunk = canonicalize_chunk(coord);
+                    saved.contains(&chunk) && !dirty.contains(&chunk)
+                };
+                if let Err(e) = journal.compact(mark, is_saved) {
+                    error!("failed to compact journal {:?}: {}", journal.path(), e);
+                }
+            }
+            info!("autosaved {} chunks", self.saved.len());
+            self.saved.clear();
+        }
+
+        if self.flushing {
+            return;
+        }
+        if self.queue.is_empty() && !autosave.dirty.is_empty()
+            && (autosave.save_soon || self.last_round.elapsed() >= autosave.interval)
+        {
+            self.queue = autosave.dirty.iter().cloned().collect();
+            self.mark = journal.as_ref().map(|journal| journal.mark());
+            autosave.save_soon = false;
+            self.last_round = Instant::now();
+        }
+        if self.queue.is_empty() {
+            return;
+        }
+
+        let budget = headroom.map_or(self.time_limit, |headroom| headroom.budget(self.time_limit));
+        let repeated = {
+            let queue = &mut self.queue;
+            let dirty = &mut autosave.dirty;
+            let io = &mut *io;
+            self.time_limiter.repeat_with_budget(budget, || {
+                let coord = match queue.pop() {
+                    Some(coord) => coord,
+                    None => return false,
+                };
+                match tracker.get_chunk(&chunks, coord) {
+                    Some(chunk) => io.save(chunk),
+                    None => error!(
+                        "chunk {:?} was deleted before it could be saved, losing its edits; unload chunks with \
+                         `Autosave::unload`",
+                        coord
+                    ),
+                }
+                dirty.remove(&coord);
+                true
+            })
+        };
+        if !repeated.finished {
+            debug!(
+                "autosave ran out of time after {:?}; {} chunks left to send",
+                repeated.elapsed,
+                self.queue.len()
+            );
+            return;
+        }
+        io.flush();
+        self.flushing = true;
+    }
+}
+
+/// The responses the `AutosaveSystem` takes.
+fn is_autosave_response<V: VoxelId>(response: &IoResponse<V>) -> bool {
+    match *response {
+        IoResponse::Saved(..) | IoResponse::Flushed(_) => true,
+        _ => false,
+    }
+}
+
+/// Save every dirty chunk now, and wait until they're on the disk; e.g. when the game quits. Returns how many
+/// chunks were saved. Edits made since `AutosaveSystem` last ran aren't noticed, so call this after a dispatch; and
+/// it takes the system's responses from the `WorldIo`, so don't run the system again after.
+pub fn save_all<V: VoxelId>(world: &World) -> io::Result<usize> {
+    let tracker = world.read_resource::<ChunkTracker>();
+    let chunks = world.read_storage::<Chunk<V>>();
+    let mut io = world.write_resource::<WorldIo<V>>();
+    let mut autosave = world.write_resource::<Autosave>();
+
+    for &coord in &autosave.dirty {
+        if let Some(chunk) = tracker.get_chunk(&chunks, coord) {
+            io.save(chunk);
+        }
+    }
+    autosave.dirty.clear();
+    io.flush();
+    io.wait();
+
+    let mut count = 0;
+    let mut result = Ok(());
+    for response in io.take(is_autosave_response) {
+        match response {
+            IoResponse::Saved(_, Ok(())) => count += 1,
+            IoResponse::Saved(coord, Err(e)) => {
+                autosave.dirty.insert(coord);
+                result = result.and(Err(e));
+            }
+            IoResponse::Flushed(flushed) => result = result.and(flushed),
+            _ => unreachable!(),
+        }
+    }
+    result.map(|()| count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta::{ChunkDeltaSystem, ChunkDeltas};
+    use io_thread::WorldIoSystem;
+    use persist::WorldStore;
+    use std::fs;
+    use tracker::ChunkTrackerSystem;
+    use {test_directory, TestVoxel};
+
+    #[test]
+    fn autosave() {
+        let directory = test_directory("voxel_autosave_test");
+
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<TestVoxel>::new());
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        world.add_resource(WorldIo::start(store.clone()));
+        world.add_resource(Autosave::new(Duration::from_secs(3600)));
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(ChunkDeltaSystem::<TestVoxel>::new(), "chunk_deltas", &["chunk_tracker"])
+            .with(WorldIoSystem::<TestVoxel>::new(), "world_io", &[])
+            .with(
+                AutosaveSystem::<TestVoxel>::new(Duration::from_millis(100)),
+                "autosave",
+                &["chunk_deltas", "world_io"],
+            )
+            .build();
+        dispatcher.setup(&mut world.res);
+        for &x in &[0, 16, 32] {
+            world
+                .create_entity()
+                .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(x, 0, 0)))
+                .build();
+        }
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+
+        // edited chunks are dirty, but aren't saved until the interval's up
+        let (a, b) = (VoxelCoord::new(3, 4, 5), VoxelCoord::new(20, 0, 0));
+        world.read_resource::<ChunkDeltas<TestVoxel>>().defer_set(a, TestVoxel::Rock);
+        dispatcher.dispatch(&mut world.res);
+        {
+            let autosave = world.read_resource::<Autosave>();
+            assert!(autosave.is_dirty(a));
+            assert!(!autosave.is_dirty(b));
+            assert_eq!(autosave.dirty_count(), 1);
+        }
+        assert!(!store.contains(a).unwrap());
+
+        // chunks are sent off to be saved, and the round ends once they're flushed
+        world.write_resource::<Autosave>().save_soon();
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(world.read_resource::<Autosave>().dirty_count(), 0);
+        world.write_resource::<WorldIo<TestVoxel>>().wait();
+        let chunk = store.load_chunk(a).unwrap().unwrap();
+        assert_eq!(chunk[a], TestVoxel::Rock);
+
+        // unloading a dirty chunk saves it first
+        let c = VoxelCoord::new(40, 0, 0);
+        world.read_resource::<ChunkDeltas<TestVoxel>>().defer_set(c, TestVoxel::Grass);
+        dispatcher.dispatch(&mut world.res);
+        world.write_resource::<Autosave>().unload(c);
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+        dispatcher.dispatch(&mut world.res);
+        assert!(world.read_resource::<ChunkTracker>().get_chunk_ent(c).is_none());
+        assert!(!world.read_resource::<Autosave>().is_dirty(c));
+        world.write_resource::<WorldIo<TestVoxel>>().wait();
+        let chunk = store.load_chunk(c).unwrap().unwrap();
+        assert_eq!(chunk[c - chunk.coord], TestVoxel::Grass);
+
+        // edits since the last round are saved on the way out
+        world.read_resource::<ChunkDeltas<TestVoxel>>().defer_set(b, TestVoxel::Grass);
+        dispatcher.dispatch(&mut world.res);
+        assert!(world.read_resource::<Autosave>().is_dirty(b));
+        assert!(!store.contains(b).unwrap());
+        assert_eq!(save_all::<TestVoxel>(&world).unwrap(), 1);
+        let chunk = store.load_chunk(b).unwrap().unwrap();
+        assert_eq!(chunk[b - chunk.coord], TestVoxel::Grass);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
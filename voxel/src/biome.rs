@@ -0,0 +1,252 @@
+//! Biomes: what kind of place each column of the world is, e.g. desert or forest.
+//!
+//! Each column of a chunk has a `BiomeId`, stored in the chunk's `ChunkBiomes` component. They're either
+//! worked out from a `BiomeSource` (e.g. `BiomeCells`, which splits the world up into irregular cells) or
+//! supplied per chunk by whatever makes it. The `BiomeRegistry` resource says what each biome id means:
+//! the voxels its surface is made of, and how it tints voxels that change color with the biome (see
+//! `Voxel::tinted_color`), like grass and leaves.
+
+use super::{Voxel, VoxelCoord, CHUNK_SIZE};
+
+use specs::prelude::*;
+use specs::HashMapStorage;
+
+/// An index into the `BiomeRegistry`.
+pub type BiomeId = u8;
+
+/// What a biome id means.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Biome<V: Voxel> {
+    pub name: String,
+    /// The top voxel of the ground, e.g. grass or sand.
+    pub surface: V,
+    /// The voxels under the surface, e.g. dirt.
+    pub filler: V,
+    /// How many filler voxels there are under the surface, before whatever's underneath.
+    pub filler_depth: u8,
+    /// What the colors of voxels that change with the biome are multiplied by.
+    pub tint: [f32; 3],
+}
+impl<V: Voxel> Biome<V> {
+    /// The voxel `depth` voxels under the top of the ground (0 for the top itself), or None if that's
+    /// below the surface layers.
+    pub fn palette(&self, depth: u16) -> Option<V> {
+        if depth == 0 {
+            Some(self.surface)
+        } else if depth <= u16::from(self.filler_depth) {
+            Some(self.filler)
+        } else {
+            None
+        }
+    }
+}
+
+/// Every biome, by id; a resource, for generators to paint the ground with and the mesher to tint voxels by.
+#[derive(Clone, Debug)]
+pub struct BiomeRegistry<V: Voxel> {
+    biomes: Vec<Biome<V>>,
+}
+impl<V: Voxel> Default for BiomeRegistry<V> {
+    fn default() -> Self {
+        BiomeRegistry { biomes: Vec::new() }
+    }
+}
+impl<V: Voxel> BiomeRegistry<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a biome, returning its id. Ids are handed out in order, starting from 0.
+    pub fn register(&mut self, biome: Biome<V>) -> BiomeId {
+        assert!(self.biomes.len() <= BiomeId::max_value() as usize, "too many biomes");
+        self.biomes.push(biome);
+        (self.biomes.len() - 1) as BiomeId
+    }
+
+    pub fn get(&self, id: BiomeId) -> Option<&Biome<V>> {
+        self.biomes.get(id as usize)
+    }
+
+    /// The id of the biome called `name`.
+    pub fn find(&self, name: &str) -> Option<BiomeId> {
+        self.biomes
+            .iter()
+            .position(|biome| biome.name == name)
+            .map(|id| id as BiomeId)
+    }
+
+    /// The tint of biome `id`; white (no change) for unknown biomes.
+    pub fn tint(&self, id: BiomeId) -> [f32; 3] {
+        self.get(id).map_or([1.0; 3], |biome| biome.tint)
+    }
+
+    pub fn len(&self) -> usize {
+        self.biomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.biomes.is_empty()
+    }
+}
+
+/// Decides the biome of each column of the world. Should always give the same biome for the same column.
+pub trait BiomeSource: Send + Sync + 'static {
+    fn biome(&self, x: i16, z: i16) -> BiomeId;
+}
+impl<F: Fn(i16, i16) -> BiomeId + Send + Sync + 'static> BiomeSource for F {
+    fn biome(&self, x: i16, z: i16) -> BiomeId {
+        self(x, z)
+    }
+}
+
+/// Splits the world up into irregular cells, about `cell_size` voxels across, and gives each a biome picked
+/// at random from `biomes` (repeat an id to make it more common). Each column is in the cell whose center it's
+/// nearest, and the centers are scattered randomly, so the borders between biomes aren't lined up with
+/// anything.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BiomeCells {
+    pub seed: u32,
+    pub cell_size: i16,
+    pub biomes: Vec<BiomeId>,
+}
+impl BiomeCells {
+    pub fn new(seed: u32, cell_size: i16, biomes: Vec<BiomeId>) -> Self {
+        assert!(cell_size > 0, "empty cells");
+        assert!(!biomes.is_empty(), "no biomes to pick from");
+        BiomeCells {
+            seed,
+            cell_size,
+            biomes,
+        }
+    }
+}
+impl BiomeSource for BiomeCells {
+    fn biome(&self, x: i16, z: i16) -> BiomeId {
+        let size = i32::from(self.cell_size);
+        let (x, z) = (i32::from(x), i32::from(z));
+        let floor_div = |c: i32| (c - ((c % size) + size) % size) / size;
+        let (cell_x, cell_z) = (floor_div(x), floor_div(z));
+
+        // a column is nearer the center of one of the 3x3 cells around it than any other
+        let mut nearest = (i32::max_value(), (cell_x, cell_z));
+        for cx in cell_x - 1..cell_x + 2 {
+            for cz in cell_z - 1..cell_z + 2 {
+                let scatter = hash(self.seed, cx, cz);
+                let center_x = cx * size + (scatter % size as u32) as i32;
+                let center_z = cz * size + ((scatter >> 16) % size as u32) as i32;
+                let distance = (center_x - x).pow(2) + (center_z - z).pow(2);
+                if distance < nearest.0 {
+                    nearest = (distance, (cx, cz));
+                }
+            }
+        }
+        let (cx, cz) = nearest.1;
+        let pick = hash(self.seed ^ 0x5bd1_e995, cx, cz);
+        self.biomes[pick as usize % self.biomes.len()]
+    }
+}
+
+/// Scramble a seed and a cell into a random-looking number.
+fn hash(seed: u32, x: i32, z: i32) -> u32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x9e37_79b1) ^ (z as u32).wrapping_mul(0x85eb_ca77);
+    h ^= h >> 15;
+    h = h.wrapping_mul(0x2c1b_3c6d);
+    h ^= h >> 12;
+    h = h.wrapping_mul(0x297a_2d39);
+    h ^ (h >> 15)
+}
+
+/// The biome of each column of a chunk, indexed by chunk-local x and z.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ChunkBiomes {
+    columns: [[BiomeId; CHUNK_SIZE]; CHUNK_SIZE],
+}
+impl ChunkBiomes {
+    /// Every column in the same biome.
+    pub fn uniform(id: BiomeId) -> Self {
+        ChunkBiomes {
+            columns: [[id; CHUNK_SIZE]; CHUNK_SIZE],
+        }
+    }
+
+    /// The biomes of the chunk at `chunk_coord`, according to `source`.
+    pub fn from_source<S: BiomeSource + ?Sized>(chunk_coord: VoxelCoord, source: &S) -> Self {
+        let mut biomes = ChunkBiomes::uniform(0);
+        for (x, row) in biomes.columns.iter_mut().enumerate() {
+            for (z, column) in row.iter_mut().enumerate() {
+                *column = source.biome(chunk_coord.x + x as i16, chunk_coord.z + z as i16);
+            }
+        }
+        biomes
+    }
+
+    /// The biome of the column at chunk-local `x` and `z`.
+    #[inline]
+    pub fn get(&self, x: i16, z: i16) -> BiomeId {
+        self.columns[x as usize][z as usize]
+    }
+
+    pub fn set(&mut self, x: i16, z: i16, id: BiomeId) {
+        self.columns[x as usize][z as usize] = id;
+    }
+}
+impl Component for ChunkBiomes {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TestVoxel;
+
+    #[test]
+    fn registry() {
+        let mut registry = BiomeRegistry::new();
+        let plains = registry.register(Biome {
+            name: "plains".into(),
+            surface: TestVoxel::Grass,
+            filler: TestVoxel::Rock,
+            filler_depth: 2,
+            tint: [0.5, 1.0, 0.5],
+        });
+        assert_eq!(plains, 0);
+        assert_eq!(registry.find("plains"), Some(plains));
+        assert_eq!(registry.find("desert"), None);
+        assert_eq!(registry.tint(plains), [0.5, 1.0, 0.5]);
+        assert_eq!(registry.tint(7), [1.0; 3]);
+
+        let biome = registry.get(plains).unwrap();
+        let palette: Vec<_> = (0..4).map(|depth| biome.palette(depth)).collect();
+        assert_eq!(
+            palette,
+            vec![Some(TestVoxel::Grass), Some(TestVoxel::Rock), Some(TestVoxel::Rock), None]
+        );
+    }
+
+    #[test]
+    fn cells() {
+        let cells = BiomeCells::new(3, 32, vec![0, 1, 2, 3]);
+        let chunk = ChunkBiomes::from_source(VoxelCoord::new(-16, 0, 32), &cells);
+        assert_eq!(chunk.get(4, 5), cells.biome(-12, 37));
+
+        // the same every time, every biome shows up, and cells are (mostly) in one piece
+        let mut seen = [0; 4];
+        let mut borders = 0;
+        for x in -200..200 {
+            for z in -200..200 {
+                let biome = cells.biome(x, z);
+                assert_eq!(biome, cells.biome(x, z));
+                seen[biome as usize] += 1;
+                if biome != cells.biome(x + 1, z) {
+                    borders += 1;
+                }
+            }
+        }
+        assert!(seen.iter().all(|&count| count > 0));
+        assert!(borders < 400 * 400 / 8);
+
+        let closure = |x: i16, _z: i16| if x < 0 { 1 } else { 0 };
+        let chunk = ChunkBiomes::from_source(VoxelCoord::new(-16, 0, 0), &closure);
+        assert_eq!(chunk, ChunkBiomes::uniform(1));
+    }
+}
@@ -0,0 +1,20 @@
+//! Adapting time budgets to how fast the game is running.
+
+use amethyst::core::timing::Time;
+use specs::prelude::*;
+
+pub use soft_time_limit::Headroom;
+
+/// Updates the `Headroom` resource from Amethyst's `Time` every frame, so that budgeted systems
+/// (like `ChunkMesherSystem`) back off when the game is struggling and speed up when it's idle.
+///
+/// Run it before the budgeted systems. It aims for 60 frames per second, unless you add a `Headroom`
+/// resource with a different target first. Without this system (or a `Headroom` resource), budgets are left alone.
+pub struct HeadroomSystem;
+impl<'a> System<'a> for HeadroomSystem {
+    type SystemData = (Read<'a, Time>, Write<'a, Headroom>);
+
+    fn run(&mut self, (time, mut headroom): Self::SystemData) {
+        headroom.update(time.delta_real_time());
+    }
+}
@@ -0,0 +1,128 @@
+//! Little-endian byte encoding shared by the wire messages (`net`), save files (`persist`) and snapshots.
+
+use std::error::Error;
+use std::io;
+
+pub fn invalid<E: Into<Box<dyn Error + Send + Sync>>>(error: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error)
+}
+
+pub fn put_u16(bytes: &mut Vec<u8>, value: u16) {
+    bytes.push(value as u8);
+    bytes.push((value >> 8) as u8);
+}
+
+pub fn put_u32(bytes: &mut Vec<u8>, value: u32) {
+    put_u16(bytes, value as u16);
+    put_u16(bytes, (value >> 16) as u16);
+}
+
+pub fn put_u64(bytes: &mut Vec<u8>, value: u64) {
+    put_u32(bytes, value as u32);
+    put_u32(bytes, (value >> 32) as u32);
+}
+
+/// A string, prefixed with its length in bytes as a u16.
+pub fn put_string(bytes: &mut Vec<u8>, string: &str) {
+    put_u16(bytes, string.len() as u16);
+    bytes.extend_from_slice(string.as_bytes());
+}
+
+/// A zigzag-encoded varint, so that small numbers of either sign take a byte.
+pub fn put_varint(bytes: &mut Vec<u8>, value: i32) {
+    let mut zigzag = ((value << 1) ^ (value >> 31)) as u32;
+    while zigzag >= 0x80 {
+        bytes.push(zigzag as u8 | 0x80);
+        zigzag >>= 7;
+    }
+    bytes.push(zigzag as u8);
+}
+
+/// Reads what the `put_` functions write from a byte slice, failing at the end.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        Reader { bytes, pos: 0 }
+    }
+
+    pub fn take(&mut self, length: usize) -> io::Result<&'a [u8]> {
+        let bytes = self.bytes
+            .get(self.pos..self.pos + length)
+            .ok_or_else(|| invalid("unexpected end of data"))?;
+        self.pos += length;
+        Ok(bytes)
+    }
+
+    /// Everything that hasn't been read yet.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        rest
+    }
+
+    pub fn u8(&mut self) -> io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from(bytes[0]) | u16::from(bytes[1]) << 8)
+    }
+
+    pub fn u32(&mut self) -> io::Result<u32> {
+        Ok(u32::from(self.u16()?) | u32::from(self.u16()?) << 16)
+    }
+
+    pub fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from(self.u32()?) | u64::from(self.u32()?) << 32)
+    }
+
+    pub fn string(&mut self) -> io::Result<String> {
+        let length = self.u16()? as usize;
+        String::from_utf8(self.take(length)?.to_vec()).map_err(invalid)
+    }
+
+    pub fn varint(&mut self) -> io::Result<i32> {
+        let mut zigzag = 0u32;
+        for i in 0..5 {
+            let byte = self.u8()?;
+            zigzag |= u32::from(byte & 0x7f) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok((zigzag >> 1) as i32 ^ -((zigzag & 1) as i32));
+            }
+        }
+        Err(invalid("varint is too long"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut bytes = Vec::new();
+        put_u16(&mut bytes, 0xbeef);
+        put_u32(&mut bytes, 0xdead_beef);
+        put_u64(&mut bytes, 0x0123_4567_89ab_cdef);
+        put_string(&mut bytes, "rock");
+        for &value in &[0, -1, 1, 63, -64, 64, ::std::i32::MAX, ::std::i32::MIN] {
+            put_varint(&mut bytes, value);
+        }
+        assert_eq!(&bytes[..2], &[0xef, 0xbe]);
+
+        let mut reader = Reader::new(&bytes);
+        assert_eq!(reader.u16().unwrap(), 0xbeef);
+        assert_eq!(reader.u32().unwrap(), 0xdead_beef);
+        assert_eq!(reader.u64().unwrap(), 0x0123_4567_89ab_cdef);
+        assert_eq!(reader.string().unwrap(), "rock");
+        for &value in &[0, -1, 1, 63, -64, 64, ::std::i32::MAX, ::std::i32::MIN] {
+            assert_eq!(reader.varint().unwrap(), value);
+        }
+        assert_eq!(reader.rest(), &[] as &[u8]);
+        assert_eq!(reader.u8().unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
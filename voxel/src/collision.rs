@@ -0,0 +1,758 @@
+//! Collision between boxes and voxels: which voxels a box overlaps, for checking that somewhere is free before
+//! spawning or teleporting something there, and moving boxes through the world without going through anything
+//! (`move_aabb`), as a character controller does.
+//!
+//! What each voxel collides with is up to the caller's `shape`; `voxel_shape` (the voxel's own
+//! `Voxel::collision_shape`) is the usual choice, and something like a ghost can pass `|_, _| CollisionShape::Empty`
+//! for everything but the walls of its haunt. Unloaded chunks are treated as empty, as with
+//! `raycast::voxel_boxcast`, so check that the chunks around a spot are loaded before trusting that it's free.
+//!
+//! Character controllers that would rather be capsules than boxes, so that they slide smoothly around corners, can
+//! use `capsule_voxels`, `is_capsule_free` and `sweep_capsule`.
+//!
+//! `submersion` says how far a box is in fluids (see `Voxel::is_fluid`), for swimming and floating.
+
+use super::{voxels_in_box, Aabb, ChunkAccess, Coord, Voxel, VoxelCoord};
+use cgmath::{InnerSpace, Vector3};
+
+/// How far boxes have to overlap to count as overlapping, so that rounding errors don't snag a box sliding along a
+/// wall or resting on the ground.
+const EPSILON: f32 = 1e-4;
+
+/// The space a voxel takes up, for collisions; see `Voxel::collision_shape`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CollisionShape {
+    /// Nothing; things pass straight through, as with air or tall grass.
+    Empty,
+    /// The whole voxel.
+    Cube,
+    /// The bottom half of the voxel.
+    Slab,
+    /// Some boxes, relative to the voxel's bottom corner, so from 0 to 1 on each axis; e.g. a fence post and its
+    /// rails. They should stay inside the voxel: parts sticking out of it are missed by boxes that don't overlap
+    /// the voxel itself.
+    Boxes(&'static [Aabb]),
+}
+impl CollisionShape {
+    /// The shape's boxes, relative to the voxel's bottom corner.
+    pub fn boxes(&self) -> &'static [Aabb] {
+        match *self {
+            CollisionShape::Empty => &[],
+            CollisionShape::Cube => &CUBE,
+            CollisionShape::Slab => &SLAB,
+            CollisionShape::Boxes(boxes) => boxes,
+        }
+    }
+
+    /// The shape's boxes for the voxel at `coord`, in world coordinates.
+    pub fn world_boxes(&self, coord: VoxelCoord) -> impl Iterator<Item = Aabb> {
+        let corner = coord.cast::<f32>().unwrap() - Coord::new(0.5, 0.5, 0.5);
+        self.boxes().iter().map(move |aabb| aabb.translate(corner))
+    }
+}
+
+static CUBE: [Aabb; 1] = [Aabb {
+    min: Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+    max: Vector3 { x: 1.0, y: 1.0, z: 1.0 },
+}];
+static SLAB: [Aabb; 1] = [Aabb {
+    min: Vector3 { x: 0.0, y: 0.0, z: 0.0 },
+    max: Vector3 { x: 1.0, y: 0.5, z: 1.0 },
+}];
+
+/// The usual `shape`: the voxel's own `Voxel::collision_shape`.
+pub fn voxel_shape<V: Voxel>(_: VoxelCoord, voxel: &V) -> CollisionShape {
+    voxel.collision_shape()
+}
+
+/// The box a voxel fills.
+pub fn voxel_aabb(coord: VoxelCoord) -> Aabb {
+    Aabb::from_center(coord.cast().unwrap(), Coord::new(0.5, 0.5, 0.5))
+}
+
+/// Whether `a` and `b` overlap along `axis`, by more than just touching.
+fn overlaps_on(a: &Aabb, b: &Aabb, axis: usize) -> bool {
+    a.min[axis] < b.max[axis] - EPSILON && b.min[axis] < a.max[axis] - EPSILON
+}
+
+/// Whether `aabb` overlaps the shape of the voxel at `coord`.
+fn collides<V, C, F>(chunks: &C, coord: VoxelCoord, aabb: &Aabb, shape: &mut F) -> bool
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    chunks.get_voxel(coord).map_or(false, |voxel| {
+        shape(coord, &voxel)
+            .world_boxes(coord)
+            .any(|other| (0..3).all(|axis| overlaps_on(aabb, &other, axis)))
+    })
+}
+
+/// The voxels whose shapes `aabb` overlaps, in x, y, z order. Shapes it only touches the surface of don't count, so
+/// a box standing on the ground doesn't overlap it.
+pub fn solid_voxels_in_aabb<V, C, F>(chunks: &C, aabb: Aabb, mut shape: F) -> Vec<VoxelCoord>
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let (min, max) = aabb.voxels();
+    voxels_in_box(min, max)
+        .filter(|&coord| collides(chunks, coord, &aabb, &mut shape))
+        .collect()
+}
+
+/// Whether `aabb` doesn't overlap any voxels' shapes; see `solid_voxels_in_aabb`.
+pub fn is_aabb_free<V, C, F>(chunks: &C, aabb: Aabb, mut shape: F) -> bool
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let (min, max) = aabb.voxels();
+    !voxels_in_box(min, max).any(|coord| collides(chunks, coord, &aabb, &mut shape))
+}
+
+/// The voxels holding `aabb` up: the ones whose shapes are under its bottom face, with their tops no more than
+/// `tolerance` below it. An empty Vec means it's not standing on anything; e.g. it's in the air, or hanging too
+/// far over an edge. A small tolerance lets things that have just walked off a ledge still jump for a moment, and
+/// checks that somewhere a mob might spawn has ground under it (see `is_aabb_free` for checking that it fits).
+pub fn supported_by<V, C, F>(chunks: &C, aabb: Aabb, tolerance: f32, mut shape: F) -> Vec<VoxelCoord>
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let below = Aabb::new(
+        Coord::new(aabb.min.x, aabb.min.y - tolerance - EPSILON, aabb.min.z),
+        Coord::new(aabb.max.x, aabb.min.y + EPSILON, aabb.max.z),
+    );
+    let (min, max) = below.voxels();
+    voxels_in_box(min, max)
+        .filter(|&coord| {
+            chunks.get_voxel(coord).map_or(false, |voxel| {
+                shape(coord, &voxel).world_boxes(coord).any(|other| {
+                    overlaps_on(&aabb, &other, 0)
+                        && overlaps_on(&aabb, &other, 2)
+                        && other.max.y >= below.min.y
+                        && other.max.y <= below.max.y
+                })
+            })
+        })
+        .collect()
+}
+
+/// Every point within `radius` of the segment from `a` to `b`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Capsule {
+    pub a: Coord,
+    pub b: Coord,
+    pub radius: f32,
+}
+impl Capsule {
+    pub fn new(a: Coord, b: Coord, radius: f32) -> Self {
+        assert!(radius >= 0.0, "negative capsule radius");
+        Capsule { a, b, radius }
+    }
+
+    /// A capsule standing upright on `feet`, `height` tall; e.g. for a player.
+    pub fn upright(feet: Coord, height: f32, radius: f32) -> Self {
+        assert!(height >= radius * 2.0, "capsule shorter than it is wide");
+        Capsule::new(
+            feet + Coord::new(0.0, radius, 0.0),
+            feet + Coord::new(0.0, height - radius, 0.0),
+            radius,
+        )
+    }
+
+    /// This capsule moved by `offset`.
+    pub fn translate(&self, offset: Coord) -> Self {
+        Capsule::new(self.a + offset, self.b + offset, self.radius)
+    }
+
+    /// The smallest box containing the capsule.
+    pub fn aabb(&self) -> Aabb {
+        let radius = Coord::new(self.radius, self.radius, self.radius);
+        let ends = Aabb::new(self.a, self.a).union(&Aabb::new(self.b, self.b));
+        Aabb::new(ends.min - radius, ends.max + radius)
+    }
+
+    /// The closest points on the capsule's segment and in `aabb`.
+    fn closest(&self, aabb: &Aabb) -> (Coord, Coord) {
+        let along = |t: f32| self.a + (self.b - self.a) * t;
+        let (t, _) = minimize(|t| {
+            let point = along(t);
+            (aabb.closest_point(point) - point).magnitude2()
+        });
+        (along(t), aabb.closest_point(along(t)))
+    }
+
+    /// How far the capsule's surface is from `aabb`; negative if they overlap.
+    pub fn distance(&self, aabb: &Aabb) -> f32 {
+        let (point, closest) = self.closest(aabb);
+        (closest - point).magnitude() - self.radius
+    }
+}
+
+/// Where a swept capsule hit something; see `sweep_capsule`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CapsuleHit {
+    /// How far along the sweep the capsule got, from 0 to 1.
+    pub fraction: f32,
+    /// The voxel it hit.
+    pub voxel: VoxelCoord,
+    /// The direction the voxel pushes back in, from the point it was hit. Against an edge or a corner it leans around
+    /// it, so sliding along it carries the capsule round.
+    pub normal: Coord,
+}
+
+/// Where a convex `f` is lowest on [0, 1], and its value there.
+fn minimize<F: FnMut(f32) -> f32>(mut f: F) -> (f32, f32) {
+    let (mut lo, mut hi) = (0.0, 1.0);
+    for _ in 0..40 {
+        let third = (hi - lo) / 3.0;
+        if f(lo + third) <= f(hi - third) {
+            hi -= third;
+        } else {
+            lo += third;
+        }
+    }
+    let t = (lo + hi) / 2.0;
+    (t, f(t))
+}
+
+/// The voxels whose shapes `capsule` overlaps, in x, y, z order. As with `solid_voxels_in_aabb`, touching doesn't
+/// count.
+pub fn capsule_voxels<V, C, F>(chunks: &C, capsule: Capsule, mut shape: F) -> Vec<VoxelCoord>
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let (min, max) = capsule.aabb().voxels();
+    voxels_in_box(min, max)
+        .filter(|&coord| {
+            chunks.get_voxel(coord).map_or(false, |voxel| {
+                shape(coord, &voxel)
+                    .world_boxes(coord)
+                    .any(|other| capsule.distance(&other) < -EPSILON)
+            })
+        })
+        .collect()
+}
+
+/// Whether `capsule` doesn't overlap any voxels' shapes; see `capsule_voxels`.
+pub fn is_capsule_free<V, C, F>(chunks: &C, capsule: Capsule, shape: F) -> bool
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    capsule_voxels(chunks, capsule, shape).is_empty()
+}
+
+/// Sweep `capsule` by `motion`, returning the first voxel it hits, if it hits one. As with `move_aabb`, shapes it
+/// already overlaps are ignored, so it can get out of them, and so are ones it's touching and moving along or away
+/// from. Each shape nearby takes some searching, so sweep a frame's movement at a time rather than across the world.
+pub fn sweep_capsule<V, C, F>(chunks: &C, capsule: Capsule, motion: Coord, mut shape: F) -> Option<CapsuleHit>
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let (min, max) = capsule.aabb().union(&capsule.translate(motion).aabb()).voxels();
+    let mut first: Option<CapsuleHit> = None;
+    for coord in voxels_in_box(min, max) {
+        let voxel = match chunks.get_voxel(coord) {
+            Some(voxel) => voxel,
+            None => continue,
+        };
+        for other in shape(coord, &voxel).world_boxes(coord) {
+            // (the capsule's distance from a box is convex as it moves, so it only gets close to it once)
+            let distance = |t: f32| capsule.translate(motion * t).distance(&other);
+            let start = distance(0.0);
+            if start < -EPSILON {
+                continue;
+            }
+            let (closest, nearest) = minimize(&distance);
+            if nearest >= -EPSILON {
+                continue;
+            }
+            let mut fraction = 0.0;
+            if start > 0.0 {
+                // the last moment it's clear of the box
+                let mut before = closest;
+                for _ in 0..30 {
+                    let middle = (fraction + before) / 2.0;
+                    if distance(middle) > 0.0 {
+                        fraction = middle;
+                    } else {
+                        before = middle;
+                    }
+                }
+            }
+            if first.map_or(true, |first| fraction < first.fraction) {
+                let (point, closest) = capsule.translate(motion * fraction).closest(&other);
+                let normal = if (point - closest).magnitude2() > 0.0 {
+                    (point - closest).normalize()
+                } else {
+                    -motion.normalize()
+                };
+                first = Some(CapsuleHit {
+                    fraction,
+                    voxel: coord,
+                    normal,
+                });
+            }
+        }
+    }
+    first
+}
+
+/// The usual `is_fluid` for `submersion`: the voxel's own `Voxel::is_fluid`.
+pub fn voxel_is_fluid<V: Voxel>(_: VoxelCoord, voxel: &V) -> bool {
+    voxel.is_fluid()
+}
+
+/// How far a box is in fluid; see `submersion`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Submersion<V: Voxel> {
+    /// How much of the box's volume is in fluid, from 0 to 1.
+    pub fraction: f32,
+    /// The fluid that most of that is, if there's any.
+    pub fluid: Option<V>,
+}
+
+/// How far `aabb` is in fluid voxels, and which fluid; e.g. for buoyancy and drag in proportion to the fraction,
+/// and swimming once it's past some depth. Fluids fill their voxels.
+pub fn submersion<V, C, F>(chunks: &C, aabb: Aabb, mut is_fluid: F) -> Submersion<V>
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> bool,
+{
+    // (volume in each fluid; there are never many kinds in one place)
+    let mut volumes: Vec<(V, f32)> = Vec::new();
+    let (min, max) = aabb.voxels();
+    for coord in voxels_in_box(min, max) {
+        let voxel = match chunks.get_voxel(coord) {
+            Some(voxel) => voxel,
+            None => continue,
+        };
+        if !is_fluid(coord, &voxel) {
+            continue;
+        }
+        let other = voxel_aabb(coord);
+        let volume: f32 = (0..3)
+            .map(|i| (aabb.max[i].min(other.max[i]) - aabb.min[i].max(other.min[i])).max(0.0))
+            .product();
+        match volumes.iter().position(|&(fluid, _)| fluid == voxel) {
+            Some(i) => volumes[i].1 += volume,
+            None => volumes.push((voxel, volume)),
+        }
+    }
+    let size = aabb.max - aabb.min;
+    let total = size.x * size.y * size.z;
+    if total <= 0.0 {
+        return Submersion::default();
+    }
+    let in_fluid: f32 = volumes.iter().map(|&(_, volume)| volume).sum();
+    let most = volumes
+        .iter()
+        .fold(None, |most: Option<(V, f32)>, &(fluid, volume)| match most {
+            Some((_, most_volume)) if most_volume >= volume => most,
+            _ => Some((fluid, volume)),
+        });
+    Submersion {
+        fraction: (in_fluid / total).min(1.0),
+        fluid: most.map(|(fluid, _)| fluid),
+    }
+}
+
+/// What a box ran into during a `move_aabb`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Contacts {
+    /// It hit something moving down; i.e. it's standing on the ground.
+    pub ground: bool,
+    /// It hit something moving up.
+    pub ceiling: bool,
+    /// The normal of the wall it hit moving sideways, if it hit one (the last one, if it hit two).
+    pub wall: Option<VoxelCoord>,
+}
+
+/// The result of `move_aabb`.
+#[derive(Clone, Copy, Debug)]
+pub struct Movement {
+    aabb: Aabb,
+    velocity: Coord,
+    contacts: Contacts,
+}
+impl Movement {
+    /// The box, moved as far as it could go.
+    pub fn aabb(&self) -> Aabb {
+        self.aabb
+    }
+    /// The velocity, with the parts that ran into something zeroed.
+    pub fn velocity(&self) -> Coord {
+        self.velocity
+    }
+    /// What the box ran into.
+    pub fn contacts(&self) -> Contacts {
+        self.contacts
+    }
+}
+
+/// How far `aabb` can move `distance` along `axis` (backwards, if it's negative) before it runs into a voxel's
+/// shape, and the voxel it runs into. Shapes it already overlaps don't stop it, so that it can get out of them.
+fn sweep<V, C, F>(chunks: &C, aabb: Aabb, axis: usize, distance: f32, shape: &mut F) -> (f32, Option<VoxelCoord>)
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let mut offset = Coord::new(0.0, 0.0, 0.0);
+    offset[axis] = distance;
+    let (min, max) = aabb.union(&aabb.translate(offset)).voxels();
+    let mut allowed = distance.abs();
+    let mut hit = None;
+    for coord in voxels_in_box(min, max) {
+        let voxel = match chunks.get_voxel(coord) {
+            Some(voxel) => voxel,
+            None => continue,
+        };
+        for other in shape(coord, &voxel).world_boxes(coord) {
+            if !(0..3).filter(|&i| i != axis).all(|i| overlaps_on(&aabb, &other, i)) {
+                continue;
+            }
+            let gap = if distance > 0.0 {
+                other.min[axis] - aabb.max[axis]
+            } else {
+                aabb.min[axis] - other.max[axis]
+            };
+            if gap > -EPSILON && gap <= allowed {
+                allowed = gap.max(0.0);
+                hit = Some(coord);
+            }
+        }
+    }
+    (allowed * distance.signum(), hit)
+}
+
+/// Move `movement`'s box along `axis` at its velocity for `dt` seconds, recording what it hits.
+fn slide<V, C, F>(chunks: &C, movement: &mut Movement, axis: usize, dt: f32, shape: &mut F)
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let distance = movement.velocity[axis] * dt;
+    if distance == 0.0 {
+        return;
+    }
+    let (moved, hit) = sweep(chunks, movement.aabb, axis, distance, shape);
+    let mut offset = Coord::new(0.0, 0.0, 0.0);
+    offset[axis] = moved;
+    movement.aabb = movement.aabb.translate(offset);
+    if hit.is_some() {
+        movement.velocity[axis] = 0.0;
+        match (axis, distance > 0.0) {
+            (1, false) => movement.contacts.ground = true,
+            (1, true) => movement.contacts.ceiling = true,
+            _ => {
+                let mut normal = VoxelCoord::new(0, 0, 0);
+                normal[axis] = if distance > 0.0 { -1 } else { 1 };
+                movement.contacts.wall = Some(normal);
+            }
+        }
+    }
+}
+
+/// Move `aabb` at `velocity` for `dt` seconds, stopping it at voxels' shapes. It's swept along y, then x, then z, so
+/// it slides along whatever it hits rather than stopping dead; the velocity along an axis it hits something on is
+/// zeroed. A box already overlapping a shape can still move out of it.
+///
+/// A box resting on the ground "hits" it whenever it's pushed down, so keep applying gravity to keep
+/// `Contacts::ground` up to date.
+pub fn move_aabb<V, C, F>(chunks: &C, aabb: Aabb, velocity: Coord, dt: f32, shape: F) -> Movement
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    move_aabb_stepping(chunks, aabb, velocity, dt, 0.0, shape)
+}
+
+/// As `move_aabb`, but a box on the ground that walks into something no taller than `step_height` (e.g. 1, for a
+/// ledge one voxel high) steps up onto it, rather than having to jump. It's tried both ways, stepping up and not, and
+/// whichever gets the box further sideways wins; so it doesn't step up onto things it could slide along instead.
+pub fn move_aabb_stepping<V, C, F>(
+    chunks: &C,
+    aabb: Aabb,
+    velocity: Coord,
+    dt: f32,
+    step_height: f32,
+    mut shape: F,
+) -> Movement
+where
+    V: Voxel,
+    C: ChunkAccess<V>,
+    F: FnMut(VoxelCoord, &V) -> CollisionShape,
+{
+    let mut movement = Movement {
+        aabb,
+        velocity,
+        contacts: Contacts::default(),
+    };
+    slide(chunks, &mut movement, 1, dt, &mut shape);
+    let fallen = movement;
+    slide(chunks, &mut movement, 0, dt, &mut shape);
+    slide(chunks, &mut movement, 2, dt, &mut shape);
+    if step_height <= 0.0 || !movement.contacts.ground || movement.contacts.wall.is_none() {
+        return movement;
+    }
+
+    // lift the box up as far as it'll go, move it sideways from there, and put it back down
+    let mut stepped = fallen;
+    let (lifted, _) = sweep(chunks, stepped.aabb, 1, step_height, &mut shape);
+    stepped.aabb = stepped.aabb.translate(Coord::new(0.0, lifted, 0.0));
+    slide(chunks, &mut stepped, 0, dt, &mut shape);
+    slide(chunks, &mut stepped, 2, dt, &mut shape);
+    let (lowered, _) = sweep(chunks, stepped.aabb, 1, -lifted, &mut shape);
+    stepped.aabb = stepped.aabb.translate(Coord::new(0.0, lowered, 0.0));
+    let sideways = |moved: &Movement| {
+        let offset = moved.aabb.min - aabb.min;
+        offset.x * offset.x + offset.z * offset.z
+    };
+    if sideways(&stepped) > sideways(&movement) + EPSILON {
+        stepped
+    } else {
+        movement
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use {Chunk, TestVoxel};
+
+    #[test]
+    fn overlaps() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        chunk[VoxelCoord::new(5, 1, 5)] = TestVoxel::Grass;
+        chunks.insert(chunk.coord, chunk);
+
+        // standing on the ground, next to the grass
+        let player = Aabb::new(Coord::new(3.6, 0.5, 4.6), Coord::new(4.4, 2.3, 5.4));
+        assert!(solid_voxels_in_aabb(&chunks, player, voxel_shape).is_empty());
+        assert!(is_aabb_free(&chunks, player, voxel_shape));
+
+        // sunk into the ground, and into the grass
+        let sunk = player.translate(Coord::new(1.0, -0.2, 0.0));
+        let overlapping = solid_voxels_in_aabb(&chunks, sunk, voxel_shape);
+        assert_eq!(overlapping, vec![VoxelCoord::new(5, 0, 5), VoxelCoord::new(5, 1, 5)]);
+        assert!(!is_aabb_free(&chunks, sunk, voxel_shape));
+        // grass doesn't count if it's not solid
+        let rock = |_, voxel: &TestVoxel| match *voxel {
+            TestVoxel::Rock => CollisionShape::Cube,
+            _ => CollisionShape::Empty,
+        };
+        assert_eq!(solid_voxels_in_aabb(&chunks, sunk, rock), vec![VoxelCoord::new(5, 0, 5)]);
+
+        // unloaded chunks are empty
+        assert!(is_aabb_free(&chunks, sunk.translate(Coord::new(-20.0, 0.0, 0.0)), voxel_shape));
+        let grass = VoxelCoord::new(5, 1, 5);
+        assert_eq!(voxel_aabb(grass).voxels(), (grass, grass));
+    }
+    #[test]
+    fn movement() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        // a wall at x = 8, and a ceiling at y = 4
+        chunk.fill_box(VoxelCoord::new(8, 1, 0), VoxelCoord::new(8, 3, 15), TestVoxel::Rock);
+        chunk.fill_box(VoxelCoord::new(0, 4, 0), VoxelCoord::new(15, 4, 15), TestVoxel::Rock);
+        chunks.insert(chunk.coord, chunk);
+        let player = Aabb::new(Coord::new(3.6, 1.0, 4.6), Coord::new(4.4, 2.8, 5.4));
+
+        // falling onto the ground, while walking
+        let fall = move_aabb(&chunks, player, Coord::new(1.0, -10.0, 0.0), 0.1, voxel_shape);
+        assert_eq!(fall.contacts(), Contacts { ground: true, ..Default::default() });
+        assert!((fall.aabb().min.y - 0.5).abs() < 1e-5);
+        assert!((fall.aabb().min.x - 3.7).abs() < 1e-5);
+        assert_eq!(fall.velocity(), Coord::new(1.0, 0.0, 0.0));
+
+        // standing still on it
+        let stand = move_aabb(&chunks, fall.aabb(), Coord::new(0.0, -1.0, 0.0), 0.1, voxel_shape);
+        assert!(stand.contacts().ground);
+        assert_eq!(stand.aabb(), fall.aabb());
+
+        // sliding along the wall
+        let slide = move_aabb(&chunks, fall.aabb(), Coord::new(20.0, 0.0, 5.0), 0.5, voxel_shape);
+        assert_eq!(slide.contacts().wall, Some(VoxelCoord::new(-1, 0, 0)));
+        assert!((slide.aabb().max.x - 7.5).abs() < 1e-5);
+        assert!((slide.aabb().min.z - 7.1).abs() < 1e-5);
+        assert_eq!(slide.velocity(), Coord::new(0.0, 0.0, 5.0));
+
+        // jumping into the ceiling
+        let jump = move_aabb(&chunks, fall.aabb(), Coord::new(0.0, 10.0, 0.0), 1.0, voxel_shape);
+        assert!(jump.contacts().ceiling && !jump.contacts().ground);
+        assert!((jump.aabb().max.y - 3.5).abs() < 1e-5);
+    }
+    #[test]
+    fn shapes() {
+        // a fence post, in the middle of its voxel
+        static POST: [Aabb; 1] = [Aabb {
+            min: Vector3 { x: 0.375, y: 0.0, z: 0.375 },
+            max: Vector3 { x: 0.625, y: 1.0, z: 0.625 },
+        }];
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        // grass slabs at x = 5, and a post at (8, 1, 5)
+        chunk.fill_box(VoxelCoord::new(5, 1, 0), VoxelCoord::new(5, 1, 15), TestVoxel::Grass);
+        chunk[VoxelCoord::new(8, 1, 5)] = TestVoxel::Rock;
+        chunks.insert(chunk.coord, chunk);
+        let shape = |coord: VoxelCoord, voxel: &TestVoxel| match *voxel {
+            TestVoxel::Grass => CollisionShape::Slab,
+            TestVoxel::Rock if coord.y == 1 => CollisionShape::Boxes(&POST),
+            _ => voxel.collision_shape(),
+        };
+        assert_eq!(CollisionShape::Slab.world_boxes(VoxelCoord::new(5, 1, 0)).count(), 1);
+
+        // landing on a slab, which only comes halfway up its voxel
+        let player = Aabb::new(Coord::new(4.6, 3.0, 4.6), Coord::new(5.4, 4.8, 5.4));
+        let fall = move_aabb(&chunks, player, Coord::new(0.0, -10.0, 0.0), 1.0, shape);
+        assert!(fall.contacts().ground);
+        assert!((fall.aabb().min.y - 1.0).abs() < 1e-5);
+        assert!(is_aabb_free(&chunks, fall.aabb(), shape));
+        assert!(!is_aabb_free(&chunks, fall.aabb().translate(Coord::new(0.0, -0.2, 0.0)), shape));
+        // (as a full cube, it's in the way)
+        assert!(!is_aabb_free(&chunks, fall.aabb(), voxel_shape));
+
+        // walking into the post stops at the post, not at its voxel
+        let walker = Aabb::new(Coord::new(6.1, 0.5, 4.6), Coord::new(6.9, 2.3, 5.4));
+        let walk = move_aabb(&chunks, walker, Coord::new(4.0, 0.0, 0.0), 1.0, shape);
+        assert_eq!(walk.contacts().wall, Some(VoxelCoord::new(-1, 0, 0)));
+        assert!((walk.aabb().max.x - 7.875).abs() < 1e-5);
+        let touching = solid_voxels_in_aabb(&chunks, walker.translate(Coord::new(1.5, 0.0, 0.0)), shape);
+        assert_eq!(touching, vec![VoxelCoord::new(8, 1, 5)]);
+        // ...and walking past it doesn't
+        let beside = walker.translate(Coord::new(0.0, 0.0, 0.7));
+        let past = move_aabb(&chunks, beside, Coord::new(4.0, 0.0, 0.0), 1.0, shape);
+        assert_eq!(past.contacts(), Contacts::default());
+        assert!((past.aabb().max.x - 10.9).abs() < 1e-5);
+    }
+    #[test]
+    fn fluids() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        // a pool of "water", two deep
+        chunk.fill_box(VoxelCoord::new(0, 1, 0), VoxelCoord::new(7, 2, 15), TestVoxel::Grass);
+        chunks.insert(chunk.coord, chunk);
+        let water = |_, voxel: &TestVoxel| *voxel == TestVoxel::Grass;
+
+        // standing in it, up to the waist
+        let player = Aabb::new(Coord::new(3.6, 0.5, 4.6), Coord::new(4.4, 4.5, 5.4));
+        let wading = submersion(&chunks, player, water);
+        assert!((wading.fraction - 0.5).abs() < 1e-5);
+        assert_eq!(wading.fluid, Some(TestVoxel::Grass));
+        // half of that, at the edge
+        let edge = submersion(&chunks, player.translate(Coord::new(3.5, 0.0, 0.0)), water);
+        assert!((edge.fraction - 0.25).abs() < 1e-5);
+        // out of it
+        let dry = submersion(&chunks, player.translate(Coord::new(5.0, 0.0, 0.0)), water);
+        assert_eq!(dry, Submersion::default());
+        assert_eq!(submersion(&chunks, player, voxel_is_fluid), Submersion::default());
+    }
+    #[test]
+    fn stepping() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        // a ledge one voxel high from x = 8 on, and a wall two high at z = 12
+        chunk.fill_box(VoxelCoord::new(8, 1, 0), VoxelCoord::new(15, 1, 15), TestVoxel::Rock);
+        chunk.fill_box(VoxelCoord::new(0, 1, 12), VoxelCoord::new(7, 2, 12), TestVoxel::Rock);
+        chunks.insert(chunk.coord, chunk);
+        let player = Aabb::new(Coord::new(3.6, 0.5, 4.6), Coord::new(4.4, 2.3, 5.4));
+        let walk = Coord::new(4.0, -1.0, 0.0);
+
+        // too high to step up
+        let blocked = move_aabb_stepping(&chunks, player, walk, 1.0, 0.5, voxel_shape);
+        assert_eq!(blocked.contacts().wall, Some(VoxelCoord::new(-1, 0, 0)));
+        assert!((blocked.aabb().max.x - 7.5).abs() < 1e-5);
+
+        // onto the ledge
+        let stepped = move_aabb_stepping(&chunks, player, walk, 1.0, 1.0, voxel_shape);
+        assert!(stepped.contacts().ground);
+        assert_eq!(stepped.contacts().wall, None);
+        assert!((stepped.aabb().min.x - 7.6).abs() < 1e-5);
+        assert!((stepped.aabb().min.y - 1.5).abs() < 1e-5);
+        assert_eq!(stepped.velocity(), Coord::new(4.0, 0.0, 0.0));
+
+        // not over the wall, or in the air
+        let wall = move_aabb_stepping(&chunks, player, Coord::new(0.0, -1.0, 8.0), 1.0, 1.0, voxel_shape);
+        assert!((wall.aabb().max.z - 11.5).abs() < 1e-5);
+        assert!((wall.aabb().min.y - 0.5).abs() < 1e-5);
+        let jumping = move_aabb_stepping(&chunks, player, Coord::new(4.0, 0.5, 0.0), 1.0, 1.0, voxel_shape);
+        assert!((jumping.aabb().max.x - 7.5).abs() < 1e-5);
+    }
+    #[test]
+    fn support() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        // a floor that ends at x = 7
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(7, 0, 15), TestVoxel::Rock);
+        chunks.insert(chunk.coord, chunk);
+        let player = Aabb::new(Coord::new(3.6, 0.5, 4.6), Coord::new(4.4, 2.3, 5.4));
+
+        let standing = supported_by(&chunks, player, 0.0, voxel_shape);
+        assert_eq!(standing, vec![VoxelCoord::new(4, 0, 5)]);
+        // over the edge, by more than half
+        let edge = player.translate(Coord::new(3.7, 0.0, 0.0));
+        assert_eq!(supported_by(&chunks, edge, 0.0, voxel_shape), vec![VoxelCoord::new(7, 0, 5)]);
+        assert!(supported_by(&chunks, edge.translate(Coord::new(0.2, 0.0, 0.0)), 0.0, voxel_shape).is_empty());
+        // just off the ground
+        let hopping = player.translate(Coord::new(0.0, 0.1, 0.0));
+        assert!(supported_by(&chunks, hopping, 0.0, voxel_shape).is_empty());
+        assert_eq!(supported_by(&chunks, hopping, 0.2, voxel_shape).len(), 1);
+        // ground it's sunk into isn't under it
+        assert!(supported_by(&chunks, player.translate(Coord::new(0.0, -0.5, 0.0)), 0.2, voxel_shape).is_empty());
+    }
+    #[test]
+    fn capsules() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        chunk[VoxelCoord::new(5, 1, 5)] = TestVoxel::Rock;
+        chunks.insert(chunk.coord, chunk);
+        let player = |x, z| Capsule::upright(Coord::new(x, 0.5, z), 1.8, 0.4);
+
+        // standing on the ground, and past the corner of the rock, where a box of the same size would overlap it
+        assert!(is_capsule_free(&chunks, player(4.2, 4.2), voxel_shape));
+        assert!(!is_aabb_free(&chunks, player(4.2, 4.2).aabb(), voxel_shape));
+        assert_eq!(capsule_voxels(&chunks, player(4.3, 4.3), voxel_shape), vec![VoxelCoord::new(5, 1, 5)]);
+
+        // walking into its face
+        let hit = sweep_capsule(&chunks, player(2.0, 5.0), Coord::new(4.0, 0.0, 0.0), voxel_shape).unwrap();
+        assert_eq!(hit.voxel, VoxelCoord::new(5, 1, 5));
+        assert!((hit.fraction - 0.525).abs() < 1e-3);
+        assert!((hit.normal - Coord::new(-1.0, 0.0, 0.0)).magnitude() < 1e-3);
+        // into its edge, which pushes back at an angle
+        let hit = sweep_capsule(&chunks, player(2.0, 4.2), Coord::new(4.0, 0.0, 0.0), voxel_shape).unwrap();
+        let x = 4.5 - (0.4f32 * 0.4 - 0.3 * 0.3).sqrt();
+        assert!((hit.fraction - (x - 2.0) / 4.0).abs() < 1e-3);
+        assert!(hit.normal.x < 0.0 && hit.normal.z < 0.0 && hit.normal.y.abs() < 1e-3);
+        assert!((hit.normal.magnitude() - 1.0).abs() < 1e-4);
+        // and past it, along the ground
+        assert!(sweep_capsule(&chunks, player(2.0, 4.0), Coord::new(4.0, 0.0, 0.0), voxel_shape).is_none());
+        // but not through it
+        let hit = sweep_capsule(&chunks, player(2.0, 2.0), Coord::new(0.0, -1.0, 0.0), voxel_shape).unwrap();
+        assert!(hit.fraction < 1e-4);
+        assert!((hit.normal - Coord::new(0.0, 1.0, 0.0)).magnitude() < 1e-3);
+    }
+}
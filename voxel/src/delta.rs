@@ -1,30 +1,662 @@
 //! A system to apply changes to voxel chunks without blocking everything that requires chunk lookup.
-use super::{canonicalize_chunk, Chunk, ChunkTracker, Voxel, VoxelCoord};
+//!
+//! Edits are queued in the `ChunkDeltas` resource and applied by `ChunkDeltaSystem` once per frame.
+//! The outcome of every edit is published to an `EventChannel<DeltaResult<V>>`,
+//! keyed by the `DeltaId` returned when it was queued; callers that don't care can ignore the id.
+//! Edits that actually change a voxel are also published as `VoxelChanged` events, for systems
+//! (lighting, fluids, sound...) that care about what changed rather than who changed it.
+//! Fills publish a `RegionFilled` for each chunk they change instead, so that filling a big box isn't
+//! followed by an event for every voxel in it, unless some system asks for those (see `want_fill_changes`).
+//!
+//! # Ordering
+//!
+//! Each frame, pending edits are applied in ascending `DeltaPriority` order, and within a priority
+//! in the order they were deferred. So when several edits target the same voxel, the one with the
+//! highest priority lands last and wins; e.g. a player's edit beats world generation.
+//! (Conditional edits are checked in the same order, so a lower tier's `defer_set_if` sees the
+//! voxel before a higher tier's edits land.)
+//! Edits deferred by a single system keep their relative order; edits from systems that run in
+//! parallel at the same priority land in whichever order the systems happened to defer them,
+//! so give them different priorities (or dispatcher dependencies) if the outcome matters.
+use super::{canonicalize_chunk, chunks_in_box, voxels_in_box, Chunk, ChunkTracker, Voxel, VoxelCoord,
+            CHUNK_SIZE};
+use structure::{MergePolicy, Placement, Rotation, Structure};
 
+use amethyst::shrev::EventChannel;
+use fnv::{FnvHashMap, FnvHashSet};
 use parking_lot::Mutex;
 use specs::prelude::*;
+use std::fmt;
 use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// The tier an edit is applied in. Lower tiers are applied first, so higher tiers win conflicts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum DeltaPriority {
+    WorldGen,
+    Simulation,
+    Normal,
+    Player,
+}
+impl Default for DeltaPriority {
+    fn default() -> Self {
+        DeltaPriority::Normal
+    }
+}
+
+/// Who or what deferred an edit. Propagated into `DeltaResult`s, `VoxelChanged` events and the journal,
+/// for attribution and permission checks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeltaSource {
+    Unknown,
+    /// An entity, e.g. a player.
+    Entity(Entity),
+    /// A system, by name.
+    System(&'static str),
+    /// A script, by some script-defined id.
+    Script(u32),
+}
+impl Default for DeltaSource {
+    fn default() -> Self {
+        DeltaSource::Unknown
+    }
+}
+
+/// Identifies a single deferred edit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct DeltaId(pub u64);
+
+/// What happened to a deferred edit.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DeltaOutcome<V: Voxel> {
+    /// The edit landed; `old` is the voxel it replaced.
+    Applied { old: V, new: V },
+    /// The edit's condition failed; `current` is the voxel that's still there.
+    Rejected { current: V },
+    /// There was no loaded chunk containing the edited coordinate.
+    NoChunk,
+    /// A later edit to the same voxel made this one redundant, so it was dropped.
+    /// Only happens when `ChunkDeltaSystem` coalesces edits.
+    Superseded,
+    /// A region edit landed, changing `changed` voxels.
+    /// `missing_chunks` chunks overlapping the region weren't loaded, and were skipped.
+    AppliedRegion {
+        changed: usize,
+        missing_chunks: usize,
+    },
+}
+
+/// The result of a deferred edit, published after the edit is applied.
+/// For region edits, `coord` is the minimum corner of the region.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DeltaResult<V: Voxel> {
+    pub id: DeltaId,
+    pub source: DeltaSource,
+    pub coord: VoxelCoord,
+    pub outcome: DeltaOutcome<V>,
+}
+
+/// Published whenever an applied edit changes a voxel.
+/// `id` is the edit responsible for the change, and `source` whoever deferred it.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct VoxelChanged<V: Voxel> {
+    pub id: DeltaId,
+    pub source: DeltaSource,
+    pub coord: VoxelCoord,
+    pub old: V,
+    pub new: V,
+}
+
+/// Published when a fill (see `ChunkDeltas::defer_fill_box`) changes voxels, once for each chunk it changed, with
+/// the part of the fill inside that chunk: every voxel from `min` to `max` (inclusive) is now `voxel`.
+/// Fills only publish `VoxelChanged` events as well if a system has called `want_fill_changes`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RegionFilled<V: Voxel> {
+    pub id: DeltaId,
+    pub source: DeltaSource,
+    pub min: VoxelCoord,
+    pub max: VoxelCoord,
+    pub voxel: V,
+}
+
+/// Have fills publish a `VoxelChanged` for every voxel they change, as well as their `RegionFilled`s; for systems
+/// that need to know about every voxel, whatever changed it (e.g. undo history). Call it from `System::setup`.
+pub fn want_fill_changes<V: Voxel>(resources: &mut Resources) {
+    Read::<ChunkDeltas<V>>::setup(resources);
+    resources.fetch::<ChunkDeltas<V>>().fill_changes.store(true, Ordering::Relaxed);
+}
+
+/// A condition on the current voxel, checked when an edit is applied.
+struct Predicate<V: Voxel>(Box<dyn Fn(&V) -> bool + Send + Sync>);
+impl<V: Voxel> fmt::Debug for Predicate<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Predicate")
+    }
+}
+
+/// A claim on the outcome of a `defer_swap`, which can be polled once deltas have been applied
+/// (i.e. next frame, or later this frame if `ChunkDeltaSystem` hasn't run yet).
+pub struct DeltaTicket<V: Voxel> {
+    id: DeltaId,
+    slot: Arc<Mutex<Option<DeltaOutcome<V>>>>,
+}
+impl<V: Voxel> DeltaTicket<V> {
+    pub fn id(&self) -> DeltaId {
+        self.id
+    }
+
+    /// The outcome of the swap, or None if it hasn't been applied yet.
+    pub fn poll(&self) -> Option<DeltaOutcome<V>> {
+        *self.slot.lock()
+    }
+
+    /// The voxel the swap replaced, or None if it hasn't been applied yet
+    /// (or its chunk wasn't loaded).
+    pub fn old(&self) -> Option<V> {
+        match self.poll() {
+            Some(DeltaOutcome::Applied { old, .. }) => Some(old),
+            _ => None,
+        }
+    }
+}
+
+/// The other half of a `DeltaTicket`.
+struct TicketSlot<V: Voxel>(Arc<Mutex<Option<DeltaOutcome<V>>>>);
+impl<V: Voxel> fmt::Debug for TicketSlot<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TicketSlot")
+    }
+}
+
+#[derive(Debug)]
+enum DeltaOp<V: Voxel> {
+    Set(V),
+    Swap { new: V, slot: TicketSlot<V> },
+    /// Fill a region with a single voxel.
+    Fill(V),
+    /// Transform every voxel in a region.
+    Map(fn(VoxelCoord, V) -> V),
+    /// Empty some of the voxels in a region.
+    Clear(FnvHashSet<VoxelCoord>),
+    /// Set some of the voxels in a region, where they pass the predicate.
+    SetVoxelsWhere {
+        coords: FnvHashSet<VoxelCoord>,
+        new: V,
+        predicate: Predicate<V>,
+    },
+    /// Set some of the voxels in a region, each to its own voxel.
+    SetVoxels(FnvHashMap<VoxelCoord, V>),
+    SetIf { expected: V, new: V },
+    SetWhere { new: V, predicate: Predicate<V> },
+    Stamp(Placement<V>),
+}
+impl<V: Voxel> DeltaOp<V> {
+    /// The voxel this op would leave at `coord` regardless of what's currently there, if any.
+    #[inline]
+    fn unconditional(&self, coord: VoxelCoord) -> Option<V> {
+        match *self {
+            DeltaOp::Set(voxel) | DeltaOp::Swap { new: voxel, .. } | DeltaOp::Fill(voxel) => Some(voxel),
+            DeltaOp::Stamp(ref placement) if placement.policy == MergePolicy::ReplaceAll => {
+                placement.voxel(coord, V::default())
+            }
+            DeltaOp::Clear(ref coords) if coords.contains(&coord) => Some(V::default()),
+            DeltaOp::SetVoxels(ref voxels) => voxels.get(&coord).cloned(),
+            _ => None,
+        }
+    }
+
+    /// The voxel this op would leave at `coord`, or None if it would be rejected
+    /// (or, for region ops, skipped).
+    #[inline]
+    fn apply(&self, coord: VoxelCoord, current: V) -> Option<V> {
+        match *self {
+            DeltaOp::Set(voxel) | DeltaOp::Swap { new: voxel, .. } | DeltaOp::Fill(voxel) => Some(voxel),
+            DeltaOp::SetIf { expected, new } => if current == expected {
+                Some(new)
+            } else {
+                None
+            },
+            DeltaOp::SetWhere {
+                ref new,
+                ref predicate,
+            } => if (predicate.0)(&current) {
+                Some(*new)
+            } else {
+                None
+            },
+            DeltaOp::Map(f) => Some(f(coord, current)),
+            DeltaOp::Stamp(ref placement) => placement.voxel(coord, current),
+            DeltaOp::Clear(ref coords) => if coords.contains(&coord) {
+                Some(V::default())
+            } else {
+                None
+            },
+            DeltaOp::SetVoxelsWhere {
+                ref coords,
+                new,
+                ref predicate,
+            } => if coords.contains(&coord) && (predicate.0)(&current) {
+                Some(new)
+            } else {
+                None
+            },
+            DeltaOp::SetVoxels(ref voxels) => voxels.get(&coord).cloned(),
+        }
+    }
+}
+
+/// The voxels a deferred edit touches.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Voxel(VoxelCoord),
+    /// The box from `min` to `max`, inclusive.
+    Region { min: VoxelCoord, max: VoxelCoord },
+}
+impl Target {
+    #[inline]
+    fn contains(&self, coord: VoxelCoord) -> bool {
+        match *self {
+            Target::Voxel(target) => target == coord,
+            Target::Region { min, max } => {
+                min.x <= coord.x && coord.x <= max.x && min.y <= coord.y && coord.y <= max.y
+                    && min.z <= coord.z && coord.z <= max.z
+            }
+        }
+    }
+}
+
+/// The smallest region containing all of `coords`, which mustn't be empty.
+fn bounding_box<'a, I: IntoIterator<Item = &'a VoxelCoord>>(coords: I) -> Target {
+    let mut coords = coords.into_iter();
+    let first = *coords.next().expect("no voxels");
+    let (mut min, mut max) = (first, first);
+    for coord in coords {
+        min = VoxelCoord::new(min.x.min(coord.x), min.y.min(coord.y), min.z.min(coord.z));
+        max = VoxelCoord::new(max.x.max(coord.x), max.y.max(coord.y), max.z.max(coord.z));
+    }
+    Target::Region { min, max }
+}
+
+#[derive(Debug)]
+struct PendingDelta<V: Voxel> {
+    id: DeltaId,
+    priority: DeltaPriority,
+    source: DeltaSource,
+    target: Target,
+    op: DeltaOp<V>,
+}
+
+#[derive(Default)]
+struct Pending<V: Voxel> {
+    deltas: Vec<PendingDelta<V>>,
+    next_id: u64,
+}
+impl<V: Voxel> Pending<V> {
+    fn push(
+        &mut self,
+        priority: DeltaPriority,
+        source: DeltaSource,
+        target: Target,
+        op: DeltaOp<V>,
+    ) -> DeltaId {
+        let id = DeltaId(self.next_id);
+        self.next_id += 1;
+        self.deltas.push(PendingDelta {
+            id,
+            priority,
+            source,
+            target,
+            op,
+        });
+        id
+    }
+
+    /// Sort the pending deltas into application order.
+    fn sort(&mut self) {
+        // stable, so deltas with the same priority stay in the order they were deferred
+        self.deltas.sort_by_key(|delta| delta.priority);
+    }
+
+    /// Remove plain sets that are immediately overwritten by another plain set to the same voxel,
+    /// returning them. Must be sorted first.
+    fn coalesce(&mut self) -> Vec<PendingDelta<V>> {
+        // walk backwards, tracking whether the next op to touch each voxel is a plain set.
+        // conditional ops and region ops observe the voxel, so they break the chain.
+        let mut next_is_set = FnvHashMap::default();
+        let mut superseded = vec![false; self.deltas.len()];
+        for (i, delta) in self.deltas.iter().enumerate().rev() {
+            match delta.target {
+                Target::Voxel(coord) => {
+                    let is_set = match delta.op {
+                        DeltaOp::Set(_) => true,
+                        _ => false,
+                    };
+                    superseded[i] = is_set && next_is_set.get(&coord) == Some(&true);
+                    next_is_set.insert(coord, is_set);
+                }
+                target => next_is_set.retain(|coord, _| !target.contains(*coord)),
+            }
+        }
+
+        let mut kept = Vec::with_capacity(self.deltas.len());
+        let mut dropped = Vec::new();
+        for (delta, superseded) in self.deltas.drain(..).zip(superseded) {
+            if superseded {
+                dropped.push(delta);
+            } else {
+                kept.push(delta);
+            }
+        }
+        self.deltas = kept;
+        dropped
+    }
+
+    /// Run the pending ops for `coord` on top of `base`, in application order.
+    /// `None` means the value can't be determined.
+    fn overlay(&self, coord: VoxelCoord, base: Option<V>) -> Option<V> {
+        let mut relevant: Vec<_> = self.deltas
+            .iter()
+            .filter(|delta| delta.target.contains(coord))
+            .collect();
+        relevant.sort_by_key(|delta| delta.priority);
+
+        let mut current = base;
+        for delta in relevant {
+            current = match delta.op.unconditional(coord) {
+                Some(voxel) => Some(voxel),
+                None => current.map(|voxel| delta.op.apply(coord, voxel).unwrap_or(voxel)),
+            };
+        }
+        current
+    }
+}
 
 #[derive(Default)]
 pub struct ChunkDeltas<V: Voxel> {
-    pending: Mutex<Vec<(VoxelCoord, V)>>,
+    pending: Mutex<Pending<V>>,
+    /// whether fills publish `VoxelChanged` events; see `want_fill_changes`
+    fill_changes: AtomicBool,
 }
 impl<V: Voxel> ChunkDeltas<V> {
     pub fn new() -> Self {
         Default::default()
     }
-    pub fn defer_set(&self, coord: VoxelCoord, voxel: V) {
-        self.pending.lock().push((coord, voxel));
+    /// A handle for deferring edits with non-default options, e.g.
+    /// `deltas.writer().priority(DeltaPriority::Player).source(DeltaSource::Entity(player)).defer_set(coord, voxel)`.
+    pub fn writer(&self) -> DeltaWriter<V> {
+        DeltaWriter {
+            deltas: self,
+            priority: DeltaPriority::default(),
+            source: DeltaSource::default(),
+        }
+    }
+
+    pub fn defer_set(&self, coord: VoxelCoord, voxel: V) -> DeltaId {
+        self.writer().defer_set(coord, voxel)
+    }
+
+    /// Set `coord` to `new`, returning a ticket that will hold the voxel that was replaced.
+    /// Useful for pick-block and "harvest whatever was there" mechanics.
+    pub fn defer_swap(&self, coord: VoxelCoord, new: V) -> DeltaTicket<V> {
+        self.writer().defer_swap(coord, new)
+    }
+
+    /// Set `coord` to `new`, but only if it's currently `expected` when the edit is applied.
+    ///
+    /// Edits are applied in order (see the module docs), so if two systems race to change the same voxel,
+    /// only the first will succeed. The outcome is published as a `DeltaResult` with the returned id.
+    pub fn defer_set_if(&self, coord: VoxelCoord, expected: V, new: V) -> DeltaId {
+        self.writer().defer_set_if(coord, expected, new)
+    }
+
+    /// Set `coord` to `new`, but only if `predicate` returns true for the voxel that's there
+    /// when the edit is applied; e.g. "only place if currently transparent".
+    ///
+    /// The outcome is published as a `DeltaResult` with the returned id.
+    pub fn defer_set_where<F>(&self, coord: VoxelCoord, new: V, predicate: F) -> DeltaId
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        self.writer().defer_set_where(coord, new, predicate)
+    }
+
+    /// Paste `structure` into the world with its minimum corner at `origin`, after rotating it.
+    ///
+    /// The structure may span several chunks; it's applied chunk by chunk, skipping chunks that
+    /// aren't loaded. The outcome is published as a `DeltaResult::AppliedRegion` with the returned id.
+    pub fn defer_stamp(
+        &self,
+        origin: VoxelCoord,
+        structure: &Structure<V>,
+        rotation: Rotation,
+        policy: MergePolicy,
+    ) -> DeltaId {
+        self.writer().defer_stamp(origin, structure, rotation, policy)
+    }
+
+    /// Set every voxel in the box from `min` to `max` (inclusive) to `voxel`.
+    ///
+    /// Applied chunk by chunk, skipping chunks that aren't loaded.
+    /// The outcome is published as a `DeltaResult::AppliedRegion` with the returned id.
+    pub fn defer_fill_box(&self, min: VoxelCoord, max: VoxelCoord, voxel: V) -> DeltaId {
+        self.writer().defer_fill_box(min, max, voxel)
+    }
+
+    /// Empty every voxel in the box from `min` to `max` (inclusive), e.g. for demolition.
+    /// See `defer_fill_box`.
+    pub fn defer_clear_box(&self, min: VoxelCoord, max: VoxelCoord) -> DeltaId {
+        self.writer().defer_clear_box(min, max)
+    }
+
+    /// Empty every voxel in `coords`, which mustn't be empty; e.g. the voxels an explosion destroys (see
+    /// `explosion`). Applied chunk by chunk over the box around them, skipping chunks that aren't loaded.
+    /// The outcome is published as a `DeltaResult::AppliedRegion` with the returned id.
+    pub fn defer_clear_voxels(&self, coords: &[VoxelCoord]) -> DeltaId {
+        self.writer().defer_clear_voxels(coords)
+    }
+
+    /// Set every voxel in `coords` that passes `predicate` to `new`; e.g. painting over just the solid voxels
+    /// in a shape (see `edit`). `coords` mustn't be empty. Applied chunk by chunk over the box around them,
+    /// skipping chunks that aren't loaded. The outcome is published as a `DeltaResult::AppliedRegion` with the
+    /// returned id.
+    pub fn defer_set_voxels_where<F>(&self, coords: &[VoxelCoord], new: V, predicate: F) -> DeltaId
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        self.writer().defer_set_voxels_where(coords, new, predicate)
+    }
+
+    /// Set each voxel in `voxels` to the voxel it maps to; e.g. putting back everything an operation changed
+    /// (see `history`). `voxels` mustn't be empty. Applied chunk by chunk over the box around them, skipping chunks
+    /// that aren't loaded. The outcome is published as a `DeltaResult::AppliedRegion` with the returned id.
+    pub fn defer_set_voxels(&self, voxels: FnvHashMap<VoxelCoord, V>) -> DeltaId {
+        self.writer().defer_set_voxels(voxels)
+    }
+
+    /// Replace every voxel in the box from `min` to `max` (inclusive) with `f(coord, voxel)`;
+    /// e.g. to turn stone into ore according to some noise function, or age crops.
+    ///
+    /// Applied chunk by chunk, skipping chunks that aren't loaded.
+    /// The outcome is published as a `DeltaResult::AppliedRegion` with the returned id.
+    pub fn defer_map_box(&self, min: VoxelCoord, max: VoxelCoord, f: fn(VoxelCoord, V) -> V) -> DeltaId {
+        self.writer().defer_map_box(min, max, f)
+    }
+
+    /// The value `coord` will have once pending deltas are applied, if that can be determined
+    /// from the pending deltas alone; i.e. if an unconditional edit to `coord` is pending.
+    ///
+    /// Note that this doesn't check that `coord` is actually loaded; if it isn't,
+    /// the edit will be dropped when deltas are applied.
+    pub fn pending_get(&self, coord: VoxelCoord) -> Option<V> {
+        self.pending.lock().overlay(coord, None)
+    }
+
+    /// The value `coord` will have once pending deltas are applied:
+    /// the stored voxel, overlaid with any pending edits.
+    ///
+    /// Returns None if `coord` isn't in a loaded chunk.
+    pub fn effective_get(
+        &self,
+        tracker: &ChunkTracker,
+        storage: &ReadStorage<Chunk<V>>,
+        coord: VoxelCoord,
+    ) -> Option<V> {
+        let chunk = tracker.get_chunk(storage, coord)?;
+        let stored = chunk[coord - chunk.coord];
+        self.pending.lock().overlay(coord, Some(stored))
     }
 }
+/// A handle for deferring edits with non-default options. See `ChunkDeltas::writer`.
+#[derive(Clone, Copy)]
+pub struct DeltaWriter<'a, V: Voxel + 'a> {
+    deltas: &'a ChunkDeltas<V>,
+    priority: DeltaPriority,
+    source: DeltaSource,
+}
+impl<'a, V: Voxel> DeltaWriter<'a, V> {
+    /// Set the priority of edits deferred through this handle.
+    pub fn priority(self, priority: DeltaPriority) -> Self {
+        DeltaWriter { priority, ..self }
+    }
+
+    /// Tag edits deferred through this handle with their source.
+    pub fn source(self, source: DeltaSource) -> Self {
+        DeltaWriter { source, ..self }
+    }
+
+    fn push(self, target: Target, op: DeltaOp<V>) -> DeltaId {
+        self.deltas
+            .pending
+            .lock()
+            .push(self.priority, self.source, target, op)
+    }
+
+    /// As `ChunkDeltas::defer_set`.
+    pub fn defer_set(self, coord: VoxelCoord, voxel: V) -> DeltaId {
+        self.push(Target::Voxel(coord), DeltaOp::Set(voxel))
+    }
+
+    /// As `ChunkDeltas::defer_swap`.
+    pub fn defer_swap(self, coord: VoxelCoord, new: V) -> DeltaTicket<V> {
+        let slot = Arc::new(Mutex::new(None));
+        let id = self.push(
+            Target::Voxel(coord),
+            DeltaOp::Swap {
+                new,
+                slot: TicketSlot(slot.clone()),
+            },
+        );
+        DeltaTicket { id, slot }
+    }
+
+    /// As `ChunkDeltas::defer_set_if`.
+    pub fn defer_set_if(self, coord: VoxelCoord, expected: V, new: V) -> DeltaId {
+        self.push(Target::Voxel(coord), DeltaOp::SetIf { expected, new })
+    }
+
+    /// As `ChunkDeltas::defer_set_where`.
+    pub fn defer_set_where<F>(self, coord: VoxelCoord, new: V, predicate: F) -> DeltaId
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        self.push(
+            Target::Voxel(coord),
+            DeltaOp::SetWhere {
+                new,
+                predicate: Predicate(Box::new(predicate)),
+            },
+        )
+    }
+
+    /// As `ChunkDeltas::defer_fill_box`.
+    pub fn defer_fill_box(self, min: VoxelCoord, max: VoxelCoord, voxel: V) -> DeltaId {
+        assert!(
+            min.x <= max.x && min.y <= max.y && min.z <= max.z,
+            "improper box: {:?} to {:?}",
+            min,
+            max
+        );
+        self.push(Target::Region { min, max }, DeltaOp::Fill(voxel))
+    }
+
+    /// As `ChunkDeltas::defer_clear_box`.
+    pub fn defer_clear_box(self, min: VoxelCoord, max: VoxelCoord) -> DeltaId {
+        self.defer_fill_box(min, max, V::default())
+    }
+
+    /// As `ChunkDeltas::defer_clear_voxels`.
+    pub fn defer_clear_voxels(self, coords: &[VoxelCoord]) -> DeltaId {
+        assert!(!coords.is_empty(), "no voxels to clear");
+        self.push(bounding_box(coords), DeltaOp::Clear(coords.iter().cloned().collect()))
+    }
+
+    /// As `ChunkDeltas::defer_set_voxels_where`.
+    pub fn defer_set_voxels_where<F>(self, coords: &[VoxelCoord], new: V, predicate: F) -> DeltaId
+    where
+        F: Fn(&V) -> bool + Send + Sync + 'static,
+    {
+        assert!(!coords.is_empty(), "no voxels to set");
+        self.push(
+            bounding_box(coords),
+            DeltaOp::SetVoxelsWhere {
+                coords: coords.iter().cloned().collect(),
+                new,
+                predicate: Predicate(Box::new(predicate)),
+            },
+        )
+    }
+
+    /// As `ChunkDeltas::defer_set_voxels`.
+    pub fn defer_set_voxels(self, voxels: FnvHashMap<VoxelCoord, V>) -> DeltaId {
+        assert!(!voxels.is_empty(), "no voxels to set");
+        self.push(bounding_box(voxels.keys()), DeltaOp::SetVoxels(voxels))
+    }
+
+    /// As `ChunkDeltas::defer_map_box`.
+    pub fn defer_map_box(self, min: VoxelCoord, max: VoxelCoord, f: fn(VoxelCoord, V) -> V) -> DeltaId {
+        assert!(
+            min.x <= max.x && min.y <= max.y && min.z <= max.z,
+            "improper box: {:?} to {:?}",
+            min,
+            max
+        );
+        self.push(Target::Region { min, max }, DeltaOp::Map(f))
+    }
+
+    /// As `ChunkDeltas::defer_stamp`.
+    pub fn defer_stamp(
+        self,
+        origin: VoxelCoord,
+        structure: &Structure<V>,
+        rotation: Rotation,
+        policy: MergePolicy,
+    ) -> DeltaId {
+        let placement = Placement::new(origin, Arc::new(structure.clone()), rotation, policy);
+        let (min, max) = placement.bounds();
+        self.push(Target::Region { min, max }, DeltaOp::Stamp(placement))
+    }
+}
+
 #[derive(Default)]
 pub struct ChunkDeltaSystem<V: Voxel> {
+    coalesce: bool,
     _phantom: PhantomData<V>,
 }
 impl<V: Voxel> ChunkDeltaSystem<V> {
     pub fn new() -> Self {
         Default::default()
     }
+
+    /// A system that drops plain `defer_set`s that are overwritten by another `defer_set` to the
+    /// same voxel in the same frame, before applying them; e.g. from rapid tool drags.
+    /// Dropped edits are reported as `DeltaOutcome::Superseded`, and don't emit `VoxelChanged` events.
+    pub fn coalescing() -> Self {
+        ChunkDeltaSystem {
+            coalesce: true,
+            _phantom: PhantomData,
+        }
+    }
 }
 impl<'a, V: Voxel> System<'a> for ChunkDeltaSystem<V> {
     type SystemData = (
@@ -34,24 +666,765 @@ impl<'a, V: Voxel> System<'a> for ChunkDeltaSystem<V> {
         // each frame.
         Write<'a, ChunkDeltas<V>>,
         WriteStorage<'a, Chunk<V>>,
+        Write<'a, EventChannel<DeltaResult<V>>>,
+        Write<'a, EventChannel<VoxelChanged<V>>>,
+        Write<'a, EventChannel<RegionFilled<V>>>,
     );
 
-    fn run(&mut self, (tracker, deltas, mut chunks): Self::SystemData) {
+    fn run(&mut self, data: Self::SystemData) {
+        let (tracker, deltas, mut chunks, mut results, mut changes, mut filled) = data;
+        let fill_changes = deltas.fill_changes.load(Ordering::Relaxed);
         let mut pending = deltas.pending.lock();
-        for (coord, voxel) in pending.drain(0..) {
-            let canon = canonicalize_chunk(coord);
-            // TODO error handling
-            let ent = tracker.get_chunk_ent(canon);
-            if let Some(ent) = ent {
-                let chunk = chunks.get_mut(ent).unwrap();
-                chunk[coord - canon] = voxel;
-            } else {
-                error!(
-                    "no chunk entity found for defer_set coord: {:?} voxel: {:?}, ignoring",
-                    coord, voxel
-                );
+        pending.sort();
+        if self.coalesce {
+            for delta in pending.coalesce() {
+                let coord = match delta.target {
+                    Target::Voxel(coord) => coord,
+                    Target::Region { min, .. } => min,
+                };
+                results.single_write(DeltaResult {
+                    id: delta.id,
+                    source: delta.source,
+                    coord,
+                    outcome: DeltaOutcome::Superseded,
+                });
+            }
+        }
+        for delta in pending.deltas.drain(0..) {
+            let result = match delta.target {
+                Target::Voxel(coord) => apply_voxel(&delta, coord, &tracker, &mut chunks, &mut changes),
+                Target::Region { min, max } => {
+                    apply_region(&delta, min, max, &tracker, &mut chunks, &mut changes, &mut filled, fill_changes)
+                }
+            };
+            results.single_write(result);
+        }
+    }
+}
+
+fn apply_voxel<V: Voxel>(
+    delta: &PendingDelta<V>,
+    coord: VoxelCoord,
+    tracker: &ChunkTracker,
+    chunks: &mut WriteStorage<Chunk<V>>,
+    changes: &mut EventChannel<VoxelChanged<V>>,
+) -> DeltaResult<V> {
+    let canon = canonicalize_chunk(coord);
+    let ent = tracker.get_chunk_ent(canon);
+    let outcome = if let Some(ent) = ent {
+        // check before taking the chunk mutably, so no-op edits don't trigger a re-mesh
+        let old = chunks.get(ent).unwrap()[coord - canon];
+        match delta.op.apply(coord, old) {
+            Some(new) => {
+                if new != old {
+                    chunks.get_mut(ent).unwrap()[coord - canon] = new;
+                    changes.single_write(VoxelChanged {
+                        id: delta.id,
+                        source: delta.source,
+                        coord,
+                        old,
+                        new,
+                    });
+                }
+                DeltaOutcome::Applied { old, new }
+            }
+            None => DeltaOutcome::Rejected { current: old },
+        }
+    } else {
+        error!(
+            "no chunk entity found for deferred edit coord: {:?} op: {:?}, ignoring",
+            coord, delta.op
+        );
+        DeltaOutcome::NoChunk
+    };
+    if let DeltaOp::Swap { ref slot, .. } = delta.op {
+        *slot.0.lock() = Some(outcome);
+    }
+    DeltaResult {
+        id: delta.id,
+        source: delta.source,
+        coord,
+        outcome,
+    }
+}
+
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn apply_region<V: Voxel>(
+    delta: &PendingDelta<V>,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    tracker: &ChunkTracker,
+    chunks: &mut WriteStorage<Chunk<V>>,
+    changes: &mut EventChannel<VoxelChanged<V>>,
+    filled: &mut EventChannel<RegionFilled<V>>,
+    fill_changes: bool,
+) -> DeltaResult<V> {
+    let mut changed = 0;
+    let mut missing_chunks = 0;
+    let mut edits = Vec::new();
+    let fill = match delta.op {
+        DeltaOp::Fill(voxel) => Some(voxel),
+        _ => None,
+    };
+
+    for chunk_coord in chunks_in_box(min, max) {
+        let ent = match tracker.get_chunk_ent(chunk_coord) {
+            Some(ent) => ent,
+            None => {
+                missing_chunks += 1;
                 continue;
             }
+        };
+
+        // the part of the region inside this chunk
+        let chunk_max = chunk_coord + VoxelCoord::new(1, 1, 1) * (CHUNK_SIZE as i16 - 1);
+        let lo = VoxelCoord::new(
+            min.x.max(chunk_coord.x),
+            min.y.max(chunk_coord.y),
+            min.z.max(chunk_coord.z),
+        );
+        let hi = VoxelCoord::new(max.x.min(chunk_max.x), max.y.min(chunk_max.y), max.z.min(chunk_max.z));
+
+        // fills only need per-voxel work if someone wants per-voxel events
+        if let (Some(voxel), false) = (fill, fill_changes) {
+            let count = {
+                let chunk = chunks.get(ent).unwrap();
+                voxels_in_box(lo, hi)
+                    .filter(|&coord| chunk[coord - chunk_coord] != voxel)
+                    .count()
+            };
+            if count > 0 {
+                chunks
+                    .get_mut(ent)
+                    .unwrap()
+                    .fill_box(lo - chunk_coord, hi - chunk_coord, voxel);
+                changed += count;
+                filled.single_write(RegionFilled {
+                    id: delta.id,
+                    source: delta.source,
+                    min: lo,
+                    max: hi,
+                    voxel,
+                });
+            }
+            continue;
+        }
+
+        // as with single voxels, only take the chunk mutably if something changes
+        edits.clear();
+        {
+            let chunk = chunks.get(ent).unwrap();
+            for coord in voxels_in_box(lo, hi) {
+                let old = chunk[coord - chunk_coord];
+                if let Some(new) = delta.op.apply(coord, old) {
+                    if new != old {
+                        edits.push(VoxelChanged {
+                            id: delta.id,
+                            source: delta.source,
+                            coord,
+                            old,
+                            new,
+                        });
+                    }
+                }
+            }
+        }
+        if !edits.is_empty() {
+            let chunk = chunks.get_mut(ent).unwrap();
+            if let Some(voxel) = fill {
+                chunk.fill_box(lo - chunk_coord, hi - chunk_coord, voxel);
+                filled.single_write(RegionFilled {
+                    id: delta.id,
+                    source: delta.source,
+                    min: lo,
+                    max: hi,
+                    voxel,
+                });
+            } else {
+                for edit in &edits {
+                    chunk[edit.coord - chunk_coord] = edit.new;
+                }
+            }
+            changed += edits.len();
+            changes.iter_write(edits.drain(..));
         }
     }
+
+    DeltaResult {
+        id: delta.id,
+        source: delta.source,
+        coord: min,
+        outcome: DeltaOutcome::AppliedRegion {
+            changed,
+            missing_chunks,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use structure::{MergePolicy, Rotation, Structure};
+    use tracker::ChunkTrackerSystem;
+    use TestVoxel;
+
+    fn setup() -> (World, Dispatcher<'static, 'static>) {
+        setup_with(ChunkDeltaSystem::new())
+    }
+
+    fn setup_with(system: ChunkDeltaSystem<TestVoxel>) -> (World, Dispatcher<'static, 'static>) {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<TestVoxel>::new());
+
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(system, "chunk_deltas", &["chunk_tracker"])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+
+        (world, dispatcher)
+    }
+
+    #[test]
+    fn read_your_writes() {
+        let (mut world, mut dispatcher) = setup();
+
+        let coord = VoxelCoord::new(1, 2, 3);
+        let unloaded = VoxelCoord::new(-1, 2, 3);
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let tracker = world.read_resource::<ChunkTracker>();
+            let chunks = world.read_storage::<Chunk<TestVoxel>>();
+
+            assert_eq!(deltas.pending_get(coord), None);
+            assert_eq!(
+                deltas.effective_get(&tracker, &chunks, coord),
+                Some(TestVoxel::Air)
+            );
+
+            deltas.defer_set(coord, TestVoxel::Rock);
+            deltas.defer_set(coord, TestVoxel::Grass);
+            deltas.defer_set(unloaded, TestVoxel::Rock);
+
+            assert_eq!(deltas.pending_get(coord), Some(TestVoxel::Grass));
+            assert_eq!(
+                deltas.effective_get(&tracker, &chunks, coord),
+                Some(TestVoxel::Grass)
+            );
+            assert_eq!(deltas.pending_get(unloaded), Some(TestVoxel::Rock));
+            assert_eq!(deltas.effective_get(&tracker, &chunks, unloaded), None);
+        }
+        dispatcher.dispatch(&mut world.res);
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let tracker = world.read_resource::<ChunkTracker>();
+            let chunks = world.read_storage::<Chunk<TestVoxel>>();
+
+            assert_eq!(deltas.pending_get(coord), None);
+            assert_eq!(
+                deltas.effective_get(&tracker, &chunks, coord),
+                Some(TestVoxel::Grass)
+            );
+        }
+    }
+
+    #[test]
+    fn missing_chunk() {
+        let (mut world, mut dispatcher) = setup();
+        let mut reader = world
+            .write_resource::<EventChannel<DeltaResult<TestVoxel>>>()
+            .register_reader();
+
+        let coord = VoxelCoord::new(-4, 4, 4);
+        let id = world
+            .read_resource::<ChunkDeltas<TestVoxel>>()
+            .defer_set(coord, TestVoxel::Rock);
+        dispatcher.dispatch(&mut world.res);
+
+        let results = world.read_resource::<EventChannel<DeltaResult<TestVoxel>>>();
+        let results: Vec<_> = results.read(&mut reader).cloned().collect();
+        assert_eq!(
+            results,
+            vec![DeltaResult {
+                id,
+                source: DeltaSource::Unknown,
+                coord,
+                outcome: DeltaOutcome::NoChunk,
+            }]
+        );
+    }
+
+    #[test]
+    fn predicates() {
+        let (mut world, mut dispatcher) = setup();
+
+        let (a, b) = (VoxelCoord::new(1, 1, 1), VoxelCoord::new(2, 2, 2));
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let tracker = world.read_resource::<ChunkTracker>();
+            let chunks = world.read_storage::<Chunk<TestVoxel>>();
+
+            deltas.defer_set(b, TestVoxel::Rock);
+            deltas.defer_set_where(a, TestVoxel::Grass, |v| v.is_transparent());
+            deltas.defer_set_where(b, TestVoxel::Grass, |v| v.is_transparent());
+
+            // can't tell without the stored voxel
+            assert_eq!(deltas.pending_get(a), None);
+            assert_eq!(
+                deltas.effective_get(&tracker, &chunks, a),
+                Some(TestVoxel::Grass)
+            );
+            assert_eq!(deltas.pending_get(b), Some(TestVoxel::Rock));
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, a).unwrap();
+        assert_eq!(chunk[a], TestVoxel::Grass);
+        assert_eq!(chunk[b], TestVoxel::Rock);
+    }
+
+    #[test]
+    fn changed_events() {
+        let (mut world, mut dispatcher) = setup();
+        let mut reader = world
+            .write_resource::<EventChannel<VoxelChanged<TestVoxel>>>()
+            .register_reader();
+
+        let coord = VoxelCoord::new(3, 2, 1);
+        let (first, third) = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let first = deltas
+                .writer()
+                .source(DeltaSource::System("test"))
+                .defer_set(coord, TestVoxel::Rock);
+            // doesn't change anything
+            deltas.defer_set(coord, TestVoxel::Rock);
+            let third = deltas.defer_set(coord, TestVoxel::Grass);
+            (first, third)
+        };
+        dispatcher.dispatch(&mut world.res);
+
+        let changes = world.read_resource::<EventChannel<VoxelChanged<TestVoxel>>>();
+        let changes: Vec<_> = changes.read(&mut reader).cloned().collect();
+        assert_eq!(
+            changes,
+            vec![
+                VoxelChanged {
+                    id: first,
+                    source: DeltaSource::System("test"),
+                    coord,
+                    old: TestVoxel::Air,
+                    new: TestVoxel::Rock,
+                },
+                VoxelChanged {
+                    id: third,
+                    source: DeltaSource::Unknown,
+                    coord,
+                    old: TestVoxel::Rock,
+                    new: TestVoxel::Grass,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn stamp() {
+        let (mut world, mut dispatcher) = setup();
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(16, 0, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+
+        // an L-shape lying along +x, with an air gap
+        let mut structure = Structure::<TestVoxel>::empty(VoxelCoord::new(3, 1, 2));
+        structure[VoxelCoord::new(0, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(1, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(2, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(0, 0, 1)] = TestVoxel::Grass;
+
+        // straddles the chunk border at x = 16, and the unloaded chunk at z = -16
+        let origin = VoxelCoord::new(15, 5, -1);
+        // lands on an air voxel in the rotated structure
+        let kept = VoxelCoord::new(15, 5, 0);
+        let id = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas.defer_set(kept, TestVoxel::Grass);
+            let id = deltas.defer_stamp(origin, &structure, Rotation::Quarter, MergePolicy::SkipAir);
+            assert_eq!(deltas.pending_get(kept), Some(TestVoxel::Grass));
+            id
+        };
+        let mut reader = world
+            .write_resource::<EventChannel<DeltaResult<TestVoxel>>>()
+            .register_reader();
+        dispatcher.dispatch(&mut world.res);
+
+        let size = structure.size();
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let mut expected_changes = 0;
+        for coord in structure.coords() {
+            let world_coord = origin + Rotation::Quarter.rotate(coord, size);
+            if let Some(chunk) = tracker.get_chunk(&chunks, world_coord) {
+                let expected = if world_coord == kept {
+                    TestVoxel::Grass
+                } else {
+                    structure[coord]
+                };
+                if structure[coord] != TestVoxel::Air {
+                    expected_changes += 1;
+                }
+                assert_eq!(chunk[world_coord - chunk.coord], expected);
+            }
+        }
+
+        let results = world.read_resource::<EventChannel<DeltaResult<TestVoxel>>>();
+        let result = results.read(&mut reader).find(|result| result.id == id).cloned();
+        assert_eq!(
+            result.map(|result| result.outcome),
+            Some(DeltaOutcome::AppliedRegion {
+                changed: expected_changes,
+                missing_chunks: 2,
+            })
+        );
+    }
+
+    #[test]
+    fn priorities() {
+        let (mut world, mut dispatcher) = setup();
+
+        let (a, b) = (VoxelCoord::new(5, 5, 5), VoxelCoord::new(6, 6, 6));
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas
+                .writer()
+                .priority(DeltaPriority::Player)
+                .defer_set(a, TestVoxel::Grass);
+            deltas.defer_set(a, TestVoxel::Rock);
+            deltas
+                .writer()
+                .priority(DeltaPriority::WorldGen)
+                .defer_set(a, TestVoxel::Air);
+
+            // conditions are checked in application order too, so the lower tier gets b
+            deltas.defer_set_if(b, TestVoxel::Air, TestVoxel::Rock);
+            deltas
+                .writer()
+                .priority(DeltaPriority::Player)
+                .defer_set_if(b, TestVoxel::Air, TestVoxel::Grass);
+
+            assert_eq!(deltas.pending_get(a), Some(TestVoxel::Grass));
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, a).unwrap();
+        assert_eq!(chunk[a], TestVoxel::Grass);
+        assert_eq!(chunk[b], TestVoxel::Rock);
+    }
+
+    #[test]
+    fn swap() {
+        let (mut world, mut dispatcher) = setup();
+
+        let coord = VoxelCoord::new(7, 8, 9);
+        let (first, second, missing) = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let first = deltas.defer_swap(coord, TestVoxel::Rock);
+            let second = deltas.defer_swap(coord, TestVoxel::Grass);
+            let missing = deltas.defer_swap(VoxelCoord::new(-1, 0, 0), TestVoxel::Grass);
+            assert_eq!(deltas.pending_get(coord), Some(TestVoxel::Grass));
+            (first, second, missing)
+        };
+        assert_eq!(first.poll(), None);
+        dispatcher.dispatch(&mut world.res);
+
+        assert_eq!(first.old(), Some(TestVoxel::Air));
+        assert_eq!(second.old(), Some(TestVoxel::Rock));
+        assert_eq!(missing.poll(), Some(DeltaOutcome::NoChunk));
+        assert_eq!(missing.old(), None);
+    }
+
+    #[test]
+    fn coalescing() {
+        let (mut world, mut dispatcher) = setup_with(ChunkDeltaSystem::coalescing());
+        let mut results_reader = world
+            .write_resource::<EventChannel<DeltaResult<TestVoxel>>>()
+            .register_reader();
+        let mut changes_reader = world
+            .write_resource::<EventChannel<VoxelChanged<TestVoxel>>>()
+            .register_reader();
+
+        let (a, b) = (VoxelCoord::new(1, 0, 0), VoxelCoord::new(2, 0, 0));
+        let ids = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            vec![
+                deltas.defer_set(a, TestVoxel::Rock),
+                deltas.defer_set(a, TestVoxel::Grass),
+                deltas.defer_set(a, TestVoxel::Rock),
+                deltas.defer_set(b, TestVoxel::Rock),
+                // observes b, so the set before it has to land
+                deltas.defer_set_if(b, TestVoxel::Rock, TestVoxel::Grass),
+                deltas.defer_set(b, TestVoxel::Rock),
+            ]
+        };
+        dispatcher.dispatch(&mut world.res);
+
+        let results = world.read_resource::<EventChannel<DeltaResult<TestVoxel>>>();
+        let superseded: Vec<_> = results
+            .read(&mut results_reader)
+            .filter(|result| result.outcome == DeltaOutcome::Superseded)
+            .map(|result| result.id)
+            .collect();
+        assert_eq!(superseded, vec![ids[0], ids[1]]);
+
+        let changes = world.read_resource::<EventChannel<VoxelChanged<TestVoxel>>>();
+        let changed: Vec<_> = changes
+            .read(&mut changes_reader)
+            .map(|change| (change.coord, change.new))
+            .collect();
+        assert_eq!(
+            changed,
+            vec![
+                (a, TestVoxel::Rock),
+                (b, TestVoxel::Rock),
+                (b, TestVoxel::Grass),
+                (b, TestVoxel::Rock),
+            ]
+        );
+    }
+
+    #[test]
+    fn clear_box() {
+        let (mut world, mut dispatcher) = setup();
+
+        let (min, max) = (VoxelCoord::new(2, 2, 2), VoxelCoord::new(4, 5, 6));
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas.defer_fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 15, 15), TestVoxel::Rock);
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let mut reader = world
+            .write_resource::<EventChannel<DeltaResult<TestVoxel>>>()
+            .register_reader();
+        let id = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            let id = deltas.defer_clear_box(min, max);
+            assert_eq!(deltas.pending_get(min), Some(TestVoxel::Air));
+            id
+        };
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, min).unwrap();
+        for coord in voxels_in_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 15, 15)) {
+            let inside = min.x <= coord.x && coord.x <= max.x && min.y <= coord.y && coord.y <= max.y
+                && min.z <= coord.z && coord.z <= max.z;
+            let expected = if inside { TestVoxel::Air } else { TestVoxel::Rock };
+            assert_eq!(chunk[coord], expected);
+        }
+
+        let results = world.read_resource::<EventChannel<DeltaResult<TestVoxel>>>();
+        let result = results.read(&mut reader).find(|result| result.id == id).cloned();
+        assert_eq!(
+            result.map(|result| result.outcome),
+            Some(DeltaOutcome::AppliedRegion {
+                changed: 3 * 4 * 5,
+                missing_chunks: 0,
+            })
+        );
+    }
+
+    #[test]
+    fn fill_events() {
+        let (mut world, mut dispatcher) = setup();
+        let mut changes = world
+            .write_resource::<EventChannel<VoxelChanged<TestVoxel>>>()
+            .register_reader();
+        let mut filled = world
+            .write_resource::<EventChannel<RegionFilled<TestVoxel>>>()
+            .register_reader();
+
+        let (min, max) = (VoxelCoord::new(1, 1, 1), VoxelCoord::new(2, 2, 2));
+        let first = world
+            .read_resource::<ChunkDeltas<TestVoxel>>()
+            .defer_fill_box(min, max, TestVoxel::Rock);
+        dispatcher.dispatch(&mut world.res);
+        {
+            let events = world.read_resource::<EventChannel<RegionFilled<TestVoxel>>>();
+            let events: Vec<_> = events.read(&mut filled).cloned().collect();
+            assert_eq!(
+                events,
+                vec![RegionFilled {
+                    id: first,
+                    source: DeltaSource::Unknown,
+                    min,
+                    max,
+                    voxel: TestVoxel::Rock,
+                }]
+            );
+            let events = world.read_resource::<EventChannel<VoxelChanged<TestVoxel>>>();
+            assert_eq!(events.read(&mut changes).count(), 0);
+        }
+
+        // once asked for, fills publish both
+        want_fill_changes::<TestVoxel>(&mut world.res);
+        let second = world
+            .read_resource::<ChunkDeltas<TestVoxel>>()
+            .defer_fill_box(min, max, TestVoxel::Grass);
+        dispatcher.dispatch(&mut world.res);
+        let events = world.read_resource::<EventChannel<RegionFilled<TestVoxel>>>();
+        let events: Vec<_> = events.read(&mut filled).map(|event| event.id).collect();
+        assert_eq!(events, vec![second]);
+        let events = world.read_resource::<EventChannel<VoxelChanged<TestVoxel>>>();
+        let events: Vec<_> = events.read(&mut changes).cloned().collect();
+        assert_eq!(events.len(), 8);
+        assert!(
+            events
+                .iter()
+                .all(|event| event.id == second && event.old == TestVoxel::Rock && event.new == TestVoxel::Grass)
+        );
+    }
+
+    #[test]
+    fn clear_voxels() {
+        let (mut world, mut dispatcher) = setup();
+
+        let (min, max) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(3, 0, 0));
+        let holes = [VoxelCoord::new(3, 0, 0), VoxelCoord::new(1, 0, 0)];
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas.defer_fill_box(min, max, TestVoxel::Rock);
+            deltas.defer_clear_voxels(&holes);
+            assert_eq!(deltas.pending_get(holes[0]), Some(TestVoxel::Air));
+            assert_eq!(deltas.pending_get(VoxelCoord::new(2, 0, 0)), Some(TestVoxel::Rock));
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, min).unwrap();
+        let row: Vec<_> = voxels_in_box(min, max).map(|coord| chunk[coord]).collect();
+        assert_eq!(row, vec![TestVoxel::Rock, TestVoxel::Air, TestVoxel::Rock, TestVoxel::Air]);
+    }
+
+    #[test]
+    fn set_voxels() {
+        let (mut world, mut dispatcher) = setup();
+
+        let (min, max) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(3, 0, 0));
+        let mut voxels = FnvHashMap::default();
+        voxels.insert(VoxelCoord::new(3, 0, 0), TestVoxel::Grass);
+        voxels.insert(VoxelCoord::new(1, 0, 0), TestVoxel::Air);
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas.defer_fill_box(min, max, TestVoxel::Rock);
+            deltas.defer_set_voxels(voxels);
+            assert_eq!(deltas.pending_get(VoxelCoord::new(3, 0, 0)), Some(TestVoxel::Grass));
+            assert_eq!(deltas.pending_get(VoxelCoord::new(2, 0, 0)), Some(TestVoxel::Rock));
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, min).unwrap();
+        let row: Vec<_> = voxels_in_box(min, max).map(|coord| chunk[coord]).collect();
+        assert_eq!(row, vec![TestVoxel::Rock, TestVoxel::Air, TestVoxel::Rock, TestVoxel::Grass]);
+    }
+
+    #[test]
+    fn map_box() {
+        let (mut world, mut dispatcher) = setup();
+
+        fn stripes(coord: VoxelCoord, voxel: TestVoxel) -> TestVoxel {
+            if voxel == TestVoxel::Rock && coord.x % 2 == 0 {
+                TestVoxel::Grass
+            } else {
+                voxel
+            }
+        }
+
+        let (min, max) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(3, 0, 0));
+        {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            deltas.defer_fill_box(min, max, TestVoxel::Rock);
+            deltas.defer_map_box(min, max, stripes);
+            // the map is run on top of the pending fill
+            assert_eq!(deltas.pending_get(min), Some(TestVoxel::Grass));
+            assert_eq!(deltas.pending_get(VoxelCoord::new(1, 0, 0)), Some(TestVoxel::Rock));
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, min).unwrap();
+        let row: Vec<_> = voxels_in_box(min, max).map(|coord| chunk[coord]).collect();
+        assert_eq!(
+            row,
+            vec![TestVoxel::Grass, TestVoxel::Rock, TestVoxel::Grass, TestVoxel::Rock]
+        );
+        assert_eq!(chunk[VoxelCoord::new(4, 0, 0)], TestVoxel::Air);
+    }
+
+    #[test]
+    fn compare_and_swap() {
+        let (mut world, mut dispatcher) = setup();
+        let mut reader = world
+            .write_resource::<EventChannel<DeltaResult<TestVoxel>>>()
+            .register_reader();
+
+        let coord = VoxelCoord::new(4, 4, 4);
+        let (first, second) = {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            // two players mining the same block
+            let first = deltas.defer_set_if(coord, TestVoxel::Air, TestVoxel::Rock);
+            let second = deltas.defer_set_if(coord, TestVoxel::Air, TestVoxel::Grass);
+            (first, second)
+        };
+        dispatcher.dispatch(&mut world.res);
+
+        let results = world.read_resource::<EventChannel<DeltaResult<TestVoxel>>>();
+        let results: Vec<_> = results.read(&mut reader).cloned().collect();
+        assert_eq!(
+            results,
+            vec![
+                DeltaResult {
+                    id: first,
+                    source: DeltaSource::Unknown,
+                    coord,
+                    outcome: DeltaOutcome::Applied {
+                        old: TestVoxel::Air,
+                        new: TestVoxel::Rock,
+                    },
+                },
+                DeltaResult {
+                    id: second,
+                    source: DeltaSource::Unknown,
+                    coord,
+                    outcome: DeltaOutcome::Rejected {
+                        current: TestVoxel::Rock,
+                    },
+                },
+            ]
+        );
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        assert_eq!(
+            tracker.get_chunk(&chunks, coord).unwrap()[coord],
+            TestVoxel::Rock
+        );
+    }
 }
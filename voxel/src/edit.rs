@@ -0,0 +1,857 @@
+//! Bulk edits, for building and terraforming tools: `apply_brush` edits every voxel in a shape at once, and
+//! `flood_fill` finds a connected region of the same voxel, to be replaced at once; both as a single deferred
+//! edit. `draw_line` and `draw_plane` do the same for beams, walls and roads, and a `Clipboard` copies a box of
+//! the world to paste somewhere else. `combine` and `combine_in_world` add, carve and intersect shapes.
+//!
+//! Tools that work on a box of the world take it from a `Selection`: either the resource, for the one being
+//! edited, or a component of each player's. `mesh::SelectionMeshSystem` outlines the resource.
+
+use super::{chunks_in_box, voxels_in_box, ChunkAccess, Coord, Voxel, VoxelCoord};
+use delta::{DeltaId, DeltaWriter};
+use structure::{Axis, MergePolicy, Rotation, Structure};
+
+use fnv::FnvHashSet;
+use hibitset::BitSet;
+use specs::prelude::*;
+
+/// A shape of voxels, centered on a voxel. Sizes are in voxels, measured from the center voxel to the centers of
+/// the voxels at the edge, so e.g. a sphere of radius 1 is the center and its six neighbours.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Brush {
+    Sphere { radius: f32 },
+    /// Inclusive, so a box with `half_size` 0,0,0 is just the center.
+    Box { half_size: VoxelCoord },
+    /// Upright, along the y axis.
+    Cylinder { radius: f32, half_height: u16 },
+    Ellipsoid { radii: Coord },
+}
+impl Brush {
+    /// The offset of the far corner of the box around the brush from its center.
+    fn extent(&self) -> VoxelCoord {
+        // (negative sizes come out negative, which `coords` takes to mean empty)
+        let reach = |radius: f32| radius.floor() as i16;
+        match *self {
+            Brush::Sphere { radius } => VoxelCoord::new(reach(radius), reach(radius), reach(radius)),
+            Brush::Box { half_size } => half_size,
+            Brush::Cylinder { radius, half_height } => {
+                VoxelCoord::new(reach(radius), half_height as i16, reach(radius))
+            }
+            Brush::Ellipsoid { radii } => {
+                VoxelCoord::new(reach(radii.x), reach(radii.y), reach(radii.z))
+            }
+        }
+    }
+
+    /// Whether the voxel `offset` from the brush's center is in it. Brushes with a negative size are empty.
+    pub fn contains(&self, offset: VoxelCoord) -> bool {
+        let (x, y, z) = (f32::from(offset.x), f32::from(offset.y), f32::from(offset.z));
+        match *self {
+            Brush::Sphere { radius } => radius >= 0.0 && x * x + y * y + z * z <= radius * radius,
+            Brush::Box { half_size } => {
+                offset.x.abs() <= half_size.x && offset.y.abs() <= half_size.y && offset.z.abs() <= half_size.z
+            }
+            Brush::Cylinder { radius, half_height } => {
+                radius >= 0.0 && x * x + z * z <= radius * radius && i32::from(offset.y).abs() <= i32::from(half_height)
+            }
+            Brush::Ellipsoid { radii } => {
+                // (a flat ellipsoid is an ellipse, so count the center plane of a zero radius as inside)
+                let term = |d: f32, radius: f32| if d == 0.0 { 0.0 } else { (d / radius) * (d / radius) };
+                radii.x >= 0.0 && radii.y >= 0.0 && radii.z >= 0.0
+                    && term(x, radii.x) + term(y, radii.y) + term(z, radii.z) <= 1.0
+            }
+        }
+    }
+
+    /// The voxels in the brush with its center at `center`, in x, y, z order.
+    pub fn coords(&self, center: VoxelCoord) -> Vec<VoxelCoord> {
+        let extent = self.extent();
+        if extent.x < 0 || extent.y < 0 || extent.z < 0 {
+            return Vec::new();
+        }
+        voxels_in_box(center - extent, center + extent)
+            .filter(|&coord| self.contains(coord - center))
+            .collect()
+    }
+}
+
+/// What a brush does to the voxels in it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum BrushMode {
+    /// Fill the empty voxels (`V::default()`), leaving the rest alone; for building.
+    Place,
+    /// Change the voxels that aren't empty, leaving the empty ones alone; for painting.
+    Replace,
+    /// Empty every voxel; the brush's voxel is ignored.
+    Erase,
+}
+
+/// Edit the voxels in `brush` around `center` with `voxel`, as one region edit through `deltas` (see
+/// `ChunkDeltas::defer_set_voxels_where` and `ChunkDeltas::defer_clear_voxels`), e.g.
+/// `apply_brush(deltas.writer().source(DeltaSource::Entity(player)), brush, center, voxel, BrushMode::Place)`;
+/// None if the brush is empty.
+///
+/// Like any region edit, the parts of the brush in unloaded chunks are skipped.
+pub fn apply_brush<V: Voxel>(
+    deltas: DeltaWriter<V>,
+    brush: Brush,
+    center: VoxelCoord,
+    voxel: V,
+    mode: BrushMode,
+) -> Option<DeltaId> {
+    let coords = brush.coords(center);
+    if coords.is_empty() {
+        return None;
+    }
+    Some(match mode {
+        BrushMode::Place => deltas.defer_set_voxels_where(&coords, voxel, |there: &V| *there == V::default()),
+        BrushMode::Replace => deltas.defer_set_voxels_where(&coords, voxel, |there: &V| *there != V::default()),
+        BrushMode::Erase => deltas.defer_clear_voxels(&coords),
+    })
+}
+
+/// The voxels on the straight line from `from` to `to`, in order, including both ends; a line of voxels touching
+/// each other at least by a corner, as Bresenham's algorithm draws it.
+fn line(from: VoxelCoord, to: VoxelCoord) -> Vec<VoxelCoord> {
+    let delta = [
+        i32::from(to.x) - i32::from(from.x),
+        i32::from(to.y) - i32::from(from.y),
+        i32::from(to.z) - i32::from(from.z),
+    ];
+    let steps = delta.iter().map(|d| d.abs()).max().unwrap();
+    if steps == 0 {
+        return vec![from];
+    }
+    // (the nearest voxel to the true line at each step along its longest axis)
+    let along = |d: i32, step: i32| {
+        let (numerator, denominator) = (2 * d * step + steps, 2 * steps);
+        let floor = if numerator < 0 {
+            (numerator - denominator + 1) / denominator
+        } else {
+            numerator / denominator
+        };
+        floor as i16
+    };
+    (0..steps + 1)
+        .map(|step| {
+            from + VoxelCoord::new(along(delta[0], step), along(delta[1], step), along(delta[2], step))
+        })
+        .collect()
+}
+
+/// The voxels within `radius` of the line from `from` to `to` (see `Brush::Sphere`), in x, y, z order; a radius of
+/// 0 is a line one voxel wide.
+pub fn line_voxels(from: VoxelCoord, to: VoxelCoord, radius: f32) -> Vec<VoxelCoord> {
+    assert!(radius >= 0.0, "lines can't be thinner than nothing");
+    let mut covered: FnvHashSet<VoxelCoord> = FnvHashSet::default();
+    for center in line(from, to) {
+        covered.extend(Brush::Sphere { radius }.coords(center));
+    }
+    let mut coords: Vec<_> = covered.into_iter().collect();
+    coords.sort_by_key(|coord| (coord.x, coord.y, coord.z));
+    coords
+}
+
+/// Set the voxels within `radius` of the line from `from` to `to` (see `line_voxels`) to `voxel`, as one region
+/// edit through `deltas`; e.g. for a beam, or with a wider radius, a tunnel.
+pub fn draw_line<V: Voxel>(deltas: DeltaWriter<V>, from: VoxelCoord, to: VoxelCoord, radius: f32, voxel: V) -> DeltaId {
+    deltas.defer_set_voxels_where(&line_voxels(from, to, radius), voxel, |_: &V| true)
+}
+
+/// The voxels in the box from `min` to `max` (inclusive) that are on the plane through the center of `through` at
+/// right angles to `normal`, which needn't be normalized; in x, y, z order.
+///
+/// The plane's `thickness` is scaled by the L1 length of `normal` (the sum of its components' sizes), so a plane 1
+/// thick is the thinnest layer of voxels that touch each other by their faces, with no gaps, whichever way it faces.
+/// Planes facing along an axis are `thickness` voxels thick, for whole numbers; other planes are thicker along each
+/// axis, e.g. one 1 thick with normal (1, 1, 0) is 2 voxels thick along x and along y.
+pub fn plane_voxels(
+    through: VoxelCoord,
+    normal: Coord,
+    thickness: f32,
+    min: VoxelCoord,
+    max: VoxelCoord,
+) -> Vec<VoxelCoord> {
+    let weight = normal.x.abs() + normal.y.abs() + normal.z.abs();
+    assert!(weight > 0.0, "planes must face somewhere");
+    let half = thickness * weight / 2.0;
+    voxels_in_box(min, max)
+        .filter(|&coord| {
+            let offset = (coord - through).cast::<f32>().unwrap();
+            let distance = normal.x * offset.x + normal.y * offset.y + normal.z * offset.z;
+            -half <= distance && distance < half
+        })
+        .collect()
+}
+
+/// Set the voxels on a plane in a box (see `plane_voxels`) to `voxel`, as one region edit through `deltas`, e.g.
+/// for a wall, or a sloping road; None if the plane misses the box. For planes that face along an axis,
+/// `ChunkDeltas::defer_fill_box` does the same more cheaply.
+pub fn draw_plane<V: Voxel>(
+    deltas: DeltaWriter<V>,
+    through: VoxelCoord,
+    normal: Coord,
+    thickness: f32,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    voxel: V,
+) -> Option<DeltaId> {
+    let coords = plane_voxels(through, normal, thickness, min, max);
+    if coords.is_empty() {
+        None
+    } else {
+        Some(deltas.defer_set_voxels_where(&coords, voxel, |_: &V| true))
+    }
+}
+
+/// Which voxels count as next to each other in a `flood_fill`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Connectivity {
+    /// The 6 voxels sharing a face.
+    Faces,
+    /// The 26 voxels sharing a face, an edge or a corner.
+    All,
+}
+impl Connectivity {
+    fn neighbours(self) -> Vec<VoxelCoord> {
+        let one = VoxelCoord::new(1, 1, 1);
+        voxels_in_box(-one, one)
+            .filter(|offset| {
+                let away = offset.x.abs() + offset.y.abs() + offset.z.abs();
+                away == 1 || (away > 1 && self == Connectivity::All)
+            })
+            .collect()
+    }
+}
+
+/// How far a `flood_fill` may go.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FloodLimits {
+    /// The box the fill stays in, inclusive. It mustn't hold more than 2^24 voxels (e.g. 256 on a side).
+    pub min: VoxelCoord,
+    pub max: VoxelCoord,
+    /// The most voxels to fill.
+    pub max_volume: usize,
+}
+impl FloodLimits {
+    /// The cube `reach` voxels out from `center` in each direction.
+    pub fn around(center: VoxelCoord, reach: i16, max_volume: usize) -> Self {
+        let reach = VoxelCoord::new(reach, reach, reach);
+        FloodLimits {
+            min: center - reach,
+            max: center + reach,
+            max_volume,
+        }
+    }
+
+    /// The index of `coord` in the box, or None if it's outside.
+    fn index(&self, coord: VoxelCoord) -> Option<u32> {
+        let (min, max) = (self.min, self.max);
+        if coord.x < min.x || coord.y < min.y || coord.z < min.z || coord.x > max.x || coord.y > max.y
+            || coord.z > max.z
+        {
+            return None;
+        }
+        let size = |lo: i16, hi: i16| (i32::from(hi) - i32::from(lo) + 1) as u32;
+        let (y_size, z_size) = (size(min.y, max.y), size(min.z, max.z));
+        let local = |lo: i16, at: i16| (i32::from(at) - i32::from(lo)) as u32;
+        Some((local(min.x, coord.x) * y_size + local(min.y, coord.y)) * z_size + local(min.z, coord.z))
+    }
+}
+
+/// The region a `flood_fill` found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Flood<V: Voxel> {
+    /// The voxel the region is made of: the one at the start.
+    pub voxel: V,
+    /// The voxels in the region, starting with the start, in no particular order.
+    pub filled: Vec<VoxelCoord>,
+    /// Whether `filled` is the whole connected region; i.e. the fill didn't run into the edge of its box, its
+    /// `max_volume`, or an unloaded chunk. For e.g. checking that a room is sealed, by filling its air.
+    pub complete: bool,
+}
+impl<V: Voxel> Flood<V> {
+    /// Replace the region with `replacement`, as one edit through `deltas` (see
+    /// `ChunkDeltas::defer_set_voxels_where`). Only voxels that are still the region's voxel when the edit is
+    /// applied are replaced.
+    pub fn defer(&self, deltas: DeltaWriter<V>, replacement: V) -> DeltaId {
+        let voxel = self.voxel;
+        deltas.defer_set_voxels_where(&self.filled, replacement, move |there: &V| *there == voxel)
+    }
+}
+
+/// The region of voxels the same as the one at `start` that can be reached from it through each other, within
+/// `limits`; None if `start` is outside them, or its chunk isn't loaded. Stops at unloaded chunks.
+///
+/// E.g. a paint bucket: `flood_fill(&chunks, start, Connectivity::Faces, limits)` then `flood.defer(deltas, paint)`.
+pub fn flood_fill<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    start: VoxelCoord,
+    connectivity: Connectivity,
+    limits: FloodLimits,
+) -> Option<Flood<V>> {
+    let volume = (0..3).fold(1u64, |volume, axis| {
+        volume * (i64::from(limits.max[axis]) - i64::from(limits.min[axis]) + 1).max(0) as u64
+    });
+    assert!(volume <= 1 << 24, "flood fill box too big: {:?} to {:?}", limits.min, limits.max);
+    let voxel = chunks.get_voxel(start)?;
+    let mut visited = BitSet::new();
+    visited.add(limits.index(start)?);
+
+    let neighbours = connectivity.neighbours();
+    let mut flood = Flood {
+        voxel,
+        filled: vec![start],
+        complete: true,
+    };
+    let mut to_visit = vec![start];
+    while let Some(coord) = to_visit.pop() {
+        for &offset in &neighbours {
+            let next = coord + offset;
+            // (what's past the edge only matters if the region carries on there)
+            let index = limits.index(next);
+            if let Some(index) = index {
+                if visited.add(index) {
+                    continue;
+                }
+            }
+            match chunks.get_voxel(next) {
+                Some(there) if there == voxel => (),
+                Some(_) => continue,
+                None => {
+                    flood.complete = false;
+                    continue;
+                }
+            }
+            if index.is_none() {
+                flood.complete = false;
+                continue;
+            }
+            if flood.filled.len() >= limits.max_volume {
+                flood.complete = false;
+                return Some(flood);
+            }
+            flood.filled.push(next);
+            to_visit.push(next);
+        }
+    }
+    Some(flood)
+}
+
+/// A copy of a box of the world, which can be turned and flipped and pasted somewhere else.
+#[derive(Clone, Debug)]
+pub struct Clipboard<V: Voxel> {
+    structure: Structure<V>,
+}
+impl<V: Voxel> Clipboard<V> {
+    /// Copy the box from `min` to `max` (inclusive); None if any of it is in a chunk that isn't loaded.
+    pub fn copy<C: ChunkAccess<V>>(chunks: &C, min: VoxelCoord, max: VoxelCoord) -> Option<Self> {
+        assert!(
+            min.x <= max.x && min.y <= max.y && min.z <= max.z,
+            "improper box: {:?} to {:?}",
+            min,
+            max
+        );
+        for chunk_coord in chunks_in_box(min, max) {
+            chunks.get_chunk(chunk_coord)?;
+        }
+        let mut structure = Structure::empty(max - min + VoxelCoord::new(1, 1, 1));
+        for coord in structure.coords() {
+            structure[coord] = chunks.get_voxel(min + coord)?;
+        }
+        Some(Clipboard { structure })
+    }
+
+    /// What's on the clipboard, turned and flipped; e.g. to save as a prefab.
+    pub fn structure(&self) -> &Structure<V> {
+        &self.structure
+    }
+
+    /// Turn the copy about the y axis.
+    pub fn rotate(&mut self, rotation: Rotation) {
+        self.structure = self.structure.rotated(rotation);
+    }
+
+    /// Flip the copy along `axis`.
+    pub fn mirror(&mut self, axis: Axis) {
+        self.structure = self.structure.mirrored(axis);
+    }
+
+    /// Paste the copy with its minimum corner at `origin`, as one edit through `deltas` (see
+    /// `ChunkDeltas::defer_stamp`); with `MergePolicy::SkipAir`, the copy's air leaves the world alone.
+    pub fn paste(&self, deltas: DeltaWriter<V>, origin: VoxelCoord, policy: MergePolicy) -> DeltaId {
+        deltas.defer_stamp(origin, &self.structure, Rotation::None, policy)
+    }
+}
+impl<V: Voxel> From<Structure<V>> for Clipboard<V> {
+    fn from(structure: Structure<V>) -> Self {
+        Clipboard { structure }
+    }
+}
+
+/// A box of the world for tools to work on, between two corners, inclusive; see the module docs. The corners can
+/// be moved as the player picks them, in any order.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Selection {
+    pub first: VoxelCoord,
+    pub second: VoxelCoord,
+}
+impl Selection {
+    pub fn new(first: VoxelCoord, second: VoxelCoord) -> Self {
+        Selection { first, second }
+    }
+
+    /// The box's minimum and maximum corners.
+    pub fn bounds(&self) -> (VoxelCoord, VoxelCoord) {
+        let (a, b) = (self.first, self.second);
+        (
+            VoxelCoord::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z)),
+            VoxelCoord::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z)),
+        )
+    }
+
+    /// How many voxels the box is across in each direction.
+    pub fn size(&self) -> VoxelCoord {
+        let (min, max) = self.bounds();
+        max - min + VoxelCoord::new(1, 1, 1)
+    }
+
+    /// How many voxels are in the box.
+    pub fn volume(&self) -> usize {
+        let size = self.size();
+        size.x as usize * size.y as usize * size.z as usize
+    }
+
+    pub fn contains(&self, coord: VoxelCoord) -> bool {
+        let (min, max) = self.bounds();
+        min.x <= coord.x && coord.x <= max.x && min.y <= coord.y && coord.y <= max.y && min.z <= coord.z
+            && coord.z <= max.z
+    }
+
+    /// Move the box by `offset`.
+    pub fn shift(&mut self, offset: VoxelCoord) {
+        self.first += offset;
+        self.second += offset;
+    }
+
+    /// Grow the box by `amount`: along each axis, its maximum side out by a positive amount, or its minimum side
+    /// out by a negative one; e.g. by 0,3,0 to take in the 3 voxels above it.
+    pub fn extend(&mut self, amount: VoxelCoord) {
+        let (mut min, mut max) = self.bounds();
+        for axis in 0..3 {
+            if amount[axis] > 0 {
+                max[axis] += amount[axis];
+            } else {
+                min[axis] += amount[axis];
+            }
+        }
+        *self = Selection::new(min, max);
+    }
+
+    /// Copy the box (see `Clipboard::copy`).
+    pub fn copy<V: Voxel, C: ChunkAccess<V>>(&self, chunks: &C) -> Option<Clipboard<V>> {
+        let (min, max) = self.bounds();
+        Clipboard::copy(chunks, min, max)
+    }
+
+    /// Fill the box with `voxel` (see `ChunkDeltas::defer_fill_box`).
+    pub fn fill<V: Voxel>(&self, deltas: DeltaWriter<V>, voxel: V) -> DeltaId {
+        let (min, max) = self.bounds();
+        deltas.defer_fill_box(min, max, voxel)
+    }
+
+    /// Keep a `flood_fill` to the box.
+    pub fn flood_limits(&self, max_volume: usize) -> FloodLimits {
+        let (min, max) = self.bounds();
+        FloodLimits { min, max, max_volume }
+    }
+}
+impl Component for Selection {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// A boolean operation on two shapes, `a` and `b`, for `combine`; the non-empty voxels are the shapes' insides.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Csg {
+    /// What's in either; where both have a voxel, `b`'s.
+    Union,
+    /// What's in `a` and not in `b`, e.g. to carve a tunnel.
+    Subtract,
+    /// What's in both, as `b`'s voxels, e.g. to stamp a structure only where it overlaps the terrain.
+    Intersect,
+}
+
+/// The voxel of `structure`, with its minimum corner at `origin`, at `coord`; empty outside it.
+fn placed<V: Voxel>(structure: &Structure<V>, origin: VoxelCoord, coord: VoxelCoord) -> V {
+    if structure.contains(coord - origin) {
+        structure[coord - origin]
+    } else {
+        V::default()
+    }
+}
+
+/// `a`, with its minimum corner at `a_origin`, combined with `b`, with its at `b_origin`: a new structure, and where
+/// its minimum corner is. The new structure fills the box around both for a union, `a`'s box for a subtraction, and
+/// the boxes' overlap for an intersection; None if that's empty.
+///
+/// Regions of the world can be copied into structures with `Clipboard::copy`.
+pub fn combine<V: Voxel>(
+    op: Csg,
+    a: &Structure<V>,
+    a_origin: VoxelCoord,
+    b: &Structure<V>,
+    b_origin: VoxelCoord,
+) -> Option<(VoxelCoord, Structure<V>)> {
+    let one = VoxelCoord::new(1, 1, 1);
+    let (a_max, b_max) = (a_origin + a.size() - one, b_origin + b.size() - one);
+    let lower = |p: VoxelCoord, q: VoxelCoord| VoxelCoord::new(p.x.min(q.x), p.y.min(q.y), p.z.min(q.z));
+    let upper = |p: VoxelCoord, q: VoxelCoord| VoxelCoord::new(p.x.max(q.x), p.y.max(q.y), p.z.max(q.z));
+    let (min, max) = match op {
+        Csg::Union => (lower(a_origin, b_origin), upper(a_max, b_max)),
+        Csg::Subtract => (a_origin, a_max),
+        Csg::Intersect => (upper(a_origin, b_origin), lower(a_max, b_max)),
+    };
+    if min.x > max.x || min.y > max.y || min.z > max.z {
+        return None;
+    }
+
+    let empty = V::default();
+    let mut combined = Structure::empty(max - min + one);
+    for coord in combined.coords() {
+        let (in_a, in_b) = (placed(a, a_origin, min + coord), placed(b, b_origin, min + coord));
+        combined[coord] = match op {
+            Csg::Union if in_b == empty => in_a,
+            Csg::Union => in_b,
+            Csg::Subtract if in_b == empty => in_a,
+            Csg::Subtract => empty,
+            Csg::Intersect if in_a == empty => empty,
+            Csg::Intersect => in_b,
+        };
+    }
+    Some((min, combined))
+}
+
+/// Combine the world, as `a`, with `b` with its minimum corner at `origin`, through `deltas`; the edits are returned
+/// in the order they'll land. Only `b`'s box is changed, so an intersection leaves the world outside it alone.
+///
+/// Unions are stamped with `MergePolicy::SkipAir`, and intersections with `MergePolicy::Overlap` after clearing
+/// what's around `b`'s voxels; subtractions clear `b`'s voxels (see `ChunkDeltas::defer_clear_voxels`).
+pub fn combine_in_world<V: Voxel>(
+    deltas: DeltaWriter<V>,
+    op: Csg,
+    b: &Structure<V>,
+    origin: VoxelCoord,
+) -> Vec<DeltaId> {
+    let clear_where = |solid: bool| {
+        let coords: Vec<_> = b.coords()
+            .filter(|&coord| (b[coord] != V::default()) == solid)
+            .map(|coord| origin + coord)
+            .collect();
+        if coords.is_empty() {
+            None
+        } else {
+            Some(deltas.defer_clear_voxels(&coords))
+        }
+    };
+    match op {
+        Csg::Union => vec![deltas.defer_stamp(origin, b, Rotation::None, MergePolicy::SkipAir)],
+        Csg::Subtract => clear_where(true).into_iter().collect(),
+        Csg::Intersect => {
+            let mut ids: Vec<_> = clear_where(false).into_iter().collect();
+            ids.push(deltas.defer_stamp(origin, b, Rotation::None, MergePolicy::Overlap));
+            ids
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta::{ChunkDeltaSystem, ChunkDeltas};
+    use std::collections::HashMap;
+    use tracker::ChunkTrackerSystem;
+    use {Chunk, ChunkTracker, TestVoxel};
+
+    #[test]
+    fn shapes() {
+        let center = VoxelCoord::new(10, -3, 7);
+        let count = |brush: Brush| brush.coords(center).len();
+        assert_eq!(count(Brush::Sphere { radius: 0.0 }), 1);
+        assert_eq!(count(Brush::Sphere { radius: 1.0 }), 7);
+        assert_eq!(count(Brush::Sphere { radius: 1.5 }), 19);
+        assert_eq!(count(Brush::Sphere { radius: -1.0 }), 0);
+        assert_eq!(count(Brush::Box { half_size: VoxelCoord::new(1, 0, 2) }), 15);
+        assert_eq!(count(Brush::Box { half_size: VoxelCoord::new(1, -1, 2) }), 0);
+        assert_eq!(count(Brush::Cylinder { radius: 1.0, half_height: 2 }), 25);
+        assert_eq!(count(Brush::Ellipsoid { radii: Coord::new(2.0, 1.0, 1.0) }), 9);
+        assert_eq!(count(Brush::Ellipsoid { radii: Coord::new(1.0, 0.0, 1.0) }), 5);
+        // the same as a sphere, when the radii are
+        assert_eq!(
+            Brush::Ellipsoid { radii: Coord::new(2.5, 2.5, 2.5) }.coords(center),
+            Brush::Sphere { radius: 2.5 }.coords(center)
+        );
+        assert!(
+            Brush::Sphere { radius: 2.0 }
+                .coords(center)
+                .iter()
+                .all(|&coord| (coord - center).x.abs() <= 2)
+        );
+    }
+
+    /// A world with a chunk of rock up to y = 4, and air above.
+    fn floor() -> (World, Dispatcher<'static, 'static>, Entity) {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<TestVoxel>::new());
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(ChunkDeltaSystem::<TestVoxel>::new(), "chunk_deltas", &["chunk_tracker"])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 4, 15), TestVoxel::Rock);
+        let ent = world.create_entity().with(chunk).build();
+        dispatcher.dispatch(&mut world.res);
+        (world, dispatcher, ent)
+    }
+
+    /// The voxels at 8, 3..6, 8 in the `floor` chunk.
+    fn column(world: &World, ent: Entity) -> Vec<TestVoxel> {
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        (3..6).map(|y| chunks.get(ent).unwrap()[VoxelCoord::new(8, y, 8)]).collect()
+    }
+
+    #[test]
+    fn modes() {
+        // a brush half in the floor
+        let (mut world, mut dispatcher, ent) = floor();
+        let center = VoxelCoord::new(8, 4, 8);
+        let brush = Brush::Box { half_size: VoxelCoord::new(1, 1, 1) };
+        let paint = |world: &World, mode| {
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            apply_brush(deltas.writer(), brush, center, TestVoxel::Grass, mode).unwrap();
+        };
+        paint(&world, BrushMode::Place);
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(column(&world, ent), vec![TestVoxel::Rock, TestVoxel::Rock, TestVoxel::Grass]);
+
+        paint(&world, BrushMode::Erase);
+        paint(&world, BrushMode::Replace);
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(column(&world, ent), vec![TestVoxel::Air, TestVoxel::Air, TestVoxel::Air]);
+        // only what was in the brush
+        assert_eq!(
+            world.read_storage::<Chunk<TestVoxel>>().get(ent).unwrap()[VoxelCoord::new(8, 2, 8)],
+            TestVoxel::Rock
+        );
+
+        paint(&world, BrushMode::Place);
+        paint(&world, BrushMode::Replace);
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(column(&world, ent), vec![TestVoxel::Grass, TestVoxel::Grass, TestVoxel::Grass]);
+    }
+
+    #[test]
+    fn flood() {
+        // a hollow rock box with air inside, and a diagonal gap in one corner
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(2, 2, 2), VoxelCoord::new(6, 6, 6), TestVoxel::Rock);
+        chunk.fill_box(VoxelCoord::new(3, 3, 3), VoxelCoord::new(5, 5, 5), TestVoxel::Air);
+        let mut chunks = HashMap::new();
+        chunks.insert(chunk.coord, chunk);
+        let inside = VoxelCoord::new(4, 4, 4);
+        let limits = FloodLimits::around(inside, 8, 10_000);
+
+        let air = flood_fill(&chunks, inside, Connectivity::Faces, limits).unwrap();
+        assert_eq!(air.voxel, TestVoxel::Air);
+        assert_eq!(air.filled.len(), 27);
+        assert_eq!(air.filled[0], inside);
+        assert!(air.complete);
+        let rock = flood_fill(&chunks, VoxelCoord::new(2, 4, 4), Connectivity::Faces, limits).unwrap();
+        assert_eq!(rock.filled.len(), 125 - 27);
+        assert!(rock.complete);
+
+        // knocking out the corner doesn't let air out through faces, but does through corners
+        chunks.get_mut(&VoxelCoord::new(0, 0, 0)).unwrap()[VoxelCoord::new(6, 6, 6)] = TestVoxel::Air;
+        assert!(flood_fill(&chunks, inside, Connectivity::Faces, limits).unwrap().complete);
+        let leak = flood_fill(&chunks, inside, Connectivity::All, limits).unwrap();
+        assert!(!leak.complete);
+        // (everything in the loaded chunk and the box, which the chunks below 0 and the box's edge cut off)
+        assert_eq!(leak.filled.len(), 13 * 13 * 13 - 125 + 28);
+
+        // out of room, or not (just reaching the edge of the box is fine)
+        let small = FloodLimits::around(inside, 8, 10);
+        let capped = flood_fill(&chunks, inside, Connectivity::All, small).unwrap();
+        assert_eq!((capped.filled.len(), capped.complete), (10, false));
+        let tight = FloodLimits::around(inside, 1, 1000);
+        let boxed = flood_fill(&chunks, inside, Connectivity::Faces, tight).unwrap();
+        assert_eq!((boxed.filled.len(), boxed.complete), (27, true));
+        assert!(flood_fill(&chunks, VoxelCoord::new(40, 4, 4), Connectivity::All, limits).is_none());
+    }
+
+    #[test]
+    fn clipboard() {
+        // an L of rock on the ground, with grass at the end of its long arm; across two chunks
+        let mut left = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        left.fill_box(VoxelCoord::new(14, 0, 4), VoxelCoord::new(15, 0, 4), TestVoxel::Rock);
+        left[VoxelCoord::new(14, 0, 5)] = TestVoxel::Rock;
+        let mut right = Chunk::<TestVoxel>::empty(VoxelCoord::new(16, 0, 0));
+        right[VoxelCoord::new(0, 0, 4)] = TestVoxel::Grass;
+        let mut chunks = HashMap::new();
+        chunks.insert(left.coord, left);
+        chunks.insert(right.coord, right);
+
+        let (min, max) = (VoxelCoord::new(14, 0, 4), VoxelCoord::new(16, 1, 5));
+        let mut clipboard = Clipboard::copy(&chunks, min, max).unwrap();
+        assert_eq!(clipboard.structure().size(), VoxelCoord::new(3, 2, 2));
+        let at = |clipboard: &Clipboard<TestVoxel>, x, z| clipboard.structure()[VoxelCoord::new(x, 0, z)];
+        assert_eq!(at(&clipboard, 2, 0), TestVoxel::Grass);
+        assert_eq!(at(&clipboard, 0, 1), TestVoxel::Rock);
+        assert_eq!(at(&clipboard, 1, 1), TestVoxel::Air);
+        assert_eq!(clipboard.structure()[VoxelCoord::new(0, 1, 0)], TestVoxel::Air);
+
+        clipboard.mirror(Axis::X);
+        assert_eq!(at(&clipboard, 0, 0), TestVoxel::Grass);
+        assert_eq!(at(&clipboard, 2, 1), TestVoxel::Rock);
+        // a quarter turn takes x to z, and z to -x
+        clipboard.rotate(Rotation::Quarter);
+        assert_eq!(clipboard.structure().size(), VoxelCoord::new(2, 2, 3));
+        assert_eq!(at(&clipboard, 1, 0), TestVoxel::Grass);
+        assert_eq!(at(&clipboard, 0, 2), TestVoxel::Rock);
+        assert_eq!(at(&clipboard, 1, 2), TestVoxel::Rock);
+        // mirroring twice, or turning all the way around, changes nothing
+        let before = clipboard.structure().clone();
+        clipboard.mirror(Axis::Y);
+        clipboard.mirror(Axis::Y);
+        for _ in 0..4 {
+            clipboard.rotate(Rotation::Quarter);
+        }
+        assert!(before.coords().all(|coord| clipboard.structure()[coord] == before[coord]));
+
+        // the next chunk over isn't loaded
+        assert!(Clipboard::copy(&chunks, min, VoxelCoord::new(32, 1, 5)).is_none());
+    }
+
+    #[test]
+    fn lines() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        assert_eq!(line_voxels(origin, origin, 0.0), vec![origin]);
+        let to = VoxelCoord::new(-6, 3, 2);
+        let drawn = line(origin, to);
+        assert_eq!(drawn.len(), 7);
+        assert_eq!((drawn[0], drawn[6]), (origin, to));
+        for pair in drawn.windows(2) {
+            let step = pair[1] - pair[0];
+            assert_eq!(step.x, -1);
+            assert!(step.y.abs() <= 1 && step.z.abs() <= 1);
+        }
+        // the same voxels either way
+        let mut back = line(to, origin);
+        back.reverse();
+        assert_eq!(back, drawn);
+
+        // a round beam: the line, its ends, and four more around each voxel of it
+        let beam = line_voxels(origin, VoxelCoord::new(3, 0, 0), 1.0);
+        assert_eq!(beam.len(), 4 + 2 + 4 * 4);
+        assert!(beam.contains(&VoxelCoord::new(-1, 0, 0)));
+        assert!(beam.contains(&VoxelCoord::new(2, 0, -1)));
+        assert!(!beam.contains(&VoxelCoord::new(2, 1, 1)));
+    }
+
+    #[test]
+    fn planes() {
+        let (min, max) = (VoxelCoord::new(-2, -2, -2), VoxelCoord::new(2, 2, 2));
+        let through = VoxelCoord::new(0, 0, 0);
+        let floor = plane_voxels(through, Coord::new(0.0, 1.0, 0.0), 1.0, min, max);
+        assert_eq!(floor.len(), 25);
+        assert!(floor.iter().all(|coord| coord.y == 0));
+        let thick = plane_voxels(through, Coord::new(0.0, -3.0, 0.0), 2.0, min, max);
+        assert_eq!(thick.len(), 50);
+        assert!(thick.iter().all(|coord| coord.y == 0 || coord.y == 1));
+
+        // a diagonal wall, with no gaps to squeeze through at the corners
+        let wall = plane_voxels(through, Coord::new(1.0, 1.0, 0.0), 1.0, min, max);
+        assert_eq!(wall.len(), (5 + 4) * 5);
+        for &coord in &wall {
+            assert!(coord.x + coord.y == 0 || coord.x + coord.y == -1);
+        }
+        assert!(plane_voxels(VoxelCoord::new(0, 9, 0), Coord::new(0.0, 1.0, 0.0), 1.0, min, max).is_empty());
+    }
+
+    #[test]
+    fn csg() {
+        let row = |voxels: &[TestVoxel]| {
+            let mut structure = Structure::empty(VoxelCoord::new(voxels.len() as i16, 1, 1));
+            for (x, &voxel) in voxels.iter().enumerate() {
+                structure[VoxelCoord::new(x as i16, 0, 0)] = voxel;
+            }
+            structure
+        };
+        let voxels = |(origin, structure): (VoxelCoord, Structure<TestVoxel>)| {
+            let voxels: Vec<_> = structure.coords().map(|coord| structure[coord]).collect();
+            (origin.x, voxels)
+        };
+        let (r, g, air) = (TestVoxel::Rock, TestVoxel::Grass, TestVoxel::Air);
+        let (a, at) = (row(&[r, r, r]), VoxelCoord::new(0, 0, 0));
+
+        let (b, bt) = (row(&[g, g, g]), VoxelCoord::new(2, 0, 0));
+        assert_eq!(voxels(combine(Csg::Union, &a, at, &b, bt).unwrap()), (0, vec![r, r, g, g, g]));
+        assert_eq!(voxels(combine(Csg::Subtract, &a, at, &b, bt).unwrap()), (0, vec![r, r, air]));
+        assert_eq!(voxels(combine(Csg::Intersect, &a, at, &b, bt).unwrap()), (2, vec![g]));
+        assert!(combine(Csg::Intersect, &a, at, &b, VoxelCoord::new(3, 0, 0)).is_none());
+
+        // air in `b` isn't part of it
+        let (b, bt) = (row(&[air, g, g]), VoxelCoord::new(-1, 0, 0));
+        assert_eq!(voxels(combine(Csg::Union, &a, at, &b, bt).unwrap()), (-1, vec![air, g, g, r]));
+        assert_eq!(voxels(combine(Csg::Subtract, &a, at, &b, bt).unwrap()), (0, vec![air, air, r]));
+        assert_eq!(voxels(combine(Csg::Intersect, &a, at, &b, bt).unwrap()), (0, vec![g, g]));
+    }
+
+    #[test]
+    fn csg_in_world() {
+        let (mut world, mut dispatcher, ent) = floor();
+        // a column through the floor's surface with a gap in it: grass at y = 3 and 5
+        let mut b = Structure::empty(VoxelCoord::new(1, 3, 1));
+        b[VoxelCoord::new(0, 0, 0)] = TestVoxel::Grass;
+        b[VoxelCoord::new(0, 2, 0)] = TestVoxel::Grass;
+        let mut combine_with = |world: &mut World, op| {
+            let ids = {
+                let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+                combine_in_world(deltas.writer(), op, &b, VoxelCoord::new(8, 3, 8))
+            };
+            dispatcher.dispatch(&mut world.res);
+            ids.len()
+        };
+        let (r, g, air) = (TestVoxel::Rock, TestVoxel::Grass, TestVoxel::Air);
+
+        assert_eq!(combine_with(&mut world, Csg::Intersect), 2);
+        assert_eq!(column(&world, ent), vec![g, air, air]);
+        assert_eq!(combine_with(&mut world, Csg::Union), 1);
+        assert_eq!(column(&world, ent), vec![g, air, g]);
+        assert_eq!(combine_with(&mut world, Csg::Subtract), 1);
+        assert_eq!(column(&world, ent), vec![air, air, air]);
+        // (the rest of the floor's untouched)
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        assert_eq!(chunks.get(ent).unwrap()[VoxelCoord::new(8, 2, 8)], r);
+        assert_eq!(chunks.get(ent).unwrap()[VoxelCoord::new(9, 4, 8)], r);
+    }
+
+    #[test]
+    fn selection() {
+        let mut selection = Selection::new(VoxelCoord::new(4, 0, -2), VoxelCoord::new(1, 2, -2));
+        assert_eq!(selection.bounds(), (VoxelCoord::new(1, 0, -2), VoxelCoord::new(4, 2, -2)));
+        assert_eq!(selection.size(), VoxelCoord::new(4, 3, 1));
+        assert_eq!(selection.volume(), 12);
+        assert!(selection.contains(VoxelCoord::new(2, 2, -2)));
+        assert!(!selection.contains(VoxelCoord::new(2, 3, -2)));
+
+        selection.extend(VoxelCoord::new(-1, 3, 0));
+        assert_eq!(selection.bounds(), (VoxelCoord::new(0, 0, -2), VoxelCoord::new(4, 5, -2)));
+        selection.shift(VoxelCoord::new(0, 0, 2));
+        assert_eq!(selection.bounds(), (VoxelCoord::new(0, 0, 0), VoxelCoord::new(4, 5, 0)));
+        // the corners can be either way round
+        selection.first = VoxelCoord::new(6, 5, 0);
+        assert_eq!(selection.bounds(), (VoxelCoord::new(4, 5, 0), VoxelCoord::new(6, 5, 0)));
+    }
+}
@@ -0,0 +1,157 @@
+//! Explosions: which voxels a blast breaks, and how much of it reaches the things around it.
+//!
+//! `explosion_affected` works out what a blast does without changing anything; `Blast::defer` then breaks the
+//! voxels as a single edit, and hurting or pushing things is up to the game, in proportion to their exposure.
+
+use super::{canonicalize, Aabb, ChunkAccess, Coord, Voxel, VoxelCoord};
+use delta::{DeltaId, DeltaWriter};
+use raycast::{line_of_sight, raycast_iter, RayAction};
+
+use cgmath::InnerSpace;
+use fnv::FnvHashSet;
+use std::f32;
+
+/// What a blast does; see `explosion_affected`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Blast {
+    /// The voxels it breaks, in no particular order.
+    pub destroyed: Vec<VoxelCoord>,
+    /// For each of the targets, how much of the blast reaches it, from 0 to 1.
+    pub exposure: Vec<f32>,
+}
+impl Blast {
+    /// Break the destroyed voxels, as one edit through `deltas` (see `ChunkDeltas::defer_clear_voxels`), e.g.
+    /// `blast.defer(deltas.writer().source(DeltaSource::Entity(bomb)))`; None if nothing was destroyed.
+    pub fn defer<V: Voxel>(&self, deltas: DeltaWriter<V>) -> Option<DeltaId> {
+        if self.destroyed.is_empty() {
+            None
+        } else {
+            Some(deltas.defer_clear_voxels(&self.destroyed))
+        }
+    }
+}
+
+/// `count` directions spread evenly over the sphere, in a fixed spiral, so blasts are repeatable.
+fn sphere_directions(count: u32) -> Vec<Coord> {
+    let golden_angle = f32::consts::PI * (3.0 - 5.0f32.sqrt());
+    (0..count)
+        .map(|i| {
+            let y = 1.0 - 2.0 * (i as f32 + 0.5) / count as f32;
+            let (r, phi) = ((1.0 - y * y).sqrt(), golden_angle * i as f32);
+            Coord::new(r * phi.cos(), y, r * phi.sin())
+        })
+        .collect()
+}
+
+/// What a blast of `power` at `center` does to the voxels within `radius` of it, and to `targets` (e.g. the boxes
+/// of entities nearby).
+///
+/// Rays go out from the center in every direction, about two for each square voxel of the sphere at `radius`.
+/// A ray's strength starts at `power` and falls off to nothing at `radius`; it breaks each voxel it reaches with
+/// less `Voxel::blast_resistance` than it has left, losing that much, and stops at the first it can't break.
+/// Unloaded chunks stop rays too.
+///
+/// A target's exposure is the fraction of points spread through its box that the center can see, past voxels
+/// with any resistance (as they were before the blast), times how far it is inside the radius (1 at the center, 0
+/// at the edge).
+pub fn explosion_affected<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    center: Coord,
+    radius: f32,
+    power: f32,
+    targets: &[Aabb],
+) -> Blast {
+    assert!(radius > 0.0, "blasts must have some size");
+    let rays = (8.0 * f32::consts::PI * radius * radius).ceil().max(32.0) as u32;
+    let start = canonicalize(center);
+    let mut destroyed = FnvHashSet::default();
+    for direction in sphere_directions(rays) {
+        let mut spent = 0.0;
+        for (coord, _, t) in raycast_iter(start, center, direction, radius) {
+            let voxel = match chunks.get_voxel(coord) {
+                Some(voxel) => voxel,
+                None => break,
+            };
+            if voxel == V::default() {
+                continue;
+            }
+            let resistance = voxel.blast_resistance();
+            if power * (1.0 - t / radius) - spent <= resistance {
+                break;
+            }
+            destroyed.insert(coord);
+            spent += resistance;
+        }
+    }
+
+    let exposure = targets
+        .iter()
+        .map(|target| {
+            let distance = (target.closest_point(center) - center).magnitude();
+            if distance >= radius {
+                return 0.0;
+            }
+            // (inset a little, so that points on the bottom of something standing on the ground aren't in it)
+            let size = target.max - target.min;
+            let fractions = [0.1, 0.5, 0.9];
+            let mut seen = 0;
+            for &x in &fractions {
+                for &y in &fractions {
+                    for &z in &fractions {
+                        let point = target.min + Coord::new(size.x * x, size.y * y, size.z * z);
+                        let clear = line_of_sight(center, point, chunks, |_, voxel: &V| {
+                            if voxel.blast_resistance() > 0.0 {
+                                RayAction::Stop
+                            } else {
+                                RayAction::Continue
+                            }
+                        });
+                        if clear {
+                            seen += 1;
+                        }
+                    }
+                }
+            }
+            (1.0 - distance / radius) * seen as f32 / 27.0
+        })
+        .collect();
+
+    Blast {
+        destroyed: destroyed.into_iter().collect(),
+        exposure,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use {Chunk, TestVoxel};
+
+    #[test]
+    fn blast() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        // a wall two thick at x = 10
+        chunk.fill_box(VoxelCoord::new(10, 1, 0), VoxelCoord::new(11, 5, 15), TestVoxel::Rock);
+        chunks.insert(chunk.coord, chunk);
+
+        let player = Aabb::new(Coord::new(4.6, 0.5, 7.6), Coord::new(5.4, 2.3, 8.4));
+        let behind = player.translate(Coord::new(7.0, 0.0, 0.0));
+        let far = player.translate(Coord::new(-4.0, 0.0, 0.0));
+        let blast = explosion_affected(&chunks, Coord::new(8.0, 1.0, 8.0), 5.0, 3.0, &[player, behind, far]);
+
+        // the ground under it and the near side of the wall break, but not the far side
+        assert!(blast.destroyed.contains(&VoxelCoord::new(8, 0, 8)));
+        assert!(blast.destroyed.contains(&VoxelCoord::new(10, 1, 8)));
+        assert!(!blast.destroyed.iter().any(|coord| coord.x == 11));
+        assert!(!blast.destroyed.contains(&VoxelCoord::new(8, 0, 13)));
+
+        // 2.6 from the center, in the open
+        assert!((blast.exposure[0] - 0.48).abs() < 1e-4);
+        // behind the wall, and out of range
+        assert_eq!(blast.exposure[1], 0.0);
+        assert_eq!(blast.exposure[2], 0.0);
+    }
+}
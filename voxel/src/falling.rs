@@ -0,0 +1,310 @@
+//! Voxels that fall when there's nothing under them, like sand and gravel (see `Voxel::falls`).
+//!
+//! Whenever a voxel's changed, the `FallingVoxelSystem` checks it and the voxel above it. A falling voxel with
+//! nothing to stand on is taken out of the world and replaced with an entity (a `FallingVoxel`, with a
+//! `physics::VoxelBody`), which falls until it lands and is put back as a voxel. Add something that draws
+//! `FallingVoxel`s to see them on the way down. If where it lands has been filled in the meantime, it's put on top
+//! of whatever filled it instead, or if there's no room for it there, it's lost, with a `FallingVoxelLost` event.
+//!
+//! Voxels that are already floating when their chunk is loaded stay put until something next to them changes.
+
+use super::{canonicalize, Aabb, Chunk, ChunkAccess, ChunkTracker, Coord, Voxel, VoxelCoord, CHUNK_SIZE};
+use collision::CollisionShape;
+use delta::{ChunkDeltas, DeltaId, DeltaOutcome, DeltaPriority, DeltaResult, DeltaSource, VoxelChanged,
+            want_fill_changes};
+use physics::VoxelBody;
+
+use amethyst::core::transform::GlobalTransform;
+use amethyst::shrev::EventChannel;
+use cgmath::Matrix4;
+use fnv::{FnvHashMap, FnvHashSet};
+use specs::prelude::*;
+
+/// What the `FallingVoxelSystem`'s edits come from.
+pub const SOURCE: DeltaSource = DeltaSource::System("falling voxels");
+
+/// A voxel on its way down; see the module docs.
+#[derive(Clone, Copy, Debug)]
+pub struct FallingVoxel<V: Voxel> {
+    pub voxel: V,
+}
+impl<V: Voxel> Component for FallingVoxel<V> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// How far above where a falling voxel lands it can be put back, if that's been filled in the meantime.
+pub const MAX_RISE: i16 = CHUNK_SIZE as i16;
+
+/// Published when a falling voxel lands with nowhere within `MAX_RISE` to go, and is lost; e.g. to drop it as an
+/// item instead. `coord` is where it landed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FallingVoxelLost<V: Voxel> {
+    pub voxel: V,
+    pub coord: VoxelCoord,
+}
+
+/// Whether the voxel at `coord` has something to stand on. Voxels over unloaded chunks count as standing on them,
+/// so that they don't fall into the unknown.
+pub fn is_supported<V: Voxel, C: ChunkAccess<V>>(chunks: &C, coord: VoxelCoord) -> bool {
+    chunks
+        .get_voxel(coord - VoxelCoord::new(0, 1, 0))
+        .map_or(true, |below| below.collision_shape() != CollisionShape::Empty)
+}
+
+/// Turns unsupported falling voxels into `FallingVoxel` entities, and puts them back once they land; see the module
+/// docs. A landed voxel's entity is only deleted once it's back in the world; if something beats it to its spot,
+/// it tries again the next frame.
+///
+/// Should run before the `ChunkDeltaSystem`, so that voxels that land are back in the world before the
+/// `VoxelPhysicsSystem` moves the ones falling onto them. Its edits are `DeltaPriority::Simulation`, so players win
+/// any arguments about where sand goes.
+#[derive(Default)]
+pub struct FallingVoxelSystem<V: Voxel> {
+    reader: Option<ReaderId<VoxelChanged<V>>>,
+    results: Option<ReaderId<DeltaResult<V>>>,
+    /// The landed voxels waiting to be put back, by the edit putting them back.
+    landing: FnvHashMap<DeltaId, Entity>,
+}
+impl<V: Voxel> FallingVoxelSystem<V> {
+    pub fn new() -> Self {
+        FallingVoxelSystem {
+            reader: None,
+            results: None,
+            landing: FnvHashMap::default(),
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for FallingVoxelSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        Read<'a, ChunkDeltas<V>>,
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        Read<'a, EventChannel<DeltaResult<V>>>,
+        Write<'a, EventChannel<FallingVoxelLost<V>>>,
+        WriteStorage<'a, FallingVoxel<V>>,
+        WriteStorage<'a, VoxelBody>,
+        WriteStorage<'a, GlobalTransform>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+        self.results = Some(
+            resources
+                .fetch_mut::<EventChannel<DeltaResult<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (entities, tracker, chunks, deltas, changes, results, mut lost, mut falling, mut bodies, mut transforms) =
+            data;
+        let access = tracker.chunks(&chunks);
+        let writer = deltas.writer().priority(DeltaPriority::Simulation).source(SOURCE);
+        for change in changes.read(self.reader.as_mut().unwrap()) {
+            // (voxels are only taken out by `defer_set_if`, so this is one that made it out)
+            if change.source == SOURCE && change.old.falls() && change.new == V::default() {
+                let ent = entities.create();
+                let center = change.coord.cast::<f32>().unwrap();
+                let aabb = Aabb::from_center(Coord::new(0.0, 0.0, 0.0), Coord::new(0.5, 0.5, 0.5));
+                falling.insert(ent, FallingVoxel { voxel: change.old }).unwrap();
+                bodies.insert(ent, VoxelBody::new(aabb, true)).unwrap();
+                transforms.insert(ent, GlobalTransform(Matrix4::from_translation(center))).unwrap();
+            }
+            for &coord in &[change.coord, change.coord + VoxelCoord::new(0, 1, 0)] {
+                if let Some(voxel) = access.get_voxel(coord) {
+                    if voxel.falls() && !is_supported(&access, coord) {
+                        writer.defer_set_if(coord, voxel, V::default());
+                    }
+                }
+            }
+        }
+
+        for result in results.read(self.results.as_mut().unwrap()) {
+            if let Some(ent) = self.landing.remove(&result.id) {
+                // (otherwise it's still there, and tries again)
+                if let DeltaOutcome::Applied { .. } = result.outcome {
+                    entities.delete(ent).unwrap();
+                }
+            }
+        }
+
+        let waiting: FnvHashSet<Entity> = self.landing.values().cloned().collect();
+        for (ent, voxel, body, transform) in (&*entities, &falling, &bodies, &transforms).join() {
+            if !body.on_ground() || waiting.contains(&ent) {
+                continue;
+            }
+            // the first free voxel from where it landed up, i.e. on top of anything put there in the meantime
+            let landed = canonicalize(Coord::new(transform.0.w.x, transform.0.w.y, transform.0.w.z));
+            let (mut free, mut unloaded) = (None, false);
+            for rise in 0..MAX_RISE {
+                let coord = landed + VoxelCoord::new(0, rise, 0);
+                match access.get_voxel(coord) {
+                    Some(there) if there.collision_shape() == CollisionShape::Empty => {
+                        free = Some(coord);
+                        break;
+                    }
+                    Some(_) => (),
+                    None => {
+                        unloaded = true;
+                        break;
+                    }
+                }
+            }
+            match free {
+                Some(coord) => {
+                    let id = writer.defer_set_where(coord, voxel.voxel, |there: &V| {
+                        there.collision_shape() == CollisionShape::Empty
+                    });
+                    self.landing.insert(id, ent);
+                }
+                // (wait for the chunk to come back)
+                None if unloaded => (),
+                None => {
+                    lost.single_write(FallingVoxelLost {
+                        voxel: voxel.voxel,
+                        coord: landed,
+                    });
+                    entities.delete(ent).unwrap();
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amethyst::core::timing::Time;
+    use delta::ChunkDeltaSystem;
+    use physics::VoxelPhysicsSystem;
+    use tracker::ChunkTrackerSystem;
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Sandy {
+        Air,
+        Rock,
+        Sand,
+    }
+    impl Default for Sandy {
+        fn default() -> Self {
+            Sandy::Air
+        }
+    }
+    impl Voxel for Sandy {
+        fn is_transparent(&self) -> bool {
+            *self == Sandy::Air
+        }
+        fn color(&self) -> [f32; 4] {
+            [1.0; 4]
+        }
+        fn falls(&self) -> bool {
+            *self == Sandy::Sand
+        }
+    }
+
+    #[test]
+    fn sand() {
+        let mut world = World::new();
+        world.register::<Chunk<Sandy>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<Sandy>::new());
+        let mut time = Time::default();
+        time.set_delta_seconds(0.05);
+        world.add_resource(time);
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<Sandy>::new(), "chunk_tracker", &[])
+            .with(FallingVoxelSystem::<Sandy>::new(), "falling", &["chunk_tracker"])
+            .with(ChunkDeltaSystem::<Sandy>::new(), "chunk_deltas", &["falling"])
+            .with(VoxelPhysicsSystem::<Sandy>::new(20.0), "physics", &["chunk_deltas"])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        // two sand on a rock on a rock, on the ground
+        let at = |y| VoxelCoord::new(4, y, 4);
+        let mut chunk = Chunk::<Sandy>::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), Sandy::Rock);
+        chunk.fill_box(at(1), at(2), Sandy::Rock);
+        chunk.fill_box(at(3), at(4), Sandy::Sand);
+        let ent = world.create_entity().with(chunk).build();
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+
+        // knock the top rock out; the sand above it comes out the next frame, and turns into an entity the one
+        // after, as the sand above that comes out
+        world.read_resource::<ChunkDeltas<Sandy>>().defer_set(at(2), Sandy::Air);
+        for _ in 0..3 {
+            dispatcher.dispatch(&mut world.res);
+            world.maintain();
+        }
+        {
+            let chunks = world.read_storage::<Chunk<Sandy>>();
+            assert_eq!(chunks.get(ent).unwrap()[at(3)], Sandy::Air);
+            assert_eq!(chunks.get(ent).unwrap()[at(4)], Sandy::Air);
+        }
+        assert_eq!(world.read_storage::<FallingVoxel<Sandy>>().join().count(), 1);
+
+        for _ in 0..40 {
+            dispatcher.dispatch(&mut world.res);
+            world.maintain();
+        }
+        let chunks = world.read_storage::<Chunk<Sandy>>();
+        let column: Vec<_> = (1..5).map(|y| chunks.get(ent).unwrap()[at(y)]).collect();
+        assert_eq!(column, vec![Sandy::Rock, Sandy::Sand, Sandy::Sand, Sandy::Air]);
+        assert_eq!(world.read_storage::<FallingVoxel<Sandy>>().join().count(), 0);
+    }
+
+    #[test]
+    fn landing_spot_filled() {
+        let mut world = World::new();
+        world.register::<Chunk<Sandy>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<Sandy>::new());
+        let mut time = Time::default();
+        time.set_delta_seconds(0.05);
+        world.add_resource(time);
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<Sandy>::new(), "chunk_tracker", &[])
+            .with(FallingVoxelSystem::<Sandy>::new(), "falling", &["chunk_tracker"])
+            .with(ChunkDeltaSystem::<Sandy>::new(), "chunk_deltas", &["falling"])
+            .with(VoxelPhysicsSystem::<Sandy>::new(20.0), "physics", &["chunk_deltas"])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        // sand on a rock, over the ground
+        let at = |y| VoxelCoord::new(4, y, 4);
+        let mut chunk = Chunk::<Sandy>::empty(VoxelCoord::new(0, 0, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), Sandy::Rock);
+        chunk[at(5)] = Sandy::Rock;
+        chunk[at(6)] = Sandy::Sand;
+        let ent = world.create_entity().with(chunk).build();
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+
+        // knock the rock out, and as soon as the sand lands, fill where it landed
+        world.read_resource::<ChunkDeltas<Sandy>>().defer_set(at(5), Sandy::Air);
+        let mut filled = false;
+        for _ in 0..40 {
+            dispatcher.dispatch(&mut world.res);
+            world.maintain();
+            if !filled && world.read_storage::<VoxelBody>().join().any(|body| body.on_ground()) {
+                world.write_storage::<Chunk<Sandy>>().get_mut(ent).unwrap()[at(1)] = Sandy::Rock;
+                filled = true;
+            }
+        }
+        assert!(filled);
+
+        // the sand goes on top instead
+        let chunks = world.read_storage::<Chunk<Sandy>>();
+        let column: Vec<_> = (1..4).map(|y| chunks.get(ent).unwrap()[at(y)]).collect();
+        assert_eq!(column, vec![Sandy::Rock, Sandy::Sand, Sandy::Air]);
+        assert_eq!(world.read_storage::<FallingVoxel<Sandy>>().join().count(), 0);
+    }
+}
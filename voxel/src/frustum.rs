@@ -0,0 +1,197 @@
+//! Finding the chunks and voxels inside a camera's view frustum.
+
+use super::{chunks_in_box, voxels_in_box, Aabb, ChunkAccess, Coord, Voxel, VoxelCoord, CHUNK_SIZE};
+
+use amethyst::core::transform::GlobalTransform;
+use amethyst::renderer::Camera;
+use cgmath::{InnerSpace, Matrix, Matrix4, SquareMatrix, Vector4};
+use std::i16;
+
+/// The volume a camera can see, as six planes.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    /// `(a, b, c, d)` such that points with `a x + b y + c z + d >= 0` are on the inside of the plane;
+    /// `(a, b, c)` is a unit vector.
+    planes: [Vector4<f32>; 6],
+}
+impl Frustum {
+    /// The frustum of a combined projection * view matrix, with OpenGL-style clip space (`-w <= z <= w`).
+    pub fn from_matrix(view_proj: Matrix4<f32>) -> Self {
+        // Gribb & Hartmann, "Fast Extraction of Viewing Frustum Planes from the World-View-Projection Matrix"
+        let (x, y, z, w) = (view_proj.row(0), view_proj.row(1), view_proj.row(2), view_proj.row(3));
+        let normalize = |plane: Vector4<f32>| plane / plane.truncate().magnitude();
+        Frustum {
+            planes: [
+                normalize(w + x),
+                normalize(w - x),
+                normalize(w + y),
+                normalize(w - y),
+                normalize(w + z),
+                normalize(w - z),
+            ],
+        }
+    }
+
+    /// The frustum of a camera entity. Returns None if its transform can't be inverted.
+    pub fn from_camera(camera: &Camera, transform: &GlobalTransform) -> Option<Self> {
+        let view = transform.0.invert()?;
+        Some(Frustum::from_matrix(camera.proj * view))
+    }
+
+    /// Whether `point` is inside the frustum.
+    pub fn contains(&self, point: Coord) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(point) + plane.w >= 0.0)
+    }
+
+    /// Whether `aabb` might overlap the frustum. This is conservative: a box near a corner of the frustum
+    /// can be outside it without being entirely outside any one plane.
+    pub fn intersects(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // the corner of the box furthest along the plane's normal
+            let corner = Coord::new(
+                if plane.x >= 0.0 { aabb.max.x } else { aabb.min.x },
+                if plane.y >= 0.0 { aabb.max.y } else { aabb.min.y },
+                if plane.z >= 0.0 { aabb.max.z } else { aabb.min.z },
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+/// The loaded chunks (by canonical coordinate) that are in `frustum` and within `max_distance` of `eye`,
+/// nearest first; e.g. for deciding which chunk meshes to draw, or what a camera could possibly see.
+///
+/// This looks up every chunk in the frustum's bounding box, so keep `max_distance` reasonable.
+pub fn visible_chunks<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    frustum: &Frustum,
+    eye: Coord,
+    max_distance: f32,
+) -> Vec<VoxelCoord> {
+    let clamp = |c: f32| c.max(i16::MIN as f32).min(i16::MAX as f32) as i16;
+    let corner = |sign: f32| {
+        let c = eye + Coord::new(sign, sign, sign) * max_distance;
+        VoxelCoord::new(clamp(c.x), clamp(c.y), clamp(c.z))
+    };
+
+    let mut visible: Vec<(f32, VoxelCoord)> = chunks_in_box(corner(-1.0), corner(1.0))
+        .filter_map(|coord| {
+            let bounds = chunk_bounds(coord);
+            let distance = distance_to(&bounds, eye);
+            if distance <= max_distance && frustum.intersects(&bounds) && chunks.get_chunk(coord).is_some() {
+                Some((distance, coord))
+            } else {
+                None
+            }
+        })
+        .collect();
+    visible.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+    visible.into_iter().map(|(_, coord)| coord).collect()
+}
+
+/// The surface voxels (non-transparent voxels next to a transparent one) in `frustum` and within
+/// `max_distance` of `eye`, chunk by chunk, nearest chunk first. Voxels next to unloaded chunks count as
+/// surface voxels, as they do when meshing. Nothing here accounts for occlusion.
+pub fn visible_voxels<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    frustum: &Frustum,
+    eye: Coord,
+    max_distance: f32,
+) -> Vec<VoxelCoord> {
+    let transparent = |coord: VoxelCoord| chunks.get_voxel(coord).map_or(true, |voxel| voxel.is_transparent());
+    let neighbors = [
+        VoxelCoord::new(1, 0, 0),
+        VoxelCoord::new(-1, 0, 0),
+        VoxelCoord::new(0, 1, 0),
+        VoxelCoord::new(0, -1, 0),
+        VoxelCoord::new(0, 0, 1),
+        VoxelCoord::new(0, 0, -1),
+    ];
+
+    let mut visible = Vec::new();
+    for coord in visible_chunks(chunks, frustum, eye, max_distance) {
+        let chunk = chunks.get_chunk(coord).unwrap();
+        let size = CHUNK_SIZE as i16 - 1;
+        for local in voxels_in_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(size, size, size)) {
+            if chunk[local].is_transparent() {
+                continue;
+            }
+            let voxel = coord + local;
+            let bounds = Aabb::from_center(voxel.cast().unwrap(), Coord::new(0.5, 0.5, 0.5));
+            if neighbors.iter().any(|&n| transparent(voxel + n))
+                && distance_to(&bounds, eye) <= max_distance
+                && frustum.intersects(&bounds)
+            {
+                visible.push(voxel);
+            }
+        }
+    }
+    visible
+}
+
+/// The box covered by the chunk at `coord`.
+fn chunk_bounds(coord: VoxelCoord) -> Aabb {
+    let min = coord.cast::<f32>().unwrap() - Coord::new(0.5, 0.5, 0.5);
+    Aabb::new(min, min + Coord::new(1.0, 1.0, 1.0) * CHUNK_SIZE as f32)
+}
+
+/// The distance from `point` to the nearest point in `aabb`.
+fn distance_to(aabb: &Aabb, point: Coord) -> f32 {
+    (aabb.closest_point(point) - point).magnitude()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{perspective, Deg};
+    use std::collections::HashMap;
+    use {Chunk, TestVoxel};
+
+    /// Looking down -z from the origin, with a 90 degree field of view.
+    fn frustum() -> Frustum {
+        Frustum::from_matrix(perspective(Deg(90.0), 1.0, 0.1, 1000.0))
+    }
+
+    #[test]
+    fn planes() {
+        let frustum = frustum();
+        assert!(frustum.contains(Coord::new(0.0, 0.0, -10.0)));
+        assert!(frustum.contains(Coord::new(9.0, -9.0, -10.0)));
+        assert!(!frustum.contains(Coord::new(0.0, 0.0, 10.0)));
+        assert!(!frustum.contains(Coord::new(11.0, 0.0, -10.0)));
+        assert!(!frustum.contains(Coord::new(0.0, 0.0, -2000.0)));
+
+        let aabb = |x: f32, z: f32| Aabb::from_center(Coord::new(x, 0.0, z), Coord::new(2.0, 2.0, 2.0));
+        assert!(frustum.intersects(&aabb(11.0, -10.0)));
+        assert!(!frustum.intersects(&aabb(15.0, -10.0)));
+        assert!(!frustum.intersects(&aabb(0.0, 3.0)));
+    }
+
+    #[test]
+    fn chunks_and_voxels() {
+        let mut chunks = HashMap::new();
+        for &(x, z) in &[(0, 0), (0, -32), (0, 16), (0, -160), (64, -32)] {
+            let coord = VoxelCoord::new(x, 0, z);
+            chunks.insert(coord, Chunk::<TestVoxel>::empty(coord));
+        }
+        {
+            let chunk = chunks.get_mut(&VoxelCoord::new(0, 0, -32)).unwrap();
+            chunk[VoxelCoord::new(3, 3, 3)] = TestVoxel::Rock;
+            for v in voxels_in_box(VoxelCoord::new(8, 8, 8), VoxelCoord::new(10, 10, 10)) {
+                chunk[v] = TestVoxel::Rock;
+            }
+        }
+
+        // the chunk we're in, and the one in front; not the ones behind, too far, or off to the side
+        let eye = Coord::new(0.0, 0.0, 0.0);
+        let visible = visible_chunks(&chunks, &frustum(), eye, 100.0);
+        assert_eq!(visible, vec![VoxelCoord::new(0, 0, 0), VoxelCoord::new(0, 0, -32)]);
+
+        // the middle of the cube is hidden
+        let voxels = visible_voxels(&chunks, &frustum(), eye, 100.0);
+        assert_eq!(voxels.len(), 1 + 26);
+        assert!(voxels.contains(&VoxelCoord::new(3, 3, -29)));
+        assert!(!voxels.contains(&VoxelCoord::new(9, 9, -23)));
+        assert!(visible_voxels(&chunks, &frustum(), eye, 20.0).is_empty());
+    }
+}
@@ -0,0 +1,772 @@
+//! Generating chunks as they're needed.
+//!
+//! Whatever decides which chunks should be loaded queues them up in the `ChunkRequests` resource, and the
+//! `ChunkGenerationSystem` generates them with a `ChunkGenerator` and inserts them (see
+//! `ChunkTracker::insert_chunk`), on worker threads if it has any. Requests that aren't wanted anymore,
+//! e.g. because the player moved away, can be cancelled.
+//!
+//! The `ChunkStreamingSystem` does the requesting (and cancelling) for you, keeping the chunks around
+//! `ChunkAnchor` entities (e.g. players and cameras) loaded.
+//!
+//! Generators can be wrapped in `WithStructures` to add trees, ruins and so on that span chunk borders, or
+//! built up from passes with a `GenerationPipeline` (see `pipeline`). Wrap one in a `persist::LoadOrGenerate`
+//! to load chunks that have been saved rather than generating them again.
+
+use super::{canonicalize, canonicalize_chunk, Chunk, ChunkTracker, Coord, Voxel, VoxelCoord, CHUNK_SIZE};
+use biome::ChunkBiomes;
+use budget::Headroom;
+use pipeline::{GenerationPass, Neighbors};
+use structure::Placement;
+
+use amethyst::core::transform::GlobalTransform;
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::Mutex;
+use soft_time_limit::TimeLimiter;
+use specs::prelude::*;
+use specs::HashMapStorage;
+use std::collections::VecDeque;
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Makes chunks from nothing: terrain, test patterns, ...
+pub trait ChunkGenerator<V: Voxel>: Send + Sync + 'static {
+    /// The chunk at `chunk_coord`, a canonical chunk coordinate. Generating the same chunk twice should
+    /// give the same chunk.
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V>;
+
+    /// The biomes of the chunk at `chunk_coord`, if this generator knows about biomes; they're added to the
+    /// chunk's entity along with it. Usually worked out from the same `BiomeSource` the generator uses to
+    /// paint the ground.
+    fn biomes(&self, _chunk_coord: VoxelCoord) -> Option<ChunkBiomes> {
+        None
+    }
+}
+
+/// A generated chunk, and its biomes if the generator has them.
+type Generated<V> = (Chunk<V>, Option<ChunkBiomes>);
+
+fn generate<V: Voxel, G: ChunkGenerator<V>>(generator: &G, coord: VoxelCoord) -> Generated<V> {
+    let chunk = generator.generate(coord);
+    debug_assert_eq!(chunk.coord, coord, "generated the wrong chunk");
+    (chunk, generator.biomes(coord))
+}
+
+/// Decides where structures (trees, ruins, ...) go, a region of columns at a time; see `WithStructures`.
+pub trait StructurePlanner<V: Voxel>: Send + Sync + 'static {
+    /// The structures whose origins are in the columns from `min` to `max` (inclusive, ignoring y).
+    /// They may stick out of the region, by up to the `reach` given to `WithStructures`.
+    /// Planning the same region twice should give the same structures.
+    fn plan(&self, min: VoxelCoord, max: VoxelCoord) -> Vec<Placement<V>>;
+}
+
+/// Stamps structures into chunks, in two phases: first `planner` decides where structures go,
+/// `region_size` by `region_size` columns at a time, and then each chunk gets the parts of the structures
+/// planned in and around its region that overlap it, as it's generated. So a tree on the border between two
+/// chunks is in both of them, whichever is generated first, instead of being cut off at the seam.
+///
+/// Structures may stick out of their regions by up to `reach` voxels (in x and z). Plans are kept, so each
+/// region is only planned once, however many chunks it overlaps.
+///
+/// This is a pass for a `GenerationPipeline`; to add structures to a plain generator, see `WithStructures`.
+pub struct StructurePass<V: Voxel, P: StructurePlanner<V>> {
+    planner: P,
+    region_size: i16,
+    reach: i16,
+    plans: Mutex<FnvHashMap<(i16, i16), Arc<Vec<Placement<V>>>>>,
+}
+impl<V: Voxel, P: StructurePlanner<V>> StructurePass<V, P> {
+    pub fn new(planner: P, region_size: i16, reach: i16) -> Self {
+        assert!(region_size > 0, "empty regions");
+        assert!(reach >= 0, "negative reach");
+        StructurePass {
+            planner,
+            region_size,
+            reach,
+            plans: Mutex::new(FnvHashMap::default()),
+        }
+    }
+
+    /// The structures planned for the region at `region` (in units of regions).
+    fn plan(&self, region: (i16, i16)) -> Arc<Vec<Placement<V>>> {
+        if let Some(plan) = self.plans.lock().get(&region) {
+            return plan.clone();
+        }
+        let size = self.region_size;
+        let min = VoxelCoord::new(region.0 * size, 0, region.1 * size);
+        let max = min + VoxelCoord::new(size - 1, 0, size - 1);
+        let plan = self.planner.plan(min, max);
+        debug_assert!(
+            plan.iter().all(|placement| {
+                let (lo, hi) = placement.bounds();
+                let inside = |i: usize| min[i] <= lo[i] && lo[i] <= max[i] && hi[i] <= max[i] + self.reach;
+                inside(0) && inside(2)
+            }),
+            "structure planned outside its region, or sticking out too far"
+        );
+        // (planned without holding the lock, so workers don't wait on each other; if two plan the same region
+        // at once, they get the same plan anyway)
+        self.plans
+            .lock()
+            .entry(region)
+            .or_insert_with(|| Arc::new(plan))
+            .clone()
+    }
+
+    /// Stamp the structures overlapping `chunk` into it.
+    pub fn stamp(&self, chunk: &mut Chunk<V>) {
+        let size = i32::from(self.region_size);
+        let region = |c: i32| ((c - ((c % size) + size) % size) / size) as i16;
+        let last = CHUNK_SIZE as i32 - 1;
+        let (x, z) = (i32::from(chunk.coord.x), i32::from(chunk.coord.z));
+        // structures from regions up to `reach` before the chunk can stick into it
+        let reach = i32::from(self.reach);
+        for region_x in region(x - reach)..=region(x + last) {
+            for region_z in region(z - reach)..=region(z + last) {
+                for placement in self.plan((region_x, region_z)).iter() {
+                    placement.stamp_chunk(chunk);
+                }
+            }
+        }
+    }
+}
+impl<V: Voxel, P: StructurePlanner<V>> GenerationPass<V> for StructurePass<V, P> {
+    fn apply(&self, chunk: &mut Chunk<V>, _neighbors: &Neighbors<V>) {
+        self.stamp(chunk);
+    }
+}
+
+/// Adds structures to the chunks of another generator; see `StructurePass`.
+pub struct WithStructures<V: Voxel, G: ChunkGenerator<V>, P: StructurePlanner<V>> {
+    generator: G,
+    structures: StructurePass<V, P>,
+}
+impl<V: Voxel, G: ChunkGenerator<V>, P: StructurePlanner<V>> WithStructures<V, G, P> {
+    pub fn new(generator: G, planner: P, region_size: i16, reach: i16) -> Self {
+        WithStructures {
+            generator,
+            structures: StructurePass::new(planner, region_size, reach),
+        }
+    }
+}
+impl<V: Voxel, G: ChunkGenerator<V>, P: StructurePlanner<V>> ChunkGenerator<V> for WithStructures<V, G, P> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        let mut chunk = self.generator.generate(chunk_coord);
+        self.structures.stamp(&mut chunk);
+        chunk
+    }
+
+    fn biomes(&self, chunk_coord: VoxelCoord) -> Option<ChunkBiomes> {
+        self.generator.biomes(chunk_coord)
+    }
+}
+
+/// Chunks waiting to be generated, by canonical coordinate, in the order they were requested.
+#[derive(Debug, Default)]
+pub struct ChunkRequests {
+    /// Requests in order, including cancelled ones, which are skipped.
+    queue: VecDeque<VoxelCoord>,
+    /// The requests that haven't been cancelled.
+    queued: FnvHashSet<VoxelCoord>,
+    /// Cancelled since the generation system last looked, which may be being generated already.
+    cancelled: Vec<VoxelCoord>,
+}
+impl ChunkRequests {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Request the chunk containing `coord`; returns false if it's already waiting.
+    /// Requests for chunks that are loaded by the time they're generated are dropped.
+    pub fn request(&mut self, coord: VoxelCoord) -> bool {
+        let coord = canonicalize_chunk(coord);
+        if self.queued.insert(coord) {
+            self.queue.push_back(coord);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Take back the request for the chunk containing `coord`, if it's waiting or being generated;
+    /// if it's generated anyway, it's dropped instead of being inserted.
+    pub fn cancel(&mut self, coord: VoxelCoord) {
+        let coord = canonicalize_chunk(coord);
+        self.queued.remove(&coord);
+        self.cancelled.push(coord);
+    }
+
+    /// Whether the chunk containing `coord` is waiting to be generated.
+    pub fn is_requested(&self, coord: VoxelCoord) -> bool {
+        self.queued.contains(&canonicalize_chunk(coord))
+    }
+
+    /// The next chunk to generate.
+    pub fn pop(&mut self) -> Option<VoxelCoord> {
+        loop {
+            let coord = self.queue.pop_front()?;
+            if self.queued.remove(&coord) {
+                return Some(coord);
+            }
+        }
+    }
+
+    /// The chunks cancelled since the last call.
+    pub fn take_cancelled(&mut self) -> Vec<VoxelCoord> {
+        self.cancelled.drain(..).collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queued.is_empty()
+    }
+}
+
+/// A chunk for a worker to generate, unless it's cancelled first.
+struct Job {
+    coord: VoxelCoord,
+    cancelled: Arc<AtomicBool>,
+}
+
+/// Threads generating chunks in the background.
+struct Workers<V: Voxel> {
+    jobs: Sender<Job>,
+    /// Finished jobs: the chunk, or None if the job was cancelled before it was generated.
+    done: Receiver<(VoxelCoord, Option<Generated<V>>)>,
+    /// Jobs sent and not received back yet, and whether they've been cancelled.
+    in_flight: FnvHashMap<VoxelCoord, Arc<AtomicBool>>,
+    /// How many jobs to have in flight at once; the rest wait in `ChunkRequests`, where they can be
+    /// reordered and cancelled cheaply.
+    max_in_flight: usize,
+}
+impl<V: Voxel> Workers<V> {
+    /// Start `count` threads generating chunks with `generator`. They stop when this is dropped.
+    fn start<G: ChunkGenerator<V>>(generator: &Arc<G>, count: usize) -> Self {
+        let (jobs, job_receiver) = mpsc::channel::<Job>();
+        let (done_sender, done) = mpsc::channel();
+        let job_receiver = Arc::new(Mutex::new(job_receiver));
+        for i in 0..count {
+            let (generator, jobs, done) = (generator.clone(), job_receiver.clone(), done_sender.clone());
+            thread::Builder::new()
+                .name(format!("chunk generation {}", i))
+                .spawn(move || loop {
+                    let job: Job = match jobs.lock().recv() {
+                        Ok(job) => job,
+                        // the system's gone
+                        Err(_) => return,
+                    };
+                    let chunk = if job.cancelled.load(Ordering::Relaxed) {
+                        None
+                    } else {
+                        Some(generate(&*generator, job.coord))
+                    };
+                    if done.send((job.coord, chunk)).is_err() {
+                        return;
+                    }
+                })
+                .expect("failed to start a chunk generation thread");
+        }
+        Workers {
+            jobs,
+            done,
+            in_flight: FnvHashMap::default(),
+            max_in_flight: count * 2,
+        }
+    }
+}
+
+/// Generates the chunks in `ChunkRequests` with its generator, spending up to its time limit per frame
+/// (scaled by the `Headroom` resource, if there is one). Whatever it doesn't get to waits for the next frame.
+///
+/// With worker threads (see `with_workers`), the generator runs on them instead, and the time limit is only
+/// spent inserting the chunks they finish, so the game doesn't hitch.
+///
+/// Should run before the `ChunkTrackerSystem`, so the tracker knows about new chunks by the next frame.
+pub struct ChunkGenerationSystem<V: Voxel, G: ChunkGenerator<V>> {
+    generator: Arc<G>,
+    workers: Option<Workers<V>>,
+    time_limiter: TimeLimiter,
+    time_limit: Duration,
+}
+impl<V: Voxel, G: ChunkGenerator<V>> ChunkGenerationSystem<V, G> {
+    /// Generate chunks on the game thread.
+    pub fn new(generator: G, time_limit: Duration) -> Self {
+        ChunkGenerationSystem {
+            generator: Arc::new(generator),
+            workers: None,
+            time_limiter: TimeLimiter::new(),
+            time_limit,
+        }
+    }
+
+    /// Generate chunks on `workers` threads of their own.
+    pub fn with_workers(generator: G, workers: usize, time_limit: Duration) -> Self {
+        assert!(workers > 0, "need at least one worker");
+        let generator = Arc::new(generator);
+        ChunkGenerationSystem {
+            workers: Some(Workers::start(&generator, workers)),
+            generator,
+            time_limiter: TimeLimiter::new(),
+            time_limit,
+        }
+    }
+}
+impl<'a, V: Voxel, G: ChunkGenerator<V>> System<'a> for ChunkGenerationSystem<V, G> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        Write<'a, ChunkRequests>,
+        WriteStorage<'a, Chunk<V>>,
+        WriteStorage<'a, GlobalTransform>,
+        WriteStorage<'a, ChunkBiomes>,
+        Option<Read<'a, Headroom>>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, tracker, mut requests, mut chunks, mut transforms, mut biomes, headroom): Self::SystemData,
+    ) {
+        let budget = headroom.map_or(self.time_limit, |headroom| headroom.budget(self.time_limit));
+        let cancelled = requests.take_cancelled();
+        let generator = &self.generator;
+        let tracker = &*tracker;
+        let mut insert = |(chunk, chunk_biomes): Generated<V>| {
+            let ent = tracker.insert_chunk(&entities, &mut chunks, &mut transforms, chunk);
+            if let (Some(ent), Some(chunk_biomes)) = (ent, chunk_biomes) {
+                // (the entity was just created, so this can't fail)
+                biomes.insert(ent, chunk_biomes).unwrap();
+            }
+        };
+        let workers = match self.workers {
+            Some(ref mut workers) => workers,
+            None => {
+                let repeated = self.time_limiter.repeat_with_budget(budget, || {
+                    let coord = match requests.pop() {
+                        Some(coord) => coord,
+                        None => return false,
+                    };
+                    if tracker.get_chunk_ent(coord).is_none() {
+                        insert(generate(&**generator, coord));
+                    }
+                    true
+                });
+                if !repeated.finished {
+                    debug!(
+                        "generation ran out of time after {:?}; {} chunks left to generate",
+                        repeated.elapsed,
+                        requests.len()
+                    );
+                }
+                return;
+            }
+        };
+
+        for coord in cancelled {
+            if let Some(flag) = workers.in_flight.get(&coord) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        }
+
+        // hand out more work
+        while workers.in_flight.len() < workers.max_in_flight {
+            let coord = match requests.pop() {
+                Some(coord) => coord,
+                None => break,
+            };
+            if let Some(flag) = workers.in_flight.get(&coord) {
+                // requested again while it was being generated; if the worker skipped it, it'll be requeued
+                flag.store(false, Ordering::Relaxed);
+                continue;
+            }
+            if tracker.get_chunk_ent(coord).is_some() {
+                continue;
+            }
+            let cancelled = Arc::new(AtomicBool::new(false));
+            workers.in_flight.insert(coord, cancelled.clone());
+            workers
+                .jobs
+                .send(Job { coord, cancelled })
+                .expect("chunk generation threads stopped");
+        }
+
+        // insert what's finished
+        let (done, in_flight) = (&workers.done, &mut workers.in_flight);
+        self.time_limiter.repeat_with_budget(budget, || {
+            let (coord, generated) = match done.try_recv() {
+                Ok(done) => done,
+                Err(TryRecvError::Empty) => return false,
+                Err(TryRecvError::Disconnected) => panic!("chunk generation threads stopped"),
+            };
+            let cancelled = in_flight
+                .remove(&coord)
+                .map_or(true, |flag| flag.load(Ordering::Relaxed));
+            match generated {
+                Some(generated) if !cancelled => insert(generated),
+                // skipped, and then requested again
+                None if !cancelled => {
+                    requests.request(coord);
+                }
+                _ => (),
+            }
+            true
+        });
+    }
+}
+
+/// Keeps the chunks within `radius` chunks of its entity (by its `GlobalTransform`) loaded, as it moves
+/// around; see `ChunkStreamingSystem`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkAnchor {
+    pub radius: u16,
+}
+impl Component for ChunkAnchor {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// The chunks within `radius` chunks of the chunk at `center`, in a ball, with the squared distance (in chunks)
+/// of each from `center`. Chunks that would be past the edge of the world are left out.
+pub fn chunks_in_radius(center: VoxelCoord, radius: u16) -> impl Iterator<Item = (i32, VoxelCoord)> {
+    let radius = i32::from(radius);
+    let size = CHUNK_SIZE as i32;
+    // (worked out in i32, since chunks near the edge have neighbors that don't fit in an i16)
+    let offset = move |center: i16, offset: i32| {
+        let coord = i32::from(center) + offset * size;
+        if coord < i32::from(i16::min_value()) || coord > i32::from(i16::max_value()) {
+            None
+        } else {
+            Some(coord as i16)
+        }
+    };
+    (-radius..=radius).flat_map(move |x| {
+        (-radius..=radius).flat_map(move |y| {
+            (-radius..=radius).filter_map(move |z| {
+                let distance = x * x + y * y + z * z;
+                if distance > radius * radius {
+                    return None;
+                }
+                let chunk = VoxelCoord::new(offset(center.x, x)?, offset(center.y, y)?, offset(center.z, z)?);
+                Some((distance, chunk))
+            })
+        })
+    })
+}
+
+/// Requests the chunks around `ChunkAnchor`s that aren't loaded, nearest first, and cancels them when the
+/// anchors move away before they're generated. Only a few requests are out at once, so that chunks nearer
+/// an anchor that's moved get requested ahead of the rest.
+///
+/// This only loads chunks; it doesn't unload the ones anchors leave behind.
+/// Should run before the `ChunkGenerationSystem`.
+pub struct ChunkStreamingSystem<V: Voxel> {
+    /// How many requests to have out at once.
+    max_requests: usize,
+    removed_id: Option<ReaderId<RemovedFlag>>,
+    /// The chunk and radius of each anchor, when `wanted` was worked out.
+    anchors: Vec<(VoxelCoord, u16)>,
+    /// Every chunk within range of an anchor, nearest first.
+    wanted: Vec<VoxelCoord>,
+    /// How far through `wanted` we've requested.
+    next: usize,
+    /// Chunks requested and not loaded yet.
+    requested: FnvHashSet<VoxelCoord>,
+    _phantom: PhantomData<V>,
+}
+impl<V: Voxel> ChunkStreamingSystem<V> {
+    pub fn new(max_requests: usize) -> Self {
+        ChunkStreamingSystem {
+            max_requests,
+            removed_id: None,
+            anchors: Vec::new(),
+            wanted: Vec::new(),
+            next: 0,
+            requested: FnvHashSet::default(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Work out `wanted` from `anchors`.
+    fn find_wanted(&mut self) {
+        let mut distances: FnvHashMap<VoxelCoord, i32> = FnvHashMap::default();
+        for &(center, radius) in &self.anchors {
+            for (distance, chunk) in chunks_in_radius(center, radius) {
+                let nearest = distances.entry(chunk).or_insert(distance);
+                *nearest = (*nearest).min(distance);
+            }
+        }
+        let mut wanted: Vec<(i32, VoxelCoord)> =
+            distances.into_iter().map(|(chunk, distance)| (distance, chunk)).collect();
+        // (ties broken by coordinate, so the order doesn't depend on the hash map)
+        wanted.sort_by_key(|&(distance, chunk)| (distance, chunk.x, chunk.y, chunk.z));
+        self.wanted = wanted.into_iter().map(|(_, chunk)| chunk).collect();
+        self.next = 0;
+    }
+}
+impl<'a, V: Voxel> System<'a> for ChunkStreamingSystem<V> {
+    type SystemData = (
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        ReadStorage<'a, ChunkAnchor>,
+        ReadStorage<'a, GlobalTransform>,
+        Write<'a, ChunkRequests>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        self.removed_id = Some(WriteStorage::<Chunk<V>>::fetch(resources).track_removed());
+    }
+
+    fn run(&mut self, (tracker, chunks, anchors, transforms, mut requests): Self::SystemData) {
+        let mut current: Vec<(VoxelCoord, u16)> = (&anchors, &transforms)
+            .join()
+            .map(|(anchor, transform)| {
+                let position = transform.0.w;
+                let voxel = canonicalize(Coord::new(position.x, position.y, position.z));
+                (canonicalize_chunk(voxel), anchor.radius)
+            })
+            .collect();
+        current.sort_by_key(|&(chunk, radius)| (chunk.x, chunk.y, chunk.z, radius));
+        if current != self.anchors {
+            self.anchors = current;
+            self.find_wanted();
+            let wanted: FnvHashSet<VoxelCoord> = self.wanted.iter().cloned().collect();
+            for &chunk in self.requested.iter().filter(|chunk| !wanted.contains(chunk)) {
+                requests.cancel(chunk);
+            }
+            self.requested.retain(|chunk| wanted.contains(chunk));
+        }
+        // unloaded chunks might be wanted again
+        if chunks.removed().read(self.removed_id.as_mut().unwrap()).next().is_some() {
+            self.next = 0;
+        }
+
+        self.requested.retain(|&chunk| tracker.get_chunk_ent(chunk).is_none());
+        while self.requested.len() < self.max_requests && self.next < self.wanted.len() {
+            let chunk = self.wanted[self.next];
+            self.next += 1;
+            if tracker.get_chunk_ent(chunk).is_none() && !self.requested.contains(&chunk) {
+                requests.request(chunk);
+                self.requested.insert(chunk);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Matrix4;
+    use std::sync::atomic::AtomicUsize;
+    use structure::{MergePolicy, Rotation, Structure};
+    use tracker::ChunkTrackerSystem;
+    use TestVoxel;
+
+    /// Rock below y = 0.
+    struct Ground;
+    impl ChunkGenerator<TestVoxel> for Ground {
+        fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<TestVoxel> {
+            let mut chunk = Chunk::empty(chunk_coord);
+            if chunk_coord.y < 0 {
+                chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 15, 15), TestVoxel::Rock);
+            }
+            chunk
+        }
+        fn biomes(&self, chunk_coord: VoxelCoord) -> Option<ChunkBiomes> {
+            Some(ChunkBiomes::uniform(if chunk_coord.y < 0 { 1 } else { 0 }))
+        }
+    }
+
+    fn setup(
+        system: ChunkGenerationSystem<TestVoxel, Ground>,
+    ) -> (World, Dispatcher<'static, 'static>) {
+        let mut world = World::new();
+        world.add_resource(ChunkTracker::new());
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(system, "generation", &[])
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &["generation"])
+            .build();
+        dispatcher.setup(&mut world.res);
+        (world, dispatcher)
+    }
+
+    #[test]
+    fn generation() {
+        let (mut world, mut dispatcher) = setup(ChunkGenerationSystem::new(Ground, Duration::from_secs(1)));
+
+        {
+            let mut requests = world.write_resource::<ChunkRequests>();
+            assert!(requests.request(VoxelCoord::new(0, -16, 0)));
+            assert!(requests.request(VoxelCoord::new(3, 4, 5)));
+            assert!(!requests.request(VoxelCoord::new(5, 4, 3)));
+            assert!(requests.is_requested(VoxelCoord::new(0, 0, 0)));
+            assert_eq!(requests.len(), 2);
+        }
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+
+        let below = {
+            let tracker = world.read_resource::<ChunkTracker>();
+            let chunks = world.read_storage::<Chunk<TestVoxel>>();
+            let below = tracker.get_chunk_ent(VoxelCoord::new(0, -16, 0)).unwrap();
+            assert_eq!(chunks.get(below).unwrap()[VoxelCoord::new(1, 2, 3)], TestVoxel::Rock);
+            let above = tracker.get_chunk_ent(VoxelCoord::new(0, 0, 0)).unwrap();
+            assert_eq!(chunks.get(above).unwrap()[VoxelCoord::new(1, 2, 3)], TestVoxel::Air);
+            assert!(world.read_storage::<GlobalTransform>().get(below).is_some());
+            assert_eq!(world.read_storage::<ChunkBiomes>().get(below), Some(&ChunkBiomes::uniform(1)));
+            assert!(world.read_resource::<ChunkRequests>().is_empty());
+            below
+        };
+
+        // loaded chunks aren't generated again
+        world.write_resource::<ChunkRequests>().request(VoxelCoord::new(0, -16, 0));
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+        let tracker = world.read_resource::<ChunkTracker>();
+        assert_eq!(tracker.get_chunk_ent(VoxelCoord::new(0, -16, 0)), Some(below));
+        assert_eq!(world.read_storage::<Chunk<TestVoxel>>().join().count(), 2);
+    }
+
+    /// A bar of rock four voxels long at y = 5, starting two voxels before the end of each region in x.
+    struct Bars(Arc<AtomicUsize>);
+    impl StructurePlanner<TestVoxel> for Bars {
+        fn plan(&self, min: VoxelCoord, max: VoxelCoord) -> Vec<Placement<TestVoxel>> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            let mut bar = Structure::empty(VoxelCoord::new(4, 1, 1));
+            for x in 0..4 {
+                bar[VoxelCoord::new(x, 0, 0)] = TestVoxel::Rock;
+            }
+            let origin = VoxelCoord::new(max.x - 1, 5, min.z + 3);
+            vec![Placement::new(origin, Arc::new(bar), Rotation::None, MergePolicy::SkipAir)]
+        }
+    }
+
+    #[test]
+    fn structures() {
+        let planned = Arc::new(AtomicUsize::new(0));
+        let generator = WithStructures::new(Ground, Bars(planned.clone()), 32, 4);
+        // the bar from the region before sticks into this chunk...
+        let chunk = generator.generate(VoxelCoord::new(0, 0, 0));
+        assert_eq!(chunk[VoxelCoord::new(0, 5, 3)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(1, 5, 3)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(2, 5, 3)], TestVoxel::Air);
+        // ...and this chunk's region's bar sticks into the next region
+        let chunk = generator.generate(VoxelCoord::new(32, 0, 0));
+        assert_eq!(chunk[VoxelCoord::new(0, 5, 3)], TestVoxel::Rock);
+        let chunk = generator.generate(VoxelCoord::new(16, 0, 0));
+        assert_eq!(chunk[VoxelCoord::new(13, 5, 3)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(14, 5, 3)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(15, 5, 3)], TestVoxel::Rock);
+        // (other chunks in the same columns only get the bar if they reach y = 5)
+        let chunk = generator.generate(VoxelCoord::new(16, 16, 0));
+        assert_eq!(chunk[VoxelCoord::new(15, 5, 3)], TestVoxel::Air);
+
+        // every region is only planned once: x -1 and 0 (by z -1 and 0) for the first chunk, then x 1 for the
+        // second, and nothing new after that
+        assert_eq!(planned.load(Ordering::Relaxed), 6);
+    }
+
+    #[test]
+    fn radius() {
+        let center = VoxelCoord::new(0, 0, 0);
+        let chunks: Vec<_> = chunks_in_radius(center, 1).collect();
+        assert_eq!(chunks.len(), 7);
+        assert!(chunks.contains(&(0, center)));
+        assert!(chunks.contains(&(1, VoxelCoord::new(0, -16, 0))));
+
+        // chunks past the edge of the world are left out
+        let corner = VoxelCoord::new(32752, -32768, 0);
+        let mut chunks: Vec<_> = chunks_in_radius(corner, 2).map(|(_, chunk)| chunk).collect();
+        chunks.sort_by_key(|chunk| (chunk.x, chunk.y, chunk.z));
+        assert_eq!(chunks.len(), 16);
+        assert_eq!(chunks[0], VoxelCoord::new(32720, -32768, 0));
+        assert!(chunks.iter().all(|chunk| chunk.x <= corner.x && chunk.y >= corner.y));
+    }
+
+    #[test]
+    fn streaming() {
+        let mut world = World::new();
+        world.add_resource(ChunkTracker::new());
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkStreamingSystem::<TestVoxel>::new(3), "streaming", &[])
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .build();
+        dispatcher.setup(&mut world.res);
+        world.register::<ChunkAnchor>();
+        let anchor = world
+            .create_entity()
+            .with(ChunkAnchor { radius: 1 })
+            .with(GlobalTransform(Matrix4::from_translation(Coord::new(20.0, 3.0, 3.0))))
+            .build();
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(16, 16, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+        dispatcher.dispatch(&mut world.res);
+        {
+            // the anchor's chunk first, then the nearest ones, skipping the loaded one
+            let mut requests = world.write_resource::<ChunkRequests>();
+            assert_eq!(requests.len(), 3);
+            assert_eq!(requests.pop(), Some(VoxelCoord::new(16, 0, 0)));
+            assert_eq!(requests.pop(), Some(VoxelCoord::new(0, 0, 0)));
+            assert_eq!(requests.pop(), Some(VoxelCoord::new(16, -16, 0)));
+        }
+
+        // as if the first was generated; the next get requested
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(16, 0, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+        dispatcher.dispatch(&mut world.res);
+        assert!(world.read_resource::<ChunkRequests>().is_requested(VoxelCoord::new(16, 0, -16)));
+
+        // move away, and whatever hasn't been generated is cancelled
+        world
+            .write_storage::<GlobalTransform>()
+            .insert(anchor, GlobalTransform(Matrix4::from_translation(Coord::new(500.0, 3.0, 3.0))))
+            .unwrap();
+        dispatcher.dispatch(&mut world.res);
+        let mut requests = world.write_resource::<ChunkRequests>();
+        let cancelled = requests.take_cancelled();
+        assert!(cancelled.contains(&VoxelCoord::new(0, 0, 0)));
+        assert!(cancelled.contains(&VoxelCoord::new(16, 0, -16)));
+        assert!(!requests.is_requested(VoxelCoord::new(16, 0, -16)));
+        assert_eq!(requests.pop(), Some(VoxelCoord::new(496, 0, 0)));
+    }
+
+    #[test]
+    fn background_generation() {
+        let system = ChunkGenerationSystem::with_workers(Ground, 2, Duration::from_secs(1));
+        let (mut world, mut dispatcher) = setup(system);
+        {
+            let mut requests = world.write_resource::<ChunkRequests>();
+            for x in 0..4 {
+                requests.request(VoxelCoord::new(x * 16, -16, 0));
+            }
+            // cancelled before it's handed out
+            requests.request(VoxelCoord::new(0, 0, 0));
+            requests.cancel(VoxelCoord::new(0, 0, 0));
+            assert_eq!(requests.len(), 4);
+        }
+
+        let generated = |world: &World| world.read_storage::<Chunk<TestVoxel>>().join().count();
+        for _ in 0..1000 {
+            if generated(&world) == 4 {
+                break;
+            }
+            dispatcher.dispatch(&mut world.res);
+            world.maintain();
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(generated(&world), 4);
+        let tracker = world.read_resource::<ChunkTracker>();
+        assert!(tracker.get_chunk_ent(VoxelCoord::new(48, -16, 0)).is_some());
+        assert!(tracker.get_chunk_ent(VoxelCoord::new(0, 0, 0)).is_none());
+    }
+}
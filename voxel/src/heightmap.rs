@@ -0,0 +1,295 @@
+//! Terrain from grayscale heightmap images, so that it can be made in an image editor or terrain tool.
+//!
+//! Images are read from PGM files (binary or plain, 8 or 16 bits), which most tools can export; if you'd
+//! rather load something else, decode it yourself and use `Heightmap::new`.
+
+use super::{Chunk, Voxel, VoxelCoord, CHUNK_SIZE};
+use generate::ChunkGenerator;
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+/// A grayscale image, as heights: one sample per column, from 0 (lowest) to `max_value` (highest).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Heightmap {
+    width: u32,
+    depth: u32,
+    max_value: u16,
+    /// Row by row: the sample for `x`, `z` is at `z * width + x`.
+    samples: Vec<u16>,
+}
+impl Heightmap {
+    /// A heightmap `width` columns across (in x) and `depth` deep (in z).
+    pub fn new(width: u32, depth: u32, max_value: u16, samples: Vec<u16>) -> Self {
+        assert_eq!(samples.len(), width as usize * depth as usize, "wrong number of samples");
+        assert!(max_value > 0, "no heights");
+        Heightmap {
+            width,
+            depth,
+            max_value,
+            samples,
+        }
+    }
+
+    /// Parse a PGM image.
+    pub fn from_pgm(bytes: &[u8]) -> io::Result<Heightmap> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+        let mut tokens = Tokens { bytes, pos: 0 };
+        let number = |token: Option<&[u8]>| -> io::Result<u32> {
+            token
+                .and_then(|token| ::std::str::from_utf8(token).ok())
+                .and_then(|token| token.parse().ok())
+                .ok_or_else(|| invalid("bad number in heightmap"))
+        };
+
+        let binary = match tokens.next_token() {
+            Some(magic) if magic == b"P5" => true,
+            Some(magic) if magic == b"P2" => false,
+            _ => return Err(invalid("not a PGM heightmap")),
+        };
+        let width = number(tokens.next_token())?;
+        let depth = number(tokens.next_token())?;
+        let max_value = number(tokens.next_token())?;
+        if max_value == 0 || max_value > u32::from(u16::max_value()) {
+            return Err(invalid("bad maximum value in heightmap"));
+        }
+        let count = width as usize * depth as usize;
+
+        let samples = if binary {
+            // (a single whitespace byte separates the header from the samples)
+            let start = tokens.pos + 1;
+            let size = if max_value < 256 { 1 } else { 2 };
+            let data = bytes
+                .get(start..start + count * size)
+                .ok_or_else(|| invalid("heightmap is too short"))?;
+            if size == 1 {
+                data.iter().map(|&b| u16::from(b)).collect()
+            } else {
+                data.chunks(2).map(|b| (u16::from(b[0]) << 8) | u16::from(b[1])).collect()
+            }
+        } else {
+            let mut samples = Vec::with_capacity(count);
+            for _ in 0..count {
+                // check before narrowing, or e.g. 65546 would wrap around to 10
+                let sample = number(tokens.next_token())?;
+                if sample > max_value {
+                    return Err(invalid("heightmap sample out of range"));
+                }
+                samples.push(sample as u16);
+            }
+            samples
+        };
+        if samples.iter().any(|&sample| u32::from(sample) > max_value) {
+            return Err(invalid("heightmap sample out of range"));
+        }
+        Ok(Heightmap::new(width, depth, max_value as u16, samples))
+    }
+
+    /// Load a PGM image from a file.
+    pub fn load_pgm<P: AsRef<Path>>(path: P) -> io::Result<Heightmap> {
+        let mut bytes = Vec::new();
+        File::open(path)?.read_to_end(&mut bytes)?;
+        Heightmap::from_pgm(&bytes)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn depth(&self) -> u32 {
+        self.depth
+    }
+
+    /// The height of the column at `x`, `z` in the image, from 0 to 1; None outside the image.
+    pub fn get(&self, x: i32, z: i32) -> Option<f32> {
+        if x < 0 || z < 0 || x as u32 >= self.width || z as u32 >= self.depth {
+            return None;
+        }
+        let sample = self.samples[z as usize * self.width as usize + x as usize];
+        Some(f32::from(sample) / f32::from(self.max_value))
+    }
+}
+
+/// The whitespace-separated parts of a PGM header (or plain PGM samples), skipping comments.
+struct Tokens<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl<'a> Tokens<'a> {
+    fn next_token(&mut self) -> Option<&'a [u8]> {
+        loop {
+            match self.bytes.get(self.pos) {
+                Some(b'#') => {
+                    while self.bytes.get(self.pos).map_or(false, |&b| b != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                Some(_) => break,
+                None => return None,
+            }
+        }
+        let start = self.pos;
+        while self.bytes.get(self.pos).map_or(false, |b| !b.is_ascii_whitespace()) {
+            self.pos += 1;
+        }
+        Some(&self.bytes[start..self.pos])
+    }
+}
+
+/// Terrain from a `Heightmap`, one column per pixel, with the image's (0, 0) pixel at `origin` (in x and z).
+///
+/// Black is at height `base`, and white `scale` voxels above it. The top `surface_depth` voxels of each
+/// column are made of the material for their height (see `with_material`), and everything under them of
+/// `ground`. Below the water level, if there is one, the space above the ground is filled with water.
+/// Outside the image there's nothing.
+#[derive(Clone, Debug)]
+pub struct HeightmapGenerator<V: Voxel> {
+    heightmap: Heightmap,
+    origin: (i16, i16),
+    base: i16,
+    scale: f32,
+    ground: V,
+    surface_depth: u16,
+    /// The lowest height each surface material is used at, and the material, lowest first.
+    materials: Vec<(i16, V)>,
+    water: Option<(i16, V)>,
+}
+impl<V: Voxel> HeightmapGenerator<V> {
+    pub fn new(heightmap: Heightmap, origin: (i16, i16), base: i16, scale: f32, ground: V) -> Self {
+        HeightmapGenerator {
+            heightmap,
+            origin,
+            base,
+            scale,
+            ground,
+            surface_depth: 1,
+            materials: Vec::new(),
+            water: None,
+        }
+    }
+
+    /// Cover columns whose tops are at `height` or higher with `material`, up to the height of the next
+    /// material; e.g. sand from the water up, then grass, then stone, then snow.
+    pub fn with_material(mut self, height: i16, material: V) -> Self {
+        self.materials.push((height, material));
+        self.materials.sort_by_key(|&(height, _)| height);
+        self
+    }
+
+    /// How many voxels deep the surface materials go.
+    pub fn with_surface_depth(mut self, depth: u16) -> Self {
+        self.surface_depth = depth;
+        self
+    }
+
+    /// Fill the space above the ground up to `level` (inclusive) with `water`.
+    pub fn with_water(mut self, level: i16, water: V) -> Self {
+        self.water = Some((level, water));
+        self
+    }
+
+    /// The height of the top voxel of the column at `x`, `z`, if it's in the image.
+    pub fn height(&self, x: i16, z: i16) -> Option<i16> {
+        let (x, z) = (i32::from(x) - i32::from(self.origin.0), i32::from(z) - i32::from(self.origin.1));
+        self.heightmap
+            .get(x, z)
+            .map(|height| self.base + (height * self.scale).round() as i16)
+    }
+
+    /// The surface material for a column whose top is at `top`.
+    fn material(&self, top: i16) -> V {
+        self.materials
+            .iter()
+            .rev()
+            .find(|&&(height, _)| height <= top)
+            .map_or(self.ground, |&(_, material)| material)
+    }
+}
+impl<V: Voxel> ChunkGenerator<V> for HeightmapGenerator<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        let mut chunk = Chunk::empty(chunk_coord);
+        for x in 0..CHUNK_SIZE as i16 {
+            for z in 0..CHUNK_SIZE as i16 {
+                let top = match self.height(chunk_coord.x + x, chunk_coord.z + z) {
+                    Some(top) => top,
+                    None => continue,
+                };
+                let surface = self.material(top);
+                for y in 0..CHUNK_SIZE as i16 {
+                    let height = chunk_coord.y + y;
+                    let voxel = if height <= top {
+                        if i32::from(top) - i32::from(height) < i32::from(self.surface_depth) {
+                            surface
+                        } else {
+                            self.ground
+                        }
+                    } else {
+                        match self.water {
+                            Some((level, water)) if height <= level => water,
+                            _ => continue,
+                        }
+                    };
+                    chunk[VoxelCoord::new(x, y, z)] = voxel;
+                }
+            }
+        }
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TestVoxel;
+
+    #[test]
+    fn pgm() {
+        let plain = b"P2\n# made by hand\n3 2\n10\n0 5 10\n10 5 0\n";
+        let heightmap = Heightmap::from_pgm(plain).unwrap();
+        assert_eq!((heightmap.width(), heightmap.depth()), (3, 2));
+        assert_eq!(heightmap.get(1, 0), Some(0.5));
+        assert_eq!(heightmap.get(0, 1), Some(1.0));
+        assert_eq!(heightmap.get(3, 0), None);
+
+        let mut binary = b"P5 3 2 10\n".to_vec();
+        binary.extend_from_slice(&[0, 5, 10, 10, 5, 0]);
+        assert_eq!(Heightmap::from_pgm(&binary).unwrap(), heightmap);
+
+        let mut wide = b"P5 1 1 1000\n".to_vec();
+        wide.extend_from_slice(&[0x01, 0xf4]);
+        assert_eq!(Heightmap::from_pgm(&wide).unwrap().get(0, 0), Some(0.5));
+
+        assert!(Heightmap::from_pgm(b"P5 3 2 10\n\x00").is_err());
+        assert!(Heightmap::from_pgm(b"P2 1 1 10\n11\n").is_err());
+        assert!(Heightmap::from_pgm(b"P2 1 1 10\n65546\n").is_err());
+        assert!(Heightmap::from_pgm(b"P6 1 1 10\n").is_err());
+    }
+
+    #[test]
+    fn terrain() {
+        // a slope from 0 up to 8 voxels high, going along x
+        let samples = (0..16).map(|x| x as u16).chain(0..16).collect();
+        let heightmap = Heightmap::new(16, 2, 16, samples);
+        let generator = HeightmapGenerator::new(heightmap, (-4, 0), 0, 8.0, TestVoxel::Rock)
+            .with_material(5, TestVoxel::Grass)
+            .with_water(3, TestVoxel::Grass);
+        assert_eq!(generator.height(-4, 0), Some(0));
+        assert_eq!(generator.height(11, 1), Some(8));
+        assert_eq!(generator.height(12, 0), None);
+
+        let chunk = generator.generate(VoxelCoord::new(0, 0, 0));
+        // x = 8 is sample 12, 6 voxels high
+        assert_eq!(chunk[VoxelCoord::new(8, 6, 0)], TestVoxel::Grass);
+        assert_eq!(chunk[VoxelCoord::new(8, 5, 0)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(8, 7, 0)], TestVoxel::Air);
+        // x = 0 is only 2 high: too low for grass, and under water
+        assert_eq!(chunk[VoxelCoord::new(0, 2, 1)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(0, 3, 1)], TestVoxel::Grass);
+        assert_eq!(chunk[VoxelCoord::new(0, 4, 1)], TestVoxel::Air);
+        // outside the image
+        assert_eq!(chunk[VoxelCoord::new(8, 0, 2)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(13, 0, 0)], TestVoxel::Air);
+    }
+}
@@ -0,0 +1,562 @@
+//! Undo/redo for voxel edits, built on `ChunkDeltas`.
+//!
+//! Edits made through `VoxelHistory` are grouped into named transactions. Once the edits land,
+//! `VoxelHistorySystem` records the voxels they replaced (via `VoxelChanged` events and `DeltaResult`s), so that
+//! `undo()` and `redo()` can re-emit the inverse edits later.
+//!
+//! Region edits (pastes, brush strokes, fills...) are recorded voxel by voxel, so a transaction wrapping one can
+//! hold thousands of edits, and still be undone in one go. To keep that from growing without bound, the history
+//! has a memory limit, past which the oldest transactions are forgotten.
+//!
+//! A transaction can also be `revert`ed out of turn, leaving the rest of the history alone; that's how
+//! `net::EditPredictions` takes back the edits the server turns down.
+
+use super::{Voxel, VoxelCoord};
+use delta::{ChunkDeltas, DeltaId, DeltaOutcome, DeltaResult, DeltaSource, VoxelChanged, want_fill_changes};
+
+use amethyst::shrev::EventChannel;
+use fnv::FnvHashMap;
+use specs::prelude::*;
+use std::collections::VecDeque;
+use std::mem;
+
+/// The default `VoxelHistory::memory_limit`, in bytes.
+pub const DEFAULT_MEMORY_LIMIT: usize = 64 << 20;
+
+/// A recorded edit: the voxel at `coord` was changed from `old` to `new`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Edit<V: Voxel> {
+    pub coord: VoxelCoord,
+    pub old: V,
+    pub new: V,
+}
+
+/// A named group of edits, undone and redone as a unit.
+#[derive(Clone, Debug)]
+pub struct Transaction<V: Voxel> {
+    serial: u64,
+    name: String,
+    edits: Vec<Edit<V>>,
+}
+impl<V: Voxel> Transaction<V> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+    /// The edits in this transaction, in the order they were applied.
+    pub fn edits(&self) -> &[Edit<V>] {
+        &self.edits
+    }
+}
+
+/// Names a transaction, so that it can be `revert`ed or `forget`ten out of turn.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TransactionId(u64);
+
+/// Where a tracked edit's changes go.
+#[derive(Clone, Copy, Debug)]
+struct Tracked {
+    serial: u64,
+    /// whether any `VoxelChanged` events have been recorded for it
+    changed: bool,
+}
+
+/// A resource storing undo and redo stacks of voxel edits.
+pub struct VoxelHistory<V: Voxel> {
+    open: Option<Transaction<V>>,
+    undo: VecDeque<Transaction<V>>,
+    redo: VecDeque<Transaction<V>>,
+    /// deferred edits we're waiting to hear back about, and the transactions they belong to
+    tracked: FnvHashMap<DeltaId, Tracked>,
+    next_serial: u64,
+    /// edits stored in all the transactions
+    recorded: usize,
+    memory_limit: usize,
+    /// reverted transactions with edits still to land, and the source to put their voxels back as
+    reverting: FnvHashMap<u64, DeltaSource>,
+    /// edits that landed after their transaction was reverted, to put back in `revert_late`
+    late: Vec<(DeltaSource, Edit<V>)>,
+}
+impl<V: Voxel> Default for VoxelHistory<V> {
+    fn default() -> Self {
+        VoxelHistory::with_memory_limit(DEFAULT_MEMORY_LIMIT)
+    }
+}
+impl<V: Voxel> VoxelHistory<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// A history storing roughly `bytes` of edits at most; see `memory_limit`.
+    pub fn with_memory_limit(bytes: usize) -> Self {
+        VoxelHistory {
+            open: None,
+            undo: VecDeque::new(),
+            redo: VecDeque::new(),
+            tracked: FnvHashMap::default(),
+            next_serial: 0,
+            recorded: 0,
+            memory_limit: bytes,
+            reverting: FnvHashMap::default(),
+            late: Vec::new(),
+        }
+    }
+
+    /// About how many bytes of edits the history keeps. Past that, whole transactions are forgotten, oldest first:
+    /// first the ones furthest back in the undo stack, then the ones furthest along the redo stack. The open
+    /// transaction, or else the one `undo` would undo next, is always kept, however big it is, so the last
+    /// operation can be undone.
+    pub fn memory_limit(&self) -> usize {
+        self.memory_limit
+    }
+
+    pub fn set_memory_limit(&mut self, bytes: usize) {
+        self.memory_limit = bytes;
+        self.evict();
+    }
+
+    /// About how many bytes the recorded edits take up.
+    pub fn memory_used(&self) -> usize {
+        self.recorded * mem::size_of::<Edit<V>>()
+    }
+
+    /// Forget the oldest transactions until the edits fit in the memory limit.
+    fn evict(&mut self) {
+        while self.memory_used() > self.memory_limit {
+            let evicted = if self.undo.len() > 1 || (self.open.is_some() && !self.undo.is_empty()) {
+                self.undo.pop_front()
+            } else {
+                self.redo.pop_front()
+            };
+            match evicted {
+                Some(evicted) => self.recorded -= evicted.edits.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Add an edit to the transaction with the given serial.
+    fn push(&mut self, serial: u64, edit: Edit<V>) {
+        if let Some(&source) = self.reverting.get(&serial) {
+            self.late.push((source, edit));
+            return;
+        }
+        {
+            let transaction = self.open
+                .iter_mut()
+                .chain(self.undo.iter_mut().rev())
+                .find(|transaction| transaction.serial == serial);
+            // if we can't find the transaction, it's already been undone or evicted; drop the edit
+            match transaction {
+                Some(transaction) => transaction.edits.push(edit),
+                None => return,
+            }
+        }
+        self.recorded += 1;
+        self.evict();
+    }
+
+    /// Take the transaction with the given serial out of the history, wherever it is.
+    fn remove(&mut self, serial: u64) -> Option<Transaction<V>> {
+        let transaction = if self.open.as_ref().map_or(false, |open| open.serial == serial) {
+            self.open.take()
+        } else if let Some(i) = self.undo.iter().position(|transaction| transaction.serial == serial) {
+            self.undo.remove(i)
+        } else if let Some(i) = self.redo.iter().position(|transaction| transaction.serial == serial) {
+            self.redo.remove(i)
+        } else {
+            None
+        };
+        if let Some(ref transaction) = transaction {
+            self.recorded -= transaction.edits.len();
+        }
+        transaction
+    }
+
+    /// Start a new transaction; edits made until the next `commit()` will be undone together.
+    /// Commits the currently open transaction, if there is one.
+    pub fn begin(&mut self, name: &str) -> TransactionId {
+        self.commit();
+        let serial = self.next_serial;
+        self.next_serial += 1;
+        self.open = Some(Transaction {
+            serial,
+            name: name.to_string(),
+            edits: Vec::new(),
+        });
+        TransactionId(serial)
+    }
+
+    /// Close the open transaction and push it onto the undo stack.
+    /// Its edits will still be recorded as they land.
+    pub fn commit(&mut self) {
+        if let Some(transaction) = self.open.take() {
+            self.undo.push_back(transaction);
+        }
+    }
+
+    /// Defer an edit, recording it in the open transaction.
+    /// If no transaction is open, the edit gets a transaction of its own.
+    pub fn set(&mut self, deltas: &ChunkDeltas<V>, coord: VoxelCoord, voxel: V) -> DeltaId {
+        let id = deltas.defer_set(coord, voxel);
+        self.track(id);
+        id
+    }
+
+    /// Record an edit already deferred through `ChunkDeltas` (e.g. with `defer_set_if`, or a region edit like
+    /// `defer_stamp`) in the open transaction.
+    /// If no transaction is open, the edit gets a transaction of its own.
+    pub fn track(&mut self, id: DeltaId) {
+        if self.open.is_none() {
+            self.begin("edit");
+            self.track(id);
+            self.commit();
+            return;
+        }
+        let serial = self.open.as_ref().unwrap().serial;
+        self.tracked.insert(id, Tracked { serial, changed: false });
+        // a new edit invalidates anything we could redo
+        for transaction in self.redo.drain(..) {
+            self.recorded -= transaction.edits.len();
+        }
+    }
+
+    /// Record a voxel changed by a deferred edit. Called by `VoxelHistorySystem`, before the `record_result`
+    /// for the same edit.
+    pub fn record_change(&mut self, change: &VoxelChanged<V>) {
+        let serial = match self.tracked.get_mut(&change.id) {
+            Some(tracked) => {
+                tracked.changed = true;
+                tracked.serial
+            }
+            None => return,
+        };
+        let edit = Edit {
+            coord: change.coord,
+            old: change.old,
+            new: change.new,
+        };
+        self.push(serial, edit);
+    }
+
+    /// Record the outcome of a deferred edit, and stop tracking it. Called by `VoxelHistorySystem`.
+    /// An applied single-voxel edit is recorded here, unless `record_change` already has it.
+    pub fn record_result(&mut self, result: &DeltaResult<V>) {
+        let tracked = match self.tracked.remove(&result.id) {
+            Some(tracked) => tracked,
+            None => return,
+        };
+        if let DeltaOutcome::Applied { old, new } = result.outcome {
+            if !tracked.changed {
+                let edit = Edit {
+                    coord: result.coord,
+                    old,
+                    new,
+                };
+                self.push(tracked.serial, edit);
+            }
+        }
+        if self.reverting.contains_key(&tracked.serial) && !self.is_landing(TransactionId(tracked.serial)) {
+            self.reverting.remove(&tracked.serial);
+        }
+    }
+
+    /// Whether any of the edits in a transaction haven't landed yet.
+    pub fn is_landing(&self, id: TransactionId) -> bool {
+        self.tracked.values().any(|tracked| tracked.serial == id.0)
+    }
+
+    /// Undo the most recent committed transaction, returning its name.
+    ///
+    /// Note that edits which haven't landed yet won't be undone. The inverse edits are deferred as one region edit
+    /// (see `ChunkDeltas::defer_set_voxels`), which puts each voxel the transaction changed back how it was before
+    /// the transaction's first change to it.
+    pub fn undo(&mut self, deltas: &ChunkDeltas<V>) -> Option<&str> {
+        let transaction = self.undo.pop_back()?;
+        // later edits are overwritten by earlier ones
+        defer_edits(deltas, transaction.edits.iter().rev().map(|edit| (edit.coord, edit.old)));
+        self.redo.push_back(transaction);
+        self.redo.back().map(|transaction| transaction.name())
+    }
+
+    /// Redo the most recently undone transaction, returning its name. As with `undo`, the edits are deferred as
+    /// one region edit.
+    pub fn redo(&mut self, deltas: &ChunkDeltas<V>) -> Option<&str> {
+        let transaction = self.redo.pop_back()?;
+        defer_edits(deltas, transaction.edits.iter().map(|edit| (edit.coord, edit.new)));
+        self.undo.push_back(transaction);
+        self.undo.back().map(|transaction| transaction.name())
+    }
+
+    /// Take back a transaction's edits and forget it, wherever it is in the history, returning whether it was
+    /// still there to take back. The voxels are put back by edits tagged with `source`, one at a time in reverse,
+    /// and unlike `undo`, each only if nothing's changed it since, so that later edits to the same voxels are kept.
+    ///
+    /// Edits in the transaction that haven't landed yet are taken back as they land, by `revert_late`.
+    pub fn revert(&mut self, id: TransactionId, deltas: &ChunkDeltas<V>, source: DeltaSource) -> bool {
+        if self.is_landing(id) {
+            self.reverting.insert(id.0, source);
+        }
+        let transaction = match self.remove(id.0) {
+            Some(transaction) => transaction,
+            None => return false,
+        };
+        let writer = deltas.writer().source(source);
+        for edit in transaction.edits.iter().rev() {
+            writer.defer_set_if(edit.coord, edit.new, edit.old);
+        }
+        true
+    }
+
+    /// Take back the edits that landed after their transactions were `revert`ed. Called by `VoxelHistorySystem`.
+    pub fn revert_late(&mut self, deltas: &ChunkDeltas<V>) {
+        for (source, edit) in self.late.drain(..) {
+            deltas.writer().source(source).defer_set_if(edit.coord, edit.new, edit.old);
+        }
+    }
+
+    /// Forget a transaction, wherever it is in the history, without taking back its edits; nor will any of them
+    /// that have yet to land be recorded.
+    pub fn forget(&mut self, id: TransactionId) {
+        self.remove(id.0);
+    }
+
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+}
+
+/// Set each voxel in `voxels` in one edit; where a voxel comes up more than once, the last one wins.
+fn defer_edits<V: Voxel, I: Iterator<Item = (VoxelCoord, V)>>(deltas: &ChunkDeltas<V>, voxels: I) {
+    let voxels: FnvHashMap<VoxelCoord, V> = voxels.collect();
+    if !voxels.is_empty() {
+        deltas.defer_set_voxels(voxels);
+    }
+}
+
+/// Feeds applied edits into the `VoxelHistory`, and takes back those of reverted transactions that landed late.
+/// Should run after `ChunkDeltaSystem`.
+pub struct VoxelHistorySystem<V: Voxel> {
+    reader: Option<ReaderId<DeltaResult<V>>>,
+    changes: Option<ReaderId<VoxelChanged<V>>>,
+}
+impl<V: Voxel> VoxelHistorySystem<V> {
+    pub fn new() -> Self {
+        VoxelHistorySystem {
+            reader: None,
+            changes: None,
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for VoxelHistorySystem<V> {
+    type SystemData = (
+        Read<'a, EventChannel<DeltaResult<V>>>,
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        Read<'a, ChunkDeltas<V>>,
+        Write<'a, VoxelHistory<V>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<DeltaResult<V>>>()
+                .register_reader(),
+        );
+        self.changes = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (results, changes, deltas, mut history): Self::SystemData) {
+        // an edit's changes are published before its result, which stops it being tracked
+        for change in changes.read(self.changes.as_mut().unwrap()) {
+            history.record_change(change);
+        }
+        for result in results.read(self.reader.as_mut().unwrap()) {
+            history.record_result(result);
+        }
+        history.revert_late(&deltas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta::DeltaSource;
+    use {voxels_in_box, TestVoxel};
+
+    fn applied(id: DeltaId, coord: VoxelCoord, old: TestVoxel, new: TestVoxel) -> DeltaResult<TestVoxel> {
+        DeltaResult {
+            id,
+            source: DeltaSource::Unknown,
+            coord,
+            outcome: DeltaOutcome::Applied { old, new },
+        }
+    }
+
+    #[test]
+    fn undo_redo() {
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let mut history = VoxelHistory::<TestVoxel>::new();
+        let (a, b) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(1, 0, 0));
+
+        history.begin("build");
+        let id_a = history.set(&deltas, a, TestVoxel::Rock);
+        let id_b = history.set(&deltas, b, TestVoxel::Grass);
+        history.commit();
+        // not tracked; shouldn't be recorded
+        let other = deltas.defer_set(b, TestVoxel::Rock);
+
+        history.record_result(&applied(id_a, a, TestVoxel::Air, TestVoxel::Rock));
+        history.record_result(&applied(id_b, b, TestVoxel::Rock, TestVoxel::Grass));
+        history.record_result(&applied(other, b, TestVoxel::Grass, TestVoxel::Rock));
+
+        assert!(history.can_undo());
+        assert_eq!(history.undo(&deltas), Some("build"));
+        assert_eq!(deltas.pending_get(a), Some(TestVoxel::Air));
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Rock));
+        assert!(!history.can_undo());
+
+        assert_eq!(history.redo(&deltas), Some("build"));
+        assert_eq!(deltas.pending_get(a), Some(TestVoxel::Rock));
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Grass));
+        assert!(!history.can_redo());
+    }
+
+    #[test]
+    fn region_transaction() {
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let mut history = VoxelHistory::<TestVoxel>::new();
+        let coords: Vec<VoxelCoord> = voxels_in_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(9, 9, 9)).collect();
+        let top = VoxelCoord::new(0, 10, 0);
+
+        history.begin("paste castle");
+        let region = deltas.defer_set_voxels_where(&coords, TestVoxel::Rock, |_| true);
+        history.track(region);
+        let flag = history.set(&deltas, top, TestVoxel::Grass);
+        history.commit();
+
+        let change = |id, coord| VoxelChanged {
+            id,
+            source: DeltaSource::Unknown,
+            coord,
+            old: TestVoxel::Air,
+            new: TestVoxel::Rock,
+        };
+        for &coord in &coords {
+            history.record_change(&change(region, coord));
+        }
+        history.record_result(&DeltaResult {
+            id: region,
+            source: DeltaSource::Unknown,
+            coord: coords[0],
+            outcome: DeltaOutcome::AppliedRegion {
+                changed: 1000,
+                missing_chunks: 0,
+            },
+        });
+        // a single voxel's change and result are only recorded once
+        history.record_change(&VoxelChanged {
+            new: TestVoxel::Grass,
+            ..change(flag, top)
+        });
+        history.record_result(&applied(flag, top, TestVoxel::Air, TestVoxel::Grass));
+        // too late; the region edit's done
+        history.record_change(&change(region, top));
+        assert_eq!(history.undo.back().unwrap().edits().len(), 1001);
+        assert_eq!(history.memory_used(), 1001 * mem::size_of::<Edit<TestVoxel>>());
+
+        assert_eq!(history.undo(&deltas), Some("paste castle"));
+        assert!(!history.can_undo());
+        for &coord in &coords {
+            assert_eq!(deltas.pending_get(coord), Some(TestVoxel::Air));
+        }
+        assert_eq!(deltas.pending_get(top), Some(TestVoxel::Air));
+    }
+
+    #[test]
+    fn memory_limit() {
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let size = mem::size_of::<Edit<TestVoxel>>();
+        let mut history = VoxelHistory::<TestVoxel>::with_memory_limit(10 * size);
+        let mut next = 0;
+        let mut transaction = |history: &mut VoxelHistory<TestVoxel>, name: &str, edits: i16| {
+            history.begin(name);
+            for _ in 0..edits {
+                let coord = VoxelCoord::new(next, 0, 0);
+                next += 1;
+                let id = history.set(&deltas, coord, TestVoxel::Rock);
+                history.record_result(&applied(id, coord, TestVoxel::Air, TestVoxel::Rock));
+            }
+            history.commit();
+        };
+        let names = |history: &VoxelHistory<TestVoxel>| -> Vec<String> {
+            history.undo.iter().map(|transaction| transaction.name().to_string()).collect()
+        };
+
+        transaction(&mut history, "a", 4);
+        transaction(&mut history, "b", 4);
+        assert_eq!(names(&history), vec!["a", "b"]);
+        // the oldest goes first
+        transaction(&mut history, "c", 4);
+        assert_eq!(names(&history), vec!["b", "c"]);
+        assert_eq!(history.memory_used(), 8 * size);
+        // the newest stays, however big
+        transaction(&mut history, "d", 20);
+        assert_eq!(names(&history), vec!["d"]);
+        assert_eq!(history.memory_used(), 20 * size);
+
+        history.set_memory_limit(100 * size);
+        assert_eq!(history.undo(&deltas), Some("d"));
+        assert!(history.can_redo());
+        history.set_memory_limit(10 * size);
+        assert!(!history.can_redo());
+        assert_eq!(history.memory_used(), 0);
+    }
+
+    #[test]
+    fn revert() {
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let mut history = VoxelHistory::<TestVoxel>::new();
+        let source = DeltaSource::System("test");
+        let (a, b, c) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(1, 0, 0), VoxelCoord::new(2, 0, 0));
+
+        let first = history.begin("first");
+        let id_a = history.set(&deltas, a, TestVoxel::Rock);
+        let id_b = history.set(&deltas, b, TestVoxel::Rock);
+        history.commit();
+        let second = history.begin("second");
+        let id_c = history.set(&deltas, c, TestVoxel::Grass);
+        history.commit();
+        history.record_result(&applied(id_a, a, TestVoxel::Air, TestVoxel::Rock));
+        history.record_result(&applied(id_c, c, TestVoxel::Air, TestVoxel::Grass));
+        // something else changes `a` again before the first is reverted, so it's left alone
+        deltas.defer_set(a, TestVoxel::Grass);
+
+        // the first goes, and the second's left
+        assert!(history.is_landing(first));
+        assert!(history.revert(first, &deltas, source));
+        assert!(!history.revert(first, &deltas, source));
+        assert_eq!(deltas.pending_get(a), Some(TestVoxel::Grass));
+        assert_eq!(history.undo.len(), 1);
+        assert_eq!(history.memory_used(), mem::size_of::<Edit<TestVoxel>>());
+
+        // and its edit that lands late is taken back then
+        history.record_result(&applied(id_b, b, TestVoxel::Air, TestVoxel::Rock));
+        assert!(!history.is_landing(first));
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Rock));
+        history.revert_late(&deltas);
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Air));
+        assert!(history.reverting.is_empty());
+
+        history.forget(second);
+        assert!(!history.can_undo());
+        assert_eq!(history.memory_used(), 0);
+    }
+}
@@ -0,0 +1,352 @@
+//! Doing a `WorldStore`'s reads and writes on a thread of its own, so that a slow disk never holds up a frame.
+//!
+//! Add a `WorldIo` resource and a `WorldIoSystem`, before anything that uses the `WorldIo`. Requests (`save`,
+//! `load`, ...) are sent off to the thread straight away, and their responses come back in the order they were
+//! made. At the start of each frame the `WorldIoSystem` collects the ones that have finished, and whatever asked
+//! for them takes them with `WorldIo::take` during that frame; responses nobody takes are dropped at the start of
+//! the next (with a warning, if they're errors). `Snapshot`s can be saved and loaded the same way.
+//!
+//! Code that's off the main thread and can wait for the disk, like the generation workers, can get a `WorldIoHandle`
+//! instead, which blocks until the thread's done what it asked. That's how `LoadOrGenerate` loads chunks.
+
+use super::{Chunk, VoxelCoord, VoxelId};
+use persist::{WorldMeta, WorldStore};
+use snapshot::Snapshot;
+
+use parking_lot::Mutex;
+use specs::prelude::*;
+use std::io;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Something for the IO thread to do.
+enum Request<V: VoxelId> {
+    Save(Chunk<V>),
+    Load(VoxelCoord),
+    /// A load for a `WorldIoHandle`, answered straight to it.
+    LoadFor(VoxelCoord, Sender<io::Result<Option<Chunk<V>>>>),
+    SaveMeta(WorldMeta),
+    SaveSnapshot(PathBuf, Snapshot<V>),
+    LoadSnapshot(PathBuf),
+    Flush,
+    Stop,
+}
+
+/// What came of a `WorldIo` request.
+pub enum IoResponse<V: VoxelId> {
+    Saved(VoxelCoord, io::Result<()>),
+    Loaded(VoxelCoord, io::Result<Option<Chunk<V>>>),
+    MetaSaved(io::Result<()>),
+    SnapshotSaved(PathBuf, io::Result<()>),
+    SnapshotLoaded(PathBuf, io::Result<Snapshot<V>>),
+    Flushed(io::Result<()>),
+}
+impl<V: VoxelId> IoResponse<V> {
+    /// The error, if the request failed.
+    pub fn error(&self) -> Option<&io::Error> {
+        match *self {
+            IoResponse::Saved(_, Err(ref e))
+            | IoResponse::Loaded(_, Err(ref e))
+            | IoResponse::MetaSaved(Err(ref e))
+            | IoResponse::SnapshotSaved(_, Err(ref e))
+            | IoResponse::SnapshotLoaded(_, Err(ref e))
+            | IoResponse::Flushed(Err(ref e)) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A resource: a `WorldStore` on a thread of its own; see the module docs.
+pub struct WorldIo<V: VoxelId> {
+    // (in mutexes, since channels can't be shared between threads, and resources have to be)
+    requests: Mutex<Sender<Request<V>>>,
+    responses: Mutex<Receiver<IoResponse<V>>>,
+    directory: PathBuf,
+    thread: Option<JoinHandle<()>>,
+    /// Requests sent that haven't been collected yet.
+    pending: usize,
+    /// Responses collected and not taken yet, and whether they were there at the start of the frame.
+    finished: Vec<(bool, IoResponse<V>)>,
+}
+impl<V: VoxelId> WorldIo<V> {
+    /// Start a thread doing requests with `store`. When this is dropped, it finishes whatever's been asked of it,
+    /// flushes the store and stops; `WorldIoHandle`s are turned away from then on.
+    pub fn start(store: WorldStore<V>) -> Self {
+        let (requests, request_receiver) = mpsc::channel::<Request<V>>();
+        let (response_sender, responses) = mpsc::channel();
+        let directory = store.directory().to_path_buf();
+        let thread = thread::Builder::new()
+            .name("world io".to_string())
+            .spawn(move || {
+                for request in request_receiver {
+                    let response = match request {
+                        Request::Save(chunk) => IoResponse::Saved(chunk.coord, store.save_chunk(&chunk)),
+                        Request::Load(coord) => IoResponse::Loaded(coord, store.load_chunk(coord)),
+                        Request::LoadFor(coord, reply) => {
+                            // (if the handle's stopped waiting, nobody wants it)
+                            let _ = reply.send(store.load_chunk(coord));
+                            continue;
+                        }
+                        Request::SaveMeta(meta) => IoResponse::MetaSaved(store.save_meta(&meta)),
+                        Request::SaveSnapshot(path, snapshot) => {
+                            let saved = snapshot.save(&path);
+                            IoResponse::SnapshotSaved(path, saved)
+                        }
+                        Request::LoadSnapshot(path) => {
+                            let loaded = Snapshot::load(&path);
+                            IoResponse::SnapshotLoaded(path, loaded)
+                        }
+                        Request::Flush => IoResponse::Flushed(store.flush()),
+                        Request::Stop => break,
+                    };
+                    if response_sender.send(response).is_err() {
+                        return;
+                    }
+                }
+                if let Err(e) = store.flush() {
+                    error!("failed to flush {:?} on shutdown: {}", store.directory(), e);
+                }
+            })
+            .expect("failed to start the world io thread");
+        WorldIo {
+            requests: Mutex::new(requests),
+            responses: Mutex::new(responses),
+            directory,
+            thread: Some(thread),
+            pending: 0,
+            finished: Vec::new(),
+        }
+    }
+
+    fn send(&mut self, request: Request<V>) {
+        self.requests
+            .lock()
+            .send(request)
+            .expect("the world io thread died");
+        self.pending += 1;
+    }
+
+    /// Save a copy of `chunk`, replacing any saved copy; answered with `IoResponse::Saved`.
+    pub fn save(&mut self, chunk: &Chunk<V>) {
+        let copy = Chunk {
+            coord: chunk.coord,
+            voxels: chunk.voxels,
+        };
+        self.send(Request::Save(copy));
+    }
+
+    /// Load the chunk at `chunk_coord`, if it's been saved; answered with `IoResponse::Loaded`.
+    pub fn load(&mut self, chunk_coord: VoxelCoord) {
+        self.send(Request::Load(chunk_coord));
+    }
+
+    /// Save the world's metadata; answered with `IoResponse::MetaSaved`.
+    pub fn save_meta(&mut self, meta: &WorldMeta) {
+        self.send(Request::SaveMeta(meta.clone()));
+    }
+
+    /// Save a copy of `snapshot` to the file at `path`, as `Snapshot::save` does; answered with
+    /// `IoResponse::SnapshotSaved`.
+    pub fn save_snapshot<P: AsRef<Path>>(&mut self, path: P, snapshot: &Snapshot<V>) {
+        self.send(Request::SaveSnapshot(path.as_ref().to_path_buf(), snapshot.clone()));
+    }
+
+    /// Load the snapshot saved at `path`, as `Snapshot::load` does; answered with `IoResponse::SnapshotLoaded`.
+    pub fn load_snapshot<P: AsRef<Path>>(&mut self, path: P) {
+        self.send(Request::LoadSnapshot(path.as_ref().to_path_buf()));
+    }
+
+    /// Make sure everything saved so far is on the disk; answered with `IoResponse::Flushed`.
+    pub fn flush(&mut self) {
+        self.send(Request::Flush);
+    }
+
+    /// A handle for loading chunks from another thread; see `WorldIoHandle`.
+    pub fn handle(&self) -> WorldIoHandle<V> {
+        WorldIoHandle {
+            requests: Mutex::new(self.requests.lock().clone()),
+            directory: self.directory.clone(),
+        }
+    }
+
+    /// How many requests haven't been collected by `poll` yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// Collect the responses to requests that have finished. The `WorldIoSystem` does this every frame.
+    pub fn poll(&mut self) {
+        while let Ok(response) = self.responses.lock().try_recv() {
+            self.pending -= 1;
+            self.finished.push((false, response));
+        }
+    }
+
+    /// Block until every request has finished, and collect the responses; e.g. when the game quits.
+    pub fn wait(&mut self) {
+        while self.pending > 0 {
+            let response = self.responses.lock().recv().expect("the world io thread died");
+            self.pending -= 1;
+            self.finished.push((false, response));
+        }
+    }
+
+    /// Take the collected responses that `wanted` picks, in the order they were requested, leaving the rest.
+    pub fn take<F: FnMut(&IoResponse<V>) -> bool>(&mut self, mut wanted: F) -> Vec<IoResponse<V>> {
+        let (taken, left): (Vec<_>, Vec<_>) = self.finished
+            .drain(..)
+            .partition(|&(_, ref response)| wanted(response));
+        self.finished = left;
+        taken.into_iter().map(|(_, response)| response).collect()
+    }
+
+    /// Remove the responses that were already waiting last time this was called, and return them.
+    fn expire(&mut self) -> Vec<IoResponse<V>> {
+        let (expired, left): (Vec<_>, Vec<_>) = self.finished.drain(..).partition(|&(old, _)| old);
+        self.finished = left.into_iter().map(|(_, response)| (true, response)).collect();
+        expired.into_iter().map(|(_, response)| response).collect()
+    }
+}
+impl<V: VoxelId> Drop for WorldIo<V> {
+    fn drop(&mut self) {
+        // (the thread stops once it's done what was asked before; if it's already gone, so be it)
+        let _ = self.requests.lock().send(Request::Stop);
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                error!("the world io thread panicked");
+            }
+        }
+    }
+}
+
+/// Loads chunks through a `WorldIo`'s thread for code that can wait for them, e.g. on the generation workers. Get
+/// one with `WorldIo::handle`.
+pub struct WorldIoHandle<V: VoxelId> {
+    requests: Mutex<Sender<Request<V>>>,
+    directory: PathBuf,
+}
+impl<V: VoxelId> WorldIoHandle<V> {
+    /// Load the chunk at `chunk_coord`, if it's been saved, waiting for the IO thread to get to it. Fails if the
+    /// `WorldIo` has been dropped.
+    pub fn load(&self, chunk_coord: VoxelCoord) -> io::Result<Option<Chunk<V>>> {
+        let stopped = || io::Error::new(io::ErrorKind::Other, "the world io thread has stopped");
+        let (reply, response) = mpsc::channel();
+        self.requests
+            .lock()
+            .send(Request::LoadFor(chunk_coord, reply))
+            .map_err(|_| stopped())?;
+        response.recv().map_err(|_| stopped())?
+    }
+
+    /// The directory of the `WorldStore` the thread uses.
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+impl<V: VoxelId> Clone for WorldIoHandle<V> {
+    fn clone(&self) -> Self {
+        WorldIoHandle {
+            requests: Mutex::new(self.requests.lock().clone()),
+            directory: self.directory.clone(),
+        }
+    }
+}
+
+/// Collects finished `WorldIo` responses at the start of each frame, dropping the ones nobody took since the last.
+#[derive(Default)]
+pub struct WorldIoSystem<V: VoxelId> {
+    _phantom: PhantomData<V>,
+}
+impl<V: VoxelId> WorldIoSystem<V> {
+    pub fn new() -> Self {
+        WorldIoSystem { _phantom: PhantomData }
+    }
+}
+impl<'a, V: VoxelId> System<'a> for WorldIoSystem<V> {
+    type SystemData = WriteExpect<'a, WorldIo<V>>;
+
+    fn run(&mut self, mut io: Self::SystemData) {
+        for response in io.expire() {
+            if let Some(e) = response.error() {
+                warn!("world io error nobody handled: {}", e);
+            }
+        }
+        io.poll();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use {test_directory, TestVoxel};
+
+    #[test]
+    fn requests() {
+        let directory = test_directory("voxel_io_thread_test");
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        let mut io = WorldIo::start(store.clone());
+
+        let mut chunk = Chunk::empty(VoxelCoord::new(16, 0, 0));
+        chunk[VoxelCoord::new(1, 2, 3)] = TestVoxel::Rock;
+        io.save(&chunk);
+        io.flush();
+        io.load(chunk.coord);
+        io.load(VoxelCoord::new(0, 0, 0));
+        io.save_meta(&WorldMeta::new::<TestVoxel>(7, ""));
+        let snapshot_path = directory.join("arena.snapshot");
+        io.save_snapshot(&snapshot_path, &Snapshot::capture_chunks("arena", Some(&chunk)));
+        io.load_snapshot(&snapshot_path);
+        io.wait();
+        assert_eq!(io.pending(), 0);
+
+        let flushed = io.take(|response| match *response {
+            IoResponse::Flushed(_) => true,
+            _ => false,
+        });
+        assert_eq!(flushed.len(), 1);
+        assert!(flushed[0].error().is_none());
+        let responses = io.take(|_| true);
+        assert_eq!(responses.len(), 6);
+        match responses[0] {
+            IoResponse::Saved(coord, Ok(())) => assert_eq!(coord, chunk.coord),
+            _ => panic!("expected the save first"),
+        }
+        match responses[1] {
+            IoResponse::Loaded(_, Ok(Some(ref loaded))) => assert!(loaded.voxels == chunk.voxels),
+            _ => panic!("expected the saved chunk to load"),
+        }
+        match responses[2] {
+            IoResponse::Loaded(_, Ok(None)) => {}
+            _ => panic!("expected nothing saved at the origin"),
+        }
+        match responses[5] {
+            IoResponse::SnapshotLoaded(ref path, Ok(ref snapshot)) => {
+                assert_eq!(path, &snapshot_path);
+                assert_eq!(snapshot.name(), "arena");
+                assert_eq!(snapshot.get(VoxelCoord::new(17, 2, 3)), Some(TestVoxel::Rock));
+            }
+            _ => panic!("expected the saved snapshot to load"),
+        }
+
+        // handles wait for their loads, and are turned away once the thread's stopped
+        let handle = io.handle();
+        assert!(handle.load(chunk.coord).unwrap().unwrap().voxels == chunk.voxels);
+        assert_eq!(io.pending(), 0);
+
+        // responses nobody takes last a frame
+        io.flush();
+        io.wait();
+        assert!(io.expire().is_empty());
+        assert_eq!(io.expire().len(), 1);
+        assert!(io.take(|_| true).is_empty());
+
+        drop(io);
+        assert!(handle.load(chunk.coord).is_err());
+        assert_eq!(store.load_meta().unwrap().unwrap().seed, 7);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
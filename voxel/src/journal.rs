@@ -0,0 +1,475 @@
+//! An append-only on-disk journal of applied voxel edits, for crash recovery.
+//!
+//! Journaling is opt-in: insert a `DeltaJournal` resource and add a `DeltaJournalSystem` after
+//! `ChunkDeltaSystem`. Every changed voxel is appended to the journal and flushed once per frame,
+//! so a crash between saves loses no edits. Take a `mark()` before copying chunks off to be saved; once they're
+//! on the disk, `compact()` drops the entries from before the mark that they hold. On world load, once chunks are
+//! inserted, `replay()` the journal; replayed edits aren't journaled again.
+//!
+//! The format is a short header followed by fixed-size little-endian entries:
+//! `x: i16, y: i16, z: i16, id: u16, source_kind: u8, source_id: u32`.
+//! A partially-written trailing entry is ignored. Version 1 journals, which lack the source fields,
+//! are rewritten in the current format when opened.
+
+use super::{Voxel, VoxelCoord, VoxelId};
+use delta::{ChunkDeltas, DeltaSource, VoxelChanged, want_fill_changes};
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read as IoRead, Write as IoWrite};
+use std::iter;
+use std::path::{Path, PathBuf};
+
+use amethyst::shrev::EventChannel;
+use fnv::FnvHashMap;
+use specs::prelude::*;
+
+const MAGIC: &[u8; 4] = b"MVJL";
+const VERSION: u8 = 2;
+const HEADER_SIZE: usize = 5;
+const ENTRY_SIZE: usize = 13;
+const ENTRY_SIZE_V1: usize = 8;
+
+/// What the journal's replayed edits come from. The `DeltaJournalSystem` doesn't journal them again.
+pub const SOURCE: DeltaSource = DeltaSource::System("journal replay");
+
+/// The source of a journaled edit.
+/// Entities and system names don't mean anything after a restart,
+/// so only the kind of source (and an entity's id) is recorded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum JournalSource {
+    Unknown,
+    Entity(u32),
+    System,
+    Script(u32),
+}
+impl From<DeltaSource> for JournalSource {
+    fn from(source: DeltaSource) -> Self {
+        match source {
+            DeltaSource::Unknown => JournalSource::Unknown,
+            DeltaSource::Entity(entity) => JournalSource::Entity(entity.id()),
+            DeltaSource::System(_) => JournalSource::System,
+            DeltaSource::Script(id) => JournalSource::Script(id),
+        }
+    }
+}
+
+/// A single journaled edit: `coord` was set to the voxel with id `id`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct JournalEntry {
+    pub coord: VoxelCoord,
+    pub id: u16,
+    pub source: JournalSource,
+}
+
+/// A point in the journal: the entries appended before it. See `DeltaJournal::mark`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct JournalMark(u64);
+
+/// A resource wrapping the journal file.
+pub struct DeltaJournal {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    /// When each entry in the file was appended, counting from when the journal was opened; ascending.
+    sequences: Vec<u64>,
+    /// The sequence number of the next entry appended.
+    next: u64,
+}
+impl DeltaJournal {
+    /// Open the journal at `path` for appending, creating it if it doesn't exist. A journal in an older format,
+    /// or with a partially-written entry at the end, is rewritten first, since new entries are only ever appended
+    /// in the current format.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<DeltaJournal> {
+        let path = path.as_ref().to_path_buf();
+        let existing = match fs::metadata(&path) {
+            Ok(metadata) => metadata.len() > 0,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => return Err(e),
+        };
+        let count = if existing {
+            let contents = read_journal(&path)?;
+            if contents.version != VERSION || contents.partial {
+                info!("rewriting journal {:?} (version {})", path, contents.version);
+                rewrite(&path, &contents.entries)?;
+            }
+            contents.entries.len() as u64
+        } else {
+            rewrite(&path, iter::empty())?;
+            0
+        };
+        let file = OpenOptions::new().append(true).open(&path)?;
+        Ok(DeltaJournal {
+            path,
+            writer: BufWriter::new(file),
+            sequences: (0..count).collect(),
+            next: count,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Append an edit. Won't hit the disk until the next `flush()`.
+    pub fn append<V: VoxelId>(
+        &mut self,
+        coord: VoxelCoord,
+        voxel: &V,
+        source: DeltaSource,
+    ) -> io::Result<()> {
+        self.writer.write_all(&encode_entry(JournalEntry {
+            coord,
+            id: voxel.id(),
+            source: source.into(),
+        }))?;
+        self.sequences.push(self.next);
+        self.next += 1;
+        Ok(())
+    }
+
+    /// Everything appended so far. Take one before copying chunks to save them, and pass it to `compact()`
+    /// once they're saved: entries appended after the copy was made aren't in the saved chunks.
+    pub fn mark(&self) -> JournalMark {
+        JournalMark(self.next)
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// Read every entry in the journal, in the order they were applied.
+    pub fn entries(&mut self) -> io::Result<Vec<JournalEntry>> {
+        self.flush()?;
+        read_journal(&self.path).map(|contents| contents.entries)
+    }
+
+    /// Defer every journaled edit through `deltas`, from `SOURCE`. Chunks must already be loaded, or the edits
+    /// will be dropped. Entries with unknown ids are skipped.
+    pub fn replay<V: VoxelId>(&mut self, deltas: &ChunkDeltas<V>) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in self.entries()? {
+            if let Some(voxel) = V::from_id(entry.id) {
+                deltas.writer().source(SOURCE).defer_set(entry.coord, voxel);
+                count += 1;
+            } else {
+                warn!("unknown voxel id {} in journal at {:?}, skipping", entry.id, entry.coord);
+            }
+        }
+        Ok(count)
+    }
+
+    /// Rewrite the journal, dropping entries appended before `mark` for coordinates where `is_saved` returns true
+    /// (i.e. coordinates in chunks that were saved after the mark was taken), and all but the last entry for each
+    /// remaining coordinate.
+    pub fn compact<F: Fn(VoxelCoord) -> bool>(&mut self, mark: JournalMark, is_saved: F) -> io::Result<()> {
+        let entries = self.entries()?;
+
+        let mut last = FnvHashMap::default();
+        for (i, entry) in entries.iter().enumerate() {
+            last.insert(entry.coord, i);
+        }
+        let kept: Vec<usize> = {
+            let sequences = &self.sequences;
+            (0..entries.len())
+                .filter(|&i| {
+                    let coord = entries[i].coord;
+                    last[&coord] == i && !(sequences[i] < mark.0 && is_saved(coord))
+                })
+                .collect()
+        };
+
+        rewrite(&self.path, kept.iter().map(|&i| &entries[i]))?;
+        self.sequences = kept.iter().map(|&i| self.sequences[i]).collect();
+
+        let file = OpenOptions::new().append(true).open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        Ok(())
+    }
+}
+
+fn write_header<W: IoWrite>(writer: &mut W) -> io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])
+}
+
+/// Replace the file at `path` with a journal holding `entries`, through a temporary file, so that a crash
+/// part-way leaves either the old journal or the new one.
+fn rewrite<'a, I: IntoIterator<Item = &'a JournalEntry>>(path: &Path, entries: I) -> io::Result<()> {
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+        write_header(&mut tmp)?;
+        for entry in entries {
+            tmp.write_all(&encode_entry(*entry))?;
+        }
+        tmp.flush()?;
+        tmp.get_ref().sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
+
+/// What's in a journal file.
+struct Contents {
+    version: u8,
+    entries: Vec<JournalEntry>,
+    /// Whether there's a partially-written entry at the end, from crashing mid-write.
+    partial: bool,
+}
+
+fn read_journal(path: &Path) -> io::Result<Contents> {
+    let mut bytes = Vec::new();
+    BufReader::new(File::open(path)?).read_to_end(&mut bytes)?;
+    if bytes.len() < HEADER_SIZE || &bytes[0..4] != MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a voxel journal"));
+    }
+    let version = bytes[4];
+    let entry_size = match version {
+        1 => ENTRY_SIZE_V1,
+        VERSION => ENTRY_SIZE,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported journal version {}", version),
+            ))
+        }
+    };
+    let body = &bytes[HEADER_SIZE..];
+    Ok(Contents {
+        version,
+        // a trailing partial entry is ignored
+        entries: body.chunks(entry_size)
+            .filter(|entry| entry.len() == entry_size)
+            .map(decode_entry)
+            .collect(),
+        partial: body.len() % entry_size != 0,
+    })
+}
+
+fn encode_entry(entry: JournalEntry) -> [u8; ENTRY_SIZE] {
+    let JournalEntry { coord, id, source } = entry;
+    let (kind, source_id) = match source {
+        JournalSource::Unknown => (0, 0),
+        JournalSource::Entity(id) => (1, id),
+        JournalSource::System => (2, 0),
+        JournalSource::Script(id) => (3, id),
+    };
+    let words = [coord.x as u16, coord.y as u16, coord.z as u16, id];
+    let mut result = [0; ENTRY_SIZE];
+    for (i, word) in words.iter().enumerate() {
+        result[i * 2] = *word as u8;
+        result[i * 2 + 1] = (*word >> 8) as u8;
+    }
+    result[8] = kind;
+    for i in 0..4 {
+        result[9 + i] = (source_id >> (i * 8)) as u8;
+    }
+    result
+}
+
+fn decode_entry(bytes: &[u8]) -> JournalEntry {
+    let word = |i: usize| bytes[i * 2] as u16 | (bytes[i * 2 + 1] as u16) << 8;
+    let source = if bytes.len() < ENTRY_SIZE {
+        JournalSource::Unknown
+    } else {
+        let source_id = (0..4).fold(0, |acc, i| acc | (bytes[9 + i] as u32) << (i * 8));
+        match bytes[8] {
+            1 => JournalSource::Entity(source_id),
+            2 => JournalSource::System,
+            3 => JournalSource::Script(source_id),
+            _ => JournalSource::Unknown,
+        }
+    };
+    JournalEntry {
+        coord: VoxelCoord::new(word(0) as i16, word(1) as i16, word(2) as i16),
+        id: word(3),
+        source,
+    }
+}
+
+/// Appends changed voxels to the `DeltaJournal`, except ones replayed from it. Should run after
+/// `ChunkDeltaSystem`.
+pub struct DeltaJournalSystem<V: Voxel> {
+    reader: Option<ReaderId<VoxelChanged<V>>>,
+}
+impl<V: VoxelId> DeltaJournalSystem<V> {
+    pub fn new() -> Self {
+        DeltaJournalSystem { reader: None }
+    }
+}
+impl<'a, V: VoxelId> System<'a> for DeltaJournalSystem<V> {
+    type SystemData = (
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        WriteExpect<'a, DeltaJournal>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (changes, mut journal): Self::SystemData) {
+        let mut written = false;
+        for change in changes.read(self.reader.as_mut().unwrap()) {
+            if change.source == SOURCE {
+                continue;
+            }
+            if let Err(e) = journal.append(change.coord, &change.new, change.source) {
+                error!("failed to journal edit at {:?}: {}", change.coord, e);
+            }
+            written = true;
+        }
+        if written {
+            if let Err(e) = journal.flush() {
+                error!("failed to flush journal {:?}: {}", journal.path(), e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta::ChunkDeltaSystem;
+    use tracker::ChunkTrackerSystem;
+    use {test_directory, Chunk, ChunkTracker, TestVoxel};
+
+    #[test]
+    fn append_replay_compact() {
+        let directory = test_directory("voxel_journal_test");
+        let path = directory.join("edits.journal");
+
+        let (a, b) = (VoxelCoord::new(-1, 2, 300), VoxelCoord::new(17, 0, 0));
+        {
+            let mut journal = DeltaJournal::open(&path).unwrap();
+            journal.append(a, &TestVoxel::Rock, DeltaSource::Unknown).unwrap();
+            journal.append(b, &TestVoxel::Rock, DeltaSource::Script(7)).unwrap();
+            journal
+                .append(a, &TestVoxel::Grass, DeltaSource::System("test"))
+                .unwrap();
+            journal.flush().unwrap();
+        }
+
+        // reopening shouldn't clobber the existing entries
+        let mut journal = DeltaJournal::open(&path).unwrap();
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        assert_eq!(journal.replay(&deltas).unwrap(), 3);
+        assert_eq!(deltas.pending_get(a), Some(TestVoxel::Grass));
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Rock));
+
+        let mark = journal.mark();
+        journal.compact(mark, |coord| coord == b).unwrap();
+        assert_eq!(
+            journal.entries().unwrap(),
+            vec![JournalEntry {
+                coord: a,
+                id: TestVoxel::Grass.id(),
+                source: JournalSource::System,
+            }]
+        );
+
+        // still appendable after compaction; entries from after the mark aren't dropped, even if their chunk's saved
+        journal.append(b, &TestVoxel::Air, DeltaSource::Script(7)).unwrap();
+        journal.compact(mark, |_| true).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!((entries[0].coord, entries[0].source), (b, JournalSource::Script(7)));
+        let mark = journal.mark();
+        journal.compact(mark, |_| true).unwrap();
+        assert_eq!(journal.entries().unwrap(), vec![]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn upgrade() {
+        let directory = test_directory("voxel_journal_upgrade_test");
+        let path = directory.join("edits.journal");
+
+        // a version 1 journal is rewritten before anything's appended to it
+        let a = VoxelCoord::new(-1, 2, 300);
+        let mut bytes = b"MVJL\x01".to_vec();
+        bytes.extend_from_slice(&encode_entry(JournalEntry {
+            coord: a,
+            id: TestVoxel::Rock.id(),
+            source: JournalSource::Unknown,
+        })[..ENTRY_SIZE_V1]);
+        fs::write(&path, &bytes).unwrap();
+        let mut journal = DeltaJournal::open(&path).unwrap();
+        journal.append(a, &TestVoxel::Grass, DeltaSource::Script(7)).unwrap();
+        let entries = journal.entries().unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].id, entries[0].source), (TestVoxel::Rock.id(), JournalSource::Unknown));
+        assert_eq!((entries[1].id, entries[1].source), (TestVoxel::Grass.id(), JournalSource::Script(7)));
+        drop(journal);
+
+        // so is one with a partial entry at the end, so that new entries don't come out misaligned
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[1, 2, 3]).unwrap();
+        drop(file);
+        let mut journal = DeltaJournal::open(&path).unwrap();
+        journal.append(a, &TestVoxel::Air, DeltaSource::Unknown).unwrap();
+        assert_eq!(journal.entries().unwrap().len(), 3);
+        assert_eq!(
+            fs::metadata(&path).unwrap().len() as usize,
+            HEADER_SIZE + 3 * ENTRY_SIZE
+        );
+
+        // but anything else is an error
+        fs::write(&path, b"MVJL\x09").unwrap();
+        assert_eq!(
+            DeltaJournal::open(&path).err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn replay_isnt_journaled() {
+        let directory = test_directory("voxel_journal_replay_test");
+        let (a, b) = (VoxelCoord::new(3, 4, 5), VoxelCoord::new(6, 7, 8));
+
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<TestVoxel>::new());
+        let mut journal = DeltaJournal::open(directory.join("edits.journal")).unwrap();
+        journal.append(a, &TestVoxel::Rock, DeltaSource::Unknown).unwrap();
+        world.add_resource(journal);
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(ChunkDeltaSystem::<TestVoxel>::new(), "chunk_deltas", &["chunk_tracker"])
+            .with(DeltaJournalSystem::<TestVoxel>::new(), "journal", &["chunk_deltas"])
+            .build();
+        dispatcher.setup(&mut world.res);
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+        world.maintain();
+
+        let replayed = world
+            .write_resource::<DeltaJournal>()
+            .replay(&world.read_resource::<ChunkDeltas<TestVoxel>>())
+            .unwrap();
+        assert_eq!(replayed, 1);
+        world.read_resource::<ChunkDeltas<TestVoxel>>().defer_set(b, TestVoxel::Grass);
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunk = tracker.get_chunk(&chunks, a).unwrap();
+        assert_eq!((chunk[a], chunk[b]), (TestVoxel::Rock, TestVoxel::Grass));
+        let entries = world.write_resource::<DeltaJournal>().entries().unwrap();
+        assert_eq!(entries.iter().map(|entry| entry.coord).collect::<Vec<_>>(), vec![a, b]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -21,14 +21,27 @@
 //!   and the next chunk in the x direction is at CHUNK_SIZE_WORLD,0,0, and so on.
 
 extern crate amethyst;
+#[cfg(feature = "network")]
+extern crate amethyst_network;
 extern crate cgmath;
 #[macro_use]
 extern crate log;
 extern crate fnv;
 extern crate hibitset;
+#[cfg(feature = "lz4")]
+extern crate lz4;
 extern crate parking_lot;
+#[cfg(feature = "serialize")]
+extern crate serde;
+#[cfg(feature = "serialize")]
+#[macro_use]
+extern crate serde_derive;
+#[cfg(all(test, feature = "serialize"))]
+extern crate serde_json;
 extern crate soft_time_limit;
 extern crate specs;
+#[cfg(feature = "zstd")]
+extern crate zstd;
 
 use std::fmt::Debug;
 use std::ops::{Index, IndexMut};
@@ -37,12 +50,41 @@ use amethyst::renderer::{Color, Separate};
 use specs::HashMapStorage;
 use specs::prelude::*;
 
+pub mod asset;
+pub mod autosave;
+pub mod biome;
+pub mod budget;
+mod bytes;
+pub mod collision;
 pub mod delta;
+pub mod edit;
+pub mod explosion;
+pub mod falling;
+pub mod frustum;
+pub mod generate;
+pub mod heightmap;
+pub mod history;
+pub mod io_thread;
+pub mod journal;
+pub mod light;
 pub mod mesh;
+pub mod net;
+#[cfg(feature = "network")]
+pub mod network;
+pub mod patterns;
+pub mod persist;
+pub mod physics;
+pub mod pick;
+pub mod pipeline;
 pub mod raycast;
+#[cfg(feature = "serialize")]
+mod serial;
+pub mod snapshot;
+pub mod structure;
 pub mod tracker;
+pub mod vox;
 
-pub use tracker::ChunkTracker;
+pub use tracker::{ChunkAccess, ChunkTracker};
 
 // TODO: chunk insertion
 // need to mark adjacent chunks for re-meshing, as well
@@ -66,12 +108,96 @@ pub fn canonicalize(coord: Coord) -> VoxelCoord {
 /// Round to the canonical coordinate of the containing chunk, i.e. the center of the chunks [0,0,0] voxel
 #[inline(always)]
 pub fn canonicalize_chunk(coord: VoxelCoord) -> VoxelCoord {
-    let coord = VoxelCoord {
-        x: coord.x as i16,
-        y: coord.y as i16,
-        z: coord.z as i16,
-    };
-    coord - (coord % (CHUNK_SIZE as i16))
+    // round down rather than towards zero, so that negative coordinates land in the right chunk
+    let size = CHUNK_SIZE as i16;
+    let floor = |c: i16| c - ((c % size) + size) % size;
+    VoxelCoord {
+        x: floor(coord.x),
+        y: floor(coord.y),
+        z: floor(coord.z),
+    }
+}
+
+/// Iterate over the voxel coordinates in the box from `min` to `max`, inclusive.
+pub fn voxels_in_box(min: VoxelCoord, max: VoxelCoord) -> impl Iterator<Item = VoxelCoord> {
+    (min.x..=max.x).flat_map(move |x| {
+        (min.y..=max.y).flat_map(move |y| (min.z..=max.z).map(move |z| VoxelCoord::new(x, y, z)))
+    })
+}
+
+/// Iterate over the canonical coordinates of the chunks overlapping the box from `min` to `max`, inclusive.
+pub fn chunks_in_box(min: VoxelCoord, max: VoxelCoord) -> impl Iterator<Item = VoxelCoord> {
+    let size = CHUNK_SIZE as i16;
+    let (min, max) = (canonicalize_chunk(min) / size, canonicalize_chunk(max) / size);
+    voxels_in_box(min, max).map(move |chunk| chunk * size)
+}
+
+/// An axis-aligned box in world coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Coord,
+    pub max: Coord,
+}
+impl Aabb {
+    pub fn new(min: Coord, max: Coord) -> Self {
+        assert!(min.x <= max.x && min.y <= max.y && min.z <= max.z, "inverted aabb");
+        Aabb { min, max }
+    }
+    /// A box with the given center and half-extents.
+    pub fn from_center(center: Coord, half_extents: Coord) -> Self {
+        Aabb::new(center - half_extents, center + half_extents)
+    }
+    pub fn center(&self) -> Coord {
+        (self.min + self.max) / 2.0
+    }
+    pub fn half_extents(&self) -> Coord {
+        (self.max - self.min) / 2.0
+    }
+    /// This box moved by `offset`.
+    pub fn translate(&self, offset: Coord) -> Self {
+        Aabb {
+            min: self.min + offset,
+            max: self.max + offset,
+        }
+    }
+    /// Whether the boxes overlap by more than just touching.
+    pub fn overlaps(&self, other: &Aabb) -> bool {
+        (0..3).all(|axis| self.min[axis] < other.max[axis] && other.min[axis] < self.max[axis])
+    }
+    /// The smallest box containing both boxes.
+    pub fn union(&self, other: &Aabb) -> Self {
+        Aabb {
+            min: Coord::new(
+                self.min.x.min(other.min.x),
+                self.min.y.min(other.min.y),
+                self.min.z.min(other.min.z),
+            ),
+            max: Coord::new(
+                self.max.x.max(other.max.x),
+                self.max.y.max(other.max.y),
+                self.max.z.max(other.max.z),
+            ),
+        }
+    }
+    /// The point in this box closest to `point`.
+    pub fn closest_point(&self, point: Coord) -> Coord {
+        Coord::new(
+            point.x.max(self.min.x).min(self.max.x),
+            point.y.max(self.min.y).min(self.max.y),
+            point.z.max(self.min.z).min(self.max.z),
+        )
+    }
+    /// The (inclusive) range of voxels this box overlaps.
+    /// Voxels the box only touches the surface of are not included.
+    pub fn voxels(&self) -> (VoxelCoord, VoxelCoord) {
+        // voxel v spans [v - .5, v + .5]
+        let lo = |c: f32| (c + 0.5).floor() as i16;
+        let hi = |c: f32| (c + 0.5).ceil() as i16 - 1;
+        (
+            VoxelCoord::new(lo(self.min.x), lo(self.min.y), lo(self.min.z)),
+            VoxelCoord::new(hi(self.max.x), hi(self.max.y), hi(self.max.z)),
+        )
+    }
 }
 
 /// Chunks are CHUNK_SIZE by CHUNK_SIZE by CHUNK_SIZE voxels.
@@ -83,10 +209,73 @@ pub const CHUNK_SIZE_WORLD: f32 = CHUNK_SIZE as f32;
 /// they should be their own entities.
 /// Try and keep your voxels as small as possible to reduce memory usage; ideally they'd be 1 byte in size.
 /// Default should return an empty voxel.
-pub trait Voxel: Copy + Debug + Default + Send + Sync + 'static {
+/// PartialEq is used to check edits against the current voxel (see `delta`).
+pub trait Voxel: Copy + Debug + Default + PartialEq + Send + Sync + 'static {
     fn is_transparent(&self) -> bool;
     /// TODO switch to textures & meshes
     fn color(&self) -> [f32; 4];
+    /// The color of this voxel in a biome with the given tint (see `biome::Biome::tint`). Most voxels look the
+    /// same everywhere; ones like grass and leaves can multiply their color by the tint.
+    fn tinted_color(&self, _tint: [f32; 3]) -> [f32; 4] {
+        self.color()
+    }
+    /// The red, green and blue levels (from 0 to `light::MAX_LIGHT`) of the light this voxel gives off;
+    /// e.g. `[15, 8, 2]` for lava. Most voxels don't give off any.
+    fn emitted_light(&self) -> [u8; 3] {
+        [0, 0, 0]
+    }
+    /// How much light this voxel soaks up, from 0 to `light::MAX_LIGHT`: light loses this many levels
+    /// getting into it, on top of the one it loses every step. By default transparent voxels let light
+    /// through, and everything else blocks it; something like 2 or 3 suits leaves and water, which are
+    /// drawn but shouldn't leave everything under them in the dark.
+    fn opacity(&self) -> u8 {
+        if self.is_transparent() {
+            0
+        } else {
+            light::MAX_LIGHT
+        }
+    }
+    /// What this voxel blocks movement with (see `collision`). By default transparent voxels don't block anything,
+    /// and everything else is a full cube; slabs, fences and the like should say what they are, or things will bump
+    /// into the empty parts of their voxels.
+    fn collision_shape(&self) -> collision::CollisionShape {
+        if self.is_transparent() {
+            collision::CollisionShape::Empty
+        } else {
+            collision::CollisionShape::Cube
+        }
+    }
+    /// Whether this voxel falls when there's nothing under it, like sand or gravel (see `falling`).
+    fn falls(&self) -> bool {
+        false
+    }
+    /// How much of an explosion's strength it takes to break this voxel, and that it soaks up when it does (see
+    /// `explosion`). By default transparent voxels don't resist at all, and everything else resists 1; make it
+    /// `f32::INFINITY` for voxels that can't be blown up.
+    fn blast_resistance(&self) -> f32 {
+        if self.is_transparent() {
+            0.0
+        } else {
+            1.0
+        }
+    }
+    /// Whether this voxel is a fluid that things can be in, like water or lava (see `collision::submersion`).
+    /// Fluids usually have an empty `collision_shape`, so that things can get into them.
+    fn is_fluid(&self) -> bool {
+        false
+    }
+}
+
+/// A voxel with a stable numeric id, so that it can be written to disk or sent over the network.
+/// Ids must not change between builds of your game; `from_id` should return None for unknown ids.
+pub trait VoxelId: Voxel {
+    fn id(&self) -> u16;
+    fn from_id(id: u16) -> Option<Self>;
+    /// A name for the voxel that doesn't change between builds, even if its id does. Encoded chunks name their
+    /// voxels (see `persist`), so they mean the same thing to builds that number them differently.
+    fn name(&self) -> &'static str;
+    /// The voxel with the given `name`, or None if there isn't one.
+    fn from_name(name: &str) -> Option<Self>;
 }
 
 /// A "voxel chunk" component.
@@ -109,6 +298,16 @@ impl<V: Voxel> Chunk<V> {
             .get_unchecked(index.y as usize)
             .get_unchecked(index.z as usize)
     }
+    /// Set every voxel in the box from `min` to `max` (inclusive, in chunk-local coordinates) to `voxel`.
+    pub fn fill_box(&mut self, min: VoxelCoord, max: VoxelCoord, voxel: V) {
+        for x in min.x..=max.x {
+            for y in min.y..=max.y {
+                for v in &mut self.voxels[x as usize][y as usize][min.z as usize..=max.z as usize] {
+                    *v = voxel;
+                }
+            }
+        }
+    }
 }
 impl<V: Voxel> Index<VoxelCoord> for Chunk<V> {
     type Output = V;
@@ -129,7 +328,21 @@ impl<V: Voxel> Component for Chunk<V> {
     type Storage = FlaggedStorage<Self, HashMapStorage<Self>>;
 }
 
+/// A new, empty directory for a test's files, named after `name` but unique to this run, so tests running at the
+/// same time (or in another checkout) can't trip over each other's files.
+#[cfg(test)]
+pub fn test_directory(name: &str) -> ::std::path::PathBuf {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let unique = format!("{}_{}_{}", name, ::std::process::id(), COUNT.fetch_add(1, Ordering::SeqCst));
+    let directory = ::std::env::temp_dir().join(unique);
+    let _ = ::std::fs::remove_dir_all(&directory);
+    ::std::fs::create_dir_all(&directory).unwrap();
+    directory
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serialize", derive(Serialize, Deserialize))]
 pub enum TestVoxel {
     Air,
     Rock,
@@ -151,6 +364,41 @@ impl Voxel for TestVoxel {
             TestVoxel::Grass => [0., 8., 0., 1.],
         }
     }
+    fn tinted_color(&self, tint: [f32; 3]) -> [f32; 4] {
+        let color = self.color();
+        match *self {
+            TestVoxel::Grass => [color[0] * tint[0], color[1] * tint[1], color[2] * tint[2], color[3]],
+            _ => color,
+        }
+    }
+}
+impl VoxelId for TestVoxel {
+    fn id(&self) -> u16 {
+        *self as u16
+    }
+    fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(TestVoxel::Air),
+            1 => Some(TestVoxel::Rock),
+            2 => Some(TestVoxel::Grass),
+            _ => None,
+        }
+    }
+    fn name(&self) -> &'static str {
+        match *self {
+            TestVoxel::Air => "air",
+            TestVoxel::Rock => "rock",
+            TestVoxel::Grass => "grass",
+        }
+    }
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "air" => Some(TestVoxel::Air),
+            "rock" => Some(TestVoxel::Rock),
+            "grass" => Some(TestVoxel::Grass),
+            _ => None,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -161,4 +409,31 @@ mod tests {
     fn sizes() {
         assert!(CHUNK_SIZE < 256);
     }
+
+    #[test]
+    fn chunk_coords() {
+        let size = CHUNK_SIZE as i16;
+        assert_eq!(canonicalize_chunk(VoxelCoord::new(0, 1, size - 1)), VoxelCoord::new(0, 0, 0));
+        assert_eq!(
+            canonicalize_chunk(VoxelCoord::new(-1, size, -size)),
+            VoxelCoord::new(-size, size, -size)
+        );
+        let chunks: Vec<_> = chunks_in_box(VoxelCoord::new(-1, 0, 0), VoxelCoord::new(size, 0, 0)).collect();
+        assert_eq!(
+            chunks,
+            vec![
+                VoxelCoord::new(-size, 0, 0),
+                VoxelCoord::new(0, 0, 0),
+                VoxelCoord::new(size, 0, 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn aabb_voxels() {
+        // touching faces don't count
+        let aabb = Aabb::new(Coord::new(-0.5, 0.0, 0.25), Coord::new(0.5, 1.5, 0.75));
+        assert_eq!(aabb.voxels(), (VoxelCoord::new(0, 0, 0), VoxelCoord::new(0, 1, 1)));
+        assert_eq!(aabb.center(), Coord::new(0.0, 0.75, 0.5));
+    }
 }
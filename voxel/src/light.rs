@@ -0,0 +1,1042 @@
+//! Voxel lighting.
+//!
+//! There are two kinds of light. Sky light pours straight down each column until it hits a voxel that isn't
+//! completely clear, so everything above a column's height (see `LightMap::height`) is fully lit; from there
+//! it spreads into overhangs and caves, one level dimmer per voxel, and dimmer still through voxels like leaves
+//! and water that only let some light through (see `Voxel::opacity`). Block light comes from emissive voxels (see
+//! `Voxel::emitted_light`), and spreads the same way, separately in each of its red, green and blue channels,
+//! so e.g. lava glows orange. Light levels go from 0 (dark) to `MAX_LIGHT`.
+//!
+//! Each lit chunk's light is in a `ChunkLight` component next to it, and `LightingSystem` keeps it up to date
+//! as chunks are loaded and edited, relighting only around the voxels that changed; `LightMap` holds what it
+//! needs to do that. Small static scenes can have softer light baked with raycasts instead; see
+//! `LightMap::bake`. The mesher shades each face by the light of the voxel in front of it.
+
+use super::{
+    canonicalize_chunk, chunks_in_box, voxels_in_box, Chunk, ChunkAccess, ChunkTracker, Voxel, VoxelCoord, CHUNK_SIZE,
+};
+
+use budget::Headroom;
+use delta::{VoxelChanged, want_fill_changes};
+use raycast::{Hemisphere, RayAction};
+
+use amethyst::core::timing::Time;
+use amethyst::shrev::EventChannel;
+use cgmath::InnerSpace;
+use fnv::{FnvHashMap, FnvHashSet};
+use hibitset::BitSetLike;
+use soft_time_limit::TimeLimiter;
+use specs::prelude::*;
+use specs::HashMapStorage;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::BuildHasher;
+use std::i16;
+use std::io;
+use std::time::Duration;
+
+/// The brightest light level, e.g. of voxels in direct sunlight.
+pub const MAX_LIGHT: u8 = 15;
+
+/// The size of a `ChunkLight` as bytes; see `ChunkLight::to_bytes`.
+const LIGHT_BYTES: usize = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE * 2;
+
+const NEIGHBORS: [VoxelCoord; 6] = [
+    VoxelCoord { x: 1, y: 0, z: 0 },
+    VoxelCoord { x: -1, y: 0, z: 0 },
+    VoxelCoord { x: 0, y: 1, z: 0 },
+    VoxelCoord { x: 0, y: -1, z: 0 },
+    VoxelCoord { x: 0, y: 0, z: 1 },
+    VoxelCoord { x: 0, y: 0, z: -1 },
+];
+
+/// One kind of light, propagated separately from the others.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Channel {
+    Sky,
+    Red,
+    Green,
+    Blue,
+}
+impl Channel {
+    pub fn all() -> [Channel; 4] {
+        use self::Channel::*;
+        [Sky, Red, Green, Blue]
+    }
+
+    /// Which nibble of a voxel's packed light this channel's level is in; see `ChunkLight`.
+    pub fn index(self) -> usize {
+        self as usize
+    }
+}
+
+/// How bright to draw a face lit at `level`, from 0 to 1.
+pub fn brightness(level: u8) -> f32 {
+    // each level is 80% as bright as the one above it
+    0.8f32.powi(i32::from(MAX_LIGHT - level.min(MAX_LIGHT)))
+}
+
+/// The light on a face, as brightnesses from 0 to 1: its sky light, and its red, green and blue block light.
+/// These are kept apart so that faces can be re-tinted when the sky changes; see `SkyLightState`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FaceLight {
+    pub sky: f32,
+    pub block: [f32; 3],
+}
+impl FaceLight {
+    /// In full sky light, e.g. for faces we have no light for.
+    pub fn full() -> Self {
+        FaceLight {
+            sky: 1.0,
+            block: [0.0; 3],
+        }
+    }
+
+    /// How much to multiply each of the red, green and blue channels of the face's color by, under `sky`.
+    /// Each channel is lit by whichever of sky and block light is brighter in it.
+    pub fn tint(&self, sky: &SkyLightState) -> [f32; 3] {
+        let sky = sky.tint();
+        let channel = |i: usize| (self.sky * sky[i]).max(self.block[i]);
+        [channel(0), channel(1), channel(2)]
+    }
+}
+
+/// The light on a face looking into the voxel at `local`, in a chunk with light `light`.
+pub fn shade(light: &ChunkLight, local: VoxelCoord) -> FaceLight {
+    let level = |channel: Channel| light.get(local, channel);
+    FaceLight {
+        sky: brightness(level(Channel::Sky)),
+        block: [
+            brightness(level(Channel::Red)),
+            brightness(level(Channel::Green)),
+            brightness(level(Channel::Blue)),
+        ],
+    }
+}
+
+/// The light of every voxel in a chunk, in every `Channel`: a nibble each, packed into a `u16` per voxel
+/// (sky light in the lowest nibble, then red, green and blue), indexed like `Chunk::voxels`.
+///
+/// This is a component on the chunk's entity; the `LightingSystem` adds and updates it, and the
+/// `ChunkTrackerSystem` removes it along with the chunk. Save it with the chunk (see `to_bytes`) and put it
+/// back on when the chunk is loaded, and the chunk won't have to be relit.
+#[derive(Clone, PartialEq, Eq)]
+pub struct ChunkLight {
+    levels: Box<[[[u16; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE]>,
+}
+impl ChunkLight {
+    /// Completely dark.
+    pub fn dark() -> Self {
+        ChunkLight {
+            levels: Box::new([[[0; CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE]),
+        }
+    }
+
+    /// The light at `local` (in chunk-local coordinates) in `channel`.
+    #[inline]
+    pub fn get(&self, local: VoxelCoord, channel: Channel) -> u8 {
+        let packed = self.levels[local.x as usize][local.y as usize][local.z as usize];
+        (packed >> (channel.index() * 4)) as u8 & 0xf
+    }
+
+    #[inline]
+    pub fn set(&mut self, local: VoxelCoord, channel: Channel, level: u8) {
+        debug_assert!(level <= MAX_LIGHT);
+        let shift = channel.index() * 4;
+        let packed = &mut self.levels[local.x as usize][local.y as usize][local.z as usize];
+        *packed = *packed & !(0xf << shift) | u16::from(level) << shift;
+    }
+
+    /// This light as bytes, e.g. to save with its chunk: each voxel's packed levels, little-endian,
+    /// in the same order as `Chunk::voxels`.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(LIGHT_BYTES);
+        for plane in self.levels.iter() {
+            for row in plane.iter() {
+                for &packed in row.iter() {
+                    bytes.push(packed as u8);
+                    bytes.push((packed >> 8) as u8);
+                }
+            }
+        }
+        bytes
+    }
+
+    /// Light saved with `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<ChunkLight> {
+        if bytes.len() != LIGHT_BYTES {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "wrong size for chunk light"));
+        }
+        let mut light = ChunkLight::dark();
+        let mut words = bytes.chunks(2).map(|word| word[0] as u16 | (word[1] as u16) << 8);
+        for plane in light.levels.iter_mut() {
+            for row in plane.iter_mut() {
+                for packed in row.iter_mut() {
+                    *packed = words.next().unwrap();
+                }
+            }
+        }
+        Ok(light)
+    }
+}
+impl fmt::Debug for ChunkLight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ChunkLight {{ .. }}")
+    }
+}
+impl Component for ChunkLight {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Anything the light of chunks can be looked up in by coordinate, like `ChunkAccess` for chunks:
+/// the ECS (see `ChunkTracker::light`), a plain `HashMap` keyed by canonical chunk coordinates in tests, ...
+pub trait LightAccess {
+    /// The light of the chunk containing `coord`, if it's lit.
+    fn get_light(&self, coord: VoxelCoord) -> Option<&ChunkLight>;
+
+    fn get_light_mut(&mut self, coord: VoxelCoord) -> Option<&mut ChunkLight>;
+
+    /// Start lighting the chunk containing `coord`, completely dark, unless it's lit already.
+    /// Returns false if it can't be lit, e.g. because there's no chunk there.
+    fn insert_light(&mut self, coord: VoxelCoord) -> bool;
+
+    /// The light at `coord` in `channel`, or None if its chunk isn't lit.
+    fn level(&self, coord: VoxelCoord, channel: Channel) -> Option<u8> {
+        let chunk = canonicalize_chunk(coord);
+        self.get_light(chunk).map(|light| light.get(coord - chunk, channel))
+    }
+
+    /// The sky light at `coord`, or None if its chunk isn't lit.
+    fn sky(&self, coord: VoxelCoord) -> Option<u8> {
+        self.level(coord, Channel::Sky)
+    }
+
+    /// The red, green and blue block light at `coord`, or None if its chunk isn't lit.
+    fn block(&self, coord: VoxelCoord) -> Option<[u8; 3]> {
+        Some([
+            self.level(coord, Channel::Red)?,
+            self.level(coord, Channel::Green)?,
+            self.level(coord, Channel::Blue)?,
+        ])
+    }
+}
+
+/// Light keyed by canonical chunk coordinates.
+impl<S: BuildHasher> LightAccess for HashMap<VoxelCoord, ChunkLight, S> {
+    fn get_light(&self, coord: VoxelCoord) -> Option<&ChunkLight> {
+        self.get(&canonicalize_chunk(coord))
+    }
+
+    fn get_light_mut(&mut self, coord: VoxelCoord) -> Option<&mut ChunkLight> {
+        self.get_mut(&canonicalize_chunk(coord))
+    }
+
+    fn insert_light(&mut self, coord: VoxelCoord) -> bool {
+        self.entry(canonicalize_chunk(coord)).or_insert_with(ChunkLight::dark);
+        true
+    }
+}
+
+/// How bright sky light is, and what color, e.g. over a day; see `DayNightSystem`.
+///
+/// Sky light levels don't change with this; instead the mesher re-tints its meshes when it changes
+/// noticeably (see `ChunkMesherSystem`), which is much cheaper than re-meshing or relighting.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SkyLightState {
+    pub intensity: f32,
+    pub color: [f32; 3],
+}
+impl SkyLightState {
+    /// The sky at `time` of day, from 0 (midnight) through 0.5 (noon) to 1 (midnight again):
+    /// white around noon, warmer around sunrise and sunset, and a dim blue at night.
+    pub fn at_time_of_day(time: f32) -> Self {
+        const NIGHT: [f32; 3] = [0.5, 0.6, 1.0];
+        const SUNSET: [f32; 3] = [1.0, 0.6, 0.35];
+        let time = time - time.floor();
+        // how high the sun is, from -1 at midnight to 1 at noon
+        let sun = -(time * 2.0 * ::std::f32::consts::PI).cos();
+        let day = (sun * 3.0 + 0.5).max(0.0).min(1.0);
+        let sunset = (1.0 - sun.abs() * 4.0).max(0.0);
+
+        let channel = |i: usize| {
+            let daylight = 1.0 + (SUNSET[i] - 1.0) * sunset;
+            NIGHT[i] + (daylight - NIGHT[i]) * day
+        };
+        SkyLightState {
+            intensity: 0.15 + 0.85 * day,
+            color: [channel(0), channel(1), channel(2)],
+        }
+    }
+
+    /// What to multiply the red, green and blue channels of something in full sky light by.
+    pub fn tint(&self) -> [f32; 3] {
+        [
+            self.color[0] * self.intensity,
+            self.color[1] * self.intensity,
+            self.color[2] * self.intensity,
+        ]
+    }
+
+    /// The biggest difference between the tints of two skies, in any channel.
+    pub fn difference(&self, other: &SkyLightState) -> f32 {
+        let (a, b) = (self.tint(), other.tint());
+        (0..3).map(|i| (a[i] - b[i]).abs()).fold(0.0, f32::max)
+    }
+}
+impl Default for SkyLightState {
+    /// Noon.
+    fn default() -> Self {
+        SkyLightState {
+            intensity: 1.0,
+            color: [1.0; 3],
+        }
+    }
+}
+
+/// The heightmap used to work out sky light, and which chunks' light has changed. The light itself is in
+/// a `LightAccess`, usually the chunks' `ChunkLight`s.
+///
+/// Only voxels in lit chunks (see `light_chunk`) get light, and light only spreads through them.
+#[derive(Debug, Default)]
+pub struct LightMap {
+    /// The y coordinate of the highest voxel that blocks or dims light in each (x, z) column.
+    heights: FnvHashMap<(i16, i16), i16>,
+    /// The chunks with faces whose light has changed since the last `take_changed`.
+    changed: FnvHashSet<VoxelCoord>,
+}
+impl LightMap {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The y coordinate of the highest voxel that blocks or dims light (see `Voxel::opacity`) in the column
+    /// at `(x, z)` that we've seen, or None if the whole column is open to the sky.
+    ///
+    /// This isn't lowered when chunks are unloaded, so columns under unloaded chunks stay in shadow.
+    pub fn height(&self, x: i16, z: i16) -> Option<i16> {
+        self.heights.get(&(x, z)).cloned()
+    }
+
+    /// Light the chunk containing `coord`, or relight it from scratch if it's already lit;
+    /// light from neighboring lit chunks spreads into it, and its light into them.
+    /// Does nothing if the chunk isn't loaded.
+    pub fn light_chunk<V, C, L>(&mut self, chunks: &C, light: &mut L, coord: VoxelCoord)
+    where
+        V: Voxel,
+        C: ChunkAccess<V>,
+        L: LightAccess,
+    {
+        let coord = canonicalize_chunk(coord);
+        if chunks.get_chunk(coord).is_none() || !light.insert_light(coord) {
+            return;
+        }
+        let size = CHUNK_SIZE as i16 - 1;
+        let voxels: Vec<VoxelCoord> = voxels_in_box(coord, coord + VoxelCoord::new(size, size, size)).collect();
+        self.relight(chunks, light, &voxels);
+    }
+
+    /// Take in the chunk containing `coord`, loaded with the light it was saved with, instead of lighting it:
+    /// this only adds its columns to the heightmap. Its light is trusted to still be right, i.e. for the
+    /// chunks around it to be as they were when it was saved.
+    pub fn restore_chunk<V: Voxel, C: ChunkAccess<V>>(&mut self, chunks: &C, coord: VoxelCoord) {
+        let chunk = match chunks.get_chunk(coord) {
+            Some(chunk) => chunk,
+            None => return,
+        };
+        for (x, plane) in chunk.voxels.iter().enumerate() {
+            for z in 0..CHUNK_SIZE {
+                if let Some(y) = plane.iter().rposition(|row| row[z].opacity() > 0) {
+                    let top = chunk.coord + VoxelCoord::new(x as i16, y as i16, z as i16);
+                    if self.height(top.x, top.z).map_or(true, |height| top.y > height) {
+                        self.heights.insert((top.x, top.z), top.y);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Bake light into every voxel that lets light through in the box from `min` to `max` (inclusive) in
+    /// loaded chunks, instead of propagating it; for small static scenes, like dioramas, where soft shadows
+    /// are worth the time.
+    ///
+    /// A voxel's sky light is how much of the sky the faces it's in front of see, sampled with `hemisphere`:
+    /// rays stop at voxels that block or dim light, and see sky if they get out of range. Its block light
+    /// is the light of the brightest emissive voxel the rays hit, dimmed by a level per voxel of distance.
+    /// Voxels in front of no faces look up.
+    ///
+    /// This casts up to six rays per sample for each voxel. Bake before the `LightingSystem` sees the chunks,
+    /// and it'll keep the baked light (see `restore_chunk`); edits are relit by propagation as usual,
+    /// overwriting the baked light around them.
+    pub fn bake<V, C, L>(
+        &mut self,
+        chunks: &C,
+        light: &mut L,
+        min: VoxelCoord,
+        max: VoxelCoord,
+        hemisphere: &Hemisphere,
+    ) where
+        V: Voxel,
+        C: ChunkAccess<V>,
+        L: LightAccess,
+    {
+        for chunk in chunks_in_box(min, max) {
+            if chunks.get_chunk(chunk).is_some() && light.insert_light(chunk) {
+                self.restore_chunk(chunks, chunk);
+            }
+        }
+        let up = VoxelCoord::new(0, 1, 0);
+        for coord in voxels_in_box(min, max) {
+            let voxel = match chunks.get_voxel(coord) {
+                Some(voxel) if voxel.opacity() < MAX_LIGHT => voxel,
+                _ => continue,
+            };
+            // (the voxel, and normal, of each face this one is in front of)
+            let mut faces: Vec<(VoxelCoord, VoxelCoord)> = NEIGHBORS
+                .iter()
+                .filter(|&&offset| chunks.get_voxel(coord + offset).map_or(false, |next| !next.is_transparent()))
+                .map(|&offset| (coord + offset, -offset))
+                .collect();
+            if faces.is_empty() {
+                faces.push((coord - up, up));
+            }
+
+            let mut sky = 0.0;
+            let mut block = voxel.emitted_light();
+            for &(face, normal) in &faces {
+                sky += hemisphere.exposure(face, normal, chunks, |hit, hit_voxel| {
+                    if hit == coord {
+                        return RayAction::Continue;
+                    }
+                    let emitted = hit_voxel.emitted_light();
+                    if emitted != [0, 0, 0] {
+                        let distance: f32 = (hit - coord).cast::<f32>().unwrap().magnitude();
+                        let distance = distance.round().min(f32::from(MAX_LIGHT)) as u8;
+                        for (level, &emitted) in block.iter_mut().zip(&emitted) {
+                            *level = (*level).max(emitted.min(MAX_LIGHT).saturating_sub(distance));
+                        }
+                    }
+                    if hit_voxel.opacity() > 0 {
+                        RayAction::Stop
+                    } else {
+                        RayAction::Continue
+                    }
+                });
+            }
+
+            let sky = (sky / faces.len() as f32 * f32::from(MAX_LIGHT)).round() as u8;
+            self.set_level(light, coord, Channel::Sky, sky);
+            for (&channel, &level) in [Channel::Red, Channel::Green, Channel::Blue].iter().zip(&block) {
+                self.set_level(light, coord, channel, level.min(MAX_LIGHT));
+            }
+        }
+    }
+
+    /// Update the light around `coord` after the voxel there changed. This only touches the voxels
+    /// whose light actually changes: the column below, if the voxel was on top of it, and whatever
+    /// the voxel cast light on or shaded.
+    pub fn voxel_changed<V, C, L>(&mut self, chunks: &C, light: &mut L, coord: VoxelCoord)
+    where
+        V: Voxel,
+        C: ChunkAccess<V>,
+        L: LightAccess,
+    {
+        if light.sky(coord).is_some() {
+            self.relight(chunks, light, &[coord]);
+        }
+    }
+
+    /// The chunks with faces whose light has changed since the last call, which will need re-meshing.
+    pub fn take_changed(&mut self) -> Vec<VoxelCoord> {
+        self.changed.drain().collect()
+    }
+
+    /// Recompute the light of `voxels` and everything that depends on it,
+    /// by darkening whatever they might have lit and then spreading light back in.
+    fn relight<V, C, L>(&mut self, chunks: &C, light: &mut L, voxels: &[VoxelCoord])
+    where
+        V: Voxel,
+        C: ChunkAccess<V>,
+        L: LightAccess,
+    {
+        let mut darken = VecDeque::new();
+        let mut brighten = VecDeque::new();
+
+        // first update the heightmap, so we know what's in direct sunlight
+        for &coord in voxels {
+            let height = self.height(coord.x, coord.z);
+            if opacity(chunks, coord) > 0 {
+                if height.map_or(true, |height| coord.y > height) {
+                    self.set_height(light, coord.x, coord.z, Some(coord.y), &mut darken, &mut brighten);
+                }
+            } else if height == Some(coord.y) {
+                let below = column_top(chunks, coord);
+                self.set_height(light, coord.x, coord.z, below, &mut darken, &mut brighten);
+            }
+        }
+
+        // (the queues start out with sky light changes from the heightmap, and are empty after each channel)
+        for &channel in &Channel::all() {
+            for &coord in voxels {
+                match light.level(coord, channel) {
+                    Some(level) if level > 0 => {
+                        self.set_level(light, coord, channel, 0);
+                        darken.push_back((coord, level));
+                    }
+                    _ => (),
+                }
+            }
+            self.darken(chunks, light, channel, &mut darken, &mut brighten);
+
+            for &coord in voxels {
+                let source = self.source(chunks, coord, channel);
+                if source > 0 {
+                    self.set_level(light, coord, channel, source);
+                    brighten.push_back(coord);
+                }
+                for offset in &NEIGHBORS {
+                    if light.level(coord + offset, channel).map_or(false, |level| level > 1) {
+                        brighten.push_back(coord + offset);
+                    }
+                }
+            }
+            self.brighten(chunks, light, channel, &mut brighten);
+        }
+    }
+
+    /// Move the top of the column at `(x, z)` to `height`. The voxels between the old and new tops
+    /// move into or out of direct sunlight; queue them up to be brightened or darkened.
+    fn set_height<L: LightAccess>(
+        &mut self,
+        light: &mut L,
+        x: i16,
+        z: i16,
+        height: Option<i16>,
+        darken: &mut VecDeque<(VoxelCoord, u8)>,
+        brighten: &mut VecDeque<VoxelCoord>,
+    ) {
+        let old = match height {
+            Some(height) => self.heights.insert((x, z), height),
+            None => self.heights.remove(&(x, z)),
+        };
+        let raised = match (old, height) {
+            (_, None) => false,
+            (None, Some(_)) => true,
+            (Some(old), Some(height)) => height > old,
+        };
+        let (top, bottom) = if raised { (height, old) } else { (old, height) };
+        let bottom = bottom.unwrap_or(i16::MIN);
+
+        // walk down from just under the new top, until we reach the old one or leave the lit chunks
+        let mut y = match top {
+            Some(top) if top > i16::MIN => top - 1,
+            _ => return,
+        };
+        while y > bottom {
+            let coord = VoxelCoord::new(x, y, z);
+            match light.sky(coord) {
+                None => break,
+                Some(level) if raised && level > 0 => {
+                    self.set_level(light, coord, Channel::Sky, 0);
+                    darken.push_back((coord, level));
+                }
+                Some(_) if !raised => {
+                    // anything in the way would have been the top of the column
+                    self.set_level(light, coord, Channel::Sky, MAX_LIGHT);
+                    brighten.push_back(coord);
+                }
+                Some(_) => (),
+            }
+            if y == i16::MIN {
+                break;
+            }
+            y -= 1;
+        }
+    }
+
+    /// Darken everything lit by the queued voxels (which have already been set to 0; the queue holds
+    /// their old levels), queueing any neighbors lit from somewhere else to spread light back in.
+    fn darken<V: Voxel, C: ChunkAccess<V>, L: LightAccess>(
+        &mut self,
+        chunks: &C,
+        light: &mut L,
+        channel: Channel,
+        darken: &mut VecDeque<(VoxelCoord, u8)>,
+        brighten: &mut VecDeque<VoxelCoord>,
+    ) {
+        while let Some((coord, level)) = darken.pop_front() {
+            for offset in &NEIGHBORS {
+                let next = coord + offset;
+                match light.level(next, channel) {
+                    Some(next_level) if next_level > 0 && next_level < level => {
+                        self.set_level(light, next, channel, 0);
+                        darken.push_back((next, next_level));
+                        // a dim light source next to a bright one keeps its own light
+                        let source = self.source(chunks, next, channel);
+                        if source > 0 {
+                            self.set_level(light, next, channel, source);
+                            brighten.push_back(next);
+                        }
+                    }
+                    Some(next_level) if next_level > 0 => brighten.push_back(next),
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Spread light from the queued voxels into their darker neighbors, dimmed by how opaque they are.
+    fn brighten<V: Voxel, C: ChunkAccess<V>, L: LightAccess>(
+        &mut self,
+        chunks: &C,
+        light: &mut L,
+        channel: Channel,
+        brighten: &mut VecDeque<VoxelCoord>,
+    ) {
+        while let Some(coord) = brighten.pop_front() {
+            let level = match light.level(coord, channel) {
+                Some(level) if level > 1 => level,
+                _ => continue,
+            };
+            for offset in &NEIGHBORS {
+                let next = coord + offset;
+                let lit = (level - 1).saturating_sub(opacity(chunks, next));
+                if light.level(next, channel).map_or(false, |next_level| next_level < lit) {
+                    self.set_level(light, next, channel, lit);
+                    brighten.push_back(next);
+                }
+            }
+        }
+    }
+
+    /// The light `coord` gives off by itself in `channel`: full sky light if it's in direct sunlight,
+    /// or whatever it emits.
+    fn source<V: Voxel, C: ChunkAccess<V>>(&self, chunks: &C, coord: VoxelCoord, channel: Channel) -> u8 {
+        let voxel = match chunks.get_voxel(coord) {
+            Some(voxel) => voxel,
+            None => return 0,
+        };
+        match channel {
+            Channel::Sky => {
+                let sunlit = voxel.opacity() == 0
+                    && self.height(coord.x, coord.z).map_or(true, |height| coord.y > height);
+                if sunlit {
+                    MAX_LIGHT
+                } else {
+                    0
+                }
+            }
+            _ => voxel.emitted_light()[channel.index() - 1].min(MAX_LIGHT),
+        }
+    }
+
+    fn set_level<L: LightAccess>(&mut self, light: &mut L, coord: VoxelCoord, channel: Channel, level: u8) {
+        let chunk = canonicalize_chunk(coord);
+        if let Some(chunk_light) = light.get_light_mut(chunk) {
+            chunk_light.set(coord - chunk, channel, level);
+        }
+        // the faces lit by this voxel belong to its neighbors, which might be in other chunks
+        for offset in &NEIGHBORS {
+            self.changed.insert(canonicalize_chunk(coord + offset));
+        }
+    }
+}
+
+/// The opacity of the voxel at `coord`; unloaded voxels block light completely.
+fn opacity<V: Voxel, C: ChunkAccess<V>>(chunks: &C, coord: VoxelCoord) -> u8 {
+    chunks.get_voxel(coord).map_or(MAX_LIGHT, |voxel| voxel.opacity())
+}
+
+/// The y coordinate of the highest voxel that blocks or dims light below `coord` in its column,
+/// or None if there isn't one before the loaded chunks run out.
+fn column_top<V: Voxel, C: ChunkAccess<V>>(chunks: &C, mut coord: VoxelCoord) -> Option<i16> {
+    while coord.y > i16::MIN {
+        coord.y -= 1;
+        match chunks.get_voxel(coord) {
+            Some(voxel) if voxel.opacity() > 0 => return Some(coord.y),
+            Some(_) => (),
+            None => return None,
+        }
+    }
+    None
+}
+
+/// The kinds of work `LightingSystem` times separately.
+#[derive(Hash)]
+enum Work {
+    /// Relighting around all the voxels changed since the last frame, or as many as there's time for.
+    Voxel,
+    Chunk,
+    Restore,
+}
+
+/// Lights chunks as they're loaded (unless they're loaded with a `ChunkLight` already), and updates the light
+/// around voxels as they're changed (by reading `VoxelChanged` events; see `delta`), spending up to its time limit
+/// per frame (scaled by the `Headroom` resource, if there is one). Whatever it doesn't get to waits for the next
+/// frame; that includes the rest of a frame's edits, which are relit one at a time, checking between each whether
+/// there's time for another (see `soft_time_limit::Task::should_yield`).
+///
+/// Chunks edited without going through `ChunkDeltas` aren't relit; call `LightMap::voxel_changed` yourself,
+/// with `ChunkTracker::light`.
+///
+/// Should run after the `ChunkTrackerSystem` and `ChunkDeltaSystem`, and before the mesher.
+pub struct LightingSystem<V: Voxel> {
+    time_limiter: TimeLimiter,
+    time_limit: Duration,
+    ids: Option<(ReaderId<InsertedFlag>, ReaderId<RemovedFlag>)>,
+    reader: Option<ReaderId<VoxelChanged<V>>>,
+    /// Chunks waiting to be lit.
+    to_do: BitSet,
+    /// Changed voxels waiting to be relit around.
+    changed: VecDeque<VoxelCoord>,
+}
+impl<V: Voxel> LightingSystem<V> {
+    pub fn new(time_limit: Duration) -> Self {
+        LightingSystem {
+            time_limiter: TimeLimiter::new(),
+            time_limit,
+            ids: None,
+            reader: None,
+            to_do: BitSet::new(),
+            changed: VecDeque::new(),
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for LightingSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        WriteStorage<'a, ChunkLight>,
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        Write<'a, LightMap>,
+        Option<Read<'a, Headroom>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        {
+            let mut chunks = WriteStorage::<Chunk<V>>::fetch(resources);
+            self.ids = Some((chunks.track_inserted(), chunks.track_removed()));
+        }
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(
+        &mut self,
+        (entities, tracker, chunks, mut chunk_light, changes, mut light, headroom): Self::SystemData,
+    ) {
+        let &mut (ref mut inserted_ids, ref mut removed_ids) = self.ids.as_mut().unwrap();
+        chunks.populate_inserted(inserted_ids, &mut self.to_do);
+        // (their light is removed by the tracker)
+        for removed_chunk in chunks.removed().read(removed_ids) {
+            self.to_do.remove(**removed_chunk);
+        }
+        self.changed
+            .extend(changes.read(self.reader.as_mut().unwrap()).map(|change| change.coord));
+
+        let budget = headroom.map_or(self.time_limit, |headroom| headroom.budget(self.time_limit));
+        let access = tracker.chunks(&chunks);
+        let mut lights = tracker.light(&mut chunk_light);
+        let mut lit = Vec::new();
+        {
+            let mut frame = self.time_limiter.frame_keyed(budget);
+            // edits first, since someone's probably looking at them, relit around one voxel at a time until there
+            // isn't time for another; edits to chunks that aren't lit yet do nothing, and are covered when the
+            // chunk is lit
+            if !self.changed.is_empty() {
+                let mut task = frame.time_task(&Work::Voxel);
+                while !self.changed.is_empty() && !task.should_yield() {
+                    light.voxel_changed(&access, &mut lights, self.changed.pop_front().unwrap());
+                    task.checkpoint();
+                }
+            }
+            for idx in (&self.to_do).iter() {
+                let coord = chunks.get(entities.entity(idx)).map(|chunk| chunk.coord);
+                let work = match coord {
+                    Some(coord) if lights.get_light(coord).is_some() => Work::Restore,
+                    _ => Work::Chunk,
+                };
+                if !frame.have_time_for(&work) {
+                    break;
+                }
+                let _task = frame.time_task(&work);
+                match (coord, work) {
+                    (Some(coord), Work::Restore) => light.restore_chunk(&access, coord),
+                    (Some(coord), _) => light.light_chunk(&access, &mut lights, coord),
+                    (None, _) => (),
+                }
+                lit.push(idx);
+            }
+        }
+        for idx in lit {
+            self.to_do.remove(idx);
+        }
+        let chunks_left = (&self.to_do).iter().count();
+        if !self.changed.is_empty() || chunks_left > 0 {
+            debug!(
+                "lighting ran out of time; {} edits and {} chunks left to light",
+                self.changed.len(),
+                chunks_left
+            );
+        }
+    }
+}
+
+/// Moves the `SkyLightState` through a day and night every `day_length` seconds of game time.
+pub struct DayNightSystem {
+    pub day_length: f32,
+    /// From 0 (midnight) to 1; see `SkyLightState::at_time_of_day`.
+    pub time_of_day: f32,
+}
+impl DayNightSystem {
+    pub fn new(day_length: f32, time_of_day: f32) -> Self {
+        assert!(day_length > 0.0, "days must take some time");
+        DayNightSystem {
+            day_length,
+            time_of_day,
+        }
+    }
+}
+impl<'a> System<'a> for DayNightSystem {
+    type SystemData = (Read<'a, Time>, Write<'a, SkyLightState>);
+
+    fn run(&mut self, (time, mut sky): Self::SystemData) {
+        self.time_of_day = (self.time_of_day + time.delta_seconds() / self.day_length) % 1.0;
+        *sky = SkyLightState::at_time_of_day(self.time_of_day);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use {Chunk, TestVoxel};
+
+    #[test]
+    fn sky_light() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::<TestVoxel>::empty(origin);
+        // a roof over half the chunk
+        chunk.fill_box(VoxelCoord::new(0, 10, 0), VoxelCoord::new(7, 10, 15), TestVoxel::Rock);
+        chunks.insert(origin, chunk);
+
+        let mut map = LightMap::new();
+        let mut light = HashMap::new();
+        map.light_chunk(&chunks, &mut light, origin);
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        assert_eq!(map.height(3, 5), Some(10));
+        assert_eq!(map.height(12, 5), None);
+        assert_eq!(light.sky(at(12, 0, 5)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(3, 11, 5)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(3, 10, 5)), Some(0));
+        // light spreads in under the roof from the open side
+        assert_eq!(light.sky(at(7, 9, 5)), Some(14));
+        assert_eq!(light.sky(at(3, 2, 5)), Some(10));
+        assert_eq!(light.sky(at(20, 2, 5)), None);
+        assert!(map.take_changed().contains(&origin));
+
+        // make a hole in the roof, then patch it
+        chunks.get_mut(&origin).unwrap()[at(3, 10, 5)] = TestVoxel::Air;
+        map.voxel_changed(&chunks, &mut light, at(3, 10, 5));
+        assert_eq!(map.height(3, 5), None);
+        assert_eq!(light.sky(at(3, 2, 5)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(2, 2, 5)), Some(14));
+        chunks.get_mut(&origin).unwrap()[at(3, 10, 5)] = TestVoxel::Rock;
+        map.voxel_changed(&chunks, &mut light, at(3, 10, 5));
+        assert_eq!(map.height(3, 5), Some(10));
+        assert_eq!(light.sky(at(3, 2, 5)), Some(10));
+        assert_eq!(light.sky(at(2, 2, 5)), Some(9));
+
+        // a chunk loaded above with a complete roof puts everything below in the dark
+        let above = VoxelCoord::new(0, 16, 0);
+        let mut chunk = Chunk::<TestVoxel>::empty(above);
+        chunk.fill_box(VoxelCoord::new(0, 4, 0), VoxelCoord::new(15, 4, 15), TestVoxel::Rock);
+        chunks.insert(above, chunk);
+        map.take_changed();
+        map.light_chunk(&chunks, &mut light, above);
+        assert_eq!(map.height(12, 5), Some(20));
+        assert_eq!(light.sky(at(12, 21, 5)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(12, 19, 5)), Some(0));
+        assert_eq!(light.sky(at(12, 0, 5)), Some(0));
+        assert_eq!(light.sky(at(7, 9, 5)), Some(0));
+        assert!(map.take_changed().contains(&origin));
+    }
+
+    #[derive(Clone, Copy, Debug, PartialEq)]
+    enum Glowing {
+        Air,
+        Rock,
+        Lava,
+        Leaves,
+    }
+    impl Default for Glowing {
+        fn default() -> Self {
+            Glowing::Air
+        }
+    }
+    impl Voxel for Glowing {
+        fn is_transparent(&self) -> bool {
+            *self == Glowing::Air
+        }
+        fn color(&self) -> [f32; 4] {
+            [1.0; 4]
+        }
+        fn emitted_light(&self) -> [u8; 3] {
+            match *self {
+                Glowing::Lava => [15, 8, 0],
+                _ => [0, 0, 0],
+            }
+        }
+        fn opacity(&self) -> u8 {
+            match *self {
+                Glowing::Air => 0,
+                Glowing::Leaves => 3,
+                _ => MAX_LIGHT,
+            }
+        }
+    }
+
+    #[test]
+    fn colored_light() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::<Glowing>::empty(origin);
+        // in the dark, under a roof
+        chunk.fill_box(at(0, 15, 0), at(15, 15, 15), Glowing::Rock);
+        chunk[at(4, 8, 8)] = Glowing::Lava;
+        chunk[at(8, 8, 8)] = Glowing::Lava;
+        chunks.insert(origin, chunk);
+
+        let mut map = LightMap::new();
+        let mut light = HashMap::new();
+        map.light_chunk(&chunks, &mut light, origin);
+        assert_eq!(light.sky(at(8, 9, 8)), Some(0));
+        assert_eq!(light.block(at(8, 8, 8)), Some([15, 8, 0]));
+        assert_eq!(light.block(at(8, 9, 8)), Some([14, 7, 0]));
+        assert_eq!(light.block(at(6, 8, 8)), Some([13, 6, 0]));
+        assert_eq!(light.block(at(15, 8, 8)), Some([8, 1, 0]));
+        let tint = shade(&light[&origin], at(8, 9, 8)).tint(&SkyLightState::default());
+        let expected = [brightness(14), brightness(7), brightness(0)];
+        assert!(tint.iter().zip(&expected).all(|(a, b)| (a - b).abs() < 1e-6));
+
+        // put one out; the other still lights the space between them
+        chunks.get_mut(&origin).unwrap()[at(8, 8, 8)] = Glowing::Air;
+        map.voxel_changed(&chunks, &mut light, at(8, 8, 8));
+        assert_eq!(light.block(at(8, 8, 8)), Some([11, 4, 0]));
+        assert_eq!(light.block(at(6, 8, 8)), Some([13, 6, 0]));
+        assert_eq!(light.block(at(15, 8, 8)), Some([4, 0, 0]));
+    }
+
+    #[test]
+    fn attenuation() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::<Glowing>::empty(origin);
+        // a canopy, with a rock in it
+        chunk.fill_box(at(0, 10, 0), at(15, 10, 15), Glowing::Leaves);
+        chunk[at(8, 10, 8)] = Glowing::Rock;
+        chunks.insert(origin, chunk);
+
+        let mut map = LightMap::new();
+        let mut light = HashMap::new();
+        map.light_chunk(&chunks, &mut light, origin);
+        assert_eq!(map.height(3, 5), Some(10));
+        assert_eq!(light.sky(at(3, 11, 5)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(3, 10, 5)), Some(11));
+        assert_eq!(light.sky(at(3, 9, 5)), Some(10));
+        assert_eq!(light.sky(at(3, 2, 5)), Some(3));
+        assert_eq!(light.sky(at(8, 10, 8)), Some(0));
+        assert_eq!(light.sky(at(8, 9, 8)), Some(9));
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::<Glowing>::empty(origin);
+        chunk.fill_box(at(0, 12, 0), at(9, 12, 15), Glowing::Rock);
+        chunk[at(4, 3, 4)] = Glowing::Lava;
+        chunks.insert(origin, chunk);
+        let mut map = LightMap::new();
+        let mut light = HashMap::new();
+        map.light_chunk(&chunks, &mut light, origin);
+
+        let bytes = light[&origin].to_bytes();
+        assert_eq!(bytes.len(), 8192);
+        let restored = ChunkLight::from_bytes(&bytes).unwrap();
+        assert!(restored == light[&origin]);
+        assert_eq!(restored.get(at(4, 3, 4), Channel::Green), 8);
+        assert!(ChunkLight::from_bytes(&bytes[1..]).is_err());
+
+        // restoring a chunk only fills in the heightmap, which edits to it then rely on
+        let mut restored_map = LightMap::new();
+        let mut restored_light = HashMap::new();
+        restored_light.insert(origin, restored);
+        restored_map.restore_chunk(&chunks, origin);
+        assert_eq!(restored_map.height(3, 5), Some(12));
+        assert_eq!(restored_map.height(12, 5), None);
+        chunks.get_mut(&origin).unwrap()[at(3, 12, 5)] = Glowing::Air;
+        map.voxel_changed(&chunks, &mut light, at(3, 12, 5));
+        restored_map.voxel_changed(&chunks, &mut restored_light, at(3, 12, 5));
+        assert_eq!(restored_light.sky(at(3, 0, 5)), Some(MAX_LIGHT));
+        assert!(restored_light[&origin] == light[&origin]);
+    }
+
+    #[test]
+    fn bake() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::<Glowing>::empty(origin);
+        // a floor, with a wall on it, and lava against the wall
+        chunk.fill_box(at(0, 0, 0), at(15, 0, 15), Glowing::Rock);
+        chunk.fill_box(at(8, 1, 0), at(8, 6, 15), Glowing::Rock);
+        chunk[at(7, 1, 3)] = Glowing::Lava;
+        chunks.insert(origin, chunk);
+
+        let mut map = LightMap::new();
+        let mut light = HashMap::new();
+        map.bake(&chunks, &mut light, origin, at(15, 15, 15), &Hemisphere::new(64, 32.0));
+        assert!(map.take_changed().contains(&origin));
+        assert_eq!(map.height(8, 3), Some(6));
+        // the crease between the floor and the wall is darker than out in the open
+        let open = light.sky(at(2, 1, 8)).unwrap();
+        let crease = light.sky(at(7, 1, 8)).unwrap();
+        assert!(open >= 13 && crease < open && crease > 0);
+        assert_eq!(light.sky(at(4, 10, 4)), Some(MAX_LIGHT));
+        assert_eq!(light.sky(at(8, 3, 8)), Some(0));
+        // the lava lights what it can see, and the wall shades the other side
+        assert_eq!(light.block(at(6, 1, 3)), Some([14, 7, 0]));
+        assert_eq!(light.block(at(9, 1, 3)), Some([0, 0, 0]));
+    }
+
+    #[test]
+    fn day_and_night() {
+        let noon = SkyLightState::at_time_of_day(0.5);
+        let midnight = SkyLightState::at_time_of_day(0.0);
+        let sunset = SkyLightState::at_time_of_day(0.75);
+        assert!(noon.difference(&SkyLightState::default()) < 1e-4);
+        assert!(midnight.intensity < 0.2 && midnight.color[2] > midnight.color[0]);
+        assert!(sunset.color[0] > sunset.color[2]);
+        assert_eq!(SkyLightState::at_time_of_day(1.5), noon);
+
+        // block light doesn't care what time it is
+        let face = FaceLight {
+            sky: 1.0,
+            block: [0.5, 0.0, 0.0],
+        };
+        let tint = face.tint(&midnight);
+        assert_eq!(tint[0], 0.5);
+        assert!(tint[1] < 0.2);
+        assert!(face.tint(&noon)[0] > 0.99);
+    }
+}
@@ -1,27 +1,141 @@
 //! Creates Amethyst Meshes from voxels.
 //!
-//! Includes a system to automatically track and re-mesh modified voxels.
+//! Includes a system to automatically track and re-mesh modified voxels, and one to outline the `edit::Selection`.
 //!
 //! Meshing takes approx. .15 ms (.00015 s) for a single voxel.
 
 use super::{Chunk, ChunkTracker, Coord, Voxel, VoxelCoord, CHUNK_SIZE};
+use biome::{BiomeRegistry, ChunkBiomes};
+use budget::Headroom;
+use edit::Selection;
+use light::{shade, Channel, ChunkLight, FaceLight, LightMap, SkyLightState, MAX_LIGHT};
 
 use std::iter::repeat;
 use std::marker::PhantomData;
 use std::time::Duration;
 
 use amethyst::assets::{AssetStorage, Handle, Loader};
+use amethyst::core::transform::GlobalTransform;
 use amethyst::renderer::{Color, ComboMeshCreator, Material, Mesh, Normal, Position, Separate, MaterialDefaults};
-use cgmath::Vector3;
+use cgmath::{Matrix4, Vector3};
+use fnv::FnvHashMap;
 use hibitset::BitSetLike;
 use soft_time_limit::TimeLimiter;
 use specs::prelude::*;
+use specs::world::Index;
+
+/// How bright a face's corner is with 0, 1, 2 or 3 of the voxels around it occluding it.
+const AO_BRIGHTNESS: [f32; 4] = [1.0, 0.8, 0.65, 0.5];
+
+/// How much the sky's tint has to change before every mesh is re-tinted; a few steps of an 8-bit color.
+const RETINT_THRESHOLD: f32 = 0.02;
+
+/// How faces are shaded; a resource, read by the `ChunkMesherSystem`, which re-meshes everything when it
+/// changes. By default corners get ambient occlusion and smooth light, and light isn't debugged.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MeshShading {
+    /// Darken corners in creases, where voxels next to the face block out some of the sky.
+    pub ambient_occlusion: bool,
+    /// Light each corner with the average light of the voxels it touches (in front of the face),
+    /// instead of lighting the whole face with the voxel right in front of it.
+    pub smooth_light: bool,
+    /// Color faces by their light instead, to look for lighting bugs.
+    pub light_debug: LightDebug,
+}
+impl Default for MeshShading {
+    fn default() -> Self {
+        MeshShading {
+            ambient_occlusion: true,
+            smooth_light: true,
+            light_debug: LightDebug::Off,
+        }
+    }
+}
+
+/// Which light to show on faces instead of their colors, if any. Each face shows the level of the voxel in
+/// front of it, as-is (no smoothing, occlusion or sky tint), from blue for 0 through green to red for
+/// `MAX_LIGHT`; faces looking into unlit chunks are magenta.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LightDebug {
+    Off,
+    Sky,
+    /// The brightest of the red, green and blue block light.
+    Block,
+    /// The brightest of sky and block light.
+    Brightest,
+}
+impl LightDebug {
+    /// The next view, to cycle through them with a key.
+    pub fn next(self) -> Self {
+        match self {
+            LightDebug::Off => LightDebug::Sky,
+            LightDebug::Sky => LightDebug::Block,
+            LightDebug::Block => LightDebug::Brightest,
+            LightDebug::Brightest => LightDebug::Off,
+        }
+    }
+
+    /// The false color of a face looking into the voxel at `local`, in a chunk with light `light`.
+    fn color(self, light: Option<&ChunkLight>, local: VoxelCoord) -> [f32; 4] {
+        let light = match light {
+            Some(light) => light,
+            None => return [1.0, 0.0, 1.0, 1.0],
+        };
+        let block = || {
+            let level = |channel| light.get(local, channel);
+            level(Channel::Red).max(level(Channel::Green)).max(level(Channel::Blue))
+        };
+        let level = match self {
+            LightDebug::Sky => light.get(local, Channel::Sky),
+            LightDebug::Block => block(),
+            LightDebug::Off | LightDebug::Brightest => light.get(local, Channel::Sky).max(block()),
+        };
+        // 0 to 2: blue to green to red
+        let heat = 2.0 * f32::from(level) / f32::from(MAX_LIGHT);
+        [(heat - 1.0).max(0.0), 1.0 - (heat - 1.0).abs(), (1.0 - heat).max(0.0), 1.0]
+    }
+}
+
+/// The light of debug faces: full block light, which the sky doesn't tint.
+const UNTINTED: FaceLight = FaceLight {
+    sky: 0.0,
+    block: [1.0; 3],
+};
+
+/// How thick the beams of selection outlines are, in voxels.
+const OUTLINE_WIDTH: f32 = 0.06;
 
+/// A mesh before it's turned into an Amethyst `Mesh`. The colors of the vertices' voxels are kept apart
+/// from the light on them, so that it can be re-tinted for a different sky without meshing it again.
 pub struct InProgress {
-    pub color: Vec<Separate<Color>>,
+    pub color: Vec<[f32; 4]>,
+    pub light: Vec<FaceLight>,
     pub position: Vec<Separate<Position>>,
     pub normal: Vec<Separate<Normal>>,
 }
+impl InProgress {
+    pub fn new() -> Self {
+        InProgress {
+            color: Vec::new(),
+            light: Vec::new(),
+            position: Vec::new(),
+            normal: Vec::new(),
+        }
+    }
+
+    /// The mesh, with its vertices lit under `sky`.
+    pub fn build(&self, sky: &SkyLightState) -> ComboMeshCreator {
+        let color: Vec<Separate<Color>> = self.color
+            .iter()
+            .zip(&self.light)
+            .map(|(color, light)| {
+                let tint = light.tint(sky);
+                Separate::new([color[0] * tint[0], color[1] * tint[1], color[2] * tint[2], color[3]])
+            })
+            .collect();
+        (self.position.clone(), Some(color), None, Some(self.normal.clone()), None).into()
+    }
+}
 
 #[derive(Clone, Copy, Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
 #[repr(u8)]
@@ -82,18 +196,107 @@ const ITERS: [(VoxelCoord, VoxelCoord); 6] = [
     ),
 ];
 const BACKWARDS: [bool; 6] = [false, false, false, true, true, true];
+// the (iter1, iter2) signs of the corners of each face, in the order of `mesh_layer`'s positions
+const CORNERS: [(i16, i16); 4] = [(1, 1), (-1, 1), (-1, -1), (1, -1)];
+const CORNER_OF_POSITION: [usize; 6] = [0, 1, 2, 3, 0, 2];
+
+/// The light at the corners of a face looking into `front` (which is in `chunk`, lit by `light`), in the
+/// order of `CORNERS`.
+///
+/// Ambient occlusion and smooth light look at the same voxels, the ones around `front` in its layer, so
+/// they're read once for both. Voxels that occlude a corner don't count towards its light (they're dark
+/// inside, and the occlusion already darkens it), and neither do voxels off the edge of `chunk`, so corners
+/// on the edges of chunks are only smoothed from one side.
+fn corner_light<V: Voxel>(
+    chunk: &Chunk<V>,
+    light: Option<&ChunkLight>,
+    front: VoxelCoord,
+    (iter1, iter2): (VoxelCoord, VoxelCoord),
+    shading: &MeshShading,
+) -> [FaceLight; 4] {
+    let center = light.map_or(FaceLight::full(), |light| shade(light, front));
+    if !shading.ambient_occlusion && !shading.smooth_light {
+        return [center; 4];
+    }
+
+    // whether each voxel occludes, and its light if it doesn't; voxels off the chunk do neither
+    let sample = |a: i16, b: i16| -> (bool, Option<FaceLight>) {
+        let local = front + iter1 * a + iter2 * b;
+        if (0..3).any(|i| local[i] < 0 || local[i] >= CHUNK_SIZE as i16) {
+            return (false, None);
+        }
+        if chunk[local].opacity() == MAX_LIGHT {
+            (true, None)
+        } else {
+            (false, Some(light.map_or(FaceLight::full(), |light| shade(light, local))))
+        }
+    };
+    let row = |a: i16| [sample(a, -1), sample(a, 0), sample(a, 1)];
+    let around = [row(-1), row(0), row(1)];
+
+    let corner = |(s1, s2): (i16, i16)| {
+        let (a, b) = ((s1 + 1) as usize, (s2 + 1) as usize);
+        let (side1, side2, diagonal) = (around[a][1], around[1][b], around[a][b]);
+        // a corner closed off on both sides is as dark as it gets, and light can't get in diagonally
+        let closed = side1.0 && side2.0;
+        let occluded = if closed {
+            3
+        } else {
+            side1.0 as usize + side2.0 as usize + diagonal.0 as usize
+        };
+
+        let mut light = center;
+        if shading.smooth_light {
+            let mut sum = FaceLight {
+                sky: 0.0,
+                block: [0.0; 3],
+            };
+            let mut count = 0.0;
+            let diagonal = if closed { None } else { diagonal.1 };
+            for sample in [Some(center), side1.1, side2.1, diagonal].iter().filter_map(|&sample| sample) {
+                sum.sky += sample.sky;
+                for (total, block) in sum.block.iter_mut().zip(&sample.block) {
+                    *total += block;
+                }
+                count += 1.0;
+            }
+            light = FaceLight {
+                sky: sum.sky / count,
+                block: [sum.block[0] / count, sum.block[1] / count, sum.block[2] / count],
+            };
+        }
+        if shading.ambient_occlusion {
+            let darken = AO_BRIGHTNESS[occluded];
+            light.sky *= darken;
+            for block in &mut light.block {
+                *block *= darken;
+            }
+        }
+        light
+    };
+    [corner(CORNERS[0]), corner(CORNERS[1]), corner(CORNERS[2]), corner(CORNERS[3])]
+}
 
 /// Mesh a single direction of a single layer.
 ///
 /// direction is in (1 - 6)
 ///
+/// Faces are shaded by `light2`, the light levels of `chunk2`, if there are any; each of their red, green
+/// and blue channels by the light in that channel. Their corners are shaded according to `shading`.
+/// If `biomes1` has the biomes of `chunk1`, and the registry saying what they are, faces are colored with
+/// `Voxel::tinted_color`.
+///
 /// TODO: greedy meshing for this layer
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
 pub fn mesh_layer<V: Voxel>(
     chunk1: &Chunk<V>,
     level1: i16,
+    biomes1: Option<(&ChunkBiomes, &BiomeRegistry<V>)>,
     chunk2: &Chunk<V>,
     level2: i16,
+    light2: Option<&ChunkLight>,
     direction: Direction,
+    shading: &MeshShading,
     in_progress: &mut InProgress,
 ) {
     let direction = direction as usize;
@@ -103,7 +306,8 @@ pub fn mesh_layer<V: Voxel>(
         y: normal.y.abs(),
         z: normal.z.abs(),
     };
-    let (iter1, iter2) = ITERS[direction];
+    let iters = ITERS[direction];
+    let (iter1, iter2) = iters;
     let backwards = BACKWARDS[direction];
 
     let halfnormalf: Vector3<f32> = normal.cast().unwrap() * 0.5;
@@ -145,12 +349,28 @@ pub fn mesh_layer<V: Voxel>(
             let kind1 = unsafe { chunk1.index_unchecked(loc1) };
             let kind2 = unsafe { chunk2.index_unchecked(loc2) };
 
-            if !kind1.is_transparent() && kind2.is_transparent() {
+            // faces can be seen through voxels that let light through, except between voxels of the
+            // same kind, e.g. inside a body of water
+            let see_through = kind2.is_transparent() || (kind2.opacity() < MAX_LIGHT && kind1 != kind2);
+            if !kind1.is_transparent() && see_through {
                 // we have a boundary
                 let face_center: Vector3<f32> = loc1.cast().unwrap() + halfnormalf;
+                let (color, light) = match shading.light_debug {
+                    LightDebug::Off => {
+                        let color = match biomes1 {
+                            Some((biomes, registry)) => {
+                                kind1.tinted_color(registry.tint(biomes.get(loc1.x, loc1.z)))
+                            }
+                            None => kind1.color(),
+                        };
+                        (color, corner_light(chunk2, light2, loc2, iters, shading))
+                    }
+                    debug => (debug.color(light2, loc2), [UNTINTED; 4]),
+                };
 
-                for p in positions.iter() {
-                    in_progress.color.push(Separate::new(kind1.color()));
+                for (p, &corner) in positions.iter().zip(&CORNER_OF_POSITION) {
+                    in_progress.color.push(color);
+                    in_progress.light.push(light[corner]);
                     in_progress
                         .position
                         .push(Separate::new((face_center + p).into()));
@@ -165,66 +385,218 @@ pub fn mesh_layer<V: Voxel>(
     in_progress.normal.extend(repeat(normal_f).take(n));
 }
 
+/// Mesh the chunk at `coord`, with the light on it from its and its neighbors' `ChunkLight`s, where they have
+/// them, and shaded according to `shading`; see `InProgress::build`. If it has `ChunkBiomes` and there's a
+/// `registry`, its voxels are tinted by their biomes.
 pub fn mesh_chunk<V: Voxel>(
     coord: VoxelCoord,
     tracker: &ChunkTracker,
     chunks: &ReadStorage<Chunk<V>>,
-) -> ComboMeshCreator {
-    let mut result = InProgress {
-        color: Vec::new(),
-        position: Vec::new(),
-        normal: Vec::new(),
-    };
+    light: &ReadStorage<ChunkLight>,
+    biomes: &ReadStorage<ChunkBiomes>,
+    registry: Option<&BiomeRegistry<V>>,
+    shading: &MeshShading,
+) -> InProgress {
+    let mut result = InProgress::new();
+    for &direction in &Direction::all() {
+        mesh_direction(coord, tracker, chunks, light, biomes, registry, shading, direction, &mut result);
+    }
+    result
+}
+
+/// Mesh the faces of the chunk at `coord` that look towards `direction`, as `mesh_chunk` does, into
+/// `in_progress`. Meshing every direction meshes the whole chunk, a sixth at a time.
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+pub fn mesh_direction<V: Voxel>(
+    coord: VoxelCoord,
+    tracker: &ChunkTracker,
+    chunks: &ReadStorage<Chunk<V>>,
+    light: &ReadStorage<ChunkLight>,
+    biomes: &ReadStorage<ChunkBiomes>,
+    registry: Option<&BiomeRegistry<V>>,
+    shading: &MeshShading,
+    direction: Direction,
+    in_progress: &mut InProgress,
+) {
     let center = tracker
         .get_chunk(chunks, coord)
         .expect("can't mesh nonexistent chunk!");
+    let center_light = tracker.get_light(light, coord);
+    let center_biomes = match (tracker.get_chunk_ent(coord).and_then(|ent| biomes.get(ent)), registry) {
+        (Some(biomes), Some(registry)) => Some((biomes, registry)),
+        _ => None,
+    };
+    let i = direction as usize;
 
-    let empty = Chunk {
-        coord: VoxelCoord::new(0, 0, 0),
-        voxels: [[[V::default(); CHUNK_SIZE]; CHUNK_SIZE]; CHUNK_SIZE],
+    let (start, end, sub) = if BACKWARDS[i] {
+        (1, CHUNK_SIZE as i16, -1)
+    } else {
+        (0, CHUNK_SIZE as i16 - 1, 1)
     };
 
-    for direction in Direction::all().into_iter() {
-        let i = *direction as usize;
+    // mesh interior faces
+    for offset in start..end {
+        mesh_layer(
+            center,
+            offset,
+            center_biomes,
+            center,
+            offset + sub,
+            center_light,
+            direction,
+            shading,
+            in_progress,
+        );
+    }
+    let adjacent_coord = coord + NORMALS[i] * CHUNK_SIZE as i16;
+    let empty;
+    let adjacent = match tracker.get_chunk(chunks, adjacent_coord) {
+        Some(adjacent) => adjacent,
+        None => {
+            empty = Chunk::empty(VoxelCoord::new(0, 0, 0));
+            &empty
+        }
+    };
+    let adjacent_light = tracker.get_light(light, adjacent_coord);
 
-        let (start, end, sub) = if BACKWARDS[i] {
-            (1, CHUNK_SIZE as i16, -1)
-        } else {
-            (0, CHUNK_SIZE as i16 - 1, 1)
-        };
+    let (center_layer, adjacent_layer) = if BACKWARDS[i] {
+        (0, CHUNK_SIZE as i16 - 1)
+    } else {
+        (CHUNK_SIZE as i16 - 1, 0)
+    };
+    mesh_layer(
+        center,
+        center_layer,
+        center_biomes,
+        adjacent,
+        adjacent_layer,
+        adjacent_light,
+        direction,
+        shading,
+        in_progress,
+    );
+}
 
-        // mesh interior faces
-        for offset in start..end {
-            mesh_layer(
-                center,
-                offset,
-                center,
-                offset + sub,
-                *direction,
-                &mut result,
-            );
+/// Add a box from `min` to `max`, facing out, in `color`.
+fn mesh_box(min: Coord, max: Coord, color: [f32; 4], in_progress: &mut InProgress) {
+    for axis in 0..3 {
+        // (the other two axes, in the order that makes their corners go round counterclockwise seen from +axis)
+        let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+        for &outward in &[true, false] {
+            let mut normal = Coord::new(0.0, 0.0, 0.0);
+            normal[axis] = if outward { 1.0 } else { -1.0 };
+            let corner = |at_b: bool, at_c: bool| {
+                let mut corner = if outward { max } else { min };
+                corner[b] = if at_b { max[b] } else { min[b] };
+                corner[c] = if at_c { max[c] } else { min[c] };
+                corner
+            };
+            let mut quad = [corner(false, false), corner(true, false), corner(true, true), corner(false, true)];
+            if !outward {
+                quad.reverse();
+            }
+            for &i in &[0, 1, 2, 0, 2, 3] {
+                in_progress.color.push(color);
+                in_progress.light.push(UNTINTED);
+                in_progress.position.push(Separate::new(quad[i].into()));
+                in_progress.normal.push(Separate::new(normal.into()));
+            }
         }
-        let adjacent_coord = coord + NORMALS[i] * CHUNK_SIZE as i16;
-        let adjacent = tracker.get_chunk(chunks, adjacent_coord).unwrap_or(&empty);
+    }
+}
 
-        let (center_layer, adjacent_layer) = if BACKWARDS[i] {
-            (0, CHUNK_SIZE as i16 - 1)
-        } else {
-            (CHUNK_SIZE as i16 - 1, 0)
-        };
-        mesh_layer(center, center_layer, adjacent, adjacent_layer, *direction, &mut result);
+/// A frame of thin beams along the edges of a box of `size` voxels, with its minimum corner at the origin, in
+/// `color`; unaffected by light.
+pub fn mesh_outline(size: VoxelCoord, color: [f32; 4]) -> InProgress {
+    let mut result = InProgress::new();
+    let size: Coord = size.cast().unwrap();
+    let half = OUTLINE_WIDTH / 2.0;
+    for axis in 0..3 {
+        let (b, c) = ((axis + 1) % 3, (axis + 2) % 3);
+        for &(at_b, at_c) in &[(0.0, 0.0), (1.0, 0.0), (0.0, 1.0), (1.0, 1.0)] {
+            let (mut min, mut max) = (Coord::new(0.0, 0.0, 0.0), Coord::new(0.0, 0.0, 0.0));
+            min[axis] = -half;
+            max[axis] = size[axis] + half;
+            min[b] = at_b * size[b] - half;
+            max[b] = at_b * size[b] + half;
+            min[c] = at_c * size[c] - half;
+            max[c] = at_c * size[c] + half;
+            mesh_box(min, max, color, &mut result);
+        }
     }
+    result
+}
 
-    let InProgress {
-        position,
-        color,
-        normal,
-    } = result;
+/// Outlines the `Selection` resource, if there is one, with an entity whose mesh goes around the selected voxels
+/// (see `mesh_outline`). The outline is only re-made when the selection changes.
+pub struct SelectionMeshSystem {
+    pub color: [f32; 4],
+    /// The outline entity, and what it's outlining.
+    outline: Option<(Entity, Selection)>,
+}
+impl SelectionMeshSystem {
+    pub fn new(color: [f32; 4]) -> Self {
+        SelectionMeshSystem { color, outline: None }
+    }
+}
+impl Default for SelectionMeshSystem {
+    /// Yellow.
+    fn default() -> Self {
+        SelectionMeshSystem::new([1.0, 0.9, 0.1, 1.0])
+    }
+}
+impl<'a> System<'a> for SelectionMeshSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, Loader>,
+        ReadExpect<'a, AssetStorage<Mesh>>,
+        ReadExpect<'a, MaterialDefaults>,
+        Option<Read<'a, Selection>>,
+        WriteStorage<'a, Handle<Mesh>>,
+        WriteStorage<'a, Material>,
+        WriteStorage<'a, GlobalTransform>,
+    );
 
-    (position, Some(color), None, Some(normal), None).into()
+    fn run(
+        &mut self,
+        (entities, loader, assets, mat, selection, mut meshes, mut materials, mut transforms): Self::SystemData,
+    ) {
+        let selection = selection.map(|selection| *selection);
+        if self.outline.map(|(_, outlined)| outlined) == selection {
+            return;
+        }
+        if let Some((ent, _)) = self.outline.take() {
+            let _ = entities.delete(ent);
+        }
+        if let Some(selection) = selection {
+            let (min, _) = selection.bounds();
+            let outline = mesh_outline(selection.size(), self.color).build(&SkyLightState::default());
+            let mesh: Handle<Mesh> = loader.load_from_data(outline.into(), (), &*assets);
+            let ent = entities.create();
+            let _ = meshes
+                .insert(ent, mesh)
+                .map_err(|e| error!("mesh insertion failed! {:?}", e));
+            let _ = materials
+                .insert(ent, mat.0.clone())
+                .map_err(|_| error!("material insertion failed!"));
+            let _ = transforms
+                .insert(ent, GlobalTransform(Matrix4::from_translation(min.cast().unwrap())))
+                .map_err(|_| error!("transform insertion failed!"));
+            self.outline = Some((ent, selection));
+        }
+    }
 }
 
-/// Tracks modified voxels and re-meshes them.
+/// Tracks modified voxels and re-meshes them, spending up to its time limit per frame
+/// (scaled by the `Headroom` resource, if there is one; see `HeadroomSystem`). Chunks are meshed a direction at a
+/// time (see `mesh_direction`), so a chunk that's slow to mesh can be left partway when the frame runs out of
+/// time, and finished next frame.
+///
+/// Faces are shaded by the `ChunkLight` of the chunks they look into, if they're lit (see `LightingSystem`),
+/// and chunks are re-meshed when their light changes (see `LightMap::take_changed`). If there's a
+/// `SkyLightState`, sky light is tinted by it, and when it changes noticeably every mesh is re-tinted
+/// (which doesn't need meshing again, but does upload the mesh again). Voxels are tinted by the biomes of their
+/// chunks, if they have `ChunkBiomes` and there's a `BiomeRegistry`.
 ///
 /// Note that this uses specs' FlaggedStorage, which means that
 /// whenever you take a &mut chunk, that chunk is marked as modified.
@@ -236,6 +608,16 @@ pub struct ChunkMesherSystem<V: Voxel> {
     time_limit: Duration,
     ids: Option<(ReaderId<InsertedFlag>, ReaderId<ModifiedFlag>, ReaderId<RemovedFlag>)>,
     to_do: BitSet,
+    /// Meshes waiting to be re-tinted.
+    retint: BitSet,
+    /// The meshes of every meshed chunk, to re-tint.
+    baked: FnvHashMap<Index, InProgress>,
+    /// The sky the meshes are (or are being) tinted for.
+    sky: SkyLightState,
+    /// How the meshes are (or are being) shaded.
+    shading: MeshShading,
+    /// The chunk left partway through meshing, if any, with its mesh so far and the next direction to mesh.
+    partial: Option<(Index, InProgress, usize)>,
     _phantom: PhantomData<V>,
 }
 
@@ -246,6 +628,11 @@ impl<V: Voxel> ChunkMesherSystem<V> {
             time_limiter: TimeLimiter::new(),
             time_limit,
             to_do: BitSet::new(),
+            retint: BitSet::new(),
+            baked: FnvHashMap::default(),
+            sky: SkyLightState::default(),
+            shading: MeshShading::default(),
+            partial: None,
             _phantom: PhantomData,
         }
     }
@@ -261,6 +648,13 @@ impl<'a, V: Voxel> System<'a> for ChunkMesherSystem<V> {
         ReadStorage<'a, Chunk<V>>,
         WriteStorage<'a, Handle<Mesh>>,
         WriteStorage<'a, Material>,
+        Option<Read<'a, Headroom>>,
+        ReadStorage<'a, ChunkLight>,
+        Option<Write<'a, LightMap>>,
+        Option<Read<'a, SkyLightState>>,
+        Option<Read<'a, MeshShading>>,
+        ReadStorage<'a, ChunkBiomes>,
+        Option<Read<'a, BiomeRegistry<V>>>,
     );
 
     fn setup(&mut self, resources: &mut Resources) {
@@ -271,50 +665,212 @@ impl<'a, V: Voxel> System<'a> for ChunkMesherSystem<V> {
 
     fn run(
         &mut self,
-        (entities, tracker, loader, assets, mat, chunks, mut meshes, mut materials): Self::SystemData,
+        (
+            entities,
+            tracker,
+            loader,
+            assets,
+            mat,
+            chunks,
+            mut meshes,
+            mut materials,
+            headroom,
+            chunk_light,
+            light,
+            sky,
+            shading,
+            biomes,
+            registry,
+        ): Self::SystemData,
     ) {
         let &mut (ref mut inserted_ids, ref mut modified_ids, ref mut removed_ids) = self.ids.as_mut().unwrap();
-        chunks.populate_inserted(inserted_ids, &mut self.to_do);
-        chunks.populate_modified(modified_ids, &mut self.to_do);
+        // chunks to mesh again, from scratch if they were partway through
+        let mut dirty = BitSet::new();
+        chunks.populate_inserted(inserted_ids, &mut dirty);
+        chunks.populate_modified(modified_ids, &mut dirty);
         for removed in chunks.removed().read(removed_ids) {
             let idx = **removed;
             self.to_do.remove(idx);
+            self.retint.remove(idx);
+            self.baked.remove(&idx);
+            dirty.add(idx);
+        }
+        if let Some(mut light) = light {
+            for coord in light.take_changed() {
+                if let Some(ent) = tracker.get_chunk_ent(coord) {
+                    dirty.add(ent.id());
+                }
+            }
         }
 
+        let shading = shading.map_or(MeshShading::default(), |shading| *shading);
+        if shading != self.shading {
+            self.shading = shading;
+            for &idx in self.baked.keys() {
+                dirty.add(idx);
+            }
+            self.partial = None;
+        }
+        if self.partial.as_ref().map_or(false, |&(idx, _, _)| dirty.contains(idx)) {
+            self.partial = None;
+        }
+        for idx in (&dirty).iter() {
+            if chunks.get(entities.entity(idx)).is_some() {
+                self.to_do.add(idx);
+            }
+        }
+
+        let sky = sky.map_or(SkyLightState::default(), |sky| *sky);
+        if sky.difference(&self.sky) > RETINT_THRESHOLD {
+            self.sky = sky;
+            for &idx in self.baked.keys() {
+                self.retint.add(idx);
+            }
+        }
+        let sky = self.sky;
+
+        let budget = headroom.map_or(self.time_limit, |headroom| headroom.budget(self.time_limit));
         let mut completed = Vec::new();
+        let mut retinted = Vec::new();
+        let mut finished = false;
         {
-            let mut iter = (&self.to_do).iter();
-            self.time_limiter.repeat_with_budget(self.time_limit, || {
-                if let Some(idx) = iter.next() {
-                    let ent = entities.entity(idx);
+            // (the partial chunk's still in `to_do`, and is picked up first)
+            let resumed = self.partial.as_ref().map(|&(idx, _, _)| idx);
+            let mut iter = (&self.to_do).iter().filter(move |&idx| Some(idx) != resumed);
+            let mut retint = (&self.retint).iter();
+            let to_do = &self.to_do;
+            let baked = &mut self.baked;
+            let partial = &mut self.partial;
+            let mut frame = self.time_limiter.frame(budget);
+            while frame.have_time() {
+                let mut task = frame.time_task();
+                let next = partial.take().or_else(|| iter.next().map(|idx| (idx, InProgress::new(), 0)));
+                let (idx, mut pre_mesh, mut direction) = match next {
+                    Some(next) => next,
+                    None => {
+                        let idx = match retint.next() {
+                            Some(idx) => idx,
+                            None => {
+                                finished = true;
+                                break;
+                            }
+                        };
+                        // (chunks waiting to be meshed will be meshed for the new sky anyway)
+                        retinted.push(idx);
+                        match baked.get(&idx) {
+                            Some(pre_mesh) if !to_do.contains(idx) => {
+                                let mesh: Handle<Mesh> =
+                                    loader.load_from_data(pre_mesh.build(&sky).into(), (), &*assets);
+                                let _ = meshes
+                                    .insert(entities.entity(idx), mesh)
+                                    .map_err(|e| error!("mesh insertion failed! {:?}", e));
+                            }
+                            _ => (),
+                        }
+                        continue;
+                    }
+                };
+
+                let ent = entities.entity(idx);
+                let chunk = match chunks.get(ent) {
+                    Some(chunk) => chunk,
+                    None => continue,
+                };
+                if direction == 0 {
                     info!("meshing {:?}", ent);
-                    let chunk = chunks.get(ent);
-                    if let None = chunk {
-                        return true;
+                }
+                let directions = Direction::all();
+                while direction < directions.len() {
+                    if direction > 0 && task.should_yield() {
+                        break;
                     }
-                    let chunk = chunk.unwrap();
-                    let pre_mesh = mesh_chunk(chunk.coord, &*tracker, &chunks);
-                    let mesh: Handle<Mesh> = loader.load_from_data(pre_mesh.into(), (), &*assets);
-
-                    let _ = meshes
-                        .insert(ent, mesh)
-                        .map_err(|e| error!("mesh insertion failed! {:?}", e));
-                    let _ = materials
-                        .insert(ent, mat.0.clone())
-                        .map_err(|_| error!("material insertion failed!"));
-
-                    completed.push(idx);
-
-                    info!("meshed {:?}", ent);
-                    true
-                } else {
-                    false
+                    mesh_direction(
+                        chunk.coord,
+                        &*tracker,
+                        &chunks,
+                        &chunk_light,
+                        &biomes,
+                        registry.as_ref().map(|registry| &**registry),
+                        &shading,
+                        directions[direction],
+                        &mut pre_mesh,
+                    );
+                    direction += 1;
+                    task.checkpoint();
                 }
-            });
+                if direction < directions.len() {
+                    *partial = Some((idx, pre_mesh, direction));
+                    break;
+                }
+
+                let mesh: Handle<Mesh> = loader.load_from_data(pre_mesh.build(&sky).into(), (), &*assets);
+                let _ = meshes
+                    .insert(ent, mesh)
+                    .map_err(|e| error!("mesh insertion failed! {:?}", e));
+                let _ = materials
+                    .insert(ent, mat.0.clone())
+                    .map_err(|_| error!("material insertion failed!"));
+
+                baked.insert(idx, pre_mesh);
+                completed.push(idx);
+
+                info!("meshed {:?}", ent);
+            }
         }
 
         for done in completed {
             self.to_do.remove(done);
         }
+        for done in retinted {
+            self.retint.remove(done);
+        }
+        let stats = self.time_limiter.stats();
+        if !finished {
+            debug!(
+                "meshing ran out of time after {:?}; {} chunks left to mesh{}, {} to re-tint",
+                stats.spent,
+                (&self.to_do).iter().count(),
+                if self.partial.is_some() { " (one partway)" } else { "" },
+                (&self.retint).iter().count()
+            );
+        }
+
+        if stats.overshoots > 0 {
+            debug!(
+                "meshing went over budget: {} chunks in {:?} of {:?}, off by {:?} per chunk on average",
+                stats.tasks,
+                stats.spent,
+                stats.budget,
+                stats.mean_prediction_error()
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outline() {
+        let size = VoxelCoord::new(2, 3, 4);
+        let outline = mesh_outline(size, [1.0, 0.0, 0.0, 1.0]);
+        // a beam along each of the 12 edges, each a box of 6 faces of 2 triangles
+        assert_eq!(outline.position.len(), 12 * 6 * 6);
+        assert_eq!(outline.normal.len(), outline.position.len());
+        assert_eq!(outline.color.len(), outline.position.len());
+        assert_eq!(outline.light.len(), outline.position.len());
+
+        // the beams are centered on the box's edges
+        let half = OUTLINE_WIDTH / 2.0;
+        let size: Coord = size.cast().unwrap();
+        for axis in 0..3 {
+            let (lo, hi) = outline
+                .position
+                .iter()
+                .map(|position| position.0[axis])
+                .fold((::std::f32::MAX, ::std::f32::MIN), |(lo, hi), x| (lo.min(x), hi.max(x)));
+            assert_eq!((lo, hi), (-half, size[axis] + half));
+        }
     }
 }
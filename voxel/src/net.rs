@@ -0,0 +1,1469 @@
+//! Replicating voxel edits over the network.
+//!
+//! The `EditCaptureSystem` collects the voxels that changed each frame into a `VoxelEdits` message, which is
+//! `encode`d, sent however the game likes, `decode`d at the other end and put into that world with
+//! `apply_remote`. Edits that came in that way are tagged with `SOURCE`, and aren't captured again, so that a
+//! client doesn't send the server's edits back to it.
+//!
+//! Messages start with the version of this format and what kind of message they are, so that old and new peers
+//! can tell each other apart. An edit message is then:
+//!
+//! ```text
+//! version: u8, kind: 0, sequence: u32, count: u32,
+//! coords: [dx: varint, dy: varint, dz: varint; count],
+//! voxels: packed(count)
+//! ```
+//!
+//! The edits are sorted, and each coord is written as its difference from the one before (the first from the
+//! origin), so that edits that are close together, as they usually are, take a byte or so per axis. A varint is
+//! a zigzag-encoded signed number, written 7 bits at a time, least significant first, with the top bit of each
+//! byte set if there's more to come. The voxels are packed as in `persist::pack_voxels`: a palette of their names,
+//! and then as few bits per voxel as the palette needs, or runs of palette indices if that's smaller. Everything
+//! else is little-endian.
+//!
+//! Clients can show their players' edits straight away, rather than waiting to hear back from the server, with
+//! `EditPredictions`: edits are made locally and sent off as a request, a `VoxelEdits` whose sequence is the
+//! request's id. The server makes them with `VoxelEdits::apply_remote`, and its `EditReplies` answers each request
+//! once its edits have landed:
+//!
+//! ```text
+//! version: u8, kind: 2, request: u32, accepted: u8
+//! ```
+//!
+//! and the client puts back the voxels from requests that weren't accepted.
+//!
+//! Whole chunks, for when a player first joins or comes somewhere new, are encoded with `encode_chunk_for_net`, as
+//! `persist::encode_chunk` saves them, and split into fragments small enough to go in a packet each:
+//!
+//! ```text
+//! version: u8, kind: 1, chunk_coord: [i16; 3], id: u32, index: u16, count: u16, part of the encoded chunk
+//! ```
+//!
+//! where `id` is a checksum of the encoded chunk, which tells fragments of different copies of a chunk apart. On
+//! the client, a `ChunkReassembler` puts the fragments back together, in whatever order they arrive, and the
+//! `ChunkReceiveSystem` adds the chunks to the world.
+//!
+//! A client that's fallen behind, or reconnected, doesn't need the whole of every chunk again either: the
+//! `ChunkVersionSystem` counts ticks (one a frame), and keeps track in `ChunkVersions` of which tick each chunk and
+//! voxel last changed on, so the server can send just the voxels that have changed since the client was last up to
+//! date.
+//!
+//! Servers shouldn't send every client the whole world. Give each client's player a `ChunkInterest`, and the
+//! `ChunkInterestSystem` works out which chunks around it the client should be sent, and which it's been sent
+//! and has left behind. Those the client is told to drop with `encode_evictions`:
+//!
+//! ```text
+//! version: u8, kind: 3, count: u16, chunk_coords: [[i16; 3]; count]
+//! ```
+//!
+//! Nor should they send it all at once: a `SendQueue` for each client spreads what it's sent over frames, a few
+//! bytes a frame, most urgent first, so that a player arriving somewhere new doesn't flood their connection.
+
+use super::{
+    canonicalize, canonicalize_chunk, Chunk, ChunkAccess, ChunkTracker, Coord, Voxel, VoxelCoord, VoxelId, CHUNK_SIZE,
+};
+use bytes::{invalid, put_u16, put_u32, put_varint, Reader};
+use delta::{ChunkDeltas, DeltaId, DeltaOutcome, DeltaResult, DeltaSource, DeltaWriter, VoxelChanged, want_fill_changes};
+use generate::chunks_in_radius;
+use history::{TransactionId, VoxelHistory};
+use persist::{decode_chunk, encode_chunk, pack_voxels, unpack_voxels, Codec};
+
+use amethyst::core::transform::GlobalTransform;
+use amethyst::shrev::EventChannel;
+use fnv::{FnvHashMap, FnvHashSet};
+use specs::prelude::*;
+use specs::HashMapStorage;
+use specs::world::Index;
+use std::cmp::{Ordering, Reverse};
+use std::collections::BinaryHeap;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+
+/// What edits received over the network are tagged with; see `VoxelEdits::apply_remote`.
+pub const SOURCE: DeltaSource = DeltaSource::System("remote");
+/// What edits made by `EditPredictions` are tagged with.
+pub const PREDICTED: DeltaSource = DeltaSource::System("predicted");
+
+/// A fragment size that fits in a UDP packet on just about any network, for `encode_chunk_for_net`.
+pub const DEFAULT_MTU: usize = 1200;
+
+const VERSION: u8 = 2;
+const EDITS: u8 = 0;
+const CHUNK: u8 = 1;
+const REPLY: u8 = 2;
+const EVICTION: u8 = 3;
+/// The bytes in a chunk fragment before its part of the chunk.
+const FRAGMENT_HEADER: usize = 16;
+
+/// The kinds of message here, for telling incoming messages apart before decoding them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageKind {
+    /// `VoxelEdits`.
+    Edits,
+    /// A piece of a chunk; see `encode_chunk_for_net`.
+    ChunkFragment,
+    /// An `EditReply`.
+    Reply,
+    /// Chunks to drop; see `encode_evictions`.
+    Eviction,
+}
+impl MessageKind {
+    /// What kind of message `bytes` is. Fails if it isn't one of these, or is from another version.
+    pub fn of(bytes: &[u8]) -> io::Result<MessageKind> {
+        let mut reader = Reader::new(bytes);
+        let version = reader.u8()?;
+        if version != VERSION {
+            return Err(invalid(format!("unsupported message version {}", version)));
+        }
+        match reader.u8()? {
+            EDITS => Ok(MessageKind::Edits),
+            CHUNK => Ok(MessageKind::ChunkFragment),
+            REPLY => Ok(MessageKind::Reply),
+            EVICTION => Ok(MessageKind::Eviction),
+            kind => Err(invalid(format!("unknown message kind {}", kind))),
+        }
+    }
+}
+
+/// The voxels that changed in a frame, and what they changed to; see the module docs.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VoxelEdits<V: Voxel> {
+    /// Counts up by one with each message, so the receiver can tell if it's missed one.
+    pub sequence: u32,
+    /// Sorted by coord, with each coord only once.
+    pub edits: Vec<(VoxelCoord, V)>,
+}
+impl<V: Voxel> VoxelEdits<V> {
+    /// Where `changes` left each voxel they touched. Voxels that changed more than once end up as they were last
+    /// changed to.
+    pub fn from_changes<'a, I: IntoIterator<Item = &'a VoxelChanged<V>>>(sequence: u32, changes: I) -> Self {
+        let mut last = FnvHashMap::default();
+        for change in changes {
+            last.insert(change.coord, change.new);
+        }
+        let mut edits: Vec<_> = last.into_iter().collect();
+        edits.sort_by_key(|&(coord, _)| (coord.x, coord.y, coord.z));
+        VoxelEdits { sequence, edits }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Make the edits through `writer`, one per voxel, returning their ids. Clients should use
+    /// `deltas.writer().source(net::SOURCE)`, so that the edits aren't sent back; servers will usually want the
+    /// sender's entity as the source instead, so that its edits are checked and passed on to everyone else.
+    pub fn apply_remote(&self, writer: DeltaWriter<V>) -> Vec<DeltaId> {
+        self.edits
+            .iter()
+            .map(|&(coord, voxel)| writer.defer_set(coord, voxel))
+            .collect()
+    }
+}
+impl<V: VoxelId> VoxelEdits<V> {
+    /// The message's bytes, to send.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION, EDITS];
+        put_u32(&mut bytes, self.sequence);
+        put_u32(&mut bytes, self.edits.len() as u32);
+        let mut previous = VoxelCoord::new(0, 0, 0);
+        for &(coord, _) in &self.edits {
+            put_varint(&mut bytes, i32::from(coord.x) - i32::from(previous.x));
+            put_varint(&mut bytes, i32::from(coord.y) - i32::from(previous.y));
+            put_varint(&mut bytes, i32::from(coord.z) - i32::from(previous.z));
+            previous = coord;
+        }
+        bytes.extend(pack_voxels(self.edits.iter().map(|&(_, voxel)| voxel)));
+        bytes
+    }
+
+    /// Read a message written by `encode`.
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(bytes);
+        header(&mut reader, EDITS)?;
+        let sequence = reader.u32()?;
+        let count = reader.u32()? as usize;
+        // (every coord takes at least 3 bytes, so a bad count can't make us allocate much)
+        if count > bytes.len() / 3 {
+            return Err(invalid("too many edits"));
+        }
+        let mut coords = Vec::with_capacity(count);
+        let mut previous = VoxelCoord::new(0, 0, 0);
+        for _ in 0..count {
+            let coord = VoxelCoord::new(
+                offset(previous.x, reader.varint()?)?,
+                offset(previous.y, reader.varint()?)?,
+                offset(previous.z, reader.varint()?)?,
+            );
+            coords.push(coord);
+            previous = coord;
+        }
+        let voxels = unpack_voxels(reader.rest(), count)?;
+        Ok(VoxelEdits {
+            sequence,
+            edits: coords.into_iter().zip(voxels).collect(),
+        })
+    }
+}
+
+/// The server's answer to a request for edits; see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EditReply {
+    pub request: u32,
+    /// Whether all of the edits landed.
+    pub accepted: bool,
+}
+impl EditReply {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = vec![VERSION, REPLY];
+        put_u32(&mut bytes, self.request);
+        bytes.push(self.accepted as u8);
+        bytes
+    }
+
+    pub fn decode(bytes: &[u8]) -> io::Result<Self> {
+        let mut reader = Reader::new(bytes);
+        header(&mut reader, REPLY)?;
+        let request = reader.u32()?;
+        let accepted = match reader.u8()? {
+            0 => false,
+            1 => true,
+            other => return Err(invalid(format!("bad reply {}", other))),
+        };
+        Ok(EditReply { request, accepted })
+    }
+}
+
+/// A client's edits that the server hasn't confirmed yet; see the module docs. Each request's edits are recorded
+/// as a transaction in a `history::VoxelHistory` of their own as they land (fed by the `EditPredictionSystem`), and
+/// a request the server turns down is `revert`ed.
+///
+/// Reverting only puts an old voxel back if the predicted one is still there, so it won't undo anything the
+/// server's sent since.
+pub struct EditPredictions<V: Voxel> {
+    next_request: u32,
+    /// the edits of the requests we're waiting to hear back about
+    history: VoxelHistory<V>,
+    pending: FnvHashMap<u32, TransactionId>,
+    /// rejected requests with edits that haven't landed yet
+    reverting: Vec<TransactionId>,
+}
+impl<V: Voxel> Default for EditPredictions<V> {
+    fn default() -> Self {
+        EditPredictions {
+            next_request: 0,
+            // (requests are forgotten as they're answered, and none can be dropped before then)
+            history: VoxelHistory::with_memory_limit(usize::max_value()),
+            pending: FnvHashMap::default(),
+            reverting: Vec::new(),
+        }
+    }
+}
+impl<V: Voxel> EditPredictions<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Make `edits` locally, tagged with `PREDICTED`, and return the request to send the server for them.
+    pub fn predict(&mut self, deltas: &ChunkDeltas<V>, edits: &[(VoxelCoord, V)]) -> VoxelEdits<V> {
+        let request = self.next_request;
+        self.next_request = self.next_request.wrapping_add(1);
+        let transaction = self.history.begin("prediction");
+        let writer = deltas.writer().source(PREDICTED);
+        for &(coord, voxel) in edits {
+            self.history.track(writer.defer_set(coord, voxel));
+        }
+        self.history.commit();
+        self.pending.insert(request, transaction);
+        VoxelEdits {
+            sequence: request,
+            edits: edits.to_vec(),
+        }
+    }
+
+    /// How many requests the server hasn't answered, or has turned down but which are still being taken back.
+    pub fn pending(&self) -> usize {
+        self.pending.len() + self.reverting.len()
+    }
+
+    /// Record the outcome of a predicted edit, taking it back if the server's already turned its request down.
+    /// Called by `EditPredictionSystem`.
+    pub fn record_result(&mut self, result: &DeltaResult<V>, deltas: &ChunkDeltas<V>) {
+        self.history.record_result(result);
+        self.history.revert_late(deltas);
+        let history = &self.history;
+        self.reverting.retain(|&transaction| history.is_landing(transaction));
+    }
+
+    /// Take in the server's answer to a request: forget about its edits if they were accepted, and take them back
+    /// if they weren't.
+    pub fn reply(&mut self, reply: EditReply, deltas: &ChunkDeltas<V>) {
+        let transaction = match self.pending.remove(&reply.request) {
+            Some(transaction) => transaction,
+            None => return,
+        };
+        if reply.accepted {
+            self.history.forget(transaction);
+        } else {
+            self.history.revert(transaction, deltas, SOURCE);
+            if self.history.is_landing(transaction) {
+                self.reverting.push(transaction);
+            }
+        }
+    }
+}
+
+/// Feeds applied edits into the `EditPredictions`. Should run after the `ChunkDeltaSystem`.
+#[derive(Default)]
+pub struct EditPredictionSystem<V: Voxel> {
+    reader: Option<ReaderId<DeltaResult<V>>>,
+}
+impl<V: Voxel> EditPredictionSystem<V> {
+    pub fn new() -> Self {
+        EditPredictionSystem { reader: None }
+    }
+}
+impl<'a, V: Voxel> System<'a> for EditPredictionSystem<V> {
+    type SystemData = (
+        Read<'a, EventChannel<DeltaResult<V>>>,
+        Read<'a, ChunkDeltas<V>>,
+        Write<'a, EditPredictions<V>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<DeltaResult<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (results, deltas, mut predictions): Self::SystemData) {
+        for result in results.read(self.reader.as_mut().unwrap()) {
+            predictions.record_result(result, &deltas);
+        }
+    }
+}
+
+/// A request from a client that the server's making; see `EditReplies`.
+#[derive(Debug)]
+struct Request {
+    client: Entity,
+    request: u32,
+    landing: usize,
+    accepted: bool,
+}
+
+/// Works out the server's answers to clients' requests for edits (see the module docs), as their edits land.
+#[derive(Default)]
+pub struct EditReplies {
+    /// deferred edits we're waiting to hear back about, and the requests they belong to
+    tracked: FnvHashMap<DeltaId, usize>,
+    requests: FnvHashMap<usize, Request>,
+    next: usize,
+    finished: Vec<(Entity, EditReply)>,
+}
+impl EditReplies {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Make the edits `client` asked for, tagged with its entity, so that they can be checked and passed on to
+    /// other clients.
+    pub fn apply<V: Voxel>(&mut self, client: Entity, request: &VoxelEdits<V>, deltas: &ChunkDeltas<V>) {
+        let ids = request.apply_remote(deltas.writer().source(DeltaSource::Entity(client)));
+        if ids.is_empty() {
+            let reply = EditReply {
+                request: request.sequence,
+                accepted: true,
+            };
+            self.finished.push((client, reply));
+            return;
+        }
+        let key = self.next;
+        self.next = self.next.wrapping_add(1);
+        for id in ids {
+            self.tracked.insert(id, key);
+        }
+        self.requests.insert(
+            key,
+            Request {
+                client,
+                request: request.sequence,
+                landing: request.edits.len(),
+                accepted: true,
+            },
+        );
+    }
+
+    /// Record the outcome of a requested edit. Called by `EditReplySystem`.
+    pub fn record_result<V: Voxel>(&mut self, result: &DeltaResult<V>) {
+        let key = match self.tracked.remove(&result.id) {
+            Some(key) => key,
+            None => return,
+        };
+        let finished = {
+            let request = self.requests.get_mut(&key).unwrap();
+            request.landing -= 1;
+            match result.outcome {
+                DeltaOutcome::Applied { .. } | DeltaOutcome::Superseded => (),
+                _ => request.accepted = false,
+            }
+            request.landing == 0
+        };
+        if finished {
+            let request = self.requests.remove(&key).unwrap();
+            let reply = EditReply {
+                request: request.request,
+                accepted: request.accepted,
+            };
+            self.finished.push((request.client, reply));
+        }
+    }
+
+    /// Turn down the edits `client` asked for without making any of them; e.g. because it isn't allowed to.
+    pub fn reject<V: Voxel>(&mut self, client: Entity, request: &VoxelEdits<V>) {
+        let reply = EditReply {
+            request: request.sequence,
+            accepted: false,
+        };
+        self.finished.push((client, reply));
+    }
+
+    /// The replies to send since this was last called, and who to send them to, oldest first.
+    pub fn take_replies(&mut self) -> Vec<(Entity, EditReply)> {
+        mem::replace(&mut self.finished, Vec::new())
+    }
+}
+
+/// Feeds applied edits into the `EditReplies`. Should run after the `ChunkDeltaSystem`.
+#[derive(Default)]
+pub struct EditReplySystem<V: Voxel> {
+    reader: Option<ReaderId<DeltaResult<V>>>,
+}
+impl<V: Voxel> EditReplySystem<V> {
+    pub fn new() -> Self {
+        EditReplySystem { reader: None }
+    }
+}
+impl<'a, V: Voxel> System<'a> for EditReplySystem<V> {
+    type SystemData = (Read<'a, EventChannel<DeltaResult<V>>>, Write<'a, EditReplies>);
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<DeltaResult<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (results, mut replies): Self::SystemData) {
+        for result in results.read(self.reader.as_mut().unwrap()) {
+            replies.record_result(result);
+        }
+    }
+}
+
+/// Encode `chunk` as `persist::encode_chunk` does, compressed with `codec`, and split it into fragments of at most
+/// `mtu` bytes, to be sent separately and put back together by a `ChunkReassembler`; see the module docs.
+pub fn encode_chunk_for_net<V: VoxelId>(chunk: &Chunk<V>, codec: Codec, mtu: usize) -> io::Result<Vec<Vec<u8>>> {
+    assert!(mtu > FRAGMENT_HEADER, "MTU too small to fit a chunk fragment");
+    let encoded = encode_chunk(chunk, codec)?;
+    let id = checksum(&encoded);
+    let parts: Vec<_> = encoded.chunks(mtu - FRAGMENT_HEADER).collect();
+    if parts.len() > u16::max_value() as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "chunk needs too many fragments"));
+    }
+    let fragments = parts
+        .iter()
+        .enumerate()
+        .map(|(index, part)| {
+            let mut bytes = Vec::with_capacity(FRAGMENT_HEADER + part.len());
+            bytes.push(VERSION);
+            bytes.push(CHUNK);
+            for i in 0..3 {
+                put_u16(&mut bytes, chunk.coord[i] as u16);
+            }
+            put_u32(&mut bytes, id);
+            put_u16(&mut bytes, index as u16);
+            put_u16(&mut bytes, parts.len() as u16);
+            bytes.extend_from_slice(part);
+            bytes
+        })
+        .collect();
+    Ok(fragments)
+}
+
+/// Tell a client to drop the chunks at `chunk_coords`, e.g. the ones `ChunkInterest::take_evicted` returns, in
+/// messages of at most `mtu` bytes; see the module docs.
+pub fn encode_evictions(chunk_coords: &[VoxelCoord], mtu: usize) -> Vec<Vec<u8>> {
+    let per_message = (mtu.saturating_sub(4) / 6).max(1).min(u16::max_value() as usize);
+    chunk_coords
+        .chunks(per_message)
+        .map(|chunk_coords| {
+            let mut bytes = vec![VERSION, EVICTION];
+            put_u16(&mut bytes, chunk_coords.len() as u16);
+            for chunk_coord in chunk_coords {
+                for i in 0..3 {
+                    put_u16(&mut bytes, chunk_coord[i] as u16);
+                }
+            }
+            bytes
+        })
+        .collect()
+}
+
+/// Read a message written by `encode_evictions`: the chunks to drop.
+pub fn decode_eviction(bytes: &[u8]) -> io::Result<Vec<VoxelCoord>> {
+    let mut reader = Reader::new(bytes);
+    header(&mut reader, EVICTION)?;
+    let count = reader.u16()?;
+    let mut chunk_coords = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let mut c = [0; 3];
+        for c in &mut c {
+            *c = reader.u16()? as i16;
+        }
+        let chunk_coord = VoxelCoord::new(c[0], c[1], c[2]);
+        if canonicalize_chunk(chunk_coord) != chunk_coord {
+            return Err(invalid(format!("{:?} isn't a chunk's coordinates", chunk_coord)));
+        }
+        chunk_coords.push(chunk_coord);
+    }
+    if !reader.rest().is_empty() {
+        return Err(invalid("too many chunks"));
+    }
+    Ok(chunk_coords)
+}
+
+/// Decode a chunk from all of the fragments `encode_chunk_for_net` split it into, in any order.
+pub fn decode_chunk_for_net<V: VoxelId>(fragments: &[Vec<u8>]) -> io::Result<Chunk<V>> {
+    let mut reassembler = ChunkReassembler::new();
+    for fragment in fragments {
+        reassembler.receive(fragment)?;
+    }
+    reassembler
+        .complete
+        .pop()
+        .ok_or_else(|| invalid("chunk is missing fragments"))
+}
+
+/// A chunk that's only partly arrived.
+struct Partial {
+    id: u32,
+    fragments: Vec<Option<Vec<u8>>>,
+    missing: usize,
+}
+impl Partial {
+    fn new(id: u32, count: usize) -> Self {
+        Partial {
+            id,
+            fragments: vec![None; count],
+            missing: count,
+        }
+    }
+}
+
+/// Puts chunks sent with `encode_chunk_for_net` back together as their fragments arrive. Clients should add one as
+/// a resource, `receive` every chunk fragment into it, and run a `ChunkReceiveSystem` to add the chunks to the
+/// world.
+///
+/// If a chunk's sent again before the last copy of it has all arrived, the new copy's fragments replace the old
+/// ones, so a lost fragment only holds up the one copy. A chunk that's never finished is kept until it's
+/// `forget`-ten.
+#[derive(Default)]
+pub struct ChunkReassembler<V: Voxel> {
+    partial: FnvHashMap<VoxelCoord, Partial>,
+    complete: Vec<Chunk<V>>,
+}
+impl<V: Voxel> ChunkReassembler<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// How many chunks have been started on and not finished.
+    pub fn pending(&self) -> usize {
+        self.partial.len()
+    }
+
+    /// Stop waiting for the rest of the chunk at `chunk_coord`, e.g. because the player's moved away from it.
+    pub fn forget(&mut self, chunk_coord: VoxelCoord) {
+        self.partial.remove(&chunk_coord);
+    }
+
+    /// The chunks that have been put back together since this was last called, oldest first.
+    pub fn take_complete(&mut self) -> Vec<Chunk<V>> {
+        mem::replace(&mut self.complete, Vec::new())
+    }
+}
+impl<V: VoxelId> ChunkReassembler<V> {
+    /// Take in one fragment, returning the coordinates of its chunk if that was the last fragment it needed. Fails if
+    /// the fragment's malformed, or finishes a chunk that can't be decoded.
+    pub fn receive(&mut self, fragment: &[u8]) -> io::Result<Option<VoxelCoord>> {
+        let mut reader = Reader::new(fragment);
+        header(&mut reader, CHUNK)?;
+        let coord = VoxelCoord::new(reader.u16()? as i16, reader.u16()? as i16, reader.u16()? as i16);
+        if canonicalize_chunk(coord) != coord {
+            return Err(invalid(format!("{:?} isn't a chunk's coordinates", coord)));
+        }
+        let id = reader.u32()?;
+        let (index, count) = (reader.u16()? as usize, reader.u16()? as usize);
+        if index >= count {
+            return Err(invalid("fragment index out of range"));
+        }
+
+        let finished = {
+            let partial = self.partial
+                .entry(coord)
+                .or_insert_with(|| Partial::new(id, count));
+            if partial.id != id || partial.fragments.len() != count {
+                *partial = Partial::new(id, count);
+            }
+            if partial.fragments[index].is_none() {
+                partial.missing -= 1;
+            }
+            partial.fragments[index] = Some(reader.rest().to_vec());
+            partial.missing == 0
+        };
+        if !finished {
+            return Ok(None);
+        }
+        let partial = self.partial.remove(&coord).unwrap();
+        let encoded: Vec<u8> = partial
+            .fragments
+            .into_iter()
+            .flat_map(|fragment| fragment.unwrap())
+            .collect();
+        if checksum(&encoded) != id {
+            return Err(invalid("chunk fragments don't match their checksum"));
+        }
+        self.complete.push(decode_chunk(coord, &encoded)?);
+        Ok(Some(coord))
+    }
+}
+
+/// 32-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes
+        .iter()
+        .fold(0x811c_9dc5, |hash, &byte| (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193))
+}
+
+/// `from` moved by `by`, if it's still a voxel coordinate.
+fn offset(from: i16, by: i32) -> io::Result<i16> {
+    let to = i32::from(from) + by;
+    if to < i32::from(i16::min_value()) || to > i32::from(i16::max_value()) {
+        return Err(invalid("edit out of bounds"));
+    }
+    Ok(to as i16)
+}
+
+/// Check the message is one of ours, of the given kind.
+fn header(reader: &mut Reader, kind: u8) -> io::Result<()> {
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(invalid(format!("unsupported message version {}", version)));
+    }
+    let found = reader.u8()?;
+    if found != kind {
+        return Err(invalid(format!("expected message kind {}, got {}", kind, found)));
+    }
+    Ok(())
+}
+
+/// Publishes a `VoxelEdits` each frame that voxels change, with everything that changed that frame apart from
+/// edits received over the network (see `SOURCE`) and `EditPredictions`, which are sent as requests, for the game
+/// to send on.
+///
+/// Should run after the `ChunkDeltaSystem`.
+#[derive(Default)]
+pub struct EditCaptureSystem<V: Voxel> {
+    reader: Option<ReaderId<VoxelChanged<V>>>,
+    sequence: u32,
+}
+impl<V: Voxel> EditCaptureSystem<V> {
+    pub fn new() -> Self {
+        EditCaptureSystem {
+            reader: None,
+            sequence: 0,
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for EditCaptureSystem<V> {
+    type SystemData = (
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        Write<'a, EventChannel<VoxelEdits<V>>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (changes, mut edits): Self::SystemData) {
+        let local = changes
+            .read(self.reader.as_mut().unwrap())
+            .filter(|change| change.source != SOURCE && change.source != PREDICTED);
+        let captured = VoxelEdits::from_changes(self.sequence, local);
+        if !captured.is_empty() {
+            self.sequence = self.sequence.wrapping_add(1);
+            edits.single_write(captured);
+        }
+    }
+}
+
+/// Adds the chunks a `ChunkReassembler` has finished to the world, with `ChunkTracker::insert_chunk`; chunks that
+/// are already there are replaced, e.g. when the server sends one again to fix it up.
+///
+/// Should run before the `ChunkTrackerSystem`, so that the tracker finds the new chunks straight away.
+#[derive(Default)]
+pub struct ChunkReceiveSystem<V: Voxel> {
+    _phantom: PhantomData<V>,
+}
+impl<V: Voxel> ChunkReceiveSystem<V> {
+    pub fn new() -> Self {
+        ChunkReceiveSystem { _phantom: PhantomData }
+    }
+}
+impl<'a, V: Voxel> System<'a> for ChunkReceiveSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        Write<'a, ChunkReassembler<V>>,
+        WriteStorage<'a, Chunk<V>>,
+        WriteStorage<'a, GlobalTransform>,
+    );
+
+    fn run(&mut self, (entities, tracker, mut reassembler, mut chunks, mut transforms): Self::SystemData) {
+        // (only the latest copy of each, since the tracker won't know about any inserted here until it next runs)
+        let mut latest = FnvHashMap::default();
+        for chunk in reassembler.take_complete() {
+            latest.insert(chunk.coord, chunk);
+        }
+        for (_, chunk) in latest {
+            match tracker.get_chunk_ent(chunk.coord) {
+                Some(ent) => *chunks.get_mut(ent).unwrap() = chunk,
+                None => {
+                    tracker.insert_chunk(&entities, &mut chunks, &mut transforms, chunk);
+                }
+            }
+        }
+    }
+}
+
+/// What's known about when a chunk's voxels changed.
+#[derive(Debug)]
+struct ChunkVersion {
+    /// The tick changes are known from: when the chunk was loaded, or forgotten about before.
+    since: u64,
+    /// The tick the chunk last changed on.
+    changed: u64,
+    /// The tick each voxel last changed on, if it's changed since `since`.
+    voxels: FnvHashMap<VoxelCoord, u64>,
+}
+
+/// The tick each chunk, and each voxel in it, last changed on; kept up to date by the `ChunkVersionSystem`. See
+/// the module docs.
+#[derive(Debug, Default)]
+pub struct ChunkVersions {
+    tick: u64,
+    chunks: FnvHashMap<VoxelCoord, ChunkVersion>,
+}
+impl ChunkVersions {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// The current tick. Something sent now is up to date as of this tick.
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    /// The tick the chunk containing `coord` last changed on (or was loaded on), if it's loaded.
+    pub fn changed(&self, coord: VoxelCoord) -> Option<u64> {
+        self.chunks
+            .get(&canonicalize_chunk(coord))
+            .map(|version| version.changed)
+    }
+
+    /// The voxels in the chunk at `chunk_coord` that have changed since `tick`, as they are in `chunks`, sorted as
+    /// in `VoxelEdits`. None if that isn't known, because the chunk's been loaded since then (or isn't loaded), or
+    /// `forget_before` has forgotten about it; send the whole chunk instead.
+    pub fn changes_since<V: Voxel, C: ChunkAccess<V>>(
+        &self,
+        chunks: &C,
+        chunk_coord: VoxelCoord,
+        tick: u64,
+    ) -> Option<Vec<(VoxelCoord, V)>> {
+        let version = self.chunks.get(&chunk_coord)?;
+        if tick < version.since {
+            return None;
+        }
+        let chunk = chunks.get_chunk(chunk_coord)?;
+        let mut changes: Vec<_> = version
+            .voxels
+            .iter()
+            .filter(|&(_, &changed)| changed > tick)
+            .map(|(&coord, _)| (coord, chunk[coord - chunk_coord]))
+            .collect();
+        changes.sort_by_key(|&(coord, _)| (coord.x, coord.y, coord.z));
+        Some(changes)
+    }
+
+    /// Forget about changes before `tick`, to save memory, e.g. once every client is up to date as of then; clients
+    /// further behind are sent whole chunks.
+    pub fn forget_before(&mut self, tick: u64) {
+        for version in self.chunks.values_mut() {
+            if version.since < tick {
+                version.since = tick;
+                version.voxels.retain(|_, &mut changed| changed > tick);
+            }
+        }
+    }
+
+    /// Count a new tick.
+    fn advance(&mut self) {
+        self.tick += 1;
+    }
+
+    /// Record a chunk being loaded (or replaced) this tick, which changes all of it.
+    fn loaded(&mut self, chunk_coord: VoxelCoord) {
+        let version = ChunkVersion {
+            since: self.tick,
+            changed: self.tick,
+            voxels: FnvHashMap::default(),
+        };
+        self.chunks.insert(chunk_coord, version);
+    }
+
+    /// Forget about a chunk that's been unloaded.
+    fn unloaded(&mut self, chunk_coord: VoxelCoord) {
+        self.chunks.remove(&chunk_coord);
+    }
+
+    /// Record the voxel at `coord` changing this tick.
+    fn voxel_changed(&mut self, coord: VoxelCoord) {
+        let tick = self.tick;
+        if let Some(version) = self.chunks.get_mut(&canonicalize_chunk(coord)) {
+            version.changed = tick;
+            version.voxels.insert(coord, tick);
+        }
+    }
+}
+
+/// Counts ticks, and records chunks being loaded and unloaded and voxels changing in the `ChunkVersions`.
+///
+/// Should run after the `ChunkDeltaSystem`, and before anything that sends clients what's changed.
+#[derive(Default)]
+pub struct ChunkVersionSystem<V: Voxel> {
+    ids: Option<(ReaderId<InsertedFlag>, ReaderId<RemovedFlag>)>,
+    reader: Option<ReaderId<VoxelChanged<V>>>,
+    /// The coordinate of each loaded chunk, by entity index; removed chunks are gone by the time they're read.
+    coords: FnvHashMap<Index, VoxelCoord>,
+}
+impl<V: Voxel> ChunkVersionSystem<V> {
+    pub fn new() -> Self {
+        ChunkVersionSystem {
+            ids: None,
+            reader: None,
+            coords: FnvHashMap::default(),
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for ChunkVersionSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, Chunk<V>>,
+        Read<'a, EventChannel<VoxelChanged<V>>>,
+        Write<'a, ChunkVersions>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        want_fill_changes::<V>(resources);
+        let mut chunks = WriteStorage::<Chunk<V>>::fetch(resources);
+        self.ids = Some((chunks.track_inserted(), chunks.track_removed()));
+        self.reader = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelChanged<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, (entities, chunks, changes, mut versions): Self::SystemData) {
+        versions.advance();
+        let &mut (ref mut inserted_ids, ref mut removed_ids) = self.ids.as_mut().unwrap();
+        // (removals first, so a chunk unloaded and loaded again in the same frame is kept)
+        for removed in chunks.removed().read(removed_ids) {
+            if let Some(coord) = self.coords.remove(&**removed) {
+                versions.unloaded(coord);
+            }
+        }
+        for inserted in chunks.inserted().read(inserted_ids) {
+            if let Some(chunk) = chunks.get(entities.entity(**inserted)) {
+                self.coords.insert(**inserted, chunk.coord);
+                versions.loaded(chunk.coord);
+            }
+        }
+        for change in changes.read(self.reader.as_mut().unwrap()) {
+            versions.voxel_changed(change.coord);
+        }
+    }
+}
+
+/// Which chunks a connected client has been sent, and which it should be sent next, for an entity with a
+/// `GlobalTransform` (usually the client's player); kept up to date by the `ChunkInterestSystem`.
+///
+/// Chunks are sent nearest first, and only once they're loaded. A chunk the client has been sent is only evicted
+/// once it's more than a chunk further away than `radius`, so that a client walking back and forth over a chunk
+/// border isn't sent the same chunks over and over.
+#[derive(Clone, Debug)]
+pub struct ChunkInterest {
+    /// How far the client can see, in chunks.
+    pub radius: u16,
+    /// The chunk and radius `wanted` was worked out for.
+    center: Option<(VoxelCoord, u16)>,
+    /// The chunks in range, nearest first.
+    wanted: Vec<VoxelCoord>,
+    sent: FnvHashSet<VoxelCoord>,
+    /// Loaded chunks in range that haven't been sent, nearest last.
+    send: Vec<VoxelCoord>,
+    evict: Vec<VoxelCoord>,
+}
+impl ChunkInterest {
+    pub fn new(radius: u16) -> Self {
+        ChunkInterest {
+            radius,
+            center: None,
+            wanted: Vec::new(),
+            sent: FnvHashSet::default(),
+            send: Vec::new(),
+            evict: Vec::new(),
+        }
+    }
+
+    /// The nearest chunk the client should be sent, which is counted as sent from now on; None if it's up to date.
+    pub fn pop_send(&mut self) -> Option<VoxelCoord> {
+        let chunk = self.send.pop()?;
+        self.sent.insert(chunk);
+        Some(chunk)
+    }
+
+    /// How many chunks the client should be sent.
+    pub fn pending_sends(&self) -> usize {
+        self.send.len()
+    }
+
+    /// The chunks the client has left behind since this was last called, which it should be told to drop. They're
+    /// counted as not sent, so they'll be sent again if the client comes back.
+    pub fn take_evicted(&mut self) -> Vec<VoxelCoord> {
+        mem::replace(&mut self.evict, Vec::new())
+    }
+
+    /// Whether the client has been sent the chunk containing `coord`, and so should be sent edits to it.
+    pub fn has_chunk(&self, coord: VoxelCoord) -> bool {
+        self.sent.contains(&canonicalize_chunk(coord))
+    }
+
+    /// Work out what to send and evict for a client in the chunk at `center`.
+    fn update<F: Fn(VoxelCoord) -> bool>(&mut self, center: VoxelCoord, is_loaded: F) {
+        if self.center != Some((center, self.radius)) {
+            self.center = Some((center, self.radius));
+            let mut wanted: Vec<_> = chunks_in_radius(center, self.radius).collect();
+            // (ties broken by coordinate, so the order doesn't depend on anything else)
+            wanted.sort_by_key(|&(distance, chunk)| (distance, chunk.x, chunk.y, chunk.z));
+            self.wanted = wanted.into_iter().map(|(_, chunk)| chunk).collect();
+
+            let keep = (i32::from(self.radius) + 1).pow(2);
+            let size = CHUNK_SIZE as i32;
+            let evict = &mut self.evict;
+            self.sent.retain(|&chunk| {
+                let offset = (chunk.cast::<i32>().unwrap() - center.cast::<i32>().unwrap()) / size;
+                let kept = offset.x * offset.x + offset.y * offset.y + offset.z * offset.z <= keep;
+                if !kept {
+                    evict.push(chunk);
+                }
+                kept
+            });
+        }
+        let sent = &self.sent;
+        self.send = self.wanted
+            .iter()
+            .rev()
+            .cloned()
+            .filter(|&chunk| !sent.contains(&chunk) && is_loaded(chunk))
+            .collect();
+    }
+}
+impl Component for ChunkInterest {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// Keeps `ChunkInterest`s up to date with where their entities are and which chunks are loaded.
+///
+/// Should run after the `ChunkTrackerSystem`, and before whatever sends clients their chunks.
+#[derive(Default)]
+pub struct ChunkInterestSystem;
+impl<'a> System<'a> for ChunkInterestSystem {
+    type SystemData = (
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, ChunkInterest>,
+    );
+
+    fn run(&mut self, (tracker, transforms, mut interests): Self::SystemData) {
+        for (transform, interest) in (&transforms, &mut interests).join() {
+            let position = transform.0.w;
+            let center = canonicalize_chunk(canonicalize(Coord::new(position.x, position.y, position.z)));
+            interest.update(center, |chunk| tracker.get_chunk_ent(chunk).is_some());
+        }
+    }
+}
+
+/// How urgently a message needs to go out; see `SendQueue`. Higher priorities are sent first.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum SendPriority {
+    /// Chunk fragments, which a client can wait a few frames for.
+    Chunks,
+    /// Voxel edits, which players see as lag.
+    Edits,
+    /// Replies to edit requests, which players see as lag, and which are tiny.
+    Replies,
+}
+
+/// A message waiting in a `SendQueue`.
+#[derive(Debug)]
+struct Queued {
+    priority: SendPriority,
+    /// The order it was queued in.
+    order: u64,
+    bytes: Vec<u8>,
+}
+impl Queued {
+    fn key(&self) -> (SendPriority, Reverse<u64>) {
+        (self.priority, Reverse(self.order))
+    }
+}
+impl PartialEq for Queued {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+impl Eq for Queued {}
+impl PartialOrd for Queued {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Queued {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.key().cmp(&other.key())
+    }
+}
+
+/// Messages waiting to be sent to a client, paced to `bytes_per_frame`, as a `TimeLimiter` paces work to a time
+/// budget. Each frame, `frame` takes the most urgent messages (the oldest first, within a priority) until the
+/// frame's budget runs out.
+///
+/// The message that uses up a frame's budget goes out whole, so a frame can go over by up to one message; later
+/// frames make up for it. So a message bigger than a frame's budget still goes out, a few frames later.
+#[derive(Debug)]
+pub struct SendQueue {
+    pub bytes_per_frame: usize,
+    /// The most budget a frame can bank for later frames when there isn't enough to send; zero (the default) to
+    /// turn carrying over off.
+    pub carry_over: usize,
+    /// How many bytes can still be sent; negative after a frame goes over.
+    credit: i64,
+    queue: BinaryHeap<Queued>,
+    queued_bytes: usize,
+    next_order: u64,
+}
+impl SendQueue {
+    pub fn new(bytes_per_frame: usize) -> Self {
+        SendQueue {
+            bytes_per_frame,
+            carry_over: 0,
+            credit: 0,
+            queue: BinaryHeap::new(),
+            queued_bytes: 0,
+            next_order: 0,
+        }
+    }
+
+    pub fn push(&mut self, priority: SendPriority, message: Vec<u8>) {
+        self.queued_bytes += message.len();
+        self.queue.push(Queued {
+            priority,
+            order: self.next_order,
+            bytes: message,
+        });
+        self.next_order += 1;
+    }
+
+    /// How many messages are waiting.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// How many bytes are waiting.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued_bytes
+    }
+
+    /// Whether everything waiting would go out next frame; i.e. whether to queue more of something that can wait,
+    /// so that e.g. chunks are only encoded shortly before they're sent. (See `ChunkInterest::pop_send`.)
+    pub fn has_room(&self) -> bool {
+        (self.queued_bytes as i64) < self.credit + self.bytes_per_frame as i64
+    }
+
+    /// Start a new frame, and take the messages to send in it, in the order to send them.
+    pub fn frame(&mut self) -> Vec<Vec<u8>> {
+        let limit = (self.bytes_per_frame + self.carry_over) as i64;
+        self.credit = (self.credit + self.bytes_per_frame as i64).min(limit);
+        let mut messages = Vec::new();
+        while self.credit > 0 {
+            let message = match self.queue.pop() {
+                Some(queued) => queued.bytes,
+                None => break,
+            };
+            self.credit -= message.len() as i64;
+            self.queued_bytes -= message.len();
+            messages.push(message);
+        }
+        messages
+    }
+}
+impl Component for SendQueue {
+    type Storage = HashMapStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {voxels_in_box, TestVoxel};
+
+    #[test]
+    fn encode_decode() {
+        let change = |x, y, z, new| VoxelChanged {
+            id: DeltaId(0),
+            source: DeltaSource::Unknown,
+            coord: VoxelCoord::new(x, y, z),
+            old: TestVoxel::Air,
+            new,
+        };
+        let changes = vec![
+            change(5, 2, 5, TestVoxel::Rock),
+            change(-32768, 32767, 0, TestVoxel::Grass),
+            change(4, 2, 5, TestVoxel::Rock),
+            change(5, 2, 5, TestVoxel::Air),
+        ];
+        let edits = VoxelEdits::from_changes(7, &changes);
+        assert_eq!(
+            edits.edits,
+            vec![
+                (VoxelCoord::new(-32768, 32767, 0), TestVoxel::Grass),
+                (VoxelCoord::new(4, 2, 5), TestVoxel::Rock),
+                (VoxelCoord::new(5, 2, 5), TestVoxel::Air),
+            ]
+        );
+
+        let bytes = edits.encode();
+        assert_eq!(VoxelEdits::decode(&bytes).unwrap(), edits);
+        // a byte per axis for edits next to each other, and no bits at all for voxels that are all the same
+        let nearby = VoxelEdits {
+            sequence: 0,
+            edits: (0..10).map(|x| (VoxelCoord::new(x, 0, 0), TestVoxel::Rock)).collect(),
+        };
+        assert_eq!(nearby.encode().len(), 2 + 8 + 10 * 3 + 2 + 6 + 1);
+
+        assert!(VoxelEdits::<TestVoxel>::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut wrong_kind = bytes.clone();
+        wrong_kind[1] = 9;
+        assert!(VoxelEdits::<TestVoxel>::decode(&wrong_kind).is_err());
+        assert!(MessageKind::of(&wrong_kind).is_err());
+        assert_eq!(MessageKind::of(&bytes).unwrap(), MessageKind::Edits);
+        let empty = VoxelEdits::<TestVoxel>::default();
+        assert_eq!(VoxelEdits::decode(&empty.encode()).unwrap(), empty);
+    }
+
+    #[test]
+    fn predictions() {
+        let applied = |id, coord, old, new| DeltaResult {
+            id,
+            source: PREDICTED,
+            coord,
+            outcome: DeltaOutcome::Applied { old, new },
+        };
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let mut predictions = EditPredictions::<TestVoxel>::new();
+        let (a, b) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(1, 0, 0));
+
+        // the first request's accepted, and the second's turned down after its first edit lands
+        let first = predictions.predict(&deltas, &[(a, TestVoxel::Rock)]);
+        let second = predictions.predict(&deltas, &[(a, TestVoxel::Grass), (b, TestVoxel::Grass)]);
+        assert_eq!((first.sequence, second.sequence), (0, 1));
+        assert_eq!(predictions.pending(), 2);
+        predictions.record_result(&applied(DeltaId(0), a, TestVoxel::Air, TestVoxel::Rock), &deltas);
+        predictions.record_result(&applied(DeltaId(1), a, TestVoxel::Rock, TestVoxel::Grass), &deltas);
+        predictions.reply(EditReply::decode(&EditReply { request: 0, accepted: true }.encode()).unwrap(), &deltas);
+        assert_eq!(predictions.pending(), 1);
+        predictions.reply(EditReply { request: 1, accepted: false }, &deltas);
+        assert_eq!(deltas.pending_get(a), Some(TestVoxel::Rock));
+        assert_eq!(predictions.pending(), 1);
+        predictions.record_result(&applied(DeltaId(2), b, TestVoxel::Air, TestVoxel::Grass), &deltas);
+        assert_eq!(deltas.pending_get(b), Some(TestVoxel::Air));
+        assert_eq!(predictions.pending(), 0);
+    }
+
+    #[test]
+    fn replies() {
+        let mut world = World::new();
+        let client = world.create_entity().build();
+        let deltas = ChunkDeltas::<TestVoxel>::new();
+        let mut replies = EditReplies::new();
+        let result = |id, outcome| DeltaResult {
+            id,
+            source: DeltaSource::Entity(client),
+            coord: VoxelCoord::new(0, 0, 0),
+            outcome,
+        };
+        let request = |sequence, count| VoxelEdits {
+            sequence,
+            edits: (0..count).map(|x| (VoxelCoord::new(x, 0, 0), TestVoxel::Rock)).collect(),
+        };
+
+        replies.apply(client, &request(3, 2), &deltas);
+        replies.apply(client, &request(4, 1), &deltas);
+        replies.apply(client, &request(5, 0), &deltas);
+        let landed = DeltaOutcome::Applied {
+            old: TestVoxel::Air,
+            new: TestVoxel::Rock,
+        };
+        replies.record_result(&result(DeltaId(0), landed));
+        replies.record_result(&result(DeltaId(2), DeltaOutcome::NoChunk));
+        assert_eq!(
+            replies.take_replies(),
+            vec![
+                (client, EditReply { request: 5, accepted: true }),
+                (client, EditReply { request: 4, accepted: false }),
+            ]
+        );
+        replies.record_result(&result(DeltaId(1), landed));
+        assert_eq!(replies.take_replies(), vec![(client, EditReply { request: 3, accepted: true })]);
+    }
+
+    #[test]
+    fn versions() {
+        let mut chunks = FnvHashMap::default();
+        let at = |x| VoxelCoord::new(x, 2, 3);
+        chunks.insert(VoxelCoord::new(0, 0, 0), Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0)));
+        let mut versions = ChunkVersions::new();
+        let origin = VoxelCoord::new(0, 0, 0);
+
+        versions.advance();
+        versions.loaded(origin);
+        assert_eq!(versions.changes_since(&chunks, origin, 0), None);
+        assert_eq!(versions.changes_since(&chunks, origin, 1), Some(vec![]));
+        for (tick, &x) in [4, 1, 4].iter().enumerate() {
+            versions.advance();
+            chunks.get_mut(&origin).unwrap()[at(x)] = if tick == 2 { TestVoxel::Grass } else { TestVoxel::Rock };
+            versions.voxel_changed(at(x));
+        }
+        assert_eq!(versions.tick(), 4);
+        assert_eq!(versions.changed(at(5)), Some(4));
+        assert_eq!(
+            versions.changes_since(&chunks, origin, 2),
+            Some(vec![(at(1), TestVoxel::Rock), (at(4), TestVoxel::Grass)])
+        );
+        assert_eq!(versions.changes_since(&chunks, origin, 3), Some(vec![(at(4), TestVoxel::Grass)]));
+
+        versions.forget_before(3);
+        assert_eq!(versions.changes_since(&chunks, origin, 2), None);
+        assert_eq!(versions.changes_since(&chunks, origin, 3), Some(vec![(at(4), TestVoxel::Grass)]));
+        assert_eq!(versions.changes_since(&chunks, VoxelCoord::new(16, 0, 0), 3), None);
+    }
+
+    #[test]
+    fn version_system() {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkVersionSystem::<TestVoxel>::new(), "chunk_versions", &[])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        let origin = VoxelCoord::new(0, 0, 0);
+        let chunk = world.create_entity().with(Chunk::<TestVoxel>::empty(origin)).build();
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(world.read_resource::<ChunkVersions>().changed(origin), Some(1));
+
+        // unloaded chunks are forgotten
+        world.delete_entity(chunk).unwrap();
+        world.maintain();
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(world.read_resource::<ChunkVersions>().changed(origin), None);
+    }
+
+    #[test]
+    fn chunk_fragments() {
+        let local = || voxels_in_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(1, 1, 1) * (CHUNK_SIZE as i16 - 1));
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(-16, 32, 0));
+        for (i, coord) in local().enumerate() {
+            if i % 3 == 0 {
+                chunk[coord] = TestVoxel::Rock;
+            }
+        }
+        let fragments = encode_chunk_for_net(&chunk, Codec::None, 100).unwrap();
+        assert!(fragments.len() > 2);
+        assert!(fragments.iter().all(|fragment| fragment.len() <= 100));
+        let decoded = decode_chunk_for_net::<TestVoxel>(&fragments).unwrap();
+        assert_eq!(decoded.coord, chunk.coord);
+        assert!(local().all(|coord| decoded[coord] == chunk[coord]));
+        assert!(decode_chunk_for_net::<TestVoxel>(&fragments[1..]).is_err());
+
+        // out of order, with a copy of another version of the chunk getting in the way
+        let mut changed = Chunk::<TestVoxel>::empty(chunk.coord);
+        changed[VoxelCoord::new(1, 2, 3)] = TestVoxel::Grass;
+        let other = encode_chunk_for_net(&changed, Codec::None, 100).unwrap();
+        let mut reassembler = ChunkReassembler::<TestVoxel>::new();
+        for fragment in fragments.iter().rev().skip(1) {
+            assert_eq!(reassembler.receive(fragment).unwrap(), None);
+        }
+        assert_eq!(reassembler.pending(), 1);
+        for fragment in &other {
+            reassembler.receive(fragment).unwrap();
+        }
+        assert_eq!(reassembler.pending(), 0);
+        assert_eq!(reassembler.receive(&fragments[0]).unwrap(), None);
+        let complete = reassembler.take_complete();
+        assert_eq!(complete.len(), 1);
+        assert_eq!(complete[0][VoxelCoord::new(1, 2, 3)], TestVoxel::Grass);
+        reassembler.forget(chunk.coord);
+        assert_eq!(reassembler.pending(), 0);
+
+        let mut bad = fragments[0].clone();
+        bad[2] = 1;
+        assert!(reassembler.receive(&bad).is_err());
+    }
+
+    #[test]
+    fn evictions() {
+        let chunk_coords: Vec<_> = (0..100).map(|x| VoxelCoord::new(x, -1, 2) * CHUNK_SIZE as i16).collect();
+        let messages = encode_evictions(&chunk_coords, 100);
+        assert_eq!(messages.len(), 7);
+        assert!(messages.iter().all(|message| message.len() <= 100));
+        assert_eq!(MessageKind::of(&messages[0]).unwrap(), MessageKind::Eviction);
+        let decoded: Vec<_> = messages
+            .iter()
+            .flat_map(|message| decode_eviction(message).unwrap())
+            .collect();
+        assert_eq!(decoded, chunk_coords);
+        assert!(encode_evictions(&[], 100).is_empty());
+
+        assert!(decode_eviction(&messages[0][..messages[0].len() - 1]).is_err());
+        let mut bad = messages[6].clone();
+        bad[4] = 1;
+        assert!(decode_eviction(&bad).is_err());
+    }
+
+    #[test]
+    fn interest() {
+        let chunk = |x, y, z| VoxelCoord::new(x, y, z) * CHUNK_SIZE as i16;
+        let mut interest = ChunkInterest::new(1);
+        // everything but the chunk above is loaded
+        interest.update(chunk(0, 0, 0), |coord| coord != chunk(0, 1, 0));
+        assert_eq!(interest.pending_sends(), 6);
+        assert_eq!(interest.pop_send(), Some(chunk(0, 0, 0)));
+        let mut sent = Vec::new();
+        while let Some(coord) = interest.pop_send() {
+            sent.push(coord);
+        }
+        sent.sort_by_key(|coord| (coord.x, coord.y, coord.z));
+        assert_eq!(sent, vec![chunk(-1, 0, 0), chunk(0, -1, 0), chunk(0, 0, -1), chunk(0, 0, 1), chunk(1, 0, 0)]);
+        assert!(interest.has_chunk(VoxelCoord::new(20, 3, 4)));
+        assert!(!interest.has_chunk(chunk(0, 1, 0)));
+
+        // the chunk above loads
+        interest.update(chunk(0, 0, 0), |_| true);
+        assert_eq!(interest.pop_send(), Some(chunk(0, 1, 0)));
+        assert_eq!(interest.pop_send(), None);
+
+        // a chunk along, the ones behind are only just out of range, and kept; another, and they're evicted
+        interest.update(chunk(1, 0, 0), |_| true);
+        assert!(interest.take_evicted().is_empty());
+        assert_eq!(interest.pending_sends(), 5);
+        interest.update(chunk(2, 0, 0), |_| true);
+        let mut evicted = interest.take_evicted();
+        evicted.sort_by_key(|coord| (coord.x, coord.y, coord.z));
+        assert_eq!(
+            evicted,
+            vec![chunk(-1, 0, 0), chunk(0, -1, 0), chunk(0, 0, -1), chunk(0, 0, 1), chunk(0, 1, 0)]
+        );
+        assert!(interest.has_chunk(chunk(0, 0, 0)));
+        assert!(!interest.has_chunk(chunk(0, 1, 0)));
+        assert!(interest.take_evicted().is_empty());
+
+        // across the edge of the world, where the difference doesn't fit in an i16
+        let mut interest = ChunkInterest::new(1);
+        interest.update(chunk(-2048, 0, 0), |_| true);
+        while interest.pop_send().is_some() {}
+        interest.update(chunk(2047, 0, 0), |_| true);
+        assert_eq!(interest.take_evicted().len(), 6);
+        assert!(!interest.has_chunk(chunk(-2048, 0, 0)));
+    }
+
+    #[test]
+    fn send_queue() {
+        let mut queue = SendQueue::new(100);
+        let message = |tag: u8, length| vec![tag; length];
+        queue.push(SendPriority::Chunks, message(0, 60));
+        queue.push(SendPriority::Chunks, message(1, 60));
+        queue.push(SendPriority::Edits, message(2, 30));
+        queue.push(SendPriority::Replies, message(3, 5));
+        assert_eq!(queue.queued_bytes(), 155);
+        assert!(!queue.has_room());
+
+        // the replies and edits, then the chunk fragments, the second of which goes 55 bytes over
+        let tags = |messages: Vec<Vec<u8>>| messages.iter().map(|message| message[0]).collect::<Vec<_>>();
+        assert_eq!(tags(queue.frame()), vec![3, 2, 0, 1]);
+        assert!(queue.is_empty() && queue.has_room());
+        // so the next frame only has 45 bytes
+        for tag in 4..7 {
+            queue.push(SendPriority::Chunks, message(tag, 30));
+        }
+        assert_eq!(tags(queue.frame()), vec![4, 5]);
+        assert_eq!(tags(queue.frame()), vec![6]);
+
+        // without carrying over, quiet frames don't bank anything
+        for _ in 0..3 {
+            assert!(queue.frame().is_empty());
+        }
+        queue.push(SendPriority::Chunks, message(7, 100));
+        queue.push(SendPriority::Chunks, message(8, 100));
+        assert_eq!(tags(queue.frame()), vec![7]);
+        assert_eq!(tags(queue.frame()), vec![8]);
+
+        // with it, they do, up to a point
+        queue.carry_over = 200;
+        for _ in 0..4 {
+            assert!(queue.frame().is_empty());
+        }
+        for tag in 9..13 {
+            queue.push(SendPriority::Chunks, message(tag, 100));
+        }
+        assert_eq!(tags(queue.frame()), vec![9, 10, 11]);
+    }
+}
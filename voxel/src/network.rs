@@ -0,0 +1,578 @@
+//! Sending voxels over `amethyst_network` connections, with the messages in `net`. Only built with the `network`
+//! feature.
+//!
+//! A server adds a `VoxelServerBundle`, and gives each client's connection entity a `ChunkInterest`, a
+//! `SendQueue` and a `GlobalTransform` (e.g. by making it the client's player). Clients are then sent the chunks
+//! around them and the edits made to those chunks, and told to drop the chunks they've left behind. The edits they
+//! ask for are checked with the bundle's `EditValidator` (by default, that they're within `DEFAULT_REACH` of the
+//! client), and made if they're all allowed, and the client's told whether they were.
+//!
+//! A client adds a `VoxelClientBundle`, and a `NetConnection` to the server. Chunks and edits from the server are
+//! put into its world, chunks it's told to drop are deleted, and whatever `VoxelEdits` are written to the
+//! `EventChannel<VoxelEdits<V>>` resource are sent to the server as requests: the ones returned by
+//! `EditPredictions::predict`, say.
+//!
+//! Both bundles include a `ChunkDeltaSystem`, so don't add another.
+
+use super::{Chunk, ChunkTracker, Coord, VoxelCoord, VoxelId};
+use delta::{ChunkDeltas, ChunkDeltaSystem};
+use net::{
+    decode_eviction, encode_chunk_for_net, encode_evictions, ChunkInterest, ChunkInterestSystem, ChunkReassembler,
+    ChunkReceiveSystem, EditCaptureSystem, EditPredictionSystem, EditPredictions, EditReplies, EditReply,
+    EditReplySystem, MessageKind, SendPriority, SendQueue, VoxelEdits, DEFAULT_MTU, SOURCE,
+};
+use persist::Codec;
+
+use amethyst::core::bundle::{Result, SystemBundle};
+use amethyst::core::transform::GlobalTransform;
+use amethyst::shrev::EventChannel;
+use amethyst_network::{NetConnection, NetEvent};
+use cgmath::InnerSpace;
+use fnv::FnvHashMap;
+use specs::prelude::*;
+use std::marker::PhantomData;
+
+/// How far from their `GlobalTransform`s clients can edit voxels, unless the server says otherwise.
+pub const DEFAULT_REACH: f32 = 8.0;
+
+/// Whether a client may make an edit it's asked for, given its entity, where it is (from its `GlobalTransform`,
+/// if it has one), and the edit's coordinate and voxel. A request with any edit the client may not make is turned
+/// down whole.
+pub type EditValidator<V> = Box<dyn Fn(Entity, Option<Coord>, VoxelCoord, &V) -> bool + Send + Sync>;
+
+/// An `EditValidator` that lets clients edit voxels within `reach` of where they are, and nowhere if they aren't
+/// anywhere.
+pub fn within_reach<V: VoxelId>(reach: f32) -> EditValidator<V> {
+    Box::new(move |_: Entity, position: Option<Coord>, coord: VoxelCoord, _: &V| {
+        position.map_or(false, |position| (coord.cast::<f32>().unwrap() - position).magnitude() <= reach)
+    })
+}
+
+/// What the voxel messages go over the connection as.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct VoxelPacket(pub Vec<u8>);
+
+/// The voxel messages that came in over `connection` since `reader` last read it.
+fn received<'a>(
+    connection: &'a NetConnection<VoxelPacket>,
+    reader: &'a mut ReaderId<NetEvent<VoxelPacket>>,
+) -> impl Iterator<Item = &'a [u8]> + 'a {
+    connection
+        .receive_buffer
+        .read(reader)
+        .filter_map(|event| match *event {
+            NetEvent::Custom(VoxelPacket(ref bytes)) => Some(&bytes[..]),
+            _ => None,
+        })
+}
+
+/// Makes the edits clients ask for, if they're allowed to, and sends them their replies, the edits made to chunks
+/// they have, the chunks they should have and the chunks they should drop; see the module docs.
+pub struct VoxelServerSystem<V: VoxelId> {
+    /// How chunks are compressed.
+    pub codec: Codec,
+    /// The most bytes to send in one packet.
+    pub mtu: usize,
+    /// Which edits clients may make.
+    pub validator: EditValidator<V>,
+    edits: Option<ReaderId<VoxelEdits<V>>>,
+    readers: FnvHashMap<Entity, ReaderId<NetEvent<VoxelPacket>>>,
+}
+impl<V: VoxelId> VoxelServerSystem<V> {
+    /// A server letting clients edit voxels within `DEFAULT_REACH` of them.
+    pub fn new(codec: Codec, mtu: usize) -> Self {
+        VoxelServerSystem {
+            codec,
+            mtu,
+            validator: within_reach(DEFAULT_REACH),
+            edits: None,
+            readers: FnvHashMap::default(),
+        }
+    }
+}
+impl<'a, V: VoxelId> System<'a> for VoxelServerSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        Read<'a, ChunkDeltas<V>>,
+        Read<'a, EventChannel<VoxelEdits<V>>>,
+        Write<'a, EditReplies>,
+        ReadStorage<'a, GlobalTransform>,
+        WriteStorage<'a, NetConnection<VoxelPacket>>,
+        WriteStorage<'a, ChunkInterest>,
+        WriteStorage<'a, SendQueue>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        self.edits = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelEdits<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (
+            entities,
+            tracker,
+            chunks,
+            deltas,
+            edits,
+            mut replies,
+            transforms,
+            mut connections,
+            mut interests,
+            mut queues,
+        ) = data;
+        self.readers.retain(|&client, _| entities.is_alive(client));
+        let validator = &self.validator;
+        for (client, connection) in (&*entities, &mut connections).join() {
+            let reader = self.readers
+                .entry(client)
+                .or_insert_with(|| connection.receive_buffer.register_reader());
+            for bytes in received(connection, reader) {
+                let request = match MessageKind::of(bytes) {
+                    Ok(MessageKind::Edits) => VoxelEdits::decode(bytes),
+                    Ok(kind) => {
+                        warn!("client {:?} sent a {:?} message, which only servers send", client, kind);
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                };
+                let request = match request {
+                    Ok(request) => request,
+                    Err(e) => {
+                        warn!("bad message from client {:?}: {}", client, e);
+                        continue;
+                    }
+                };
+                let position = transforms.get(client).map(|transform| transform.0.w.truncate());
+                if request
+                    .edits
+                    .iter()
+                    .all(|&(coord, ref voxel)| validator(client, position, coord, voxel))
+                {
+                    replies.apply(client, &request, &deltas);
+                } else {
+                    debug!("turned down edits from client {:?} it isn't allowed to make", client);
+                    replies.reject(client, &request);
+                }
+            }
+        }
+
+        for (client, reply) in replies.take_replies() {
+            if let Some(queue) = queues.get_mut(client) {
+                queue.push(SendPriority::Replies, reply.encode());
+            }
+        }
+        let edits: Vec<&VoxelEdits<V>> = edits.read(self.edits.as_mut().unwrap()).collect();
+        for (interest, queue) in (&mut interests, &mut queues).join() {
+            for captured in &edits {
+                let visible = VoxelEdits {
+                    sequence: captured.sequence,
+                    edits: captured
+                        .edits
+                        .iter()
+                        .cloned()
+                        .filter(|&(coord, _)| interest.has_chunk(coord))
+                        .collect(),
+                };
+                if !visible.is_empty() {
+                    queue.push(SendPriority::Edits, visible.encode());
+                }
+            }
+            for message in encode_evictions(&interest.take_evicted(), self.mtu) {
+                queue.push(SendPriority::Chunks, message);
+            }
+            while queue.has_room() {
+                let chunk_coord = match interest.pop_send() {
+                    Some(chunk_coord) => chunk_coord,
+                    None => break,
+                };
+                let chunk = match tracker.get_chunk(&chunks, chunk_coord) {
+                    Some(chunk) => chunk,
+                    None => continue,
+                };
+                match encode_chunk_for_net(chunk, self.codec, self.mtu) {
+                    Ok(fragments) => {
+                        for fragment in fragments {
+                            queue.push(SendPriority::Chunks, fragment);
+                        }
+                    }
+                    Err(e) => error!("failed to encode chunk {:?} to send: {}", chunk_coord, e),
+                }
+            }
+        }
+
+        for (connection, queue) in (&mut connections, &mut queues).join() {
+            for message in queue.frame() {
+                connection.send_buffer.single_write(NetEvent::Custom(VoxelPacket(message)));
+            }
+        }
+    }
+}
+
+/// Puts what the server sends into the world, drops the chunks it says to, and sends it edit requests; see the
+/// module docs.
+#[derive(Default)]
+pub struct VoxelClientSystem<V: VoxelId> {
+    requests: Option<ReaderId<VoxelEdits<V>>>,
+    readers: FnvHashMap<Entity, ReaderId<NetEvent<VoxelPacket>>>,
+}
+impl<V: VoxelId> VoxelClientSystem<V> {
+    pub fn new() -> Self {
+        VoxelClientSystem {
+            requests: None,
+            readers: FnvHashMap::default(),
+        }
+    }
+}
+impl<'a, V: VoxelId> System<'a> for VoxelClientSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        ReadExpect<'a, ChunkTracker>,
+        Read<'a, ChunkDeltas<V>>,
+        Read<'a, EventChannel<VoxelEdits<V>>>,
+        Write<'a, ChunkReassembler<V>>,
+        Write<'a, EditPredictions<V>>,
+        WriteStorage<'a, NetConnection<VoxelPacket>>,
+    );
+
+    fn setup(&mut self, resources: &mut Resources) {
+        Self::SystemData::setup(resources);
+        self.requests = Some(
+            resources
+                .fetch_mut::<EventChannel<VoxelEdits<V>>>()
+                .register_reader(),
+        );
+    }
+
+    fn run(
+        &mut self,
+        (entities, tracker, deltas, requests, mut reassembler, mut predictions, mut connections): Self::SystemData,
+    ) {
+        let requests: Vec<Vec<u8>> = requests
+            .read(self.requests.as_mut().unwrap())
+            .map(|request| request.encode())
+            .collect();
+        self.readers.retain(|&server, _| entities.is_alive(server));
+        for (server, connection) in (&*entities, &mut connections).join() {
+            {
+                let reader = self.readers
+                    .entry(server)
+                    .or_insert_with(|| connection.receive_buffer.register_reader());
+                for bytes in received(connection, reader) {
+                    let handled = MessageKind::of(bytes).and_then(|kind| match kind {
+                        MessageKind::ChunkFragment => reassembler.receive(bytes).map(|_| ()),
+                        MessageKind::Edits => VoxelEdits::decode(bytes).map(|edits| {
+                            edits.apply_remote(deltas.writer().source(SOURCE));
+                        }),
+                        MessageKind::Reply => {
+                            EditReply::decode(bytes).map(|reply| predictions.reply(reply, &deltas))
+                        }
+                        MessageKind::Eviction => decode_eviction(bytes).map(|chunk_coords| {
+                            for chunk_coord in chunk_coords {
+                                reassembler.forget(chunk_coord);
+                                if let Some(chunk) = tracker.get_chunk_ent(chunk_coord) {
+                                    if let Err(e) = entities.delete(chunk) {
+                                        warn!("failed to drop chunk {:?}: {}", chunk_coord, e);
+                                    }
+                                }
+                            }
+                        }),
+                    });
+                    if let Err(e) = handled {
+                        warn!("bad message from server {:?}: {}", server, e);
+                    }
+                }
+            }
+            for request in &requests {
+                connection.send_buffer.single_write(NetEvent::Custom(VoxelPacket(request.clone())));
+            }
+        }
+    }
+}
+
+/// Adds the systems a server needs to send voxels to its clients; see the module docs.
+pub struct VoxelServerBundle<V: VoxelId> {
+    codec: Codec,
+    mtu: usize,
+    validator: EditValidator<V>,
+}
+impl<V: VoxelId> VoxelServerBundle<V> {
+    /// Chunks compressed with the default `Codec`, in packets of up to `DEFAULT_MTU` bytes, and edits allowed within
+    /// `DEFAULT_REACH` of each client.
+    pub fn new() -> Self {
+        VoxelServerBundle {
+            codec: Codec::default(),
+            mtu: DEFAULT_MTU,
+            validator: within_reach(DEFAULT_REACH),
+        }
+    }
+
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub fn with_mtu(mut self, mtu: usize) -> Self {
+        self.mtu = mtu;
+        self
+    }
+
+    /// Check the edits clients ask for with `validator` (e.g. for reach and permissions) rather than just for reach.
+    pub fn with_validator(mut self, validator: EditValidator<V>) -> Self {
+        self.validator = validator;
+        self
+    }
+}
+impl<V: VoxelId> Default for VoxelServerBundle<V> {
+    fn default() -> Self {
+        VoxelServerBundle::new()
+    }
+}
+impl<'a, 'b, V: VoxelId> SystemBundle<'a, 'b> for VoxelServerBundle<V> {
+    fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> Result<()> {
+        builder.add(ChunkDeltaSystem::<V>::new(), "voxel_deltas", &[]);
+        builder.add(EditCaptureSystem::<V>::new(), "voxel_edit_capture", &["voxel_deltas"]);
+        builder.add(EditReplySystem::<V>::new(), "voxel_edit_replies", &["voxel_deltas"]);
+        builder.add(ChunkInterestSystem, "voxel_chunk_interest", &[]);
+        let mut server = VoxelServerSystem::<V>::new(self.codec, self.mtu);
+        server.validator = self.validator;
+        builder.add(
+            server,
+            "voxel_server",
+            &["voxel_edit_capture", "voxel_edit_replies", "voxel_chunk_interest"],
+        );
+        Ok(())
+    }
+}
+
+/// Adds the systems a client needs to get voxels from its server; see the module docs.
+#[derive(Default)]
+pub struct VoxelClientBundle<V: VoxelId> {
+    _phantom: PhantomData<V>,
+}
+impl<V: VoxelId> VoxelClientBundle<V> {
+    pub fn new() -> Self {
+        VoxelClientBundle { _phantom: PhantomData }
+    }
+}
+impl<'a, 'b, V: VoxelId> SystemBundle<'a, 'b> for VoxelClientBundle<V> {
+    fn build(self, builder: &mut DispatcherBuilder<'a, 'b>) -> Result<()> {
+        builder.add(VoxelClientSystem::<V>::new(), "voxel_client", &[]);
+        builder.add(ChunkReceiveSystem::<V>::new(), "voxel_chunk_receive", &["voxel_client"]);
+        builder.add(ChunkDeltaSystem::<V>::new(), "voxel_deltas", &["voxel_client"]);
+        builder.add(EditPredictionSystem::<V>::new(), "voxel_edit_predictions", &["voxel_deltas"]);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Matrix4, Vector3};
+    use serde_json;
+    use std::net::SocketAddr;
+    use tracker::ChunkTrackerSystem;
+    use TestVoxel;
+
+    #[test]
+    fn packets() {
+        let edits = VoxelEdits {
+            sequence: 3,
+            edits: vec![(VoxelCoord::new(1, -2, 300), TestVoxel::Rock)],
+        };
+        let packet = VoxelPacket(edits.encode());
+        let back: VoxelPacket = serde_json::from_str(&serde_json::to_string(&packet).unwrap()).unwrap();
+        assert_eq!(back, packet);
+        assert_eq!(VoxelEdits::decode(&back.0).unwrap(), edits);
+    }
+
+    /// A server and a client, connected by handing over what each sends before the other's next frame.
+    struct Loopback {
+        server: World,
+        server_dispatcher: Dispatcher<'static, 'static>,
+        /// The client's connection entity, on the server.
+        client_entity: Entity,
+        to_client: ReaderId<NetEvent<VoxelPacket>>,
+        client: World,
+        client_dispatcher: Dispatcher<'static, 'static>,
+        /// The server's connection entity, on the client.
+        server_entity: Entity,
+        to_server: ReaderId<NetEvent<VoxelPacket>>,
+    }
+    impl Loopback {
+        /// A server with chunks at the origin and 64 voxels along x, and a client in the middle of the first.
+        fn new() -> Self {
+            let address: SocketAddr = "127.0.0.1:0".parse().unwrap();
+
+            let mut server = World::new();
+            server.add_resource(ChunkTracker::new());
+            let mut builder =
+                DispatcherBuilder::new().with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[]);
+            VoxelServerBundle::<TestVoxel>::new()
+                .with_codec(Codec::None)
+                .build(&mut builder)
+                .unwrap();
+            let mut server_dispatcher = builder.build();
+            server_dispatcher.setup(&mut server.res);
+            for &x in &[0, 64] {
+                server
+                    .create_entity()
+                    .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(x, 0, 0)))
+                    .build();
+            }
+            let mut connection = NetConnection::<VoxelPacket>::new(address);
+            let to_client = connection.send_buffer.register_reader();
+            let client_entity = server
+                .create_entity()
+                .with(connection)
+                .with(ChunkInterest::new(1))
+                .with(SendQueue::new(1 << 16))
+                .with(GlobalTransform(Matrix4::from_translation(Vector3::new(8.0, 8.0, 8.0))))
+                .build();
+
+            let mut client = World::new();
+            client.add_resource(ChunkTracker::new());
+            let mut builder = DispatcherBuilder::new();
+            VoxelClientBundle::<TestVoxel>::new().build(&mut builder).unwrap();
+            builder.add(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &["voxel_chunk_receive"]);
+            let mut client_dispatcher = builder.build();
+            client_dispatcher.setup(&mut client.res);
+            let mut connection = NetConnection::<VoxelPacket>::new(address);
+            let to_server = connection.send_buffer.register_reader();
+            let server_entity = client.create_entity().with(connection).build();
+
+            Loopback {
+                server,
+                server_dispatcher,
+                client_entity,
+                to_client,
+                client,
+                client_dispatcher,
+                server_entity,
+                to_server,
+            }
+        }
+
+        /// Run the server and then the client for a frame, each hearing what the other last sent.
+        fn frame(&mut self) {
+            self.server_dispatcher.dispatch(&mut self.server.res);
+            self.server.maintain();
+            deliver(&self.server, self.client_entity, &mut self.to_client, &self.client, self.server_entity);
+            self.client_dispatcher.dispatch(&mut self.client.res);
+            self.client.maintain();
+            deliver(&self.client, self.server_entity, &mut self.to_server, &self.server, self.client_entity);
+        }
+
+        /// The voxel at `coord` on the server and on the client, if they have it.
+        fn voxels(&self, coord: VoxelCoord) -> (Option<TestVoxel>, Option<TestVoxel>) {
+            let voxel = |world: &World| {
+                let tracker = world.read_resource::<ChunkTracker>();
+                let chunks = world.read_storage::<Chunk<TestVoxel>>();
+                tracker
+                    .get_chunk(&chunks, coord)
+                    .map(|chunk| chunk[coord - chunk.coord])
+            };
+            (voxel(&self.server), voxel(&self.client))
+        }
+
+        /// Have the client predict an edit, and ask the server for it.
+        fn predict(&mut self, coord: VoxelCoord, voxel: TestVoxel) {
+            let request = {
+                let deltas = self.client.read_resource::<ChunkDeltas<TestVoxel>>();
+                let mut predictions = self.client.write_resource::<EditPredictions<TestVoxel>>();
+                predictions.predict(&deltas, &[(coord, voxel)])
+            };
+            self.client
+                .write_resource::<EventChannel<VoxelEdits<TestVoxel>>>()
+                .single_write(request);
+        }
+    }
+
+    /// Hand what's been sent on `from`'s connection `from_entity` to `to`'s connection `to_entity`.
+    fn deliver(
+        from: &World,
+        from_entity: Entity,
+        reader: &mut ReaderId<NetEvent<VoxelPacket>>,
+        to: &World,
+        to_entity: Entity,
+    ) {
+        let packets: Vec<VoxelPacket> = {
+            let connections = from.read_storage::<NetConnection<VoxelPacket>>();
+            connections
+                .get(from_entity)
+                .unwrap()
+                .send_buffer
+                .read(reader)
+                .filter_map(|event| match *event {
+                    NetEvent::Custom(ref packet) => Some(packet.clone()),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut connections = to.write_storage::<NetConnection<VoxelPacket>>();
+        let connection = connections.get_mut(to_entity).unwrap();
+        for packet in packets {
+            connection.receive_buffer.single_write(NetEvent::Custom(packet));
+        }
+    }
+
+    #[test]
+    fn server_and_client() {
+        let mut loopback = Loopback::new();
+        let (near, far, along) = (
+            VoxelCoord::new(9, 8, 7),
+            VoxelCoord::new(15, 15, 15),
+            VoxelCoord::new(70, 8, 8),
+        );
+        for _ in 0..4 {
+            loopback.frame();
+        }
+        // the client's sent the chunk it's in, but not the one it's nowhere near
+        assert_eq!(loopback.voxels(near), (Some(TestVoxel::Air), Some(TestVoxel::Air)));
+        assert_eq!(loopback.voxels(along), (Some(TestVoxel::Air), None));
+
+        // edits within reach are made on the server
+        loopback.predict(near, TestVoxel::Rock);
+        for _ in 0..4 {
+            loopback.frame();
+        }
+        assert_eq!(loopback.voxels(near), (Some(TestVoxel::Rock), Some(TestVoxel::Rock)));
+
+        // and ones out of reach are turned down, and undone on the client
+        loopback.predict(far, TestVoxel::Rock);
+        loopback.frame();
+        assert_eq!(loopback.voxels(far).1, Some(TestVoxel::Rock));
+        for _ in 0..4 {
+            loopback.frame();
+        }
+        assert_eq!(loopback.voxels(far), (Some(TestVoxel::Air), Some(TestVoxel::Air)));
+
+        // edits made on the server are passed on
+        loopback
+            .server
+            .read_resource::<ChunkDeltas<TestVoxel>>()
+            .defer_set(far, TestVoxel::Grass);
+        for _ in 0..4 {
+            loopback.frame();
+        }
+        assert_eq!(loopback.voxels(far), (Some(TestVoxel::Grass), Some(TestVoxel::Grass)));
+
+        // a client that's moved on is sent the chunks where it is, and drops the ones it's left behind
+        let transform = GlobalTransform(Matrix4::from_translation(Vector3::new(72.0, 8.0, 8.0)));
+        *loopback
+            .server
+            .write_storage::<GlobalTransform>()
+            .get_mut(loopback.client_entity)
+            .unwrap() = transform;
+        for _ in 0..4 {
+            loopback.frame();
+        }
+        assert_eq!(loopback.voxels(along), (Some(TestVoxel::Air), Some(TestVoxel::Air)));
+        assert_eq!(loopback.voxels(near), (Some(TestVoxel::Rock), None));
+    }
+}
@@ -0,0 +1,200 @@
+//! Simple generators, for testing meshing, lighting and physics (and for having something to look at).
+
+use super::{Chunk, Voxel, VoxelCoord, CHUNK_SIZE};
+use generate::ChunkGenerator;
+
+/// A chunk with every voxel set to `voxel(coord)`, by world coordinate.
+fn fill<V: Voxel, F: Fn(VoxelCoord) -> V>(chunk_coord: VoxelCoord, voxel: F) -> Chunk<V> {
+    let mut chunk = Chunk::empty(chunk_coord);
+    for (x, plane) in chunk.voxels.iter_mut().enumerate() {
+        for (y, row) in plane.iter_mut().enumerate() {
+            for (z, v) in row.iter_mut().enumerate() {
+                *v = voxel(chunk_coord + VoxelCoord::new(x as i16, y as i16, z as i16));
+            }
+        }
+    }
+    chunk
+}
+
+/// Flat layers of voxels, stacked up from `bottom`, with empty voxels above them and below `bottom`.
+#[derive(Clone, Debug)]
+pub struct Superflat<V: Voxel> {
+    pub bottom: i16,
+    /// Each layer's voxel and thickness, bottom first.
+    pub layers: Vec<(V, u16)>,
+}
+impl<V: Voxel> Superflat<V> {
+    pub fn new(bottom: i16, layers: Vec<(V, u16)>) -> Self {
+        Superflat { bottom, layers }
+    }
+
+    /// The voxel at height `y`.
+    fn voxel(&self, y: i16) -> V {
+        if y < self.bottom {
+            return V::default();
+        }
+        let mut height = i32::from(y) - i32::from(self.bottom);
+        for &(voxel, thickness) in &self.layers {
+            height -= i32::from(thickness);
+            if height < 0 {
+                return voxel;
+            }
+        }
+        V::default()
+    }
+}
+impl<V: Voxel> ChunkGenerator<V> for Superflat<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        let mut chunk = Chunk::empty(chunk_coord);
+        for y in 0..CHUNK_SIZE as i16 {
+            let voxel = self.voxel(chunk_coord.y + y);
+            if voxel != V::default() {
+                let last = CHUNK_SIZE as i16 - 1;
+                chunk.fill_box(VoxelCoord::new(0, y, 0), VoxelCoord::new(last, y, last), voxel);
+            }
+        }
+        chunk
+    }
+}
+
+/// Cubes of `a` and `b`, `cell` voxels across, alternating in every direction, below `top`.
+/// With `b` empty, that's about as many faces as there can be, for stress-testing the mesher.
+#[derive(Clone, Copy, Debug)]
+pub struct Checkerboard<V: Voxel> {
+    pub cell: i16,
+    pub top: i16,
+    pub a: V,
+    pub b: V,
+}
+impl<V: Voxel> ChunkGenerator<V> for Checkerboard<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        assert!(self.cell > 0, "empty cells");
+        let size = i32::from(self.cell);
+        // (rounding down, so cells are the same size either side of 0)
+        let cell = |c: i16| (i32::from(c) - (i32::from(c) % size + size) % size) / size;
+        fill(chunk_coord, |coord| {
+            if coord.y >= self.top {
+                V::default()
+            } else if (cell(coord.x) + cell(coord.y) + cell(coord.z)) % 2 == 0 {
+                self.a
+            } else {
+                self.b
+            }
+        })
+    }
+}
+
+/// A single column of `voxel` at `x` and `z`, from `bottom` to `top` inclusive, and nothing else; e.g. for
+/// checking shadows and collision against one thin thing.
+#[derive(Clone, Copy, Debug)]
+pub struct Pillar<V: Voxel> {
+    pub x: i16,
+    pub z: i16,
+    pub bottom: i16,
+    pub top: i16,
+    pub voxel: V,
+}
+impl<V: Voxel> ChunkGenerator<V> for Pillar<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        fill(chunk_coord, |coord| {
+            if coord.x == self.x && coord.z == self.z && self.bottom <= coord.y && coord.y <= self.top {
+                self.voxel
+            } else {
+                V::default()
+            }
+        })
+    }
+}
+
+/// A ball of `sphere` with radius `radius` around `center`, inside a closed box of `walls` `half_size`
+/// voxels out from `center` in every direction. No light gets in from outside, so it's good for testing
+/// block light, or what happens when the sky's shut out.
+#[derive(Clone, Copy, Debug)]
+pub struct SphereInBox<V: Voxel> {
+    pub center: VoxelCoord,
+    pub radius: i16,
+    pub half_size: i16,
+    pub sphere: V,
+    pub walls: V,
+}
+impl<V: Voxel> ChunkGenerator<V> for SphereInBox<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        let radius = i32::from(self.radius);
+        let half_size = i32::from(self.half_size);
+        fill(chunk_coord, |coord| {
+            let offset = [
+                i32::from(coord.x) - i32::from(self.center.x),
+                i32::from(coord.y) - i32::from(self.center.y),
+                i32::from(coord.z) - i32::from(self.center.z),
+            ];
+            let distance = offset.iter().map(|c| c * c).sum::<i32>();
+            let out = offset.iter().map(|c| c.abs()).max().unwrap();
+            if distance <= radius * radius {
+                self.sphere
+            } else if out == half_size {
+                self.walls
+            } else {
+                V::default()
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TestVoxel;
+
+    #[test]
+    fn superflat() {
+        let flat = Superflat::new(-2, vec![(TestVoxel::Rock, 3), (TestVoxel::Grass, 1)]);
+        let chunk = flat.generate(VoxelCoord::new(0, 0, 16));
+        assert_eq!(chunk[VoxelCoord::new(3, 0, 3)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(3, 1, 3)], TestVoxel::Grass);
+        assert_eq!(chunk[VoxelCoord::new(3, 2, 3)], TestVoxel::Air);
+        let chunk = flat.generate(VoxelCoord::new(0, -16, 0));
+        assert_eq!(chunk[VoxelCoord::new(0, 13, 0)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(15, 14, 15)], TestVoxel::Rock);
+    }
+
+    #[test]
+    fn patterns() {
+        let checkers = Checkerboard {
+            cell: 2,
+            top: 4,
+            a: TestVoxel::Rock,
+            b: TestVoxel::Air,
+        };
+        let chunk = checkers.generate(VoxelCoord::new(-16, 0, 0));
+        assert_eq!(chunk[VoxelCoord::new(15, 0, 0)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(14, 0, 0)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(13, 0, 0)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(15, 2, 0)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(15, 4, 0)], TestVoxel::Air);
+
+        let pillar = Pillar {
+            x: 3,
+            z: -5,
+            bottom: 0,
+            top: 20,
+            voxel: TestVoxel::Rock,
+        };
+        let chunk = pillar.generate(VoxelCoord::new(0, 16, -16));
+        assert_eq!(chunk[VoxelCoord::new(3, 4, 11)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(3, 5, 11)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(4, 4, 11)], TestVoxel::Air);
+
+        let sphere = SphereInBox {
+            center: VoxelCoord::new(8, 8, 8),
+            radius: 3,
+            half_size: 6,
+            sphere: TestVoxel::Grass,
+            walls: TestVoxel::Rock,
+        };
+        let chunk = sphere.generate(VoxelCoord::new(0, 0, 0));
+        assert_eq!(chunk[VoxelCoord::new(8, 11, 8)], TestVoxel::Grass);
+        assert_eq!(chunk[VoxelCoord::new(8, 12, 8)], TestVoxel::Air);
+        assert_eq!(chunk[VoxelCoord::new(2, 12, 13)], TestVoxel::Rock);
+        assert_eq!(chunk[VoxelCoord::new(1, 8, 8)], TestVoxel::Air);
+    }
+}
@@ -0,0 +1,1084 @@
+//! Saving chunks to disk, and loading them back.
+//!
+//! Chunks are grouped into region files, each holding a cube of `REGION_SIZE` chunks on a side, so a big world is
+//! a few hundred files rather than millions of tiny ones. A region file is a header, a table with the offset and
+//! length of each of its chunks (zero if it hasn't been saved), and the chunks themselves:
+//!
+//! ```text
+//! magic: "MVRG", version: u8,
+//! table: [offset: u32, length: u32; REGION_SIZE^3], in x, y, z order,
+//! chunks...
+//! ```
+//!
+//! Each chunk is written as a palette of the names of the voxels in it (see `VoxelId::name`), so that it means the
+//! same thing to builds that number their voxels differently, and then the palette index of each voxel, in storage
+//! order, packed into as few bits as the palette needs; a chunk that's all one voxel needs none. If it's smaller,
+//! as it is for mostly empty chunks, the indices are written as runs instead. That's then compressed with a
+//! `Codec`, whose id is in the header, so that chunks saved with different codecs can sit side by side and new
+//! codecs don't break old saves:
+//!
+//! ```text
+//! magic: "V", version: u8, codec: u8, compressed(
+//!     palette_length: u16, palette: [name: string; palette_length],
+//!     bits: u8, indices: [index: bits; CHUNK_SIZE^3], least significant bit first
+//!     or bits: 255, runs: [index: varint, length: varint], adding up to CHUNK_SIZE^3
+//! )
+//! ```
+//!
+//! where a string is a u16 length and then that many bytes of UTF-8, and a varint is a zigzag-encoded number,
+//! written 7 bits a byte, least significant first, with the top bit set on all but the last byte. Version 1 chunks
+//! never use runs. Chunks saved before the header was added start with their codec id, and hold voxel ids and
+//! runs of palette indices instead; they can still be loaded:
+//!
+//! ```text
+//! codec: u8, compressed(
+//!     palette_length: u16, palette: [id: u16; palette_length],
+//!     run_count: u16, runs: [index: u16, length: u16; run_count]
+//! )
+//! ```
+//!
+//! The lz4 and zstd codecs are only there with the `lz4` and `zstd` features; chunks saved with a codec that
+//! isn't built in fail to load. Networking uses the same encoding, via `encode_chunk` and `decode_chunk`.
+//!
+//! A `WorldStore` reads and writes the disk on whatever thread calls it; to keep that off the main thread, use it
+//! through a `WorldIo` (see `io_thread`). To load saved chunks instead of generating them, wrap the generator in a
+//! `LoadOrGenerate`; to save chunks as they change, see `autosave`.
+//!
+//! Alongside the region files, `world.meta` holds a `WorldMeta`: the seed and generator settings the world was
+//! made with, how long it's been played, and the name of each voxel id, so that a build that's renumbered its
+//! voxels doesn't silently load the world wrong:
+//!
+//! ```text
+//! magic: "MVWM", version: u8, region_version: u8, seed: u32, playtime_ms: u64,
+//! generator: string, voxel_count: u16, voxels: [id: u16, name: string; voxel_count]
+//! ```
+//!
+//! Everything is little-endian. A chunk that's saved again is written at the end of the file, and only then does
+//! the table point at it, so a crash part way through a save leaves the old copy. Once old copies take up half of
+//! a region file, it's compacted: rewritten without them alongside, then renamed over the old file.
+
+use super::{canonicalize_chunk, Chunk, VoxelCoord, VoxelId, CHUNK_SIZE};
+use biome::ChunkBiomes;
+use bytes::{invalid, put_string, put_u16, put_u32, put_u64, put_varint, Reader};
+use generate::ChunkGenerator;
+use io_thread::WorldIoHandle;
+
+use fnv::{FnvHashMap, FnvHashSet};
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::iter::repeat;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How many chunks a region file holds along each axis.
+pub const REGION_SIZE: usize = 8;
+
+const MAGIC: &[u8; 4] = b"MVRG";
+const VERSION: u8 = 1;
+/// The first byte of an encoded chunk; chunks saved before there was a header start with a codec id instead.
+const CHUNK_MAGIC: u8 = b'V';
+/// Version 1 chunks are read the same way; they just never hold runs.
+const CHUNK_VERSION: u8 = 2;
+const SLOTS: usize = REGION_SIZE * REGION_SIZE * REGION_SIZE;
+const HEADER_SIZE: usize = 5 + SLOTS * 8;
+/// The `bits` that marks packed voxels as runs of indices.
+const RUNS: u8 = 0xff;
+const META_FILE: &str = "world.meta";
+const META_MAGIC: &[u8; 4] = b"MVWM";
+const META_VERSION: u8 = 1;
+/// How many region files to keep open at once.
+const MAX_OPEN: usize = 64;
+/// How many bytes of old chunk copies a region file can have before it's worth compacting; see the module docs.
+const MIN_COMPACT: u64 = 64 << 10;
+
+/// The coordinate of the region holding the chunk at `chunk_coord`, in regions, and the chunk's slot in it.
+fn region_slot(chunk_coord: VoxelCoord) -> (VoxelCoord, usize) {
+    let size = REGION_SIZE as i16;
+    // (chunk indices rounded down to the region)
+    let split = |c: i16| {
+        let index = c / CHUNK_SIZE as i16;
+        let slot = ((index % size) + size) % size;
+        ((index - slot) / size, slot as usize)
+    };
+    let (x, slot_x) = split(chunk_coord.x);
+    let (y, slot_y) = split(chunk_coord.y);
+    let (z, slot_z) = split(chunk_coord.z);
+    (
+        VoxelCoord::new(x, y, z),
+        (slot_x * REGION_SIZE + slot_y) * REGION_SIZE + slot_z,
+    )
+}
+
+/// An open region file.
+struct Region {
+    path: PathBuf,
+    file: File,
+    /// The offset and length of each chunk in the file; (0, 0) for chunks that aren't there.
+    table: Vec<(u32, u32)>,
+    end: u64,
+    /// How many bytes old copies of chunks take up.
+    garbage: u64,
+}
+impl Region {
+    fn open(path: &Path) -> io::Result<Region> {
+        let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+        let mut header = vec![0; HEADER_SIZE];
+        file.read_exact(&mut header)
+            .map_err(|_| invalid(format!("{:?} is too short to be a region file", path)))?;
+        if &header[0..4] != MAGIC {
+            return Err(invalid(format!("{:?} is not a region file", path)));
+        }
+        if header[4] != VERSION {
+            return Err(invalid(format!("unsupported region file version {}", header[4])));
+        }
+        let mut reader = Reader::new(&header[5..]);
+        let mut table = Vec::with_capacity(SLOTS);
+        for _ in 0..SLOTS {
+            table.push((reader.u32()?, reader.u32()?));
+        }
+        let end = file.seek(SeekFrom::End(0))?;
+        let live: u64 = table.iter().map(|&(_, length)| u64::from(length)).sum();
+        Ok(Region {
+            path: path.to_path_buf(),
+            file,
+            table,
+            end,
+            garbage: end.saturating_sub(HEADER_SIZE as u64 + live),
+        })
+    }
+
+    fn create(path: &Path) -> io::Result<Region> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(path)?;
+        let mut header = vec![0; HEADER_SIZE];
+        header[0..4].copy_from_slice(MAGIC);
+        header[4] = VERSION;
+        file.write_all(&header)?;
+        Ok(Region {
+            path: path.to_path_buf(),
+            file,
+            table: vec![(0, 0); SLOTS],
+            end: HEADER_SIZE as u64,
+            garbage: 0,
+        })
+    }
+
+    fn read(&mut self, slot: usize) -> io::Result<Option<Vec<u8>>> {
+        let (offset, length) = self.table[slot];
+        if offset == 0 {
+            return Ok(None);
+        }
+        let mut bytes = vec![0; length as usize];
+        self.file.seek(SeekFrom::Start(u64::from(offset)))?;
+        self.file.read_exact(&mut bytes)?;
+        Ok(Some(bytes))
+    }
+
+    /// Save a chunk in `slot`. It goes at the end of the file, and is synced to disk before the table points at
+    /// it, so a crash part way through leaves the old copy.
+    fn write(&mut self, slot: usize, bytes: &[u8]) -> io::Result<()> {
+        let full = self.end + bytes.len() as u64 > u64::from(u32::max_value());
+        if (self.garbage >= MIN_COMPACT && self.garbage * 2 >= self.end) || (full && self.garbage > 0) {
+            self.compact()?;
+        }
+        let offset = self.append(bytes)?;
+        self.file.sync_data()?;
+        self.point(slot, offset, bytes.len() as u32)
+    }
+
+    /// Write `bytes` at the end of the file, returning where they went.
+    fn append(&mut self, bytes: &[u8]) -> io::Result<u64> {
+        let offset = self.end;
+        if offset + bytes.len() as u64 > u64::from(u32::max_value()) {
+            return Err(io::Error::new(io::ErrorKind::Other, "region file is full"));
+        }
+        self.file.seek(SeekFrom::Start(offset))?;
+        self.file.write_all(bytes)?;
+        self.end = offset + bytes.len() as u64;
+        Ok(offset)
+    }
+
+    /// Point the table entry for `slot` at a chunk that's been written.
+    fn point(&mut self, slot: usize, offset: u64, length: u32) -> io::Result<()> {
+        self.garbage += u64::from(self.table[slot].1);
+        self.table[slot] = (offset as u32, length);
+        let mut entry = Vec::with_capacity(8);
+        put_u32(&mut entry, offset as u32);
+        put_u32(&mut entry, length);
+        self.file.seek(SeekFrom::Start(5 + slot as u64 * 8))?;
+        self.file.write_all(&entry)
+    }
+
+    /// Rewrite the file without the old copies of chunks. The new file's written alongside the old one, then
+    /// renamed over it, so a crash part way through leaves one or the other.
+    fn compact(&mut self) -> io::Result<()> {
+        let temporary = self.path.with_extension("region.new");
+        // (left behind by a crash while compacting)
+        let _ = fs::remove_file(&temporary);
+        let mut compacted = Region::create(&temporary)?;
+        for slot in 0..SLOTS {
+            if let Some(bytes) = self.read(slot)? {
+                let offset = compacted.append(&bytes)?;
+                compacted.point(slot, offset, bytes.len() as u32)?;
+            }
+        }
+        compacted.file.sync_data()?;
+        fs::rename(&temporary, &self.path)?;
+        compacted.path = self.path.clone();
+        *self = compacted;
+        Ok(())
+    }
+}
+
+/// How encoded chunks are compressed; see the module docs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Codec {
+    /// No compression beyond the palette encoding.
+    None,
+    /// Fast, and squeezes chunks with lots of little runs a fair bit.
+    Lz4,
+    /// Slower, but squeezes harder; better for chunks that are going over the network.
+    Zstd,
+}
+impl Codec {
+    /// Every codec, whether or not it's built in.
+    pub const ALL: [Codec; 3] = [Codec::None, Codec::Lz4, Codec::Zstd];
+
+    /// The id written before each chunk. These mustn't change.
+    pub fn id(self) -> u8 {
+        match self {
+            Codec::None => 0,
+            Codec::Lz4 => 1,
+            Codec::Zstd => 2,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Codec> {
+        Codec::ALL.iter().cloned().find(|codec| codec.id() == id)
+    }
+
+    /// Whether this codec was built in, i.e. its feature is on.
+    pub fn is_available(self) -> bool {
+        match self {
+            Codec::None => true,
+            Codec::Lz4 => cfg!(feature = "lz4"),
+            Codec::Zstd => cfg!(feature = "zstd"),
+        }
+    }
+
+    fn unavailable(self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Other,
+            format!("the {:?} codec isn't built in; turn on its feature", self),
+        )
+    }
+
+    pub fn compress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4::block::compress(bytes, None, true),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::encode_all(bytes, 0),
+            #[cfg_attr(all(feature = "lz4", feature = "zstd"), allow(unreachable_patterns))]
+            _ => Err(self.unavailable()),
+        }
+    }
+
+    pub fn decompress(self, bytes: &[u8]) -> io::Result<Vec<u8>> {
+        match self {
+            Codec::None => Ok(bytes.to_vec()),
+            #[cfg(feature = "lz4")]
+            Codec::Lz4 => lz4::block::decompress(bytes, None),
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => zstd::decode_all(bytes),
+            #[cfg_attr(all(feature = "lz4", feature = "zstd"), allow(unreachable_patterns))]
+            _ => Err(self.unavailable()),
+        }
+    }
+}
+impl Default for Codec {
+    /// Lz4 if it's built in, then zstd, then none.
+    fn default() -> Self {
+        if Codec::Lz4.is_available() {
+            Codec::Lz4
+        } else if Codec::Zstd.is_available() {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+}
+
+/// A world saved on disk: a directory of region files. Clones share their open files, so a clone can be handed
+/// to another thread.
+#[derive(Clone)]
+pub struct WorldStore<V: VoxelId> {
+    directory: PathBuf,
+    codec: Codec,
+    regions: Arc<Mutex<FnvHashMap<VoxelCoord, Region>>>,
+    /// The chunks that failed to load, which aren't saved over.
+    damaged: Arc<Mutex<FnvHashSet<VoxelCoord>>>,
+    voxels: PhantomData<V>,
+}
+impl<V: VoxelId> WorldStore<V> {
+    /// Open the world saved in `directory`, creating the directory if it doesn't exist.
+    pub fn open<P: AsRef<Path>>(directory: P) -> io::Result<WorldStore<V>> {
+        let directory = directory.as_ref().to_path_buf();
+        fs::create_dir_all(&directory)?;
+        Ok(WorldStore {
+            directory,
+            codec: Codec::default(),
+            regions: Arc::new(Mutex::new(FnvHashMap::default())),
+            damaged: Arc::new(Mutex::new(FnvHashSet::default())),
+            voxels: PhantomData,
+        })
+    }
+
+    /// Save chunks with `codec` from now on (`Codec::default()` to start with). Chunks that are already saved
+    /// keep theirs until they're saved again.
+    pub fn with_codec(mut self, codec: Codec) -> Self {
+        assert!(codec.is_available(), "the {:?} codec isn't built in", codec);
+        self.codec = codec;
+        self
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    /// The path of the region file with the given region coordinate.
+    fn region_path(&self, region: VoxelCoord) -> PathBuf {
+        self.directory
+            .join(format!("{}.{}.{}.region", region.x, region.y, region.z))
+    }
+
+    /// Do something with the region file holding `chunk_coord`, opening it (or, if `create`, creating it) if it
+    /// isn't already. Returns None if the region file doesn't exist and `create` is false.
+    fn with_region<R, F: FnOnce(&mut Region, usize) -> io::Result<R>>(
+        &self,
+        chunk_coord: VoxelCoord,
+        create: bool,
+        f: F,
+    ) -> io::Result<Option<R>> {
+        let (region, slot) = region_slot(canonicalize_chunk(chunk_coord));
+        let mut regions = self.regions.lock();
+        if !regions.contains_key(&region) {
+            let path = self.region_path(region);
+            let file = if path.exists() {
+                Region::open(&path)?
+            } else if create {
+                Region::create(&path)?
+            } else {
+                return Ok(None);
+            };
+            if regions.len() >= MAX_OPEN {
+                let close = *regions.keys().next().unwrap();
+                regions.remove(&close);
+            }
+            regions.insert(region, file);
+        }
+        f(regions.get_mut(&region).unwrap(), slot).map(Some)
+    }
+
+    /// Save `chunk`, replacing any saved copy. Chunks that failed to load aren't saved, so that whatever took
+    /// their place doesn't replace data that might still be recovered; see `forget_damaged`.
+    pub fn save_chunk(&self, chunk: &Chunk<V>) -> io::Result<()> {
+        if self.is_damaged(chunk.coord) {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("chunk {:?} failed to load, so it isn't saved over", chunk.coord),
+            ));
+        }
+        let bytes = encode_chunk(chunk, self.codec)?;
+        self.with_region(chunk.coord, true, |region, slot| region.write(slot, &bytes))
+            .map(|_| ())
+    }
+
+    /// Load the chunk at `chunk_coord`, if it's been saved. If it fails, the chunk is marked as damaged.
+    pub fn load_chunk(&self, chunk_coord: VoxelCoord) -> io::Result<Option<Chunk<V>>> {
+        let chunk_coord = canonicalize_chunk(chunk_coord);
+        let loaded = self.with_region(chunk_coord, false, |region, slot| region.read(slot))
+            .and_then(|bytes| match bytes {
+                Some(Some(bytes)) => decode_chunk(chunk_coord, &bytes).map(Some),
+                _ => Ok(None),
+            });
+        if loaded.is_err() {
+            self.damaged.lock().insert(chunk_coord);
+        }
+        loaded
+    }
+
+    /// Whether the chunk at `chunk_coord` failed to load, and so won't be saved.
+    pub fn is_damaged(&self, chunk_coord: VoxelCoord) -> bool {
+        self.damaged.lock().contains(&canonicalize_chunk(chunk_coord))
+    }
+
+    /// Let the chunk at `chunk_coord` be saved again after it failed to load, e.g. once its data has been
+    /// recovered, or given up on.
+    pub fn forget_damaged(&self, chunk_coord: VoxelCoord) {
+        self.damaged.lock().remove(&canonicalize_chunk(chunk_coord));
+    }
+
+    /// Whether the chunk at `chunk_coord` has been saved.
+    pub fn contains(&self, chunk_coord: VoxelCoord) -> io::Result<bool> {
+        let saved = self.with_region(chunk_coord, false, |region, slot| Ok(region.table[slot].0 != 0))?;
+        Ok(saved == Some(true))
+    }
+
+    /// Load the world's metadata, if it's been saved.
+    pub fn load_meta(&self) -> io::Result<Option<WorldMeta>> {
+        match fs::read(self.directory.join(META_FILE)) {
+            Ok(bytes) => WorldMeta::decode(&bytes).map(Some),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Save the world's metadata, replacing what was there. The old file is only replaced once the new one is
+    /// written, so a crash part way through doesn't lose both.
+    pub fn save_meta(&self, meta: &WorldMeta) -> io::Result<()> {
+        let temporary = self.directory.join(format!("{}.new", META_FILE));
+        {
+            let mut file = File::create(&temporary)?;
+            file.write_all(&meta.encode()?)?;
+            file.sync_data()?;
+        }
+        fs::rename(temporary, self.directory.join(META_FILE))
+    }
+
+    /// What to do at startup: load the world's metadata and check its voxel ids against `V`'s (see
+    /// `WorldMeta::check_voxels`), or, for a new world, save the metadata from `new`. The world's generator should
+    /// then be set up from the returned seed and settings. Voxels added to `V` since the world was saved are added
+    /// to its metadata.
+    pub fn load_or_create_meta<F: FnOnce() -> WorldMeta>(&self, new: F) -> io::Result<WorldMeta> {
+        let meta = match self.load_meta()? {
+            Some(mut meta) => {
+                meta.check_voxels::<V>()?;
+                let voxels = voxel_names::<V>();
+                if meta.voxels != voxels {
+                    meta.voxels = voxels;
+                    self.save_meta(&meta)?;
+                }
+                meta
+            }
+            None => {
+                let meta = new();
+                self.save_meta(&meta)?;
+                meta
+            }
+        };
+        Ok(meta)
+    }
+
+    /// Make sure everything saved so far is on the disk.
+    pub fn flush(&self) -> io::Result<()> {
+        for region in self.regions.lock().values() {
+            region.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// What a saved world needs besides its chunks: how it was generated, what its voxel ids meant, and how long it's
+/// been played. A `WorldStore` keeps it in `world.meta`; see `WorldStore::load_or_create_meta`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorldMeta {
+    /// The version of the region files the world was saved with.
+    pub format_version: u8,
+    pub seed: u32,
+    /// The generator's settings, in whatever form it likes; e.g. a list of superflat layers.
+    pub generator: String,
+    /// The name of each voxel id, as of when the world was saved.
+    pub voxels: Vec<(u16, String)>,
+    /// How long the world's been played for; it's up to the game to add to this before saving.
+    pub playtime: Duration,
+}
+impl WorldMeta {
+    /// The metadata for a new world, with the voxel names from `V`.
+    pub fn new<V: VoxelId>(seed: u32, generator: &str) -> Self {
+        WorldMeta {
+            format_version: VERSION,
+            seed,
+            generator: generator.to_string(),
+            voxels: voxel_names::<V>(),
+            playtime: Duration::from_secs(0),
+        }
+    }
+
+    /// Check that `V` still gives each saved voxel id the name it had, since chunks saved with an id that's been
+    /// given to another voxel would load as the wrong thing. Ids that `V` has added since are fine.
+    pub fn check_voxels<V: VoxelId>(&self) -> io::Result<()> {
+        for &(id, ref name) in &self.voxels {
+            match V::from_id(id) {
+                Some(voxel) if voxel.name() == *name => {}
+                Some(voxel) => {
+                    return Err(invalid(format!(
+                        "voxel id {} was {:?} when the world was saved, but is now {:?}",
+                        id,
+                        name,
+                        voxel.name()
+                    )))
+                }
+                None => return Err(invalid(format!("voxel {:?} (id {}) no longer exists", name, id))),
+            }
+        }
+        Ok(())
+    }
+
+    fn encode(&self) -> io::Result<Vec<u8>> {
+        let too_long = |string: &str| string.len() > u16::max_value() as usize;
+        if too_long(&self.generator) || self.voxels.iter().any(|&(_, ref name)| too_long(name))
+            || self.voxels.len() > u16::max_value() as usize
+        {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "world metadata is too big"));
+        }
+        let mut bytes = META_MAGIC.to_vec();
+        bytes.push(META_VERSION);
+        bytes.push(self.format_version);
+        put_u32(&mut bytes, self.seed);
+        let playtime = self.playtime.as_secs() * 1000 + u64::from(self.playtime.subsec_nanos() / 1_000_000);
+        put_u64(&mut bytes, playtime);
+        put_string(&mut bytes, &self.generator);
+        put_u16(&mut bytes, self.voxels.len() as u16);
+        for &(id, ref name) in &self.voxels {
+            put_u16(&mut bytes, id);
+            put_string(&mut bytes, name);
+        }
+        Ok(bytes)
+    }
+
+    fn decode(bytes: &[u8]) -> io::Result<WorldMeta> {
+        let mut reader = Reader::new(bytes);
+        if reader.take(4)? != META_MAGIC {
+            return Err(invalid("not a world metadata file"));
+        }
+        let version = reader.u8()?;
+        if version != META_VERSION {
+            return Err(invalid(format!("unsupported world metadata version {}", version)));
+        }
+        let format_version = reader.u8()?;
+        if format_version > VERSION {
+            return Err(invalid(format!(
+                "the world was saved with region file version {}, but only up to {} is supported",
+                format_version, VERSION
+            )));
+        }
+        let seed = reader.u32()?;
+        let playtime = Duration::from_millis(reader.u64()?);
+        let generator = reader.string()?;
+        let count = reader.u16()?;
+        let mut voxels = Vec::with_capacity(count as usize);
+        for _ in 0..count {
+            voxels.push((reader.u16()?, reader.string()?));
+        }
+        Ok(WorldMeta {
+            format_version,
+            seed,
+            generator,
+            voxels,
+            playtime,
+        })
+    }
+}
+
+/// The name of every voxel id `V` knows.
+fn voxel_names<V: VoxelId>() -> Vec<(u16, String)> {
+    (0..=u16::max_value())
+        .filter_map(|id| V::from_id(id).map(|voxel| (id, voxel.name().to_string())))
+        .collect()
+}
+
+/// Loads chunks through a `WorldIo` (see `io_thread`) if they've been saved, and only generates them with
+/// `generator` if they haven't, so a `ChunkGenerationSystem` with one loads saved chunks the same way it generates
+/// new ones (on its workers, if it has any, which wait for the IO thread). Biomes aren't saved, so they always come
+/// from `generator`.
+///
+/// Chunks that fail to load are logged and generated instead, but the store won't save over them (see
+/// `WorldStore::save_chunk`), so the saved data is left to be recovered.
+pub struct LoadOrGenerate<V: VoxelId, G: ChunkGenerator<V>> {
+    io: WorldIoHandle<V>,
+    generator: G,
+}
+impl<V: VoxelId, G: ChunkGenerator<V>> LoadOrGenerate<V, G> {
+    /// Load chunks with `io`, from `WorldIo::handle`.
+    pub fn new(io: WorldIoHandle<V>, generator: G) -> Self {
+        LoadOrGenerate { io, generator }
+    }
+}
+impl<V: VoxelId, G: ChunkGenerator<V>> ChunkGenerator<V> for LoadOrGenerate<V, G> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        match self.io.load(chunk_coord) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => self.generator.generate(chunk_coord),
+            Err(e) => {
+                error!(
+                    "failed to load chunk {:?} from {:?}, generating it instead (and not saving it): {}",
+                    chunk_coord,
+                    self.io.directory(),
+                    e
+                );
+                self.generator.generate(chunk_coord)
+            }
+        }
+    }
+
+    fn biomes(&self, chunk_coord: VoxelCoord) -> Option<ChunkBiomes> {
+        self.generator.biomes(chunk_coord)
+    }
+}
+
+/// Encode a chunk as a palette and packed indices, compressed with `codec`; see the module docs.
+pub fn encode_chunk<V: VoxelId>(chunk: &Chunk<V>, codec: Codec) -> io::Result<Vec<u8>> {
+    let mut bytes = vec![CHUNK_MAGIC, CHUNK_VERSION, codec.id()];
+    bytes.extend(codec.compress(&pack(chunk))?);
+    Ok(bytes)
+}
+
+/// Decode a chunk written by `encode_chunk` (or saved before it had a header), as the chunk at `chunk_coord`.
+pub fn decode_chunk<V: VoxelId>(chunk_coord: VoxelCoord, bytes: &[u8]) -> io::Result<Chunk<V>> {
+    let mut reader = Reader::new(bytes);
+    let first = reader.u8()?;
+    let (legacy, id) = if first == CHUNK_MAGIC {
+        let version = reader.u8()?;
+        if version != 1 && version != CHUNK_VERSION {
+            return Err(invalid(format!("unsupported chunk version {}", version)));
+        }
+        (false, reader.u8()?)
+    } else {
+        (true, first)
+    };
+    let codec = Codec::from_id(id).ok_or_else(|| invalid(format!("unknown codec {} for chunk", id)))?;
+    let bytes = codec.decompress(reader.rest())?;
+    let count = CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE;
+    let voxels = if legacy {
+        unpack_voxel_runs(&bytes, count)?
+    } else {
+        unpack_voxels(&bytes, count)?
+    };
+
+    let mut chunk = Chunk::empty(chunk_coord);
+    for (v, voxel) in chunk
+        .voxels
+        .iter_mut()
+        .flat_map(|plane| plane.iter_mut().flat_map(|row| row.iter_mut()))
+        .zip(voxels)
+    {
+        *v = voxel;
+    }
+    Ok(chunk)
+}
+
+/// The palette and indices of a chunk.
+fn pack<V: VoxelId>(chunk: &Chunk<V>) -> Vec<u8> {
+    pack_voxels(chunk.voxels.iter().flat_map(|plane| plane.iter().flat_map(|row| row.iter().cloned())))
+}
+
+/// How many bits it takes to tell apart `n` palette entries.
+fn index_bits(n: usize) -> u8 {
+    if n <= 1 {
+        0
+    } else {
+        (32 - ((n - 1) as u32).leading_zeros()) as u8
+    }
+}
+
+/// Write `voxels` as a palette and packed indices, or runs of indices if that's smaller, the way chunks are (see
+/// the module docs), uncompressed. The number of voxels isn't written, so it has to be known when they're read
+/// back.
+pub fn pack_voxels<V: VoxelId, I: IntoIterator<Item = V>>(voxels: I) -> Vec<u8> {
+    let mut palette: Vec<V> = Vec::new();
+    let mut indices: Vec<u16> = Vec::new();
+    for voxel in voxels {
+        let index = match palette.iter().position(|&p| p == voxel) {
+            Some(index) => index,
+            None => {
+                palette.push(voxel);
+                palette.len() - 1
+            }
+        };
+        indices.push(index as u16);
+    }
+    assert!(palette.len() <= u16::max_value() as usize, "too many kinds of voxel to pack");
+
+    let bits = index_bits(palette.len());
+    let mut bytes = Vec::with_capacity(3 + palette.len() * 8 + (indices.len() * bits as usize + 7) / 8);
+    put_u16(&mut bytes, palette.len() as u16);
+    for voxel in &palette {
+        put_string(&mut bytes, voxel.name());
+    }
+    let packed = pack_indices(&indices, bits);
+    let runs = index_runs(&indices);
+    if runs.len() < packed.len() {
+        bytes.push(RUNS);
+        bytes.extend(runs);
+    } else {
+        bytes.push(bits);
+        bytes.extend(packed);
+    }
+    bytes
+}
+
+/// `indices`, `bits` each, least significant bit first.
+fn pack_indices(indices: &[u16], bits: u8) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity((indices.len() * bits as usize + 7) / 8);
+    // (indices go in from the bottom of `buffer`, and whole bytes come out)
+    let (mut buffer, mut filled) = (0u32, 0);
+    for &index in indices {
+        buffer |= u32::from(index) << filled;
+        filled += bits;
+        while filled >= 8 {
+            bytes.push(buffer as u8);
+            buffer >>= 8;
+            filled -= 8;
+        }
+    }
+    if filled > 0 {
+        bytes.push(buffer as u8);
+    }
+    bytes
+}
+
+/// `indices` as runs of the same index: the index, and then how many there are, as varints.
+fn index_runs(indices: &[u16]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut rest = indices;
+    while let Some(&index) = rest.first() {
+        let length = rest.iter().take_while(|&&i| i == index).count();
+        put_varint(&mut bytes, i32::from(index));
+        put_varint(&mut bytes, length as i32);
+        rest = &rest[length..];
+    }
+    bytes
+}
+
+/// Read `count` voxels written by `pack_voxels`.
+pub fn unpack_voxels<V: VoxelId>(bytes: &[u8], count: usize) -> io::Result<Vec<V>> {
+    let mut reader = Reader::new(bytes);
+    let palette_length = reader.u16()?;
+    let mut palette = Vec::with_capacity(palette_length as usize);
+    for _ in 0..palette_length {
+        let name = reader.string()?;
+        palette.push(V::from_name(&name).ok_or_else(|| invalid(format!("unknown voxel {:?}", name)))?);
+    }
+    let bits = reader.u8()?;
+    if bits == RUNS {
+        return unpack_index_runs(&mut reader, &palette, count);
+    }
+    if bits > 16 {
+        return Err(invalid(format!("{} bits per voxel is too many", bits)));
+    }
+    let packed = reader.rest();
+    if packed.len() != (count * bits as usize + 7) / 8 {
+        return Err(invalid("wrong number of voxels"));
+    }
+
+    let mask = (1u32 << bits) - 1;
+    let mut packed = packed.iter();
+    let (mut buffer, mut filled) = (0u32, 0);
+    let mut voxels = Vec::with_capacity(count);
+    for _ in 0..count {
+        while filled < bits {
+            // (there are enough bytes, from the length check)
+            buffer |= u32::from(*packed.next().unwrap()) << filled;
+            filled += 8;
+        }
+        let index = buffer & mask;
+        buffer >>= bits;
+        filled -= bits;
+        voxels.push(*palette
+            .get(index as usize)
+            .ok_or_else(|| invalid("voxel missing from palette"))?);
+    }
+    Ok(voxels)
+}
+
+/// Read the runs of indices into `palette` that `pack_voxels` writes, for `count` voxels.
+fn unpack_index_runs<V: VoxelId>(reader: &mut Reader, palette: &[V], count: usize) -> io::Result<Vec<V>> {
+    let mut voxels = Vec::with_capacity(count);
+    while voxels.len() < count {
+        let (index, length) = (reader.varint()?, reader.varint()?);
+        let voxel = *palette
+            .get(index as usize)
+            .ok_or_else(|| invalid("voxel missing from palette"))?;
+        if length <= 0 || voxels.len() + length as usize > count {
+            return Err(invalid("wrong number of voxels"));
+        }
+        voxels.extend(repeat(voxel).take(length as usize));
+    }
+    if !reader.rest().is_empty() {
+        return Err(invalid("wrong number of voxels"));
+    }
+    Ok(voxels)
+}
+
+/// Read `count` voxels written as a palette of ids and runs of indices, as chunks were before they had a header
+/// (see the module docs).
+pub fn unpack_voxel_runs<V: VoxelId>(bytes: &[u8], count: usize) -> io::Result<Vec<V>> {
+    let mut reader = Reader::new(bytes);
+    let palette_length = reader.u16()?;
+    let mut palette = Vec::with_capacity(palette_length as usize);
+    for _ in 0..palette_length {
+        let id = reader.u16()?;
+        palette.push(V::from_id(id).ok_or_else(|| invalid(format!("unknown voxel id {}", id)))?);
+    }
+
+    let mut voxels = Vec::with_capacity(count);
+    for _ in 0..reader.u16()? {
+        let (index, length) = (reader.u16()?, reader.u16()?);
+        let voxel = *palette
+            .get(index as usize)
+            .ok_or_else(|| invalid("voxel missing from palette"))?;
+        if voxels.len() + length as usize > count {
+            return Err(invalid("too many voxels"));
+        }
+        voxels.extend(repeat(voxel).take(length as usize));
+    }
+    if voxels.len() != count {
+        return Err(invalid("too few voxels"));
+    }
+    Ok(voxels)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use io_thread::WorldIo;
+    use patterns::Superflat;
+    use {test_directory, TestVoxel};
+
+    #[test]
+    fn encoding() {
+        let empty = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        // header, palette: ["air"], 0 bits per index
+        assert_eq!(encode_chunk(&empty, Codec::None).unwrap().len(), 3 + 2 + 5 + 1);
+
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, -16, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 3, 15), TestVoxel::Rock);
+        chunk[VoxelCoord::new(7, 4, 7)] = TestVoxel::Grass;
+        let bytes = encode_chunk(&chunk, Codec::None).unwrap();
+        // three voxels would take two bits each, but the runs of them take less: two for every x, and two more for
+        // the grass
+        assert_eq!(bytes.len(), 3 + 2 + 6 + 5 + 7 + 1 + 15 * 6 + 10);
+        let back: Chunk<TestVoxel> = decode_chunk(chunk.coord, &bytes).unwrap();
+        assert!(back.voxels == chunk.voxels);
+
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &bytes[..bytes.len() - 1]).is_err());
+        // palette: ["lava"]
+        let unknown = [b'V', 1, 0, 1, 0, 4, 0, b'l', b'a', b'v', b'a', 0];
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &unknown).is_err());
+        // an unknown codec, and a newer version
+        let mut future = bytes.clone();
+        future[2] = 200;
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &future).is_err());
+        let mut future = bytes.clone();
+        future[1] = 9;
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &future).is_err());
+
+        // chunks saved before the header: palette: [Rock], runs: [0 x 4096]
+        let legacy = [0, 1, 0, 1, 0, 1, 0, 0, 0, 0, 0x10];
+        let back: Chunk<TestVoxel> = decode_chunk(chunk.coord, &legacy).unwrap();
+        assert_eq!(back[VoxelCoord::new(15, 15, 15)], TestVoxel::Rock);
+        // palette: [Air], runs: [0 x 4095]
+        let short = [0, 1, 0, 0, 0, 1, 0, 0, 0, 0xff, 0x0f];
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &short).is_err());
+        // an unknown voxel id
+        let unknown = [0, 1, 0, 9, 0, 1, 0, 0, 0, 0, 0x10];
+        assert!(decode_chunk::<TestVoxel>(chunk.coord, &unknown).is_err());
+    }
+
+    #[test]
+    fn packing() {
+        assert_eq!((index_bits(1), index_bits(2), index_bits(3), index_bits(4)), (0, 1, 2, 2));
+        assert_eq!((index_bits(5), index_bits(256), index_bits(257)), (3, 8, 9));
+        let voxels = vec![TestVoxel::Grass, TestVoxel::Air, TestVoxel::Rock, TestVoxel::Rock, TestVoxel::Air];
+        let bytes = pack_voxels(voxels.clone());
+        assert_eq!(unpack_voxels::<TestVoxel>(&bytes, 5).unwrap(), voxels);
+        assert!(unpack_voxels::<TestVoxel>(&bytes, 4).is_err());
+        assert!(unpack_voxels::<TestVoxel>(&bytes, 9).is_err());
+        assert!(unpack_voxels::<TestVoxel>(&pack_voxels::<TestVoxel, _>(vec![]), 0).unwrap().is_empty());
+
+        // runs, when they're smaller
+        let mut voxels = vec![TestVoxel::Rock; 1000];
+        voxels.push(TestVoxel::Air);
+        let bytes = pack_voxels(voxels.clone());
+        // palette: ["rock", "air"], runs: [0 x 1000, 1 x 1]
+        assert_eq!(bytes.len(), 2 + 6 + 5 + 1 + 1 + 2 + 1 + 1);
+        assert_eq!(unpack_voxels::<TestVoxel>(&bytes, 1001).unwrap(), voxels);
+        assert!(unpack_voxels::<TestVoxel>(&bytes, 1000).is_err());
+        assert!(unpack_voxels::<TestVoxel>(&bytes, 1002).is_err());
+    }
+
+    #[test]
+    fn codecs() {
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        for x in 0..16 {
+            for z in 0..16 {
+                let height = (x * 7 + z * 3) % 11;
+                chunk.fill_box(VoxelCoord::new(x, 0, z), VoxelCoord::new(x, height, z), TestVoxel::Rock);
+            }
+        }
+        let plain = encode_chunk(&chunk, Codec::None).unwrap();
+        for &codec in &Codec::ALL {
+            assert_eq!(Codec::from_id(codec.id()), Some(codec));
+            if !codec.is_available() {
+                assert!(encode_chunk(&chunk, codec).is_err());
+                continue;
+            }
+            let bytes = encode_chunk(&chunk, codec).unwrap();
+            assert!(bytes.len() <= plain.len(), "{:?} made the chunk bigger", codec);
+            let back: Chunk<TestVoxel> = decode_chunk(chunk.coord, &bytes).unwrap();
+            assert!(back.voxels == chunk.voxels);
+        }
+    }
+
+    #[test]
+    fn regions() {
+        assert_eq!(region_slot(VoxelCoord::new(0, 0, 0)), (VoxelCoord::new(0, 0, 0), 0));
+        assert_eq!(region_slot(VoxelCoord::new(-16, 0, 16)), (VoxelCoord::new(-1, 0, 0), 7 * 64 + 1));
+        assert_eq!(region_slot(VoxelCoord::new(128, -128, 0)), (VoxelCoord::new(1, -1, 0), 0));
+    }
+
+    #[test]
+    fn save_load() {
+        let directory = test_directory("voxel_persist_test");
+
+        let flat = |coord, height| {
+            let mut chunk = Chunk::empty(coord);
+            chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, height, 15), TestVoxel::Rock);
+            chunk
+        };
+        let (a, b, c) = (
+            VoxelCoord::new(0, 0, 0),
+            VoxelCoord::new(-16, 32, 16),
+            VoxelCoord::new(512, 0, -512),
+        );
+        {
+            let store = WorldStore::open(&directory).unwrap();
+            store.save_chunk(&flat(a, 3)).unwrap();
+            store.save_chunk(&flat(b, 5)).unwrap();
+            store.save_chunk(&flat(c, 7)).unwrap();
+            store.flush().unwrap();
+            assert_eq!(fs::read_dir(&directory).unwrap().count(), 3);
+        }
+
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        assert!(store.contains(b + VoxelCoord::new(1, 2, 3)).unwrap());
+        assert!(!store.contains(VoxelCoord::new(16, 0, 0)).unwrap());
+        assert!(store.load_chunk(VoxelCoord::new(0, 1024, 0)).unwrap().is_none());
+        let chunk = store.load_chunk(b).unwrap().unwrap();
+        assert_eq!(chunk.coord, b);
+        assert!(chunk.voxels == flat(b, 5).voxels);
+
+        // a chunk that's saved again goes at the end of the file, whether it's bigger or smaller
+        let mut bumpy = flat(a, 3);
+        for x in 0..16 {
+            bumpy[VoxelCoord::new(x, 8, x)] = TestVoxel::Grass;
+        }
+        store.save_chunk(&bumpy).unwrap();
+        assert!(store.load_chunk(a).unwrap().unwrap().voxels == bumpy.voxels);
+        store.save_chunk(&flat(a, 15)).unwrap();
+        assert!(store.load_chunk(a).unwrap().unwrap().voxels == flat(a, 15).voxels);
+        assert!(store.load_chunk(b).unwrap().unwrap().voxels == flat(b, 5).voxels);
+
+        // saved chunks are loaded rather than generated
+        let io = WorldIo::start(store.clone());
+        let loader = LoadOrGenerate::new(io.handle(), Superflat::new(0, vec![(TestVoxel::Grass, 1)]));
+        assert!(loader.generate(b).voxels == flat(b, 5).voxels);
+        let generated = loader.generate(VoxelCoord::new(16, 0, 0));
+        assert_eq!(generated[VoxelCoord::new(4, 0, 4)], TestVoxel::Grass);
+        assert_eq!(generated[VoxelCoord::new(4, 1, 4)], TestVoxel::Air);
+
+        fs::write(directory.join("0.0.0.region"), b"MVRG\x07").unwrap();
+        drop(io);
+        assert!(loader.io.load(b).is_err());
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        assert!(store.load_chunk(a).is_err());
+        // and what's generated in place of a chunk that failed to load isn't saved over it
+        let io = WorldIo::start(store.clone());
+        let loader = LoadOrGenerate::new(io.handle(), Superflat::new(0, vec![(TestVoxel::Grass, 1)]));
+        let generated = loader.generate(a);
+        assert!(store.is_damaged(a) && !store.is_damaged(b));
+        assert!(store.save_chunk(&generated).is_err());
+        assert_eq!(fs::read(directory.join("0.0.0.region")).unwrap(), b"MVRG\x07");
+
+        drop(io);
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let directory = test_directory("voxel_persist_compaction_test");
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap().with_codec(Codec::None);
+        let path = directory.join("0.0.0.region");
+
+        let (a, b) = (VoxelCoord::new(0, 0, 0), VoxelCoord::new(16, 0, 0));
+        let mut chunk = Chunk::empty(a);
+        store.save_chunk(&Chunk::empty(b)).unwrap();
+        let mut largest = 0;
+        for i in 0..200 {
+            chunk[VoxelCoord::new(i % 16, i / 16, 0)] = if i % 2 == 0 { TestVoxel::Rock } else { TestVoxel::Grass };
+            store.save_chunk(&chunk).unwrap();
+            largest = largest.max(fs::metadata(&path).unwrap().len());
+        }
+        // every save leaves an old copy of about a kilobyte behind, but they're cleared out
+        assert!(largest < (HEADER_SIZE as u64 + 2 * MIN_COMPACT) + 2048, "{}", largest);
+        assert!(store.load_chunk(a).unwrap().unwrap().voxels == chunk.voxels);
+        assert!(store.load_chunk(b).unwrap().unwrap().voxels == Chunk::empty(b).voxels);
+        // with no compacted copy left behind
+        assert_eq!(fs::read_dir(&directory).unwrap().count(), 1);
+
+        // and a fresh store sees the compacted file
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        assert!(store.load_chunk(a).unwrap().unwrap().voxels == chunk.voxels);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn meta() {
+        let directory = test_directory("voxel_persist_meta_test");
+        let store = WorldStore::<TestVoxel>::open(&directory).unwrap();
+        assert_eq!(store.load_meta().unwrap(), None);
+
+        let new = WorldMeta::new::<TestVoxel>(1234, "superflat rock*3,grass");
+        assert_eq!(new.voxels[1], (1, "rock".to_string()));
+        let meta = store.load_or_create_meta(|| new.clone()).unwrap();
+        assert_eq!(meta, new);
+
+        let mut played = meta.clone();
+        played.playtime = Duration::from_millis(90_500);
+        store.save_meta(&played).unwrap();
+        let meta = store.load_or_create_meta(|| panic!("the world is already there")).unwrap();
+        assert_eq!(meta, played);
+        assert_eq!(meta.seed, 1234);
+
+        // renumbered and removed voxels are caught
+        let mut shuffled = played.clone();
+        shuffled.voxels[1].1 = "grass".to_string();
+        assert!(shuffled.check_voxels::<TestVoxel>().is_err());
+        shuffled.voxels[1] = (7, "lava".to_string());
+        assert!(shuffled.check_voxels::<TestVoxel>().is_err());
+        store.save_meta(&shuffled).unwrap();
+        assert!(store.load_or_create_meta(|| new.clone()).is_err());
+        // but new ones are just added
+        let mut older = played.clone();
+        older.voxels.pop();
+        store.save_meta(&older).unwrap();
+        assert_eq!(store.load_or_create_meta(|| new.clone()).unwrap(), played);
+
+        let bytes = played.encode().unwrap();
+        assert!(WorldMeta::decode(&bytes[..bytes.len() - 1]).is_err());
+        let mut future = bytes.clone();
+        future[5] = VERSION + 1;
+        assert!(WorldMeta::decode(&future).is_err());
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,171 @@
+//! Walking and falling: entities with a `VoxelBody` are moved through the world by the `VoxelPhysicsSystem`, which
+//! stops them at solid voxels (see `collision::move_aabb`).
+//!
+//! Bodies are positioned by their `GlobalTransform`'s translation, which the system moves them by; so give them a
+//! `GlobalTransform` and no `Transform`, which the transform system would write over it.
+//!
+//! Give a body a `Submersion` too to have the system keep track of how far it's in fluid (see
+//! `collision::submersion`), for whatever makes it float or swim.
+
+use super::{canonicalize, canonicalize_chunk, Aabb, Chunk, ChunkTracker, Coord, Voxel};
+use collision::{move_aabb_stepping, submersion, voxel_is_fluid, voxel_shape, Contacts, Submersion};
+
+use amethyst::core::timing::Time;
+use amethyst::core::transform::GlobalTransform;
+use specs::prelude::*;
+use std::marker::PhantomData;
+
+/// Something that moves through the world and collides with it, like a player or a falling block.
+#[derive(Clone, Copy, Debug)]
+pub struct VoxelBody {
+    /// The body's box, relative to its position.
+    pub aabb: Aabb,
+    pub velocity: Coord,
+    /// Whether gravity pulls the body down.
+    pub gravity: bool,
+    /// How high a ledge the body can walk up onto without jumping (see `collision::move_aabb_stepping`); 0 by
+    /// default.
+    pub step_height: f32,
+    contacts: Contacts,
+}
+impl VoxelBody {
+    pub fn new(aabb: Aabb, gravity: bool) -> Self {
+        VoxelBody {
+            aabb,
+            velocity: Coord::new(0.0, 0.0, 0.0),
+            gravity,
+            step_height: 0.0,
+            contacts: Contacts::default(),
+        }
+    }
+
+    /// What the body ran into the last time it moved.
+    pub fn contacts(&self) -> Contacts {
+        self.contacts
+    }
+
+    /// Whether the body's standing on something; e.g. whether it can jump.
+    pub fn on_ground(&self) -> bool {
+        self.contacts.ground
+    }
+}
+impl Component for VoxelBody {
+    type Storage = DenseVecStorage<Self>;
+}
+
+impl<V: Voxel> Component for Submersion<V> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Moves `VoxelBody`s by their velocity every frame, pulling the ones with `gravity` down, and stopping them at
+/// voxels' `Voxel::collision_shape`s. Bodies in chunks that aren't loaded stay put, so that they don't fall through
+/// the ground before it's there.
+///
+/// Bodies with a `Submersion` have it updated after they move.
+///
+/// Should run after whatever sets bodies' velocities (e.g. input), and after the `ChunkTrackerSystem`.
+pub struct VoxelPhysicsSystem<V: Voxel> {
+    /// How fast gravity speeds bodies up, in voxels per second per second.
+    pub gravity: f32,
+    _phantom: PhantomData<V>,
+}
+impl<V: Voxel> VoxelPhysicsSystem<V> {
+    pub fn new(gravity: f32) -> Self {
+        VoxelPhysicsSystem {
+            gravity,
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a, V: Voxel> System<'a> for VoxelPhysicsSystem<V> {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, Time>,
+        ReadExpect<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        WriteStorage<'a, VoxelBody>,
+        WriteStorage<'a, GlobalTransform>,
+        WriteStorage<'a, Submersion<V>>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, time, tracker, chunks, mut bodies, mut transforms, mut submersions): Self::SystemData,
+    ) {
+        let dt = time.delta_seconds();
+        let chunks = tracker.chunks(&chunks);
+        for (ent, body, transform) in (&*entities, &mut bodies, &mut transforms).join() {
+            let position = Coord::new(transform.0.w.x, transform.0.w.y, transform.0.w.z);
+            if tracker.get_chunk_ent(canonicalize_chunk(canonicalize(position))).is_none() {
+                continue;
+            }
+            if body.gravity {
+                body.velocity.y -= self.gravity * dt;
+            }
+            let start = body.aabb.translate(position);
+            let movement = move_aabb_stepping(&chunks, start, body.velocity, dt, body.step_height, voxel_shape);
+            let moved = movement.aabb().min - start.min;
+            transform.0.w.x += moved.x;
+            transform.0.w.y += moved.y;
+            transform.0.w.z += moved.z;
+            body.velocity = movement.velocity();
+            body.contacts = movement.contacts();
+            if let Some(submerged) = submersions.get_mut(ent) {
+                *submerged = submersion(&chunks, movement.aabb(), voxel_is_fluid);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::Matrix4;
+    use tracker::ChunkTrackerSystem;
+    use {TestVoxel, VoxelCoord};
+
+    #[test]
+    fn falling() {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.register::<VoxelBody>();
+        world.register::<GlobalTransform>();
+        world.add_resource(ChunkTracker::new());
+        let mut time = Time::default();
+        time.set_delta_seconds(0.05);
+        world.add_resource(time);
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(VoxelPhysicsSystem::<TestVoxel>::new(20.0), "physics", &["chunk_tracker"])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        let mut ground = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        ground.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 0, 15), TestVoxel::Rock);
+        world.create_entity().with(ground).build();
+        // feet at the bottom of the box, which is at the body's position
+        let body = VoxelBody::new(Aabb::new(Coord::new(-0.4, 0.0, -0.4), Coord::new(0.4, 1.8, 0.4)), true);
+        let player = world
+            .create_entity()
+            .with(body)
+            .with(GlobalTransform(Matrix4::from_translation(Coord::new(4.0, 6.0, 4.0))))
+            .build();
+        // nothing's loaded out here, so this one doesn't move
+        let lost = world
+            .create_entity()
+            .with(body)
+            .with(GlobalTransform(Matrix4::from_translation(Coord::new(40.0, 6.0, 4.0))))
+            .build();
+
+        for _ in 0..40 {
+            dispatcher.dispatch(&mut world.res);
+        }
+        let transforms = world.read_storage::<GlobalTransform>();
+        let bodies = world.read_storage::<VoxelBody>();
+        assert!((transforms.get(player).unwrap().0.w.y - 0.5).abs() < 1e-4);
+        assert!(bodies.get(player).unwrap().on_ground());
+        assert_eq!(bodies.get(player).unwrap().velocity, Coord::new(0.0, 0.0, 0.0));
+        assert_eq!(transforms.get(lost).unwrap().0.w.y, 6.0);
+        assert!(!bodies.get(lost).unwrap().on_ground());
+    }
+}
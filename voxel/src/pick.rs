@@ -0,0 +1,289 @@
+//! Picking voxels with the mouse cursor, and breaking and placing them.
+
+use super::{canonicalize, canonicalize_chunk, Aabb, Chunk, ChunkAccess, ChunkTracker, Coord, Voxel, VoxelCoord,
+            CHUNK_SIZE};
+use delta::{ChunkDeltas, DeltaPriority, DeltaSource};
+use physics::VoxelBody;
+use raycast::voxel_raycast;
+
+use amethyst::core::transform::GlobalTransform;
+use amethyst::input::InputHandler;
+use amethyst::renderer::{ActiveCamera, Camera, ScreenDimensions};
+use cgmath::{InnerSpace, SquareMatrix, Vector4};
+use specs::prelude::*;
+use std::hash::Hash;
+use std::marker::PhantomData;
+
+/// The voxel under the mouse cursor; updated every frame by `VoxelPickerSystem`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PickedVoxel {
+    /// The non-empty voxel under the cursor, if there is one within reach.
+    pub hit: Option<VoxelCoord>,
+    /// Where to put a voxel placed against the face of `hit` under the cursor.
+    pub place_pos: Option<VoxelCoord>,
+    /// The chunk containing `hit`.
+    pub entity: Option<Entity>,
+}
+
+/// The world-space ray through a point on the screen, as `(origin, direction)`.
+/// `origin` is on the camera's near plane; `direction` is normalized.
+/// `cursor` is in pixels from the top-left of a screen of size `dimensions`.
+///
+/// Returns None if the camera's matrices can't be inverted.
+pub fn screen_ray(
+    camera: &Camera,
+    transform: &GlobalTransform,
+    dimensions: (f32, f32),
+    cursor: (f32, f32),
+) -> Option<(Coord, Coord)> {
+    let x = 2.0 * cursor.0 / dimensions.0 - 1.0;
+    let y = 1.0 - 2.0 * cursor.1 / dimensions.1;
+
+    let view = transform.0.invert()?;
+    let inverse = (camera.proj * view).invert()?;
+    let unproject = |z: f32| {
+        let point = inverse * Vector4::new(x, y, z, 1.0);
+        point.truncate() / point.w
+    };
+    let (near, far) = (unproject(-1.0), unproject(1.0));
+    Some((near, (far - near).normalize()))
+}
+
+/// Casts a ray from the active camera (or the first camera, if there's no `ActiveCamera`) through the mouse
+/// cursor every frame, and stores what it hit in the `PickedVoxel` resource.
+///
+/// `AX` and `AC` are the axis and action types of your `InputHandler`.
+pub struct VoxelPickerSystem<V: Voxel, AX = String, AC = String> {
+    reach: f32,
+    _phantom: PhantomData<(V, AX, AC)>,
+}
+impl<V: Voxel, AX, AC> VoxelPickerSystem<V, AX, AC> {
+    /// Pick voxels up to `reach` away from the camera.
+    pub fn new(reach: f32) -> Self {
+        assert!(reach > 0.0 && reach < ::std::i16::MAX as f32, "reach must be positive and finite");
+        VoxelPickerSystem {
+            reach,
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a, V, AX, AC> System<'a> for VoxelPickerSystem<V, AX, AC>
+where
+    V: Voxel,
+    AX: Hash + Eq + Clone + Send + Sync + 'static,
+    AC: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    type SystemData = (
+        Option<Read<'a, ActiveCamera>>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadExpect<'a, ScreenDimensions>,
+        Read<'a, InputHandler<AX, AC>>,
+        Read<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        Write<'a, PickedVoxel>,
+    );
+
+    fn run(
+        &mut self,
+        (active, cameras, transforms, dimensions, input, tracker, chunks, mut picked): Self::SystemData,
+    ) {
+        *picked = PickedVoxel::default();
+
+        let camera = active
+            .and_then(|active| Some((cameras.get(active.entity)?, transforms.get(active.entity)?)))
+            .or_else(|| (&cameras, &transforms).join().next());
+        let (camera, transform) = match camera {
+            Some(camera) => camera,
+            None => return,
+        };
+        let cursor = match input.mouse_position() {
+            Some((x, y)) => (x as f32, y as f32),
+            None => return,
+        };
+        let dimensions = (dimensions.width(), dimensions.height());
+        let (origin, direction) = match screen_ray(camera, transform, dimensions, cursor) {
+            Some(ray) => ray,
+            None => return,
+        };
+
+        // search the chunks within reach
+        let reach = self.reach.ceil() as i16 + CHUNK_SIZE as i16;
+        let reach = VoxelCoord::new(reach, reach, reach);
+        let center = canonicalize(origin);
+        let hit = voxel_raycast(
+            &tracker.chunks(&chunks),
+            origin,
+            direction,
+            canonicalize_chunk(center - reach),
+            canonicalize_chunk(center + reach),
+            self.reach,
+        );
+
+        if hit.hit_interesting() {
+            picked.hit = Some(hit.end_voxel());
+            picked.place_pos = hit.placement();
+            picked.entity = hit.entity();
+        }
+    }
+}
+
+/// The voxel `VoxelInteractionSystem` places; e.g. the one in the player's hand. The default voxel (air) places
+/// nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SelectedVoxel<V: Voxel>(pub V);
+
+/// Where `VoxelInteractionSystem` edits come from when it has no `player`.
+pub const SOURCE: DeltaSource = DeltaSource::System("voxel interaction");
+
+/// Whether placing `voxel` at `coord` would put it inside one of `bodies` (boxes in world coordinates). Voxels
+/// with an empty `Voxel::collision_shape` never would, and bodies only touching the voxel don't count.
+pub fn placement_blocked<V, I>(voxel: &V, coord: VoxelCoord, bodies: I) -> bool
+where
+    V: Voxel,
+    I: IntoIterator<Item = Aabb>,
+{
+    let shape = voxel.collision_shape();
+    bodies
+        .into_iter()
+        .any(|body| shape.world_boxes(coord).any(|other| body.overlaps(&other)))
+}
+
+/// Breaks and places voxels at the `PickedVoxel`: pressing the break action replaces the picked voxel with air,
+/// and pressing the place action puts the `SelectedVoxel` against the face under the cursor, unless that would put
+/// it inside a `VoxelBody`, or there's something there already. Each press edits once; holding an action down
+/// doesn't repeat it.
+///
+/// Edits are deferred at `DeltaPriority::Player`, from `player` if there is one (otherwise from `SOURCE`), and only
+/// made within `reach` of the player, or of the active camera if there's no player. They're conditional on the
+/// voxel still being what was picked, so an edit racing another one doesn't clobber it.
+///
+/// Should run after the `VoxelPickerSystem`.
+pub struct VoxelInteractionSystem<V: Voxel, AX = String, AC = String> {
+    /// The action that breaks the picked voxel.
+    pub break_action: AC,
+    /// The action that places the selected voxel.
+    pub place_action: AC,
+    /// How far from the player (or camera) voxels can be edited.
+    pub reach: f32,
+    /// The entity doing the editing, if any.
+    pub player: Option<Entity>,
+    was_down: (bool, bool),
+    _phantom: PhantomData<(V, AX)>,
+}
+impl<V: Voxel, AX, AC> VoxelInteractionSystem<V, AX, AC> {
+    pub fn new(break_action: AC, place_action: AC, reach: f32) -> Self {
+        VoxelInteractionSystem {
+            break_action,
+            place_action,
+            reach,
+            player: None,
+            was_down: (false, false),
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<'a, V, AX, AC> System<'a> for VoxelInteractionSystem<V, AX, AC>
+where
+    V: Voxel,
+    AX: Hash + Eq + Clone + Send + Sync + 'static,
+    AC: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    type SystemData = (
+        Option<Read<'a, ActiveCamera>>,
+        ReadStorage<'a, Camera>,
+        ReadStorage<'a, GlobalTransform>,
+        ReadStorage<'a, VoxelBody>,
+        Read<'a, InputHandler<AX, AC>>,
+        Read<'a, PickedVoxel>,
+        Read<'a, SelectedVoxel<V>>,
+        Read<'a, ChunkTracker>,
+        ReadStorage<'a, Chunk<V>>,
+        Read<'a, ChunkDeltas<V>>,
+    );
+
+    fn run(&mut self, data: Self::SystemData) {
+        let (active, cameras, transforms, bodies, input, picked, selected, tracker, chunks, deltas) = data;
+        let down = (
+            input.action_is_down(&self.break_action).unwrap_or(false),
+            input.action_is_down(&self.place_action).unwrap_or(false),
+        );
+        let (break_pressed, place_pressed) = (down.0 && !self.was_down.0, down.1 && !self.was_down.1);
+        self.was_down = down;
+        if !break_pressed && !place_pressed {
+            return;
+        }
+
+        let eye = match self.player {
+            Some(player) => transforms.get(player),
+            None => active
+                .and_then(|active| transforms.get(active.entity))
+                .or_else(|| (&cameras, &transforms).join().next().map(|(_, transform)| transform)),
+        };
+        let eye = match eye {
+            Some(transform) => transform.0.w.truncate(),
+            None => return,
+        };
+        let reach = self.reach;
+        let in_reach = |coord: VoxelCoord| (coord.cast::<f32>().unwrap() - eye).magnitude() <= reach;
+        let chunks = tracker.chunks(&chunks);
+        let writer = deltas
+            .writer()
+            .priority(DeltaPriority::Player)
+            .source(self.player.map_or(SOURCE, DeltaSource::Entity));
+
+        if let (true, Some(hit)) = (break_pressed, picked.hit) {
+            if let (true, Some(current)) = (in_reach(hit), chunks.get_voxel(hit)) {
+                writer.defer_set_if(hit, current, V::default());
+            }
+        }
+        if let (true, Some(place)) = (place_pressed && selected.0 != V::default(), picked.place_pos) {
+            if in_reach(place) {
+                let boxes = (&bodies, &transforms)
+                    .join()
+                    .map(|(body, transform)| body.aabb.translate(transform.0.w.truncate()));
+                if !placement_blocked(&selected.0, place, boxes) {
+                    writer.defer_set_where(place, selected.0, |current| *current == V::default());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix4};
+    use amethyst::renderer::Projection;
+    use TestVoxel;
+
+    #[test]
+    fn screen_center() {
+        let camera = Camera::from(Projection::perspective(1.0, Deg(60.0)));
+        // cameras look down -z
+        let transform = GlobalTransform(Matrix4::from_translation(Coord::new(1.0, 2.0, 3.0)));
+        let (origin, direction) = screen_ray(&camera, &transform, (800.0, 800.0), (400.0, 400.0)).unwrap();
+        assert!((direction - Coord::new(0.0, 0.0, -1.0)).magnitude() < 1e-4);
+        assert!((origin.x - 1.0).abs() < 1e-4 && (origin.y - 2.0).abs() < 1e-4);
+
+        // the top of the screen is up
+        let (_, up) = screen_ray(&camera, &transform, (800.0, 800.0), (400.0, 0.0)).unwrap();
+        assert!(up.y > 0.0 && up.x.abs() < 1e-4);
+    }
+
+    #[test]
+    fn blocked_placement() {
+        // a player standing on the voxel at (0, 0, 0), so with their feet at y = 0.5
+        let player = Aabb::new(Coord::new(-0.4, 0.5, -0.4), Coord::new(0.4, 2.3, 0.4));
+        let at = |x, y, z| VoxelCoord::new(x, y, z);
+        assert!(placement_blocked(&TestVoxel::Rock, at(0, 1, 0), vec![player]));
+        assert!(placement_blocked(&TestVoxel::Rock, at(0, 2, 0), vec![player]));
+        // touching, or clear of the player
+        assert!(!placement_blocked(&TestVoxel::Rock, at(0, 0, 0), vec![player]));
+        assert!(!placement_blocked(&TestVoxel::Rock, at(0, 3, 0), vec![player]));
+        assert!(!placement_blocked(&TestVoxel::Rock, at(1, 1, 0), vec![player]));
+        // nothing to bump into
+        assert!(!placement_blocked(&TestVoxel::Air, at(0, 1, 0), vec![player]));
+        assert!(!placement_blocked(&TestVoxel::Rock, at(0, 1, 0), vec![]));
+    }
+}
@@ -0,0 +1,224 @@
+//! Generating chunks in ordered passes: base terrain, then caves, ores, surface decoration, structures...
+//!
+//! Each pass gets the chunk as the passes before it left it, and read access to the neighboring chunks
+//! that the pipeline has already finished, if it still has them; e.g. so that decoration can look at the
+//! ground across a chunk border. Which neighbors are there depends on the order chunks are generated in,
+//! so passes that should always give the same chunk mustn't depend on them.
+//!
+//! ```ignore
+//! let pipeline = GenerationPipeline::new()
+//!     .with_generator("terrain", HeightmapGenerator::new(heightmap, (0, 0), 0, 64.0, Stone))
+//!     .with_pass("caves", carve_caves)
+//!     .with_pass("structures", StructurePass::new(Trees, 64, 8));
+//! ```
+
+use super::{canonicalize_chunk, Chunk, Voxel, VoxelCoord, CHUNK_SIZE};
+use generate::ChunkGenerator;
+
+use fnv::FnvHashMap;
+use parking_lot::Mutex;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// One step of generating a chunk; see `GenerationPipeline`.
+pub trait GenerationPass<V: Voxel>: Send + Sync + 'static {
+    /// Do this pass's part to `chunk`.
+    fn apply(&self, chunk: &mut Chunk<V>, neighbors: &Neighbors<V>);
+}
+impl<V: Voxel, F: Fn(&mut Chunk<V>, &Neighbors<V>) + Send + Sync + 'static> GenerationPass<V> for F {
+    fn apply(&self, chunk: &mut Chunk<V>, neighbors: &Neighbors<V>) {
+        self(chunk, neighbors)
+    }
+}
+
+/// Runs a `ChunkGenerator` as a pass, replacing the chunk with what it generates; usually the first pass.
+pub struct GeneratorPass<G>(pub G);
+impl<V: Voxel, G: ChunkGenerator<V>> GenerationPass<V> for GeneratorPass<G> {
+    fn apply(&self, chunk: &mut Chunk<V>, _neighbors: &Neighbors<V>) {
+        *chunk = self.0.generate(chunk.coord);
+    }
+}
+
+/// The chunks around the one being generated that are already finished.
+pub struct Neighbors<V: Voxel> {
+    chunks: FnvHashMap<VoxelCoord, Arc<Chunk<V>>>,
+}
+impl<V: Voxel> Neighbors<V> {
+    /// No neighbors.
+    pub fn none() -> Self {
+        Neighbors {
+            chunks: FnvHashMap::default(),
+        }
+    }
+
+    /// The finished chunk containing `coord`, if it's next to the chunk being generated.
+    pub fn chunk(&self, coord: VoxelCoord) -> Option<&Chunk<V>> {
+        self.chunks.get(&canonicalize_chunk(coord)).map(|chunk| &**chunk)
+    }
+
+    /// The voxel at `coord`, if it's in a finished neighbor.
+    pub fn get(&self, coord: VoxelCoord) -> Option<V> {
+        self.chunk(coord).map(|chunk| chunk[coord - chunk.coord])
+    }
+}
+
+/// The chunks a pipeline finished most recently, oldest first.
+struct Finished<V: Voxel> {
+    chunks: FnvHashMap<VoxelCoord, Arc<Chunk<V>>>,
+    order: VecDeque<VoxelCoord>,
+}
+
+/// A `ChunkGenerator` made of ordered passes, built with `with_pass` and `with_generator`. Chunks start out
+/// empty, and each pass works on them in turn.
+///
+/// The pipeline keeps copies of the last `keep` chunks it finished, for the passes of later chunks to look
+/// at; see `Neighbors`.
+pub struct GenerationPipeline<V: Voxel> {
+    passes: Vec<(String, Box<dyn GenerationPass<V>>)>,
+    finished: Mutex<Finished<V>>,
+    keep: usize,
+}
+impl<V: Voxel> GenerationPipeline<V> {
+    pub fn new() -> Self {
+        GenerationPipeline {
+            passes: Vec::new(),
+            finished: Mutex::new(Finished {
+                chunks: FnvHashMap::default(),
+                order: VecDeque::new(),
+            }),
+            keep: 256,
+        }
+    }
+
+    /// Add a pass, after the ones already added.
+    pub fn with_pass<P: GenerationPass<V>>(mut self, name: &str, pass: P) -> Self {
+        self.passes.push((name.to_string(), Box::new(pass)));
+        self
+    }
+
+    /// Add a pass that runs `generator`; see `GeneratorPass`.
+    pub fn with_generator<G: ChunkGenerator<V>>(self, name: &str, generator: G) -> Self {
+        self.with_pass(name, GeneratorPass(generator))
+    }
+
+    /// How many finished chunks to keep for neighbors; 256 by default.
+    pub fn keep(mut self, keep: usize) -> Self {
+        self.keep = keep;
+        self
+    }
+
+    /// The names of the passes, in order.
+    pub fn passes(&self) -> Vec<&str> {
+        self.passes.iter().map(|&(ref name, _)| &name[..]).collect()
+    }
+
+    /// The finished chunks next to the chunk at `chunk_coord`.
+    fn neighbors(&self, chunk_coord: VoxelCoord) -> Neighbors<V> {
+        let finished = self.finished.lock();
+        let mut neighbors = Neighbors::none();
+        let size = CHUNK_SIZE as i16;
+        for x in -1..2 {
+            for y in -1..2 {
+                for z in -1..2 {
+                    let coord = chunk_coord + VoxelCoord::new(x, y, z) * size;
+                    if coord == chunk_coord {
+                        continue;
+                    }
+                    if let Some(chunk) = finished.chunks.get(&coord) {
+                        neighbors.chunks.insert(coord, chunk.clone());
+                    }
+                }
+            }
+        }
+        neighbors
+    }
+
+    /// Keep a copy of a finished chunk, forgetting the oldest if there are too many.
+    fn finish(&self, chunk: &Chunk<V>) {
+        if self.keep == 0 {
+            return;
+        }
+        let copy = Chunk {
+            coord: chunk.coord,
+            voxels: chunk.voxels,
+        };
+        let mut finished = self.finished.lock();
+        if finished.chunks.insert(chunk.coord, Arc::new(copy)).is_none() {
+            finished.order.push_back(chunk.coord);
+        }
+        while finished.order.len() > self.keep {
+            let oldest = finished.order.pop_front().unwrap();
+            finished.chunks.remove(&oldest);
+        }
+    }
+}
+impl<V: Voxel> Default for GenerationPipeline<V> {
+    fn default() -> Self {
+        GenerationPipeline::new()
+    }
+}
+impl<V: Voxel> ChunkGenerator<V> for GenerationPipeline<V> {
+    fn generate(&self, chunk_coord: VoxelCoord) -> Chunk<V> {
+        let neighbors = self.neighbors(chunk_coord);
+        let mut chunk = Chunk::empty(chunk_coord);
+        for &(ref name, ref pass) in &self.passes {
+            pass.apply(&mut chunk, &neighbors);
+            debug_assert_eq!(chunk.coord, chunk_coord, "pass {} moved the chunk", name);
+        }
+        self.finish(&chunk);
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use patterns::Superflat;
+    use TestVoxel;
+
+    /// Grass on rock that has air above it, including across the chunk's top, if the chunk above is there.
+    fn grow_grass(chunk: &mut Chunk<TestVoxel>, neighbors: &Neighbors<TestVoxel>) {
+        let coord = chunk.coord;
+        for x in 0..16 {
+            for y in 0..16 {
+                for z in 0..16 {
+                    let local = VoxelCoord::new(x, y, z);
+                    let above = if y == 15 {
+                        neighbors.get(coord + local + VoxelCoord::new(0, 1, 0))
+                    } else {
+                        Some(chunk[local + VoxelCoord::new(0, 1, 0)])
+                    };
+                    if chunk[local] == TestVoxel::Rock && above == Some(TestVoxel::Air) {
+                        chunk[local] = TestVoxel::Grass;
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn passes() {
+        let pipeline = GenerationPipeline::new()
+            .with_generator("terrain", Superflat::new(-16, vec![(TestVoxel::Rock, 16)]))
+            .with_pass("grass", grow_grass)
+            .keep(2);
+        assert_eq!(pipeline.passes(), vec!["terrain", "grass"]);
+
+        // the chunk above hasn't been generated, so there's no telling if the top is exposed
+        let below = VoxelCoord::new(0, -16, 0);
+        let chunk = pipeline.generate(below);
+        assert_eq!(chunk[VoxelCoord::new(3, 15, 3)], TestVoxel::Rock);
+
+        let above = pipeline.generate(VoxelCoord::new(0, 0, 0));
+        assert_eq!(above[VoxelCoord::new(3, 0, 3)], TestVoxel::Air);
+        let chunk = pipeline.generate(below);
+        assert_eq!(chunk[VoxelCoord::new(3, 15, 3)], TestVoxel::Grass);
+        assert_eq!(chunk[VoxelCoord::new(3, 14, 3)], TestVoxel::Rock);
+
+        // only the last two chunks are kept
+        pipeline.generate(VoxelCoord::new(64, 0, 0));
+        pipeline.generate(VoxelCoord::new(128, 0, 0));
+        let chunk = pipeline.generate(below);
+        assert_eq!(chunk[VoxelCoord::new(3, 15, 3)], TestVoxel::Rock);
+    }
+}
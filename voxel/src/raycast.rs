@@ -2,10 +2,15 @@
 //! "A Fast Voxel Traversal Algorithm for Ray Tracing", John Amanatides, Andrew Woo, 1987
 //! http://citeseerx.ist.psu.edu/viewdoc/download?doi=10.1.1.42.3443&rep=rep1&type=pdf
 
-use super::{canonicalize, canonicalize_chunk, Coord, VoxelCoord, Voxel, Chunk, ChunkTracker, CHUNK_SIZE};
-use std::f32;
-use cgmath::InnerSpace;
-use specs::ReadStorage;
+use super::{canonicalize, canonicalize_chunk, voxels_in_box, Aabb, Coord, VoxelCoord, Voxel, Chunk, ChunkAccess, CHUNK_SIZE};
+use std::{error, f32, fmt};
+use amethyst::core::transform::GlobalTransform;
+use cgmath::{BaseFloat, EuclideanSpace, InnerSpace, Matrix, Point3, SquareMatrix, Transform, Vector3};
+use fnv::FnvHashMap;
+use soft_time_limit::TimeLimiter;
+use specs::Entity;
+use std::collections::VecDeque;
+use std::time::Duration;
 
 /// The face a raycasting operation hit.
 /// 
@@ -15,18 +20,119 @@ pub enum FaceHit {
 }
 
 /// Information about a cast ray.
+/// `S` is the precision it was cast with; see `raycast`.
 #[derive(Clone, Copy, Debug)]
-pub struct Raycast {
+pub struct Raycast<S = f32> {
     /// The face the ray hit.
     face_hit: FaceHit,
     /// The ending point of the ray.
     /// Note: may be slightly outside `end_voxel` due to floating point error.
-    end: Coord,
+    end: Vector3<S>,
     /// The voxel the ray ended on.
     end_voxel: VoxelCoord,
     /// Whether the voxel we hit was "interesting", i.e.
     /// if this is false, we hit the border of the voxel.
     hit_interesting: bool,
+    /// The normal of the face we hit, pointing back along the ray; zero if `Contained`.
+    normal: VoxelCoord,
+    /// `end = start + direction * t`
+    t: S,
+    /// The direction the ray was cast in.
+    direction: Vector3<S>,
+    /// Whether the ray stopped because it reached its maximum distance.
+    hit_max_distance: bool,
+    /// The `t` at which the ray entered `end_voxel`; 0 if `Contained`.
+    t_enter: S,
+    /// The `t` at which the ray would leave `end_voxel`.
+    t_exit: S,
+    /// The chunk entity containing `end_voxel`, for hits from `voxel_raycast`.
+    entity: Option<Entity>,
+}
+impl<S: BaseFloat> Raycast<S> {
+    /// The face the ray hit.
+    pub fn face_hit(&self) -> FaceHit {
+        self.face_hit
+    }
+    /// The ending point of the ray.
+    /// Note: may be slightly outside `end_voxel` due to floating point error.
+    pub fn end(&self) -> Vector3<S> {
+        self.end
+    }
+    /// The voxel the ray ended on.
+    pub fn end_voxel(&self) -> VoxelCoord {
+        self.end_voxel
+    }
+    /// The canonical coordinate of the chunk containing `end_voxel`.
+    pub fn chunk(&self) -> VoxelCoord {
+        canonicalize_chunk(self.end_voxel)
+    }
+    /// `end_voxel` relative to its chunk, i.e. its index into the `Chunk`.
+    pub fn local_voxel(&self) -> VoxelCoord {
+        self.end_voxel - self.chunk()
+    }
+    /// The entity of the chunk containing `end_voxel`, if this came from `voxel_raycast`, the ray hit something,
+    /// and the chunks are entities (see `ChunkAccess::get_chunk_ent`).
+    pub fn entity(&self) -> Option<Entity> {
+        self.entity
+    }
+    /// Whether the voxel we hit was "interesting", i.e.
+    /// if this is false, we hit the border of the search or ran out of distance.
+    pub fn hit_interesting(&self) -> bool {
+        self.hit_interesting
+    }
+    /// Whether the ray stopped because it reached its maximum distance.
+    /// If so, `end` is the point at that distance, `end_voxel` is the voxel containing it,
+    /// and `face_hit` / `normal` describe the last face the ray crossed.
+    pub fn hit_max_distance(&self) -> bool {
+        self.hit_max_distance
+    }
+    /// The normal of the face we hit, pointing back towards the start of the ray;
+    /// zero if the ray started inside the voxel it ended on.
+    pub fn normal(&self) -> VoxelCoord {
+        self.normal
+    }
+    /// The multiple of the direction vector that takes the start of the ray to `end`.
+    pub fn t(&self) -> S {
+        self.t
+    }
+    /// The distance travelled from the start of the ray to `end`.
+    pub fn distance(&self) -> S {
+        self.t * self.direction.magnitude()
+    }
+    /// The `t` at which the ray entered `end_voxel`, or 0 if it started inside it.
+    /// Equal to `t` unless the ray stopped at its maximum distance.
+    pub fn t_enter(&self) -> S {
+        self.t_enter
+    }
+    /// The `t` at which the ray would leave `end_voxel` if it kept going.
+    pub fn t_exit(&self) -> S {
+        self.t_exit
+    }
+    /// The point where the ray would leave `end_voxel` if it kept going.
+    pub fn exit(&self) -> Vector3<S> {
+        self.end + self.direction * (self.t_exit - self.t)
+    }
+    /// How far the ray would travel inside `end_voxel`, i.e. the thickness of the hit voxel along the ray.
+    pub fn depth(&self) -> S {
+        (self.t_exit - self.t_enter) * self.direction.magnitude()
+    }
+    /// The voxel in front of the face we hit, i.e. the last voxel the ray passed through before
+    /// `end_voxel`; where you'd place a block. Equal to `end_voxel` if the ray started inside it.
+    pub fn previous_voxel(&self) -> VoxelCoord {
+        self.end_voxel + self.normal
+    }
+    /// Where to place a voxel against the face we hit: the empty voxel in front of it.
+    /// `None` if we didn't hit anything, or if the ray started inside the voxel it hit
+    /// (there's no face to place against).
+    ///
+    /// Note that the placement voxel may be in an unloaded chunk, if the ray entered a loaded chunk from an unloaded one.
+    pub fn placement(&self) -> Option<VoxelCoord> {
+        if self.hit_interesting && self.face_hit != FaceHit::Contained {
+            Some(self.previous_voxel())
+        } else {
+            None
+        }
+    }
 }
 
 /// Starting at "start_voxel" / "start", walk through grid squares
@@ -43,139 +149,464 @@ pub struct Raycast {
 /// is_interesting should return "true" to signal that the raycast should stop.
 /// it will not be evaluated for border voxels, that is, voxels where x == min.x and so on.
 ///
+/// The ray also stops once it has travelled `max_distance` (in units of the grid);
+/// pass `f32::INFINITY` to only stop at the box.
+///
 /// Returns a multiple of the direction vector that puts it in the target voxel,
 /// and the coordinate of the voxel that occluded the ray.
 /// 
 /// Note that voxels are centered at integer coordinates.
+///
+/// The ray can be cast in `f32` or `f64`. Error in `f32` builds up as the ray crosses voxels, and after
+/// a few thousand it can end up in a neighbour of the voxel it should be in; cast rays that need to be
+/// exact at long range (e.g. validating hits on a server) with `Vector3<f64>`s.
+///
+/// `start_voxel` must be inside the box; see `clipped_raycast` for rays that start outside it.
 #[inline]
-pub fn raycast<F: FnMut(VoxelCoord) -> bool>(
+pub fn raycast<S: BaseFloat, F: FnMut(VoxelCoord) -> bool>(
     start_voxel: VoxelCoord,
-    start: Coord,
-    direction: Coord,
+    start: Vector3<S>,
+    direction: Vector3<S>,
     min: VoxelCoord,
     max: VoxelCoord,
+    max_distance: S,
     mut is_interesting: F,
-) -> Raycast {
+) -> Raycast<S> {
+    let VoxelCoord { x, y, z } = start_voxel;
+    assert!(min.x <= x && x <= max.x);
+    assert!(min.y <= y && y <= max.y);
+    assert!(min.z <= z && z <= max.z);
+
+    let mut iter = raycast_iter(start_voxel, start, direction, max_distance);
+    // the start voxel
+    iter.next();
+
     // if we're in a target block, return immediately
     if is_interesting(start_voxel) {
         return Raycast {
             face_hit: FaceHit::Contained,
             end: start,
             end_voxel: start_voxel,
-            hit_interesting: true
+            hit_interesting: true,
+            normal: VoxelCoord::new(0, 0, 0),
+            t: S::zero(),
+            direction,
+            hit_max_distance: false,
+            t_enter: S::zero(),
+            t_exit: iter.t_next(),
+            entity: None,
         };
     }
 
-    // integer coordinates of the center of our voxel
-    let VoxelCoord {
-        mut x,
-        mut y,
-        mut z,
-    } = start_voxel;
-    let step = canonicalize(direction);
-    let (step_x, step_y, step_z) = (step.x.signum(), step.y.signum(), step.z.signum());
-
-    assert!(!start.x.is_nan() && !start.y.is_nan() && !start.z.is_nan());
-    assert!(!direction.x.is_nan() && !direction.y.is_nan() && !direction.z.is_nan());
-    assert!(min.x <= x && x <= max.x);
-    assert!(min.y <= y && x <= max.y);
-    assert!(min.z <= z && x <= max.z);
-
     // box defining stopping voxels
-    let lim_x =
-        if step_x > 0 { max.x } else { min.x };
-    let lim_y =
-        if step_y > 0 { max.y } else { min.y };
-    let lim_z =
-        if step_z > 0 { max.z } else { min.z };
+    let lim = |c: S, min: i16, max: i16| if c > S::zero() { max } else { min };
+    let (lim_x, lim_y, lim_z) = (
+        lim(direction.x, min.x, max.x),
+        lim(direction.y, min.y, max.y),
+        lim(direction.z, min.z, max.z),
+    );
 
-    // floating point coordinates
-    // t_max_c: multiple of direction to get to that edge of voxel
-    // t_dc: multiple of direction to move 1 voxel
-    let Coord {
-        x: dx,
-        y: dy,
-        z: dz,
-    } = direction;
-    let (mut t_max_x, t_dx) = init(start_voxel.x, start.x, dx);
-    let (mut t_max_y, t_dy) = init(start_voxel.y, start.y, dy);
-    let (mut t_max_z, t_dz) = init(start_voxel.z, start.z, dz);
+    // the voxel we're in and how we got there, for rays that stop at max_distance
+    let mut last = (start_voxel, FaceHit::Contained, S::zero());
 
     loop {
-        if t_max_x <= t_max_y && t_max_x <= t_max_z {
-            x += step_x;
-            let cur = VoxelCoord { x, y, z };
-
-            let hit_border = x == lim_x;
-            // note: evaluation order is important here:
-            // we don't want to evaluate the predicate on border voxels
-            if hit_border || is_interesting(cur) {
+        let (cur, face_hit, t) = match iter.next() {
+            Some(step) => step,
+            None => {
+                let (end_voxel, face_hit, t_enter) = last;
                 return Raycast {
-                    face_hit: FaceHit::X,
-                    end: start + direction * t_max_x,
-                    end_voxel: cur,
-                    // if we hit the border, it can't be interesting;
-                    // if we hit interesting, it can't be border
-                    hit_interesting: !hit_border,
+                    face_hit,
+                    end: start + direction * iter.max_t,
+                    end_voxel,
+                    hit_interesting: false,
+                    normal: face_normal(face_hit, direction),
+                    t: iter.max_t,
+                    direction,
+                    hit_max_distance: true,
+                    t_enter,
+                    t_exit: iter.t_next(),
+                    entity: None,
                 };
             }
+        };
+
+        let hit_border = match face_hit {
+            FaceHit::X => cur.x == lim_x,
+            FaceHit::Y => cur.y == lim_y,
+            FaceHit::Z => cur.z == lim_z,
+            FaceHit::Contained => unreachable!(),
+        };
+        // note: evaluation order is important here:
+        // we don't want to evaluate the predicate on border voxels
+        if hit_border || is_interesting(cur) {
+            return Raycast {
+                face_hit,
+                end: start + direction * t,
+                end_voxel: cur,
+                // if we hit the border, it can't be interesting;
+                // if we hit interesting, it can't be border
+                hit_interesting: !hit_border,
+                normal: face_normal(face_hit, direction),
+                t,
+                direction,
+                hit_max_distance: false,
+                t_enter: t,
+                t_exit: iter.t_next(),
+                entity: None,
+            };
+        }
+        last = (cur, face_hit, t);
+    }
+}
+
+/// As `raycast`, but the ray can start anywhere, even far outside the box from `min` to `max`
+/// (e.g. sun shadows, or cameras orbiting the world): it's first moved forward to where it enters the box,
+/// and traversed from there. Returns None if it never enters the box within `max_distance`.
+///
+/// The result's `t`s and distances are still measured from `start`. Voxels on the border of the box
+/// aren't evaluated, including the one the ray enters through.
+pub fn clipped_raycast<S: BaseFloat, F: FnMut(VoxelCoord) -> bool>(
+    start: Vector3<S>,
+    direction: Vector3<S>,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    max_distance: S,
+    mut is_interesting: F,
+) -> Option<Raycast<S>> {
+    let (t0, start_voxel, entry) = enter_box(start, direction, min, max)?;
+    let travelled = t0 * direction.magnitude();
+    if travelled > max_distance {
+        return None;
+    }
+    let outside = t0 > S::zero();
+    let hit = raycast(start_voxel, entry, direction, min, max, max_distance - travelled, |v| {
+        !(outside && v == start_voxel) && is_interesting(v)
+    });
+    Some(Raycast {
+        t: hit.t + t0,
+        t_enter: hit.t_enter + t0,
+        t_exit: hit.t_exit + t0,
+        ..hit
+    })
+}
 
-            t_max_x += t_dx;
-        } else if t_max_y < t_max_x && t_max_y <= t_max_z {
-            y += step_y;
-            let cur = VoxelCoord { x, y, z };
+/// Where a ray enters the box of voxels from `min` to `max` (inclusive), as `(t, voxel, point)`;
+/// None if it misses the box entirely.
+///
+/// If `start` is in the box, that's `(0, the voxel containing start, start)`. Otherwise `point` is
+/// exactly on the face of the box the ray enters through, and `voxel` is the border voxel behind that face;
+/// so a traversal starting from there steps straight into the interior, through the right face.
+fn enter_box<S: BaseFloat>(
+    start: Vector3<S>,
+    direction: Vector3<S>,
+    min: VoxelCoord,
+    max: VoxelCoord,
+) -> Option<(S, VoxelCoord, Vector3<S>)> {
+    let half = S::from(0.5).unwrap();
+    let lo = |i: usize| S::from(min[i]).unwrap() - half;
+    let hi = |i: usize| S::from(max[i]).unwrap() + half;
 
-            let hit_border = y == lim_y;
-            if hit_border || is_interesting(cur) {
-                return Raycast {
-                    face_hit: FaceHit::Y,
-                    end: start + direction * t_max_y,
-                    end_voxel: cur,
-                    hit_interesting: !hit_border,
-                };
+    let (mut t_enter, mut t_exit, mut axis) = (S::zero(), S::infinity(), None);
+    for i in 0..3 {
+        if direction[i] == S::zero() {
+            if start[i] < lo(i) || start[i] > hi(i) {
+                return None;
             }
+            continue;
+        }
+        let a = (lo(i) - start[i]) / direction[i];
+        let b = (hi(i) - start[i]) / direction[i];
+        let (near, far) = if a < b { (a, b) } else { (b, a) };
+        if near > t_enter {
+            t_enter = near;
+            axis = Some(i);
+        }
+        t_exit = t_exit.min(far);
+    }
+    if t_enter > t_exit {
+        return None;
+    }
 
-            t_max_y += t_dy;
+    let mut point = start + direction * t_enter;
+    let mut voxel = VoxelCoord::new(0, 0, 0);
+    for i in 0..3 {
+        // floating point error can leave us slightly outside
+        point[i] = point[i].max(lo(i)).min(hi(i));
+        let v = point[i].round().max(S::from(min[i]).unwrap()).min(S::from(max[i]).unwrap());
+        voxel[i] = v.to_i16().unwrap();
+    }
+    if let Some(i) = axis {
+        if direction[i] > S::zero() {
+            point[i] = lo(i);
+            voxel[i] = min[i];
         } else {
-            z += step_z;
-            let cur = VoxelCoord { x, y, z };
+            point[i] = hi(i);
+            voxel[i] = max[i];
+        }
+    }
+    Some((t_enter, voxel, point))
+}
 
-            let hit_border = z == lim_z;
-            if hit_border || is_interesting(cur) {
-                return Raycast {
-                    face_hit: FaceHit::Z,
-                    end: start + direction * t_max_z,
-                    end_voxel: cur,
-                    hit_interesting: !hit_border,
-                };
-            }
+/// Why a ray couldn't be cast; see `try_raycast`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RaycastError {
+    /// The start or direction was NaN or infinite, or the maximum distance was NaN.
+    NotFinite,
+    /// The direction was zero (or so small that its length underflows).
+    ZeroDirection,
+    /// The maximum distance was negative.
+    NegativeDistance,
+    /// `start` wasn't in (or within floating point error of) `start_voxel`.
+    StartNotInVoxel,
+    /// The start was outside the search bounds.
+    StartOutOfBounds,
+}
+impl fmt::Display for RaycastError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(error::Error::description(self))
+    }
+}
+impl error::Error for RaycastError {
+    fn description(&self) -> &str {
+        match *self {
+            RaycastError::NotFinite => "ray has a non-finite start, direction, or maximum distance",
+            RaycastError::ZeroDirection => "ray has zero direction",
+            RaycastError::NegativeDistance => "ray has negative maximum distance",
+            RaycastError::StartNotInVoxel => "ray doesn't start in its start voxel",
+            RaycastError::StartOutOfBounds => "ray starts outside the search bounds",
+        }
+    }
+}
+
+/// Check the parts of a ray that every cast cares about.
+fn check_ray(start: Coord, direction: Coord, max_distance: f32) -> Result<(), RaycastError> {
+    let finite = |c: Coord| c.x.is_finite() && c.y.is_finite() && c.z.is_finite();
+    if !finite(start) || !finite(direction) || max_distance.is_nan() {
+        Err(RaycastError::NotFinite)
+    } else if direction.magnitude() <= 0.0 {
+        Err(RaycastError::ZeroDirection)
+    } else if max_distance < 0.0 {
+        Err(RaycastError::NegativeDistance)
+    } else {
+        Ok(())
+    }
+}
+
+/// As `raycast`, but returns an error instead of panicking or misbehaving on bad input:
+/// NaNs, zero directions, negative distances, and starts outside `start_voxel` or the bounds.
+///
+/// Axes the direction is zero along are fine; the ray just never steps along them.
+pub fn try_raycast<F: FnMut(VoxelCoord) -> bool>(
+    start_voxel: VoxelCoord,
+    start: Coord,
+    direction: Coord,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    max_distance: f32,
+    is_interesting: F,
+) -> Result<Raycast, RaycastError> {
+    check_ray(start, direction, max_distance)?;
+
+    // allow for a little floating point error
+    let off = |v: i16, c: f32| (c - v as f32).abs() > 0.5 + 1e-3;
+    if off(start_voxel.x, start.x) || off(start_voxel.y, start.y) || off(start_voxel.z, start.z) {
+        return Err(RaycastError::StartNotInVoxel);
+    }
+    let VoxelCoord { x, y, z } = start_voxel;
+    if x < min.x || x > max.x || y < min.y || y > max.y || z < min.z || z > max.z {
+        return Err(RaycastError::StartOutOfBounds);
+    }
+
+    Ok(raycast(start_voxel, start, direction, min, max, max_distance, is_interesting))
+}
+
+/// As `voxel_raycast`, but returns an error instead of panicking or misbehaving on bad input;
+/// see `try_raycast`.
+pub fn try_voxel_raycast<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    coord: Coord,
+    direction: Coord,
+    min_chunk: VoxelCoord,
+    max_chunk: VoxelCoord,
+    max_distance: f32,
+) -> Result<Raycast, RaycastError> {
+    check_ray(coord, direction, max_distance)?;
+
+    // make sure we can convert to voxel coordinates at all
+    let limit = ::std::i16::MAX as f32 - SIZE_F;
+    if coord.x.abs() > limit || coord.y.abs() > limit || coord.z.abs() > limit {
+        return Err(RaycastError::StartOutOfBounds);
+    }
+    let chunk = canonicalize_chunk(canonicalize(coord)) / SIZE_I;
+    let (min, max) = (min_chunk / SIZE_I, max_chunk / SIZE_I);
+    if chunk.x < min.x || chunk.x > max.x || chunk.y < min.y || chunk.y > max.y || chunk.z < min.z || chunk.z > max.z {
+        return Err(RaycastError::StartOutOfBounds);
+    }
+
+    Ok(voxel_raycast(chunks, coord, direction, min_chunk, max_chunk, max_distance))
+}
+
+/// Walk through every voxel a ray passes through, in order, without stopping.
+///
+/// Yields `(voxel, face, t)`: the voxel entered, the face it was entered through, and the `t`
+/// at which that happened (`start + direction * t` is on that face). The first item is always
+/// `(start_voxel, FaceHit::Contained, 0.0)`. Iteration ends once the ray has travelled `max_distance`;
+/// pass `f32::INFINITY` for an unbounded ray (and bound it yourself, e.g. with `take_while`).
+///
+/// `start_voxel` and `start` are as in `raycast`, which also explains when to use `f64`.
+pub fn raycast_iter<S: BaseFloat>(
+    start_voxel: VoxelCoord,
+    start: Vector3<S>,
+    direction: Vector3<S>,
+    max_distance: S,
+) -> RaycastIter<S> {
+    assert!(!start.x.is_nan() && !start.y.is_nan() && !start.z.is_nan());
+    assert!(!direction.x.is_nan() && !direction.y.is_nan() && !direction.z.is_nan());
+    assert!(!max_distance.is_nan() && max_distance >= S::zero());
+
+    // floating point coordinates
+    // t_max_c: multiple of direction to get to that edge of voxel
+    // t_dc: multiple of direction to move 1 voxel
+    let (t_max_x, t_dx) = init(start_voxel.x, start.x, direction.x);
+    let (t_max_y, t_dy) = init(start_voxel.y, start.y, direction.y);
+    let (t_max_z, t_dz) = init(start_voxel.z, start.z, direction.z);
+
+    let length = direction.magnitude();
+    // note: signum(0.0) is 1.0, but we never step along an axis the ray is parallel to
+    let step = |dc: S| if dc.is_sign_negative() { -1 } else { 1 };
+    RaycastIter {
+        cur: start_voxel,
+        step: VoxelCoord::new(step(direction.x), step(direction.y), step(direction.z)),
+        t_max: Vector3::new(t_max_x, t_max_y, t_max_z),
+        t_d: Vector3::new(t_dx, t_dy, t_dz),
+        max_t: if length > S::zero() { max_distance / length } else { S::zero() },
+        started: false,
+    }
+}
+
+/// An iterator over the voxels a ray passes through; see `raycast_iter`.
+#[derive(Clone, Debug)]
+pub struct RaycastIter<S = f32> {
+    /// The voxel we're in.
+    cur: VoxelCoord,
+    /// Which way to step along each axis.
+    step: VoxelCoord,
+    /// `t` of the next crossing along each axis.
+    t_max: Vector3<S>,
+    /// `t` to cross one voxel along each axis.
+    t_d: Vector3<S>,
+    /// Stop once crossings are further than this.
+    max_t: S,
+    /// Whether we've yielded the start voxel.
+    started: bool,
+}
+impl<S: BaseFloat> RaycastIter<S> {
+    /// The `t` at which the ray will leave the voxel it's currently in.
+    pub fn t_next(&self) -> S {
+        self.t_max.x.min(self.t_max.y).min(self.t_max.z)
+    }
+}
+impl<S: BaseFloat> Iterator for RaycastIter<S> {
+    type Item = (VoxelCoord, FaceHit, S);
 
-            t_max_z += t_dz;
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if !self.started {
+            self.started = true;
+            return Some((self.cur, FaceHit::Contained, S::zero()));
+        }
+        let t_max = self.t_max;
+        // the next crossing is past the end of the ray (or the ray isn't going anywhere)
+        let t = self.t_next();
+        if t > self.max_t || t.is_infinite() {
+            return None;
         }
+
+        let face = if t_max.x <= t_max.y && t_max.x <= t_max.z {
+            self.cur.x += self.step.x;
+            self.t_max.x += self.t_d.x;
+            FaceHit::X
+        } else if t_max.y < t_max.x && t_max.y <= t_max.z {
+            self.cur.y += self.step.y;
+            self.t_max.y += self.t_d.y;
+            FaceHit::Y
+        } else {
+            self.cur.z += self.step.z;
+            self.t_max.z += self.t_d.z;
+            FaceHit::Z
+        };
+        Some((self.cur, face, t))
+    }
+}
+
+/// The normal of a voxel face hit by a ray going in `direction`, pointing back along the ray.
+fn face_normal<S: BaseFloat>(face: FaceHit, direction: Vector3<S>) -> VoxelCoord {
+    let sign = |c: S| if c.is_sign_negative() { 1 } else { -1 };
+    match face {
+        FaceHit::X => VoxelCoord::new(sign(direction.x), 0, 0),
+        FaceHit::Y => VoxelCoord::new(0, sign(direction.y), 0),
+        FaceHit::Z => VoxelCoord::new(0, 0, sign(direction.z)),
+        FaceHit::Contained => VoxelCoord::new(0, 0, 0),
     }
 }
 
-fn init(v_c: i16, c: f32, dc: f32) -> (f32, f32) {
-    let max_c = v_c as f32 + dc.signum() * 0.5;
+fn init<S: BaseFloat>(v_c: i16, c: S, dc: S) -> (S, S) {
+    let half = S::from(0.5).unwrap();
+    let max_c = S::from(v_c).unwrap() + dc.signum() * half;
     let mut t_max_c = (max_c - c) / dc;
-    if t_max_c < 0.0 {
-        t_max_c = f32::INFINITY;
+    // NaN if we're parallel to this axis and exactly on a voxel edge
+    if t_max_c < S::zero() || t_max_c.is_nan() {
+        t_max_c = S::infinity();
     }
-    let t_dc = 1.0 / dc;
+    // t_max_c moves forward regardless of which way the ray is going
+    let t_dc = dc.abs().recip();
     (t_max_c, t_dc)
 }
 
 const SIZE_F: f32 = CHUNK_SIZE as f32;
 const SIZE_I: i16 = CHUNK_SIZE as i16;
 
+/// What a ray should do when it passes through a voxel; see `voxel_raycast_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RayAction {
+    /// Keep going.
+    Continue,
+    /// Stop here; this voxel is the hit.
+    Stop,
+}
+
 /// Raycast through a voxel world looking for a non-empty voxel.
-pub fn voxel_raycast<V: Voxel>(
-    tracker: &ChunkTracker,
-    storage: &ReadStorage<Chunk<V>>,
+/// The result's `face_hit` and `normal` are those of the face the ray entered the hit voxel through,
+/// even if that face is on a chunk border; use `placement` to find where to put a new block.
+/// Stops after `max_distance` voxels; pass `f32::INFINITY` to only stop at the chunk bounds.
+pub fn voxel_raycast<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    coord: Coord,
+    direction: Coord,
+    min_chunk: VoxelCoord,
+    max_chunk: VoxelCoord,
+    max_distance: f32,
+) -> Raycast {
+    voxel_raycast_with(chunks, coord, direction, min_chunk, max_chunk, max_distance, |_, voxel: &V| {
+        if voxel.is_transparent() {
+            RayAction::Continue
+        } else {
+            RayAction::Stop
+        }
+    })
+}
+
+/// As `voxel_raycast`, but `action` decides which voxels stop the ray, e.g. to pass through water,
+/// or to ignore foliage for line-of-sight checks.
+/// It's called on each voxel the ray passes through in loaded chunks (including the one it starts in), in order.
+pub fn voxel_raycast_with<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> RayAction>(
+    chunks: &C,
     coord: Coord,
     direction: Coord,
     min_chunk: VoxelCoord,
-    max_chunk: VoxelCoord
+    max_chunk: VoxelCoord,
+    max_distance: f32,
+    mut action: F,
 ) -> Raycast {
     let start_coord_v = coord;
 
@@ -198,38 +629,81 @@ pub fn voxel_raycast<V: Voxel>(
     let min_chunk_c = min_chunk / SIZE_I;
     let max_chunk_c = max_chunk / SIZE_I;
 
+    // the face we entered cur_voxel_v through; each segment starts inside its first voxel,
+    // so it would report `Contained` if that voxel is the one we hit.
+    let mut entry_face = FaceHit::Contained;
+    let mut entry_normal = VoxelCoord::new(0, 0, 0);
+
     loop {
         // in voxel space, NOT chunk space
         let cur_chunk_v = canonicalize_chunk(cur_voxel_v);
+        // segments restart from cur_coord_v, so only give them what's left of the ray
+        let remaining = (max_distance - (cur_coord_v - start_coord_v).magnitude()).max(0.0);
 
-        if let Some(chunk) = tracker.get_chunk(storage, cur_chunk_v) {
+        if let Some(chunk) = chunks.get_chunk(cur_chunk_v) {
             // raycast through voxel space
             let hit = raycast(cur_voxel_v, cur_coord_v, direction,
                 // set bounds outside this voxel
-                cur_chunk_v - VoxelCoord::new(-1,-1,-1),
+                cur_chunk_v - VoxelCoord::new(1, 1, 1),
                 cur_chunk_v + VoxelCoord::new(SIZE_I, SIZE_I, SIZE_I),
-                |v| !chunk[v - cur_chunk_v].is_transparent()
+                remaining,
+                |v| action(v, &chunk[v - cur_chunk_v]) == RayAction::Stop
             );
 
-            if hit.hit_interesting ||
+            let (face_hit, normal) = if hit.face_hit == FaceHit::Contained {
+                (entry_face, entry_normal)
+            } else {
+                (hit.face_hit, hit.normal)
+            };
+
+            if hit.hit_interesting || hit.hit_max_distance ||
                 hit.end_voxel.x <= min_chunk_v.x ||
                 hit.end_voxel.y <= min_chunk_v.y ||
                 hit.end_voxel.z <= min_chunk_v.z ||
                 hit.end_voxel.x >= max_chunk_v.x ||
                 hit.end_voxel.y >= max_chunk_v.y ||
                 hit.end_voxel.z >= max_chunk_v.z {
-                // did we hit an interesting voxel, or the edge of our search?
+                // did we hit an interesting voxel, the edge of our search, or the end of the ray?
                 // if so, we're done.
-                return hit;
+                // the segment started at cur_coord_v; shift its t values to be relative to the real start
+                let t = t_from(start_coord_v, hit.end, direction);
+                let offset = t - hit.t;
+                return Raycast {
+                    face_hit,
+                    normal,
+                    t,
+                    t_enter: hit.t_enter + offset,
+                    t_exit: hit.t_exit + offset,
+                    entity: if hit.hit_interesting {
+                        chunks.get_chunk_ent(cur_chunk_v)
+                    } else {
+                        None
+                    },
+                    ..hit
+                };
             }
             // we hit the border of the chunk
             cur_coord_v = hit.end;
             cur_voxel_v = hit.end_voxel;
+            entry_face = face_hit;
+            entry_normal = normal;
             // go again, look for more chunks
         } else {
             // we're outside of loaded chunks
-            let cur_voxel_c = canonicalize_chunk(cur_voxel_v) / SIZE_I;
-            let cur_coord_c = to_chunk(cur_coord_v);
+            let mut cur_voxel_c = canonicalize_chunk(cur_voxel_v) / SIZE_I;
+            let mut cur_coord_c = to_chunk(cur_coord_v);
+            // chunk space is scaled down by the chunk size
+            let mut remaining_c = remaining / SIZE_F;
+
+            let loaded = chunks.loaded_bounds();
+            let skip = skip_unloaded(loaded, cur_voxel_c, cur_coord_c, direction, min_chunk_c, max_chunk_c, remaining_c);
+            if let Some((chunk, t, face)) = skip {
+                cur_voxel_c = chunk;
+                cur_coord_c += direction * t;
+                remaining_c = (remaining_c - t * direction.magnitude()).max(0.0);
+                entry_face = face;
+                entry_normal = face_normal(face, direction);
+            }
 
             let hit_c = raycast(
                 cur_voxel_c,
@@ -237,9 +711,15 @@ pub fn voxel_raycast<V: Voxel>(
                 direction,
                 min_chunk_c,
                 max_chunk_c,
-                |v| tracker.get_chunk(storage, v * SIZE_I).is_some()
+                remaining_c,
+                |v| chunks.get_chunk(v * SIZE_I).is_some()
             );
             cur_coord_v = from_chunk(hit_c.end);
+            // normals are the same in chunk space
+            if hit_c.face_hit != FaceHit::Contained {
+                entry_face = hit_c.face_hit;
+                entry_normal = hit_c.normal;
+            }
 
             // finicky: have to recover integer voxel from coordinate hit
             // algorithm: take 
@@ -253,9 +733,16 @@ pub fn voxel_raycast<V: Voxel>(
             }
 
             if !hit_c.hit_interesting {
+                // hit_c's interval is for a whole chunk
+                let (t_enter, t_exit) = voxel_interval(cur_voxel_v, start_coord_v, direction);
                 return Raycast {
+                    face_hit: entry_face,
+                    normal: entry_normal,
                     end: cur_coord_v,
                     end_voxel: cur_voxel_v,
+                    t: t_from(start_coord_v, cur_coord_v, direction),
+                    t_enter: t_enter.max(0.0),
+                    t_exit,
                     ..hit_c
                 }
             }
@@ -263,56 +750,1838 @@ pub fn voxel_raycast<V: Voxel>(
     }
 }
 
-// used by voxel_raycast:
-// we use a bespoke coordinate system for this operation, since
-// `raycast` always uses a grid size of 1, with edges at .5. 
+/// A ray hitting a voxel in a transformed chunk entity; see `transformed_raycast`.
+#[derive(Clone, Copy, Debug)]
+pub struct TransformedHit {
+    /// The chunk entity we hit.
+    entity: Entity,
+    /// The voxel we hit, as an index into the chunk.
+    voxel: VoxelCoord,
+    /// `end = start + direction * t`
+    t: f32,
+    /// Where the ray hit, in world space.
+    end: Coord,
+    /// The world-space normal of the face we hit; zero if the ray started inside the voxel.
+    normal: Coord,
+    /// The direction the ray was cast in.
+    direction: Coord,
+}
+impl TransformedHit {
+    /// The chunk entity we hit.
+    pub fn entity(&self) -> Entity {
+        self.entity
+    }
+    /// The voxel we hit, as an index into the entity's `Chunk`.
+    pub fn voxel(&self) -> VoxelCoord {
+        self.voxel
+    }
+    /// The multiple of the direction vector that takes the start of the ray to `end`.
+    pub fn t(&self) -> f32 {
+        self.t
+    }
+    /// The distance travelled from the start of the ray to `end`.
+    pub fn distance(&self) -> f32 {
+        self.t * self.direction.magnitude()
+    }
+    /// Where the ray hit, in world space.
+    pub fn end(&self) -> Coord {
+        self.end
+    }
+    /// The world-space unit normal of the face we hit, pointing back towards the start of the ray;
+    /// zero if the ray started inside the voxel it hit.
+    pub fn normal(&self) -> Coord {
+        self.normal
+    }
+}
 
-const OFFSET_F: f32 = SIZE_F / 2.0 - 0.5;
-const OFFSET: Coord = Coord { x: OFFSET_F, y: OFFSET_F, z: OFFSET_F};
+/// Raycast against chunk entities that may be moved, rotated or scaled by their `GlobalTransform`s
+/// (vehicles, moving platforms, ...), and return the nearest non-empty voxel hit.
+///
+/// `chunks` is usually `(&*entities, &chunks, &transforms).join()`. A chunk's transform maps the
+/// indices of its voxels (centered on integers, as usual) into world space, just as for its mesh.
+/// The ray is transformed into each chunk's local space and traversed there, so this costs a little for
+/// every chunk, even the ones it misses; `voxel_raycast` is faster for the axis-aligned world grid.
+/// Stops after `max_distance` (in world units); pass `f32::INFINITY` for no limit.
+pub fn transformed_raycast<'a, V, I>(
+    chunks: I,
+    start: Coord,
+    direction: Coord,
+    max_distance: f32,
+) -> Option<TransformedHit>
+where
+    V: Voxel,
+    I: IntoIterator<Item = (Entity, &'a Chunk<V>, &'a GlobalTransform)>,
+{
+    let length = direction.magnitude();
+    if length == 0.0 {
+        return None;
+    }
+    let max_t = max_distance / length;
+    // the chunk, and a border around it for the traversal to stop at
+    let (min, max) = (VoxelCoord::new(-1, -1, -1), VoxelCoord::new(SIZE_I, SIZE_I, SIZE_I));
+    let in_chunk = |v: VoxelCoord| (0..3).all(|i| 0 <= v[i] && v[i] < SIZE_I);
 
-#[inline]
-fn to_chunk(voxel_coord: Coord) -> Coord {
-    (voxel_coord - OFFSET) / SIZE_F
+    let mut best: Option<TransformedHit> = None;
+    for (entity, chunk, transform) in chunks {
+        let inverse = match transform.0.invert() {
+            Some(inverse) => inverse,
+            None => continue,
+        };
+        // the transform is affine, so t is the same in local and world space
+        let local_start = inverse.transform_point(Point3::from_vec(start)).to_vec();
+        let local_direction = inverse.transform_vector(direction);
+
+        let limit = best.map_or(max_t, |best| best.t);
+        let hit = clipped_raycast(local_start, local_direction, min, max, limit * local_direction.magnitude(), |v| {
+            in_chunk(v) && !chunk[v].is_transparent()
+        });
+        let hit = match hit {
+            Some(ref hit) if hit.hit_interesting() => hit,
+            _ => continue,
+        };
+
+        // normals transform by the inverse transpose
+        let normal = (inverse.transpose() * hit.normal().cast::<f32>().unwrap().extend(0.0)).truncate();
+        let t = hit.t();
+        best = Some(TransformedHit {
+            entity,
+            voxel: hit.end_voxel(),
+            t,
+            end: start + direction * t,
+            normal: if normal.magnitude2() > 0.0 {
+                normal.normalize()
+            } else {
+                normal
+            },
+            direction,
+        });
+    }
+    best
 }
-#[inline]
-fn from_chunk(chunk_coord: Coord) -> Coord {
-    SIZE_F * chunk_coord + OFFSET
+
+/// Used by `voxel_raycast` when it's outside of loaded chunks: everything the ray passes through
+/// before it reaches the box of loaded chunks (`loaded`, in voxel space), leaves the search bounds,
+/// or runs out of distance is unloaded, so there's no point looking at it chunk by chunk.
+///
+/// Returns the last chunk (in chunk space) before that happens, with the `t` and face the ray enters it at;
+/// None if there's nothing worth skipping.
+fn skip_unloaded(
+    loaded: Option<(VoxelCoord, VoxelCoord)>,
+    start_chunk: VoxelCoord,
+    start: Coord,
+    direction: Coord,
+    min_chunk_c: VoxelCoord,
+    max_chunk_c: VoxelCoord,
+    max_distance_c: f32,
+) -> Option<(VoxelCoord, f32, FaceHit)> {
+    let (lo, hi) = loaded?;
+    let (lo, hi) = (lo / SIZE_I, hi / SIZE_I);
+    let length = direction.magnitude();
+    // we can't skip over gaps between loaded chunks
+    if length == 0.0 || (0..3).all(|i| lo[i] <= start_chunk[i] && start_chunk[i] <= hi[i]) {
+        return None;
+    }
+    let chunk_box = |min: VoxelCoord, max: VoxelCoord| {
+        let half = Coord::new(0.5, 0.5, 0.5);
+        Aabb::new(min.cast::<f32>().unwrap() - half, max.cast::<f32>().unwrap() + half)
+    };
+    let one = VoxelCoord::new(1, 1, 1);
+    let (inner_min, inner_max) = (min_chunk_c + one, max_chunk_c - one);
+    if (0..3).any(|i| inner_min[i] > inner_max[i]) {
+        return None;
+    }
+
+    // (if we've just left the loaded box, we might still be on its surface)
+    let (loaded_enter, loaded_exit) = aabb_interval(&chunk_box(lo, hi), start, direction);
+    let (_, leave_bounds) = aabb_interval(&chunk_box(inner_min, inner_max), start, direction);
+    let mut target = (max_distance_c / length).min(leave_bounds);
+    if loaded_enter <= loaded_exit && loaded_enter > 0.0 {
+        target = target.min(loaded_enter);
+    }
+
+    // back off a little, so that we land before the target rather than on it
+    let back = 0.5 / direction.x.abs().max(direction.y.abs()).max(direction.z.abs());
+    let chunk = canonicalize(start + direction * (target - back).max(0.0));
+    // the border of the bounds is where the traversal stops, so we can't start there
+    if (0..3).any(|i| chunk[i] < inner_min[i] || chunk[i] > inner_max[i]) {
+        return None;
+    }
+    let (t, _) = voxel_interval(chunk, start, direction);
+    if t.is_nan() || t <= 0.0 {
+        return None;
+    }
+
+    // the face we enter through is on the axis we reach last
+    let enter = |i: usize| {
+        let plane = chunk[i] as f32 - direction[i].signum() * 0.5;
+        if direction[i] == 0.0 {
+            f32::NEG_INFINITY
+        } else {
+            (plane - start[i]) / direction[i]
+        }
+    };
+    let face = if enter(0) >= enter(1) && enter(0) >= enter(2) {
+        FaceHit::X
+    } else if enter(1) >= enter(2) {
+        FaceHit::Y
+    } else {
+        FaceHit::Z
+    };
+    Some((chunk, t, face))
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+/// The ground under a point; see `surface_below`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Surface {
+    /// The voxel the ground is made of.
+    voxel: VoxelCoord,
+    /// The y coordinate of the top face of `voxel`.
+    height: f32,
+    /// The point on the top face of `voxel` straight below the start.
+    position: Coord,
+    /// How far `position` is below the start.
+    depth: f32,
+}
+impl Surface {
+    /// The voxel the ground is made of.
+    pub fn voxel(&self) -> VoxelCoord {
+        self.voxel
+    }
+    /// The y coordinate of the ground, i.e. the top face of `voxel`.
+    pub fn height(&self) -> f32 {
+        self.height
+    }
+    /// The point on the ground straight below the start; e.g. where to put feet or a drop shadow.
+    pub fn position(&self) -> Coord {
+        self.position
+    }
+    /// How far the ground is below the start. Negative if the start is inside `voxel`.
+    pub fn depth(&self) -> f32 {
+        self.depth
+    }
+}
 
-    const MIN: VoxelCoord = VoxelCoord {
-        x: -20,
-        y: -20,
-        z: -20,
+/// The first non-transparent voxel straight down from `coord`, no more than `max_depth` below it.
+/// Uses the chunks' heightmap (see `ChunkAccess::surface_height`) when `coord` is above the top of its
+/// column, and walks down the column otherwise. Unloaded chunks are treated as empty.
+///
+/// If `coord` is inside a non-transparent voxel, that voxel is the surface, so something that's sunk
+/// into the ground can be pushed back up onto it.
+///
+/// Columns are only walked down to a chunk above the bottom of the world, and not at all from the chunks at its
+/// edges, where chunk coordinates would overflow.
+pub fn surface_below<V: Voxel, C: ChunkAccess<V>>(chunks: &C, coord: Coord, max_depth: f32) -> Option<Surface> {
+    assert!(max_depth >= 0.0 && max_depth < ::std::i16::MAX as f32, "max_depth must be non-negative and finite");
+    let start = canonicalize(coord);
+    let surface = |y: i16| {
+        let height = y as f32 + 0.5;
+        Surface {
+            voxel: VoxelCoord::new(start.x, y, start.z),
+            height,
+            position: Coord::new(coord.x, height, coord.z),
+            depth: coord.y - height,
+        }
     };
-    const MAX: VoxelCoord = VoxelCoord {
-        x: 20,
-        y: 20,
-        z: 20,
+
+    if let Some(y) = chunks.surface_height(start.x, start.z) {
+        if y <= start.y {
+            let found = surface(y);
+            return if found.depth <= max_depth { Some(found) } else { None };
+        }
+    }
+
+    // deep searches, or ones near the top or bottom of the world, would run off its edges; keep them a chunk
+    // inside, since the raycast looks at the chunks just outside its bounds
+    let size = i32::from(SIZE_I);
+    let (lowest, highest) = (i32::from(::std::i16::MIN) + size, i32::from(::std::i16::MAX) - 2 * size);
+    let edge = |c: i32| c.max(lowest).min(highest) as i16;
+    let depth = max_depth.ceil() as i32 + size;
+    let (x, y, z) = (i32::from(start.x), i32::from(start.y), i32::from(start.z));
+    let min = canonicalize_chunk(VoxelCoord::new(edge(x - size), edge(y - depth), edge(z - size)));
+    let max = canonicalize_chunk(VoxelCoord::new(edge(x + size), edge(y + size), edge(z + size)));
+    let chunk = canonicalize_chunk(start);
+    if (0..3).any(|axis| chunk[axis] < min[axis] || chunk[axis] > max[axis]) {
+        return None;
+    }
+    let hit = voxel_raycast(chunks, coord, Coord::new(0.0, -1.0, 0.0), min, max, max_depth);
+    if hit.hit_interesting() {
+        Some(surface(hit.end_voxel().y))
+    } else {
+        None
+    }
+}
+
+/// Whether there's an unobstructed line between `a` and `b`; `filter` decides which voxels block it.
+///
+/// The voxels containing `a` and `b` themselves are ignored, so an eye or target that's embedded in
+/// (or exactly on the surface of) a voxel can still see out. Unloaded chunks don't block anything.
+pub fn line_of_sight<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> RayAction>(
+    a: Coord,
+    b: Coord,
+    chunks: &C,
+    mut filter: F,
+) -> bool {
+    let blocked = |coord: VoxelCoord, voxel: &V| match filter(coord, voxel) {
+        RayAction::Stop => 1.0,
+        RayAction::Continue => 0.0,
     };
+    transmittance(a, b, chunks, blocked) > 0.0
+}
 
-    #[test]
-    fn raycast_basic() {
-        let start = Coord::new(0.0, 0.0, 0.0);
-        let target = VoxelCoord::new(5, 10, 15);
-        let dir = target.cast().unwrap();
-        let hit = raycast(start.cast().unwrap(), start, dir, MIN, MAX, |v| v == target);
-        assert_eq!(hit.end_voxel, target);
+/// How much light gets from `a` to `b`, from 0 (blocked) to 1 (clear): the product of `1 - opacity`
+/// over the voxels in between, for partially transparent things like glass, leaves or smoke.
+/// `opacity` should return values from 0 to 1; as with `line_of_sight`, the endpoint voxels are ignored.
+pub fn transmittance<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> f32>(
+    a: Coord,
+    b: Coord,
+    chunks: &C,
+    mut opacity: F,
+) -> f32 {
+    let (start, end) = (canonicalize(a), canonicalize(b));
+    let mut light = 1.0;
+
+    // the chunk we're in, to avoid a lookup per voxel
+    let mut cached: Option<(VoxelCoord, Option<&Chunk<V>>)> = None;
+    for (voxel, _, _) in raycast_iter(start, a, b - a, (b - a).magnitude()).skip(1) {
+        if voxel == end {
+            break;
+        }
+        let chunk_coord = canonicalize_chunk(voxel);
+        if cached.map_or(true, |(coord, _)| coord != chunk_coord) {
+            cached = Some((chunk_coord, chunks.get_chunk(chunk_coord)));
+        }
+        if let Some((_, Some(chunk))) = cached {
+            let opacity = opacity(voxel, &chunk[voxel - chunk_coord]).max(0.0).min(1.0);
+            light *= 1.0 - opacity;
+            if light <= 0.0 {
+                return 0.0;
+            }
+        }
     }
+    light
+}
 
-    #[test]
-    fn raycast_edge() {
-        let hit = raycast(
-            VoxelCoord::new(0, 0, 0),
-            Coord::new(0.0, 0.0, 0.0),
-            Coord::new(1.0, 0.0, 0.0),
-            MIN,
-            MAX,
-            |_| false,
-        );
-        assert_eq!(hit.end_voxel, VoxelCoord::new(20, 0, 0));
+/// A set of sample rays spread over a field of view, e.g. for an AI's vision.
+/// The sample directions are computed once, up front; build one per kind of viewer and reuse it.
+#[derive(Clone, Debug)]
+pub struct VisionCone {
+    /// Sample directions, relative to a viewer looking down +z with +y up.
+    directions: Vec<Coord>,
+    /// How far the viewer can see.
+    range: f32,
+}
+impl VisionCone {
+    /// A cone of rays up to `half_angle` radians from the center: one ray down the middle,
+    /// and `rings` evenly-spaced rings of `per_ring` rays around it.
+    pub fn cone(half_angle: f32, range: f32, rings: u32, per_ring: u32) -> Self {
+        let mut directions = vec![Coord::new(0.0, 0.0, 1.0)];
+        for ring in 1..=rings {
+            let theta = half_angle * ring as f32 / rings as f32;
+            for i in 0..per_ring {
+                let phi = 2.0 * f32::consts::PI * i as f32 / per_ring as f32;
+                directions.push(Coord::new(theta.sin() * phi.cos(), theta.sin() * phi.sin(), theta.cos()));
+            }
+        }
+        VisionCone { directions, range }
+    }
+
+    /// A horizontal fan of `count` rays, evenly spread from `half_angle` radians left of center to
+    /// `half_angle` right of it.
+    pub fn fan(half_angle: f32, range: f32, count: u32) -> Self {
+        let directions = (0..count)
+            .map(|i| {
+                let angle = if count > 1 {
+                    -half_angle + 2.0 * half_angle * i as f32 / (count - 1) as f32
+                } else {
+                    0.0
+                };
+                Coord::new(angle.sin(), 0.0, angle.cos())
+            })
+            .collect();
+        VisionCone { directions, range }
+    }
+
+    /// The sample directions, relative to a viewer looking down +z with +y up.
+    pub fn directions(&self) -> &[Coord] {
+        &self.directions
+    }
+
+    /// The sample directions in world space, for a viewer looking along `forward`.
+    pub fn directions_towards(&self, forward: Coord) -> Vec<Coord> {
+        rotate_towards(&self.directions, forward)
+    }
+
+    /// Cast every sample ray from `origin`, looking along `forward`. Returns, for each of `directions()`,
+    /// whether that ray reaches `target` within range without `filter` stopping it.
+    ///
+    /// The voxel containing `origin` and voxels overlapping `target` don't block anything,
+    /// and unloaded chunks are clear, as with `line_of_sight`.
+    pub fn cast<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> RayAction>(
+        &self,
+        origin: Coord,
+        forward: Coord,
+        target: &Aabb,
+        chunks: &C,
+        mut filter: F,
+    ) -> Vec<bool> {
+        let (target_min, target_max) = target.voxels();
+        let in_target = |v: VoxelCoord| {
+            target_min.x <= v.x && v.x <= target_max.x &&
+            target_min.y <= v.y && v.y <= target_max.y &&
+            target_min.z <= v.z && v.z <= target_max.z
+        };
+        let start = canonicalize(origin);
+
+        // the rays all start at the same place, so they mostly look at the same few chunks
+        let mut cache: FnvHashMap<VoxelCoord, Option<&Chunk<V>>> = FnvHashMap::default();
+
+        self.directions_towards(forward)
+            .into_iter()
+            .map(|direction| {
+                let (t_enter, t_exit) = aabb_interval(target, origin, direction);
+                if t_enter > t_exit || t_exit < 0.0 || t_enter > self.range {
+                    return false;
+                }
+                // directions are normalized, so t is distance
+                for (voxel, _, _) in raycast_iter(start, origin, direction, t_enter.max(0.0)).skip(1) {
+                    if in_target(voxel) {
+                        break;
+                    }
+                    let chunk_coord = canonicalize_chunk(voxel);
+                    let chunk = *cache
+                        .entry(chunk_coord)
+                        .or_insert_with(|| chunks.get_chunk(chunk_coord));
+                    if let Some(chunk) = chunk {
+                        if filter(voxel, &chunk[voxel - chunk_coord]) == RayAction::Stop {
+                            return false;
+                        }
+                    }
+                }
+                true
+            })
+            .collect()
+    }
+}
+
+/// Directions relative to +z (with +y up), rotated to be relative to `forward`.
+fn rotate_towards(directions: &[Coord], forward: Coord) -> Vec<Coord> {
+    let forward = forward.normalize();
+    // a basis around forward; any up will do if we're looking straight up or down
+    let hint = if forward.y.abs() < 0.999 {
+        Coord::new(0.0, 1.0, 0.0)
+    } else {
+        Coord::new(1.0, 0.0, 0.0)
+    };
+    let right = hint.cross(forward).normalize();
+    let up = forward.cross(right);
+    directions
+        .iter()
+        .map(|d| right * d.x + up * d.y + forward * d.z)
+        .collect()
+}
+
+/// Sample rays over a hemisphere, cosine-distributed (more of them near the pole than the rim), for
+/// estimating how much sky or ambient light reaches a voxel face: the fraction of rays that escape is
+/// the fraction of incoming light, without weighting each ray by its angle.
+/// As with `VisionCone`, the directions are computed once; build one and reuse it.
+#[derive(Clone, Debug)]
+pub struct Hemisphere {
+    /// Sample directions, relative to a face pointing along +z.
+    directions: Vec<Coord>,
+    /// How far a ray has to get to count as escaping.
+    range: f32,
+}
+impl Hemisphere {
+    /// `samples` rays that escape if nothing blocks them within `range`.
+    /// The rays are spread in a fixed spiral rather than at random, so results are repeatable.
+    pub fn new(samples: u32, range: f32) -> Self {
+        assert!(samples > 0, "need at least one sample");
+        // a golden-angle spiral over the unit disk, projected up onto the hemisphere (Malley's method)
+        let golden_angle = f32::consts::PI * (3.0 - 5.0f32.sqrt());
+        let directions = (0..samples)
+            .map(|i| {
+                let r2 = (i as f32 + 0.5) / samples as f32;
+                let (r, phi) = (r2.sqrt(), golden_angle * i as f32);
+                Coord::new(r * phi.cos(), r * phi.sin(), (1.0 - r2).sqrt())
+            })
+            .collect();
+        Hemisphere { directions, range }
+    }
+
+    /// The sample directions, relative to a face pointing along +z.
+    pub fn directions(&self) -> &[Coord] {
+        &self.directions
+    }
+
+    /// The sample directions in world space, for a face pointing along `normal`.
+    pub fn directions_around(&self, normal: Coord) -> Vec<Coord> {
+        rotate_towards(&self.directions, normal)
+    }
+
+    /// The fraction of sample rays from the face of `voxel` pointing along `normal` (a unit axis, like
+    /// `Raycast::normal`) that get `range` away without `filter` stopping them; 1 is fully exposed.
+    /// Rays start from the middle of the face. Unloaded chunks are clear, as with `line_of_sight`.
+    pub fn exposure<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> RayAction>(
+        &self,
+        voxel: VoxelCoord,
+        normal: VoxelCoord,
+        chunks: &C,
+        mut filter: F,
+    ) -> f32 {
+        assert_eq!(normal.x.abs() + normal.y.abs() + normal.z.abs(), 1, "normal must be a unit axis");
+        let normal_f: Coord = normal.cast().unwrap();
+        let origin = voxel.cast::<f32>().unwrap() + normal_f * 0.5;
+        // the voxel in front of the face; every ray passes through it first
+        let start = voxel + normal;
+
+        // the rays all start at the same place, so they mostly look at the same few chunks
+        let mut cache: FnvHashMap<VoxelCoord, Option<&Chunk<V>>> = FnvHashMap::default();
+
+        let escaped = self
+            .directions_around(normal_f)
+            .into_iter()
+            .filter(|&direction| {
+                for (voxel, _, _) in raycast_iter(start, origin, direction, self.range) {
+                    let chunk_coord = canonicalize_chunk(voxel);
+                    let chunk = *cache
+                        .entry(chunk_coord)
+                        .or_insert_with(|| chunks.get_chunk(chunk_coord));
+                    if let Some(chunk) = chunk {
+                        if filter(voxel, &chunk[voxel - chunk_coord]) == RayAction::Stop {
+                            return false;
+                        }
+                    }
+                }
+                true
+            })
+            .count();
+        escaped as f32 / self.directions.len() as f32
+    }
+}
+
+/// Computes `Hemisphere::exposure` for a queue of faces, a few at a time, within a time budget per frame;
+/// e.g. for baking lighting in the background.
+#[derive(Clone, Debug)]
+pub struct ExposureQueue {
+    hemisphere: Hemisphere,
+    /// `(voxel, normal)` of faces waiting to be sampled.
+    faces: VecDeque<(VoxelCoord, VoxelCoord)>,
+    time_limiter: TimeLimiter,
+}
+impl ExposureQueue {
+    pub fn new(hemisphere: Hemisphere) -> Self {
+        ExposureQueue {
+            hemisphere,
+            faces: VecDeque::new(),
+            time_limiter: TimeLimiter::new(),
+        }
+    }
+
+    /// Queue the face of `voxel` pointing along `normal`.
+    pub fn push(&mut self, voxel: VoxelCoord, normal: VoxelCoord) {
+        self.faces.push_back((voxel, normal));
+    }
+
+    /// The number of faces still waiting.
+    pub fn len(&self) -> usize {
+        self.faces.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.faces.is_empty()
+    }
+
+    /// Sample queued faces, in the order they were pushed, until they run out or `budget` does.
+    /// Returns `(voxel, normal, exposure)` for each face sampled.
+    pub fn run<V: Voxel, C: ChunkAccess<V>, F: FnMut(VoxelCoord, &V) -> RayAction>(
+        &mut self,
+        budget: Duration,
+        chunks: &C,
+        mut filter: F,
+    ) -> Vec<(VoxelCoord, VoxelCoord, f32)> {
+        let mut done = Vec::new();
+        let (hemisphere, faces) = (&self.hemisphere, &mut self.faces);
+        self.time_limiter.repeat_with_budget(budget, || {
+            if let Some((voxel, normal)) = faces.pop_front() {
+                done.push((voxel, normal, hemisphere.exposure(voxel, normal, chunks, &mut filter)));
+            }
+            !faces.is_empty()
+        });
+        done
+    }
+}
+
+/// The `t` such that `end = start + direction * t`.
+/// voxel_raycast casts several segments (in different coordinate systems), so we can't just add them up.
+#[inline]
+fn t_from(start: Coord, end: Coord, direction: Coord) -> f32 {
+    (end - start).dot(direction) / direction.magnitude2()
+}
+
+/// The range of `t` for which `start + direction * t` is inside `voxel`.
+fn voxel_interval(voxel: VoxelCoord, start: Coord, direction: Coord) -> (f32, f32) {
+    box_interval(voxel, 0.5, start, direction)
+}
+
+/// The range of `t` for which `start + direction * t` is inside the cube of half-size `half`
+/// centered on `voxel`. Empty (`t_enter > t_exit`) if the ray misses.
+fn box_interval(voxel: VoxelCoord, half: f32, start: Coord, direction: Coord) -> (f32, f32) {
+    let center = Coord::new(voxel.x as f32, voxel.y as f32, voxel.z as f32);
+    aabb_interval(&Aabb::from_center(center, Coord::new(half, half, half)), start, direction)
+}
+
+/// The range of `t` for which `start + direction * t` is inside `aabb`. Empty (`t_enter > t_exit`) if the ray misses.
+fn aabb_interval(aabb: &Aabb, start: Coord, direction: Coord) -> (f32, f32) {
+    let slab = |min: f32, max: f32, c: f32, dc: f32| {
+        let a = (min - c) / dc;
+        let b = (max - c) / dc;
+        if a.is_nan() || b.is_nan() {
+            // parallel to the slab and exactly on its edge
+            (f32::NEG_INFINITY, f32::INFINITY)
+        } else {
+            (a.min(b), a.max(b))
+        }
+    };
+    let (x0, x1) = slab(aabb.min.x, aabb.max.x, start.x, direction.x);
+    let (y0, y1) = slab(aabb.min.y, aabb.max.y, start.y, direction.y);
+    let (z0, z1) = slab(aabb.min.z, aabb.max.z, start.z, direction.z);
+    (x0.max(y0).max(z0), x1.min(y1).min(z1))
+}
+
+// used by voxel_raycast:
+// we use a bespoke coordinate system for this operation, since
+// `raycast` always uses a grid size of 1, with edges at .5. 
+
+const OFFSET_F: f32 = SIZE_F / 2.0 - 0.5;
+const OFFSET: Coord = Coord { x: OFFSET_F, y: OFFSET_F, z: OFFSET_F};
+
+#[inline]
+fn to_chunk(voxel_coord: Coord) -> Coord {
+    (voxel_coord - OFFSET) / SIZE_F
+}
+#[inline]
+fn from_chunk(chunk_coord: Coord) -> Coord {
+    SIZE_F * chunk_coord + OFFSET
+}
+
+/// The result of sweeping a box through the voxel grid; see `boxcast`.
+#[derive(Clone, Copy, Debug)]
+pub struct Boxcast {
+    /// The solid voxel the box ran into, if any.
+    hit: Option<VoxelCoord>,
+    /// The axis the box was moving along when it hit something.
+    face_hit: FaceHit,
+    /// The normal of the face we hit; zero if we didn't hit anything.
+    normal: VoxelCoord,
+    /// How far the box can move, as a multiple of the direction vector.
+    t: f32,
+    /// How far the box was asked to move.
+    max_t: f32,
+    /// The box after moving.
+    end: Aabb,
+}
+impl Boxcast {
+    /// The first solid voxel the box ran into, if any.
+    pub fn hit(&self) -> Option<VoxelCoord> {
+        self.hit
+    }
+    /// The axis the box was moving along when it hit something; `Contained` if it didn't hit anything.
+    pub fn face_hit(&self) -> FaceHit {
+        self.face_hit
+    }
+    /// The normal of the face we hit, pointing back against the motion; zero if we didn't hit anything.
+    pub fn normal(&self) -> VoxelCoord {
+        self.normal
+    }
+    /// How far the box can move before touching a solid voxel, as a multiple of the direction vector.
+    pub fn t(&self) -> f32 {
+        self.t
+    }
+    /// The fraction of the requested move the box can make before touching a solid voxel, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        if self.max_t > 0.0 {
+            self.t / self.max_t
+        } else {
+            1.0
+        }
+    }
+    /// The box moved as far as it can go.
+    pub fn end(&self) -> Aabb {
+        self.end
+    }
+}
+
+/// Sweep `aabb` along `direction` for up to `max_distance`, stopping when it touches a voxel
+/// for which `is_solid` returns true.
+///
+/// Voxels the box already overlaps at the start are ignored, so that a box that has ended up
+/// inside something can still move out of it. A box resting exactly on a solid voxel's face
+/// counts as touching it: it can slide along the face, but not move into it.
+///
+/// This walks the layers of voxels the leading faces of the box pass into, so it's exact
+/// (no tunnelling) and costs roughly (area of the leading faces) * (distance travelled).
+pub fn boxcast<F: FnMut(VoxelCoord) -> bool>(
+    aabb: Aabb,
+    direction: Coord,
+    max_distance: f32,
+    mut is_solid: F,
+) -> Boxcast {
+    let sweep = BoxSweep::new(aabb, direction, max_distance);
+    let (max_t, step) = (sweep.max_t, sweep.step);
+
+    for (t, axis, min, max) in sweep {
+        if let Some(hit) = voxels_in_box(min, max).find(|&v| is_solid(v)) {
+            let mut normal = VoxelCoord::new(0, 0, 0);
+            normal[axis] = -step[axis];
+            return Boxcast {
+                hit: Some(hit),
+                face_hit: [FaceHit::X, FaceHit::Y, FaceHit::Z][axis],
+                normal,
+                t,
+                max_t,
+                end: aabb.translate(direction * t),
+            };
+        }
+    }
+    Boxcast {
+        hit: None,
+        face_hit: FaceHit::Contained,
+        normal: VoxelCoord::new(0, 0, 0),
+        t: max_t,
+        max_t,
+        end: aabb.translate(direction * max_t),
+    }
+}
+
+/// Walks the layers of voxels the leading faces of a moving box pass into, in order.
+/// Yields `(t, axis, min, max)`: when the layer is entered, the axis it's perpendicular to,
+/// and the (inclusive) voxels in it that the box overlaps.
+struct BoxSweep {
+    aabb: Aabb,
+    direction: Coord,
+    max_t: f32,
+    /// Which way we're going along each axis.
+    step: VoxelCoord,
+    /// The last layer of voxels the leading face has entered along each axis.
+    lead: VoxelCoord,
+    /// The `t` at which the leading face will enter the next layer along each axis.
+    t_next: Coord,
+    /// The `t` to cross a layer along each axis.
+    t_d: Coord,
+}
+impl BoxSweep {
+    fn new(aabb: Aabb, direction: Coord, max_distance: f32) -> BoxSweep {
+        assert!(!direction.x.is_nan() && !direction.y.is_nan() && !direction.z.is_nan());
+        assert!(max_distance >= 0.0 && max_distance.is_finite(), "sweeps need a finite max_distance");
+
+        let length = direction.magnitude();
+        let (lo, hi) = aabb.voxels();
+        let mut sweep = BoxSweep {
+            aabb,
+            direction,
+            max_t: if length > 0.0 { max_distance / length } else { 0.0 },
+            step: VoxelCoord::new(0, 0, 0),
+            lead: VoxelCoord::new(0, 0, 0),
+            t_next: Coord::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            t_d: Coord::new(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+        };
+        for i in 0..3 {
+            let d = direction[i];
+            if d > 0.0 {
+                sweep.step[i] = 1;
+                sweep.lead[i] = hi[i];
+                sweep.t_next[i] = (hi[i] as f32 + 0.5 - aabb.max[i]) / d;
+                sweep.t_d[i] = 1.0 / d;
+            } else if d < 0.0 {
+                sweep.step[i] = -1;
+                sweep.lead[i] = lo[i];
+                sweep.t_next[i] = (lo[i] as f32 - 0.5 - aabb.min[i]) / d;
+                sweep.t_d[i] = -1.0 / d;
+            }
+        }
+        sweep
+    }
+}
+impl Iterator for BoxSweep {
+    type Item = (f32, usize, VoxelCoord, VoxelCoord);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let t_next = self.t_next;
+        let axis = if t_next.x <= t_next.y && t_next.x <= t_next.z {
+            0
+        } else if t_next.y <= t_next.z {
+            1
+        } else {
+            2
+        };
+        let t = t_next[axis];
+        if t > self.max_t {
+            return None;
+        }
+        self.lead[axis] += self.step[axis];
+        self.t_next[axis] += self.t_d[axis];
+
+        // the new layer of voxels, as wide as the box is along the other axes.
+        // the leading sides come from `lead` rather than the box's position, so that when we cross
+        // two layers at once (i.e. move diagonally past an edge) we still check the voxel on the edge.
+        let (mut min, mut max) = self.aabb.translate(self.direction * t).voxels();
+        for i in 0..3 {
+            if i == axis {
+                min[i] = self.lead[i];
+                max[i] = self.lead[i];
+            } else if self.step[i] > 0 {
+                max[i] = self.lead[i];
+            } else if self.step[i] < 0 {
+                min[i] = self.lead[i];
+            }
+        }
+        Some((t, axis, min, max))
+    }
+}
+
+/// Sweep a box through a voxel world, stopping at non-transparent voxels; see `boxcast`.
+/// Unloaded chunks are treated as empty.
+pub fn voxel_boxcast<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    aabb: Aabb,
+    direction: Coord,
+    max_distance: f32,
+) -> Boxcast {
+    boxcast(aabb, direction, max_distance, |v| {
+        chunks.get_voxel(v).map_or(false, |voxel| !voxel.is_transparent())
+    })
+}
+
+/// The result of sweeping a sphere through the voxel grid; see `spherecast`.
+#[derive(Clone, Copy, Debug)]
+pub struct Spherecast {
+    /// The solid voxel the sphere ran into, if any.
+    hit: Option<VoxelCoord>,
+    /// How far the sphere can move, as a multiple of the direction vector.
+    t: f32,
+    /// How far the sphere was asked to move.
+    max_t: f32,
+    /// The center of the sphere after moving.
+    end: Coord,
+    /// The point on the hit voxel the sphere touches.
+    contact: Coord,
+}
+impl Spherecast {
+    /// The first solid voxel the sphere ran into, if any.
+    pub fn hit(&self) -> Option<VoxelCoord> {
+        self.hit
+    }
+    /// How far the sphere can move before touching a solid voxel, as a multiple of the direction vector.
+    pub fn t(&self) -> f32 {
+        self.t
+    }
+    /// The fraction of the requested move the sphere can make before touching a solid voxel, from 0 to 1.
+    pub fn fraction(&self) -> f32 {
+        if self.max_t > 0.0 {
+            self.t / self.max_t
+        } else {
+            1.0
+        }
+    }
+    /// The center of the sphere, moved as far as it can go.
+    pub fn end(&self) -> Coord {
+        self.end
+    }
+    /// The point where the sphere touches the voxel it hit; equal to `end` if it didn't hit anything.
+    pub fn contact(&self) -> Coord {
+        self.contact
+    }
+    /// The unit normal of the surface we hit at `contact`, pointing towards the sphere's center;
+    /// not axis-aligned if we hit an edge or corner. Zero if we didn't hit anything.
+    pub fn normal(&self) -> Coord {
+        let offset = self.end - self.contact;
+        if offset.magnitude2() > 0.0 {
+            offset.normalize()
+        } else {
+            Coord::new(0.0, 0.0, 0.0)
+        }
+    }
+}
+
+/// Sweep a sphere along `direction` for up to `max_distance`, stopping when it touches a voxel
+/// for which `is_solid` returns true. As with `boxcast`, voxels the sphere already overlaps are ignored.
+///
+/// Candidate voxels are found by sweeping the sphere's bounding box (which is conservative), and then
+/// tested exactly against the sphere.
+pub fn spherecast<F: FnMut(VoxelCoord) -> bool>(
+    center: Coord,
+    radius: f32,
+    direction: Coord,
+    max_distance: f32,
+    mut is_solid: F,
+) -> Spherecast {
+    assert!(radius >= 0.0, "negative radius");
+    let bounds = Aabb::from_center(center, Coord::new(radius, radius, radius));
+    let sweep = BoxSweep::new(bounds, direction, max_distance);
+    let max_t = sweep.max_t;
+
+    let mut best: Option<(f32, VoxelCoord)> = None;
+    {
+        let mut test = |v: VoxelCoord, best: &mut Option<(f32, VoxelCoord)>| {
+            if !is_solid(v) {
+                return;
+            }
+            if let Some(t) = sphere_voxel_toi(center, radius, direction, v) {
+                if t <= max_t && best.map_or(true, |(best_t, _)| t < best_t) {
+                    *best = Some((t, v));
+                }
+            }
+        };
+
+        // the corners of the bounding box aren't in the sphere, so the sweep won't report them
+        let (min, max) = bounds.voxels();
+        for v in voxels_in_box(min, max) {
+            test(v, &mut best);
+        }
+        for (t, _, min, max) in sweep {
+            // the box reaches every voxel no later than the sphere does
+            if best.map_or(false, |(best_t, _)| t > best_t) {
+                break;
+            }
+            for v in voxels_in_box(min, max) {
+                test(v, &mut best);
+            }
+        }
+    }
+
+    match best {
+        Some((t, hit)) => {
+            let end = center + direction * t;
+            Spherecast {
+                hit: Some(hit),
+                t,
+                max_t,
+                end,
+                contact: closest_point(hit, end),
+            }
+        }
+        None => {
+            let end = center + direction * max_t;
+            Spherecast {
+                hit: None,
+                t: max_t,
+                max_t,
+                end,
+                contact: end,
+            }
+        }
+    }
+}
+
+/// Sweep a sphere through a voxel world, stopping at non-transparent voxels; see `spherecast`.
+/// Unloaded chunks are treated as empty.
+pub fn voxel_spherecast<V: Voxel, C: ChunkAccess<V>>(
+    chunks: &C,
+    center: Coord,
+    radius: f32,
+    direction: Coord,
+    max_distance: f32,
+) -> Spherecast {
+    spherecast(center, radius, direction, max_distance, |v| {
+        chunks.get_voxel(v).map_or(false, |voxel| !voxel.is_transparent())
+    })
+}
+
+/// All the voxels within `radius` of the segment from `start` to `end` (i.e. that overlap the capsule
+/// around it), roughly in order from `start` to `end`. With a radius of 0, these are the voxels the segment touches.
+///
+/// Like `spherecast`, this sweeps the bounding box of a sphere along the segment to find candidates,
+/// then tests each one exactly, so it never misses a voxel and costs roughly radius^2 * length.
+pub fn capsule_voxels(start: Coord, end: Coord, radius: f32) -> impl Iterator<Item = VoxelCoord> {
+    assert!(radius >= 0.0 && radius.is_finite(), "radius must be non-negative and finite");
+    let direction = end - start;
+    let bounds = Aabb::from_center(start, Coord::new(radius, radius, radius));
+    let sweep = BoxSweep::new(bounds, direction, direction.magnitude());
+    let (min, max) = bounds.voxels();
+
+    voxels_in_box(min, max)
+        .chain(sweep.flat_map(|(_, _, min, max)| voxels_in_box(min, max)))
+        .filter(move |&v| segment_voxel_distance2(start, direction, v) <= radius * radius)
+}
+
+/// The closest point in `voxel` to `point`.
+fn closest_point(voxel: VoxelCoord, point: Coord) -> Coord {
+    let clamp = |v: i16, c: f32| c.max(v as f32 - 0.5).min(v as f32 + 0.5);
+    Coord::new(
+        clamp(voxel.x, point.x),
+        clamp(voxel.y, point.y),
+        clamp(voxel.z, point.z),
+    )
+}
+
+/// The squared distance from `voxel` to the segment from `start` to `start + direction`.
+///
+/// The squared distance from a point to a box is a sum of per-axis terms, each either 0 or a quadratic
+/// in `t`, depending on which side of the box the point is on; so split the segment where it crosses the
+/// planes of the box's faces, and minimize the quadratic on each piece.
+fn segment_voxel_distance2(start: Coord, direction: Coord, voxel: VoxelCoord) -> f32 {
+    let lo = voxel.cast::<f32>().unwrap() - Coord::new(0.5, 0.5, 0.5);
+    let hi = voxel.cast::<f32>().unwrap() + Coord::new(0.5, 0.5, 0.5);
+
+    let mut splits = vec![0.0, 1.0];
+    for i in 0..3 {
+        if direction[i] != 0.0 {
+            for &plane in &[lo[i], hi[i]] {
+                let t = (plane - start[i]) / direction[i];
+                if 0.0 < t && t < 1.0 {
+                    splits.push(t);
+                }
+            }
+        }
+    }
+    splits.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let mut best = f32::INFINITY;
+    for piece in splits.windows(2) {
+        let (t0, t1) = (piece[0], piece[1]);
+        let middle = start + direction * ((t0 + t1) / 2.0);
+
+        // a t^2 + b t + c
+        let (mut a, mut b, mut c) = (0.0, 0.0, 0.0);
+        for i in 0..3 {
+            let plane = if middle[i] < lo[i] {
+                lo[i]
+            } else if middle[i] > hi[i] {
+                hi[i]
+            } else {
+                continue;
+            };
+            let o = start[i] - plane;
+            a += direction[i] * direction[i];
+            b += 2.0 * direction[i] * o;
+            c += o * o;
+        }
+        let at = |t: f32| (a * t + b) * t + c;
+        best = best.min(at(t0)).min(at(t1));
+        if a > 0.0 {
+            let vertex = -b / (2.0 * a);
+            if t0 < vertex && vertex < t1 {
+                best = best.min(at(vertex));
+            }
+        }
+    }
+    best.max(0.0)
+}
+
+/// The first `t >= 0` at which a sphere at `center + direction * t` touches `voxel`, if any.
+/// None if the sphere overlaps the voxel at `t = 0`.
+///
+/// See Real-Time Collision Detection (Ericson), 5.5.7: intersect the path of the center with the voxel
+/// grown by `radius`; if that point is next to a face of the voxel we're done, otherwise we're
+/// near an edge or corner, which are rounded, so intersect with the capsules around the nearby edges.
+fn sphere_voxel_toi(center: Coord, radius: f32, direction: Coord, voxel: VoxelCoord) -> Option<f32> {
+    if (closest_point(voxel, center) - center).magnitude2() < radius * radius {
+        return None;
+    }
+
+    let lo = voxel.cast::<f32>().unwrap() - Coord::new(0.5, 0.5, 0.5);
+    let hi = voxel.cast::<f32>().unwrap() + Coord::new(0.5, 0.5, 0.5);
+
+    // the path of the center against the grown voxel
+    let (t_enter, t_exit) = box_interval(voxel, 0.5 + radius, center, direction);
+    if t_enter > t_exit || t_exit < 0.0 {
+        return None;
+    }
+    let t = t_enter.max(0.0);
+
+    // which side of the voxel we're on along each axis, at that point
+    let p = center + direction * t;
+    let mut side = VoxelCoord::new(0, 0, 0);
+    for i in 0..3 {
+        if p[i] < lo[i] {
+            side[i] = -1;
+        } else if p[i] > hi[i] {
+            side[i] = 1;
+        }
+    }
+    let outside = (0..3).filter(|&i| side[i] != 0).count();
+    if outside <= 1 {
+        // next to a face (or already touching)
+        return Some(t);
+    }
+
+    // the corner of the voxel nearest p; edges along axes where we're not outside
+    // (or all three, if we're near the corner) run from here
+    let mut corner = Coord::new(0.0, 0.0, 0.0);
+    for i in 0..3 {
+        corner[i] = if side[i] < 0 { lo[i] } else { hi[i] };
+    }
+    (0..3)
+        .filter(|&axis| outside == 3 || side[axis] == 0)
+        .filter_map(|axis| {
+            let mut start = corner;
+            start[axis] = lo[axis];
+            capsule_toi(center, direction, start, axis, radius)
+        })
+        .fold(None, |best: Option<f32>, t| Some(best.map_or(t, |best| best.min(t))))
+}
+
+/// The first `t >= 0` at which `origin + direction * t` is within `radius` of the
+/// unit-length segment from `start` along `axis`.
+fn capsule_toi(origin: Coord, direction: Coord, start: Coord, axis: usize, radius: f32) -> Option<f32> {
+    let (i, j) = ((axis + 1) % 3, (axis + 2) % 3);
+    let r2 = radius * radius;
+
+    // the side of the capsule
+    let (oi, oj) = (origin[i] - start[i], origin[j] - start[j]);
+    let side = first_root(
+        direction[i] * direction[i] + direction[j] * direction[j],
+        2.0 * (direction[i] * oi + direction[j] * oj),
+        oi * oi + oj * oj - r2,
+    ).and_then(|t| {
+        let along = origin[axis] + direction[axis] * t - start[axis];
+        if 0.0 <= along && along <= 1.0 {
+            Some(t)
+        } else {
+            None
+        }
+    });
+
+    // the ends
+    let end = |offset: f32| {
+        let mut point = start;
+        point[axis] += offset;
+        let o = origin - point;
+        first_root(direction.magnitude2(), 2.0 * direction.dot(o), o.magnitude2() - r2)
+    };
+
+    [side, end(0.0), end(1.0)]
+        .iter()
+        .filter_map(|&t| t)
+        .fold(None, |best: Option<f32>, t| Some(best.map_or(t, |best| best.min(t))))
+}
+
+/// The smaller root of `a t^2 + b t + c = 0`, if there is one and it isn't (much) less than 0.
+fn first_root(a: f32, b: f32, c: f32) -> Option<f32> {
+    if a == 0.0 {
+        return None;
+    }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 {
+        return None;
+    }
+    let t = (-b - discriminant.sqrt()) / (2.0 * a);
+    // touching at the start counts, if we're moving inwards
+    if t >= -1e-5 {
+        Some(t.max(0.0))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix4};
+    use specs::prelude::*;
+    use std::cell::Cell;
+    use std::collections::HashMap;
+    use tracker::{ChunkTracker, ChunkTrackerSystem};
+    use TestVoxel;
+
+    const MIN: VoxelCoord = VoxelCoord {
+        x: -20,
+        y: -20,
+        z: -20,
+    };
+    const MAX: VoxelCoord = VoxelCoord {
+        x: 20,
+        y: 20,
+        z: 20,
+    };
+
+    #[test]
+    fn raycast_basic() {
+        let start = Coord::new(0.0, 0.0, 0.0);
+        let target = VoxelCoord::new(5, 10, 15);
+        let dir = target.cast().unwrap();
+        let hit = raycast(start.cast().unwrap(), start, dir, MIN, MAX, f32::INFINITY, |v| v == target);
+        assert_eq!(hit.end_voxel(), target);
+        assert!(hit.hit_interesting());
+        // the ray's path only changes z at the face in front of the target
+        assert_eq!(hit.face_hit(), FaceHit::Z);
+        assert_eq!(hit.normal(), VoxelCoord::new(0, 0, -1));
+        assert_eq!(hit.previous_voxel(), target - VoxelCoord::new(0, 0, 1));
+        assert!((hit.end() - (start + dir * hit.t())).magnitude() < 1e-4);
+        assert!((hit.distance() - (hit.end() - start).magnitude()).abs() < 1e-4);
+
+        let target = VoxelCoord::new(-1, 17, 3);
+        let hit = raycast(start.cast().unwrap(), start, target.cast().unwrap(), MIN, MAX, f32::INFINITY, |v| v == target);
+        assert_eq!(hit.chunk(), VoxelCoord::new(-16, 16, 0));
+        assert_eq!(hit.local_voxel(), VoxelCoord::new(15, 1, 3));
+        assert_eq!(hit.entity(), None);
+    }
+
+    #[test]
+    fn raycast_edge() {
+        let hit = raycast(
+            VoxelCoord::new(0, 0, 0),
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(1.0, 0.0, 0.0),
+            MIN,
+            MAX,
+            f32::INFINITY,
+            |_| false,
+        );
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(20, 0, 0));
+        assert!(!hit.hit_interesting());
+        assert!(!hit.hit_max_distance());
+        assert_eq!(hit.normal(), VoxelCoord::new(-1, 0, 0));
+        assert!((hit.t() - 19.5).abs() < 1e-4);
+    }
+
+    #[test]
+    fn raycast_iter_visits_all() {
+        let steps: Vec<_> = raycast_iter(
+            VoxelCoord::new(0, 0, 0),
+            Coord::new(0.0, 0.0, 0.0),
+            Coord::new(1.0, 0.0, 0.0),
+            3.0,
+        ).collect();
+        assert_eq!(
+            steps,
+            vec![
+                (VoxelCoord::new(0, 0, 0), FaceHit::Contained, 0.0),
+                (VoxelCoord::new(1, 0, 0), FaceHit::X, 0.5),
+                (VoxelCoord::new(2, 0, 0), FaceHit::X, 1.5),
+                (VoxelCoord::new(3, 0, 0), FaceHit::X, 2.5),
+            ]
+        );
+
+        // every step moves to a face-adjacent voxel, and t only goes up
+        let start = Coord::new(0.2, -0.3, 0.1);
+        let dir = Coord::new(-0.37, 0.299, 0.936);
+        let mut prev = (canonicalize(start), 0.0);
+        for (voxel, face, t) in raycast_iter(canonicalize(start), start, dir, 20.0).skip(1) {
+            let diff = voxel - prev.0;
+            assert_eq!(diff.x.abs() + diff.y.abs() + diff.z.abs(), 1);
+            assert_eq!(face_normal(face, dir), -diff);
+            assert!(t >= prev.1);
+            assert_eq!(canonicalize(start + dir * (t + 1e-4)), voxel);
+            prev = (voxel, t);
+        }
+        assert!(prev.1 <= 20.0 / dir.magnitude());
+    }
+
+    #[test]
+    fn raycast_f64() {
+        // in f32, this ray drifts out of the voxels it should be in after about 1000 of them
+        let start = Vector3::new(0.1f64, 0.2, 0.3);
+        let direction = Vector3::new(1.0f64, 0.37, 0.61);
+        let contains = |v: VoxelCoord, t: f64| (0..3).all(|i| (start[i] + direction[i] * t - v[i] as f64).abs() <= 0.5);
+
+        let mut previous = (VoxelCoord::new(0, 0, 0), 0.0);
+        for (voxel, _, t) in raycast_iter(VoxelCoord::new(0, 0, 0), start, direction, 30000.0).skip(1) {
+            let (previous_voxel, previous_t) = previous;
+            assert!(contains(previous_voxel, (previous_t + t) / 2.0), "lost the ray at {:?}", previous_voxel);
+            previous = (voxel, t);
+        }
+
+        let far = |v: VoxelCoord| v.x == 25000;
+        let bounds = VoxelCoord::new(30000, 30000, 30000);
+        let hit = raycast(VoxelCoord::new(0, 0, 0), start, direction, -bounds, bounds, ::std::f64::INFINITY, far);
+        assert!(hit.hit_interesting() && hit.face_hit() == FaceHit::X);
+        assert!(contains(hit.end_voxel(), (hit.t_enter() + hit.t_exit()) / 2.0));
+    }
+
+    #[test]
+    fn raycast_from_outside() {
+        let east = Coord::new(1.0, 0.0, 0.0);
+        let target = VoxelCoord::new(-19, 3, 3);
+        let is_target = |v| v == target;
+
+        // the first voxel inside the border is hit through its face
+        let hit = clipped_raycast(Coord::new(-1000.0, 3.0, 3.0), east, MIN, MAX, f32::INFINITY, is_target).unwrap();
+        assert!(hit.hit_interesting());
+        assert_eq!(hit.end_voxel(), target);
+        assert_eq!(hit.normal(), VoxelCoord::new(-1, 0, 0));
+        assert!((hit.t() - 980.5).abs() < 1e-3);
+
+        // the same as `raycast` from inside
+        let start = Coord::new(-30.0, 3.0, 3.0);
+        let inside = clipped_raycast(start, -east, MIN * 2, MAX, f32::INFINITY, is_target).unwrap();
+        let plain = raycast(canonicalize(start), start, -east, MIN * 2, MAX, f32::INFINITY, is_target);
+        assert_eq!((inside.end_voxel(), inside.t()), (plain.end_voxel(), plain.t()));
+
+        // never entering
+        assert!(clipped_raycast(Coord::new(-1000.0, 30.0, 3.0), east, MIN, MAX, f32::INFINITY, is_target).is_none());
+        assert!(clipped_raycast(Coord::new(-1000.0, 3.0, 3.0), -east, MIN, MAX, f32::INFINITY, is_target).is_none());
+        assert!(clipped_raycast(Coord::new(-1000.0, 3.0, 3.0), east, MIN, MAX, 900.0, is_target).is_none());
+
+        // from far outside the range of voxel coordinates
+        let far = Vector3::new(1e7, 3.0, 3.0);
+        let hit = clipped_raycast(far, Vector3::new(-1.0, 0.0, 0.0), MIN, MAX, ::std::f64::INFINITY, |v| v.x == 0);
+        assert_eq!(hit.unwrap().end_voxel(), VoxelCoord::new(0, 3, 3));
+    }
+
+    #[test]
+    fn raycast_max_distance() {
+        let start = Coord::new(0.0, 0.0, 0.0);
+        let dir = Coord::new(2.0, 0.0, 0.0);
+        let hit = raycast(VoxelCoord::new(0, 0, 0), start, dir, MIN, MAX, 3.7, |v| v.x == 10);
+        assert!(hit.hit_max_distance());
+        assert!(!hit.hit_interesting());
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(4, 0, 0));
+        assert_eq!(hit.face_hit(), FaceHit::X);
+        assert!((hit.distance() - 3.7).abs() < 1e-4);
+        assert!((hit.end() - Coord::new(3.7, 0.0, 0.0)).magnitude() < 1e-4);
+
+        // the target is in range
+        let hit = raycast(VoxelCoord::new(0, 0, 0), start, dir, MIN, MAX, 12.0, |v| v.x == 10);
+        assert!(hit.hit_interesting());
+        assert!(!hit.hit_max_distance());
+
+        // no crossings at all
+        let hit = raycast(VoxelCoord::new(0, 0, 0), start, dir, MIN, MAX, 0.2, |_| false);
+        assert!(hit.hit_max_distance());
+        assert_eq!(hit.face_hit(), FaceHit::Contained);
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(0, 0, 0));
+    }
+
+    #[test]
+    fn raycast_interval() {
+        let start = Coord::new(0.0, 0.0, 0.0);
+        let dir = Coord::new(-1.0, -0.5, 0.0);
+        let target = VoxelCoord::new(-4, -2, 0);
+        let hit = raycast(start.cast().unwrap(), start, dir, MIN, MAX, f32::INFINITY, |v| v == target);
+        assert!(hit.hit_interesting());
+        assert_eq!(hit.end_voxel(), target);
+        assert!((hit.t_enter() - hit.t()).abs() < 1e-4);
+        assert_eq!(
+            (hit.t_enter(), hit.t_exit()),
+            voxel_interval(target, start, dir)
+        );
+        // enters at x = -3.5, leaves at y = -2.5
+        assert!((hit.t_enter() - 3.5).abs() < 1e-4);
+        assert!((hit.t_exit() - 4.5).abs() < 1e-4);
+        assert!((hit.exit() - Coord::new(-4.5, -2.25, 0.0)).magnitude() < 1e-4);
+
+        let hit = raycast(start.cast().unwrap(), start, dir, MIN, MAX, f32::INFINITY, |_| true);
+        assert_eq!(hit.face_hit(), FaceHit::Contained);
+        assert_eq!(hit.t_enter(), 0.0);
+        assert!((hit.t_exit() - 0.5).abs() < 1e-4);
+    }
+
+    /// A world with a single tracked chunk at the origin.
+    fn world_with(chunk: Chunk<TestVoxel>) -> World {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .build();
+        dispatcher.setup(&mut world.res);
+
+        world.create_entity().with(chunk).build();
+        dispatcher.dispatch(&mut world.res);
+        world
+    }
+
+    /// A plain map with a single chunk at the origin.
+    fn map_with(chunk: Chunk<TestVoxel>) -> HashMap<VoxelCoord, Chunk<TestVoxel>> {
+        let mut map = HashMap::new();
+        map.insert(chunk.coord, chunk);
+        map
+    }
+
+    #[test]
+    fn voxel_raycast_placement() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(0, 3, 3)] = TestVoxel::Rock;
+        chunk[VoxelCoord::new(15, 15, 15)] = TestVoxel::Rock;
+        let world = world_with(chunk);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let storage = world.read_storage::<Chunk<TestVoxel>>();
+        let chunks = tracker.chunks(&storage);
+        let cast = |start: Coord, direction: Coord| {
+            voxel_raycast(&chunks, start, direction, MIN * 3, MAX * 3, f32::INFINITY)
+        };
+
+        // entering the chunk from unloaded space: the face is on the chunk border
+        let hit = cast(Coord::new(-40.0, 3.0, 3.0), Coord::new(1.0, 0.0, 0.0));
+        assert!(hit.hit_interesting());
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(0, 3, 3));
+        assert_eq!(hit.face_hit(), FaceHit::X);
+        assert_eq!(hit.placement(), Some(VoxelCoord::new(-1, 3, 3)));
+        assert_eq!(hit.entity(), tracker.get_chunk_ent(VoxelCoord::new(0, 0, 0)));
+        assert!(hit.entity().is_some());
+
+        let hit = cast(Coord::new(15.0, 15.0, 40.0), Coord::new(0.0, 0.0, -1.0));
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(15, 15, 15));
+        assert_eq!(hit.normal(), VoxelCoord::new(0, 0, 1));
+        assert_eq!(hit.placement(), Some(VoxelCoord::new(15, 15, 16)));
+
+        // within the chunk
+        let hit = cast(Coord::new(5.0, 3.0, 3.0), Coord::new(-1.0, 0.0, 0.0));
+        assert_eq!(hit.placement(), Some(VoxelCoord::new(1, 3, 3)));
+
+        // starting inside the hit voxel
+        let hit = cast(Coord::new(0.0, 3.0, 3.0), Coord::new(-1.0, 0.0, 0.0));
+        assert_eq!(hit.face_hit(), FaceHit::Contained);
+        assert_eq!(hit.placement(), None);
+
+        // missing
+        let hit = cast(Coord::new(5.0, 5.0, 5.0), Coord::new(0.0, 1.0, 0.0));
+        assert!(!hit.hit_interesting());
+        assert_eq!(hit.placement(), None);
+        assert_eq!(hit.entity(), None);
+    }
+
+    #[test]
+    fn boxcast_floor_and_walls() {
+        // a floor at y = 0, and a wall at x = 3
+        let solid = |v: VoxelCoord| v.y <= 0 || v.x == 3;
+        let player = Aabb::new(Coord::new(-0.4, 0.5, -0.4), Coord::new(0.4, 2.3, 0.4));
+
+        // resting on the floor: can't fall, but can slide along it up to the wall
+        let down = boxcast(player, Coord::new(0.0, -1.0, 0.0), 5.0, solid);
+        assert_eq!(down.hit(), Some(VoxelCoord::new(0, 0, 0)));
+        assert_eq!(down.normal(), VoxelCoord::new(0, 1, 0));
+        assert_eq!(down.fraction(), 0.0);
+
+        let side = boxcast(player, Coord::new(2.0, 0.0, 0.0), 5.0, solid);
+        assert_eq!(side.face_hit(), FaceHit::X);
+        assert_eq!(side.normal(), VoxelCoord::new(-1, 0, 0));
+        assert_eq!(side.hit().map(|v| v.x), Some(3));
+        assert!((side.end().max.x - 2.5).abs() < 1e-4);
+        assert!((side.fraction() - 2.1 / 5.0).abs() < 1e-4);
+
+        // short of the wall
+        let short = boxcast(player, Coord::new(1.0, 0.0, 0.0), 1.5, solid);
+        assert_eq!(short.hit(), None);
+        assert_eq!(short.fraction(), 1.0);
+        assert!((short.end().min.x - 1.1).abs() < 1e-4);
+    }
+
+    #[test]
+    fn boxcast_edges() {
+        // a single voxel, approached diagonally so that the box crosses its x and z faces at the same time
+        let target = VoxelCoord::new(2, 0, 2);
+        let cube = Aabb::new(Coord::new(-0.5, -0.5, -0.5), Coord::new(0.5, 0.5, 0.5));
+        let hit = boxcast(cube, Coord::new(1.0, 0.0, 1.0), 10.0, |v| v == target);
+        assert_eq!(hit.hit(), Some(target));
+        assert!((hit.t() - 1.0).abs() < 1e-4);
+
+        // missing it by a hair
+        let nudged = cube.translate(Coord::new(0.0, 1.0, 0.0));
+        let miss = boxcast(nudged, Coord::new(1.0, 0.0, 1.0), 10.0, |v| v == target);
+        assert_eq!(miss.hit(), None);
+
+        // voxels we start inside of don't count
+        let stuck = boxcast(cube, Coord::new(0.0, 1.0, 0.0), 3.0, |v| v.y == 0 || v.y == 3);
+        assert_eq!(stuck.hit(), Some(VoxelCoord::new(0, 3, 0)));
+        assert!((stuck.t() - 2.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn spherecast_faces_edges_corners() {
+        // falling onto a floor
+        let fall = spherecast(Coord::new(0.3, 3.0, 0.0), 0.5, Coord::new(0.0, -1.0, 0.0), 10.0, |v| v.y <= 0);
+        assert_eq!(fall.hit(), Some(VoxelCoord::new(0, 0, 0)));
+        assert!((fall.t() - 2.0).abs() < 1e-4);
+        assert!((fall.normal() - Coord::new(0.0, 1.0, 0.0)).magnitude() < 1e-4);
+
+        let single = |v: VoxelCoord| v == VoxelCoord::new(0, 0, 0);
+        let east = Coord::new(1.0, 0.0, 0.0);
+
+        // grazing the top edge: the sphere gets closer than its bounding box would
+        let edge = spherecast(Coord::new(-3.0, 0.8, 0.0), 0.5, east, 10.0, single);
+        assert_eq!(edge.hit(), Some(VoxelCoord::new(0, 0, 0)));
+        assert!((edge.end().x - -0.9).abs() < 1e-4);
+        assert!((edge.contact() - Coord::new(-0.5, 0.5, 0.0)).magnitude() < 1e-4);
+        assert!((edge.normal() - Coord::new(-0.8, 0.6, 0.0)).magnitude() < 1e-4);
+
+        // near the corner
+        let corner = spherecast(Coord::new(-3.0, 0.8, 0.8), 0.5, east, 10.0, single);
+        assert!((corner.end().x - -(0.5 + 0.07f32.sqrt())).abs() < 1e-4);
+        assert!((corner.contact() - Coord::new(-0.5, 0.5, 0.5)).magnitude() < 1e-4);
+
+        // the bounding box would hit this, but the sphere passes the corner
+        let miss = spherecast(Coord::new(-3.0, 0.95, 0.95), 0.5, east, 10.0, single);
+        assert_eq!(miss.hit(), None);
+        assert_eq!(miss.fraction(), 1.0);
+    }
+
+    #[test]
+    fn capsule_rasterization() {
+        let voxels = |start, end, radius| {
+            let mut voxels: Vec<VoxelCoord> = capsule_voxels(start, end, radius).collect();
+            voxels.sort_by_key(|v| (v.x, v.y, v.z));
+            voxels
+        };
+
+        // a thin line down the middle of a row of voxels only touches that row
+        let row: Vec<_> = (0..6).map(|x| VoxelCoord::new(x, 0, 0)).collect();
+        assert_eq!(voxels(Coord::new(0.0, 0.0, 0.0), Coord::new(5.0, 0.0, 0.0), 0.0), row);
+
+        // a thicker one reaches the faces of the neighbours and the voxels past the ends, but not the diagonals
+        let thick = voxels(Coord::new(0.0, 0.0, 0.0), Coord::new(5.0, 0.0, 0.0), 0.6);
+        assert_eq!(thick.len(), 8 + 6 * 4);
+        assert!(thick.contains(&VoxelCoord::new(-1, 0, 0)) && thick.contains(&VoxelCoord::new(3, 0, -1)));
+        assert!(!thick.contains(&VoxelCoord::new(3, 1, 1)) && !thick.contains(&VoxelCoord::new(6, 1, 0)));
+
+        // the same as checking every voxel in the bounding box
+        let (start, end, radius) = (Coord::new(-2.3, 4.1, 0.7), Coord::new(6.6, -3.2, 2.9), 1.3);
+        let bounds = Aabb::new(Coord::new(-3.6, -4.5, -0.6), Coord::new(7.9, 5.4, 4.2));
+        let (min, max) = bounds.voxels();
+        let brute: Vec<_> = voxels_in_box(min, max)
+            .filter(|&v| segment_voxel_distance2(start, end - start, v) <= radius * radius)
+            .collect();
+        assert_eq!(voxels(start, end, radius), brute);
+    }
+
+    #[test]
+    fn voxel_raycast_actions() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(3, 3, 3)] = TestVoxel::Grass;
+        chunk[VoxelCoord::new(6, 3, 3)] = TestVoxel::Rock;
+        let chunks = map_with(chunk);
+
+        let (start, east) = (Coord::new(-20.0, 3.0, 3.0), Coord::new(1.0, 0.0, 0.0));
+        let (min, max) = (MIN * 3, MAX * 3);
+        let hit = voxel_raycast(&chunks, start, east, min, max, f32::INFINITY);
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(3, 3, 3));
+
+        // see through grass
+        let mut seen = vec![];
+        let hit = voxel_raycast_with(&chunks, start, east, min, max, f32::INFINITY, |coord, voxel| {
+            seen.push(coord);
+            match *voxel {
+                TestVoxel::Rock => RayAction::Stop,
+                _ => RayAction::Continue,
+            }
+        });
+        assert_eq!(hit.end_voxel(), VoxelCoord::new(6, 3, 3));
+        assert_eq!(hit.placement(), Some(VoxelCoord::new(5, 3, 3)));
+        let expected: Vec<_> = (0..7).map(|x| VoxelCoord::new(x, 3, 3)).collect();
+        assert_eq!(seen, expected);
+    }
+
+    /// Chunks that know their bounds, and count how often they're looked up.
+    struct Counted {
+        chunks: HashMap<VoxelCoord, Chunk<TestVoxel>>,
+        bounds: Option<(VoxelCoord, VoxelCoord)>,
+        heights: HashMap<(i16, i16), i16>,
+        lookups: Cell<usize>,
+    }
+    impl ChunkAccess<TestVoxel> for Counted {
+        fn get_chunk(&self, coord: VoxelCoord) -> Option<&Chunk<TestVoxel>> {
+            self.lookups.set(self.lookups.get() + 1);
+            self.chunks.get_chunk(coord)
+        }
+        fn loaded_bounds(&self) -> Option<(VoxelCoord, VoxelCoord)> {
+            self.bounds
+        }
+        fn surface_height(&self, x: i16, z: i16) -> Option<i16> {
+            self.heights.get(&(x, z)).cloned()
+        }
+    }
+
+    #[test]
+    fn voxel_raycast_skips_unloaded() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(3, 3, 3)] = TestVoxel::Rock;
+        let map = map_with(chunk);
+        let origin = VoxelCoord::new(0, 0, 0);
+        let counted = Counted {
+            chunks: map_with(Chunk { ..*map.get(&origin).unwrap() }),
+            bounds: Some((origin, origin)),
+            heights: HashMap::new(),
+            lookups: Cell::new(0),
+        };
+
+        let (min, max) = (MIN * 100, MAX * 100);
+        let rays = [
+            // hitting the rock from far away
+            (Coord::new(-1500.0, 3.0, 3.0), Coord::new(1.0, 0.0, 0.0), f32::INFINITY),
+            (Coord::new(-1000.0, 900.0, -400.0), Coord::new(3.0, -2.7, 1.2) - Coord::new(-1000.0, 900.0, -400.0), f32::INFINITY),
+            // missing: out of bounds, out of distance, and leaving the loaded chunk
+            (Coord::new(-1500.0, 8.0, 3.0), Coord::new(1.0, 0.1, 0.0), f32::INFINITY),
+            (Coord::new(-1500.0, 3.0, 3.0), Coord::new(1.0, 0.0, 0.0), 777.7),
+            (Coord::new(5.0, 5.0, 5.0), Coord::new(0.2, 1.0, 0.3), f32::INFINITY),
+        ];
+        for &(start, direction, max_distance) in rays.iter() {
+            counted.lookups.set(0);
+            let fast = voxel_raycast(&counted, start, direction, min, max, max_distance);
+            let slow = voxel_raycast(&map, start, direction, min, max, max_distance);
+            assert_eq!(fast.hit_interesting(), slow.hit_interesting());
+            assert_eq!(fast.hit_max_distance(), slow.hit_max_distance());
+            assert_eq!((fast.end_voxel(), fast.face_hit(), fast.normal()), (slow.end_voxel(), slow.face_hit(), slow.normal()));
+            assert!((fast.end() - slow.end()).magnitude() < 1e-2);
+            assert!((fast.t() - slow.t()).abs() < 1e-3 && (fast.t_enter() - slow.t_enter()).abs() < 1e-3);
+            assert!(counted.lookups.get() < 20, "looked at {} chunks", counted.lookups.get());
+        }
+    }
+
+    #[test]
+    fn ground_probe() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        // a floor at y = 2, and a ledge at y = 9 over part of it
+        for v in voxels_in_box(VoxelCoord::new(0, 2, 0), VoxelCoord::new(15, 2, 15)) {
+            chunk[v] = TestVoxel::Rock;
+        }
+        for v in voxels_in_box(VoxelCoord::new(0, 9, 0), VoxelCoord::new(4, 9, 15)) {
+            chunk[v] = TestVoxel::Rock;
+        }
+        let map = map_with(chunk);
+
+        let found = surface_below(&map, Coord::new(6.2, 12.0, 3.7), 100.0).unwrap();
+        assert_eq!(found.voxel(), VoxelCoord::new(6, 2, 4));
+        assert_eq!(found.height(), 2.5);
+        assert_eq!(found.position(), Coord::new(6.2, 2.5, 3.7));
+        assert_eq!(found.depth(), 9.5);
+        // over the ledge, under it, and inside it
+        assert_eq!(surface_below(&map, Coord::new(2.0, 12.0, 3.0), 100.0).unwrap().height(), 9.5);
+        assert_eq!(surface_below(&map, Coord::new(2.0, 8.0, 3.0), 100.0).unwrap().height(), 2.5);
+        let sunk = surface_below(&map, Coord::new(2.0, 9.2, 3.0), 100.0).unwrap();
+        assert_eq!(sunk.voxel(), VoxelCoord::new(2, 9, 3));
+        assert!((sunk.depth() + 0.3).abs() < 1e-5);
+        // too deep, or nothing there
+        assert_eq!(surface_below(&map, Coord::new(6.0, 12.0, 3.0), 9.0), None);
+        assert_eq!(surface_below(&map, Coord::new(6.0, 1.0, 3.0), 100.0), None);
+        assert_eq!(surface_below(&map, Coord::new(20.0, 12.0, 3.0), 100.0), None);
+        // searches running off the edges of the world
+        let deepest = ::std::i16::MAX as f32 - 0.5;
+        assert_eq!(surface_below(&map, Coord::new(6.0, 12.0, 3.0), deepest).unwrap().height(), 2.5);
+        assert_eq!(surface_below(&map, Coord::new(6.0, -32760.0, 3.0), deepest), None);
+        assert_eq!(surface_below(&map, Coord::new(6.0, 32760.0, 3.0), 100.0), None);
+
+        // with a heightmap, columns we're above don't need to be walked
+        let mut heights = HashMap::new();
+        for x in 0..16 {
+            for z in 0..16 {
+                heights.insert((x, z), if x <= 4 { 9 } else { 2 });
+            }
+        }
+        let counted = Counted {
+            chunks: map,
+            bounds: None,
+            heights,
+            lookups: Cell::new(0),
+        };
+        for &(x, y, z) in &[(6.2, 12.0, 3.7), (2.0, 12.0, 3.0), (2.0, 8.0, 3.0), (2.0, 9.2, 3.0), (6.0, 1.0, 3.0)] {
+            let start = Coord::new(x, y, z);
+            assert_eq!(surface_below(&counted, start, 100.0), surface_below(&counted.chunks, start, 100.0));
+        }
+        counted.lookups.set(0);
+        assert_eq!(surface_below(&counted, Coord::new(6.0, 12.0, 3.0), 9.0), None);
+        assert!(surface_below(&counted, Coord::new(6.0, 12.0, 3.0), 10.0).is_some());
+        assert_eq!(counted.lookups.get(), 0);
+    }
+
+    #[test]
+    fn sight_lines() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(2, 2, 2)] = TestVoxel::Rock;
+        chunk[VoxelCoord::new(8, 2, 2)] = TestVoxel::Rock;
+        chunk[VoxelCoord::new(2, 8, 2)] = TestVoxel::Grass;
+        chunk[VoxelCoord::new(2, 9, 2)] = TestVoxel::Grass;
+        let chunks = map_with(chunk);
+
+        let solid = |_: VoxelCoord, voxel: &TestVoxel| {
+            if voxel.is_transparent() {
+                RayAction::Continue
+            } else {
+                RayAction::Stop
+            }
+        };
+        let at = |x: f32, y: f32, z: f32| Coord::new(x, y, z);
+
+        assert!(!line_of_sight(at(0.0, 2.0, 2.0), at(5.0, 2.0, 2.0), &chunks, solid));
+        assert!(line_of_sight(at(0.0, 3.0, 2.0), at(5.0, 3.0, 2.0), &chunks, solid));
+        // looking out of (and into) a voxel
+        assert!(line_of_sight(at(2.0, 2.0, 2.0), at(7.6, 2.0, 2.0), &chunks, solid));
+        assert!(line_of_sight(at(3.0, 2.0, 2.0), at(8.0, 2.0, 2.0), &chunks, solid));
+        // unloaded chunks are clear
+        assert!(line_of_sight(at(-10.0, 3.0, 2.0), at(5.0, 3.0, 2.0), &chunks, solid));
+
+        // two leaves, each letting half the light through
+        let leafy = |_: VoxelCoord, voxel: &TestVoxel| match *voxel {
+            TestVoxel::Air => 0.0,
+            TestVoxel::Grass => 0.5,
+            TestVoxel::Rock => 1.0,
+        };
+        let light = transmittance(at(2.0, 6.0, 2.0), at(2.0, 12.0, 2.0), &chunks, leafy);
+        assert!((light - 0.25).abs() < 1e-6);
+    }
+
+    #[test]
+    fn vision_cone() {
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(8, 8, 6)] = TestVoxel::Rock;
+        let chunks = map_with(chunk);
+        let solid = |_: VoxelCoord, voxel: &TestVoxel| {
+            if voxel.is_transparent() {
+                RayAction::Continue
+            } else {
+                RayAction::Stop
+            }
+        };
+
+        let origin = Coord::new(8.0, 8.0, 2.0);
+        let forward = Coord::new(0.0, 0.0, 1.0);
+        let target = Aabb::new(Coord::new(5.0, 5.0, 10.0), Coord::new(11.0, 11.0, 11.0));
+
+        // the rock is in the way of the middle ray only
+        let fan = VisionCone::fan(0.2, 20.0, 3);
+        assert_eq!(fan.cast(origin, forward, &target, &chunks, solid), vec![true, false, true]);
+
+        // too far away
+        let short = VisionCone::fan(0.2, 5.0, 3);
+        assert_eq!(short.cast(origin, forward, &target, &chunks, solid), vec![false; 3]);
+
+        // looking the other way
+        let cone = VisionCone::cone(0.3, 20.0, 2, 6);
+        assert_eq!(cone.directions().len(), 13);
+        let visible = cone.cast(origin, -forward, &target, &chunks, solid);
+        assert!(visible.iter().all(|&v| !v));
+
+        let world_dirs = cone.directions_towards(Coord::new(1.0, 0.0, 0.0));
+        assert!((world_dirs[0] - Coord::new(1.0, 0.0, 0.0)).magnitude() < 1e-5);
+        assert!(world_dirs.iter().all(|d| d.x >= 0.3f32.cos() - 1e-5));
+    }
+
+    #[test]
+    fn hemisphere_exposure() {
+        let hemisphere = Hemisphere::new(64, 32.0);
+        let directions = hemisphere.directions();
+        assert!(directions.iter().all(|d| d.z > 0.0 && (d.magnitude() - 1.0).abs() < 1e-5));
+        // cosine-distributed rays are, on average, 2/3 of the way up
+        let mean = directions.iter().map(|d| d.z).sum::<f32>() / directions.len() as f32;
+        assert!((mean - 2.0 / 3.0).abs() < 0.01);
+        let sideways = hemisphere.directions_around(Coord::new(-1.0, 0.0, 0.0));
+        assert!(sideways.iter().all(|d| d.x < 0.0));
+
+        // a floor at y = 2, with a covered voxel, and a shallow and a deep well
+        let mut chunk = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        for v in voxels_in_box(VoxelCoord::new(0, 2, 0), VoxelCoord::new(15, 2, 15)) {
+            chunk[v] = TestVoxel::Rock;
+        }
+        let flat = map_with(Chunk { ..chunk });
+        chunk[VoxelCoord::new(2, 3, 2)] = TestVoxel::Grass;
+        for v in voxels_in_box(VoxelCoord::new(3, 3, 9), VoxelCoord::new(5, 4, 11)) {
+            chunk[v] = TestVoxel::Rock;
+        }
+        for v in voxels_in_box(VoxelCoord::new(10, 3, 9), VoxelCoord::new(12, 6, 11)) {
+            chunk[v] = TestVoxel::Rock;
+        }
+        chunk[VoxelCoord::new(4, 4, 10)] = TestVoxel::Air;
+        chunk[VoxelCoord::new(4, 3, 10)] = TestVoxel::Air;
+        for y in 3..7 {
+            chunk[VoxelCoord::new(11, y, 10)] = TestVoxel::Air;
+        }
+        let chunks = map_with(chunk);
+        let solid = |_: VoxelCoord, voxel: &TestVoxel| {
+            if voxel.is_transparent() {
+                RayAction::Continue
+            } else {
+                RayAction::Stop
+            }
+        };
+        let up = VoxelCoord::new(0, 1, 0);
+
+        assert_eq!(hemisphere.exposure(VoxelCoord::new(8, 2, 3), up, &flat, solid), 1.0);
+        // the underside faces into unloaded chunks
+        assert_eq!(hemisphere.exposure(VoxelCoord::new(8, 2, 3), -up, &flat, solid), 1.0);
+        // nearby walls get in the way of low rays
+        let open = hemisphere.exposure(VoxelCoord::new(8, 2, 3), up, &chunks, solid);
+        assert!(0.9 < open && open < 1.0);
+        assert_eq!(hemisphere.exposure(VoxelCoord::new(2, 2, 2), up, &chunks, solid), 0.0);
+        let through_grass = |v: VoxelCoord, voxel: &TestVoxel| {
+            if *voxel == TestVoxel::Grass { RayAction::Continue } else { solid(v, voxel) }
+        };
+        assert!(hemisphere.exposure(VoxelCoord::new(2, 2, 2), up, &chunks, through_grass) > 0.9);
+
+        let shallow = hemisphere.exposure(VoxelCoord::new(4, 2, 10), up, &chunks, solid);
+        let deep = hemisphere.exposure(VoxelCoord::new(11, 2, 10), up, &chunks, solid);
+        assert!(0.0 < deep && deep < shallow && shallow < 1.0, "{} {}", deep, shallow);
+
+        // queued faces come out in order, as much as the budget allows
+        let mut queue = ExposureQueue::new(hemisphere.clone());
+        queue.push(VoxelCoord::new(8, 2, 3), up);
+        queue.push(VoxelCoord::new(4, 2, 10), up);
+        queue.push(VoxelCoord::new(2, 2, 2), up);
+        assert!(queue.run(Duration::from_millis(0), &chunks, solid).is_empty());
+        assert_eq!(queue.len(), 3);
+        let done = queue.run(Duration::from_secs(10), &chunks, solid);
+        assert!(queue.is_empty());
+        assert_eq!(
+            done,
+            vec![
+                (VoxelCoord::new(8, 2, 3), up, open),
+                (VoxelCoord::new(4, 2, 10), up, shallow),
+                (VoxelCoord::new(2, 2, 2), up, 0.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn checked_raycast() {
+        let origin = VoxelCoord::new(0, 0, 0);
+        let start = Coord::new(0.0, 0.0, 0.0);
+        let east = Coord::new(1.0, 0.0, 0.0);
+        let check = |start_voxel, start, direction, max_distance| {
+            try_raycast(start_voxel, start, direction, MIN, MAX, max_distance, |_| false).map(|hit| hit.end_voxel())
+        };
+
+        assert_eq!(check(origin, start, east, f32::INFINITY), Ok(VoxelCoord::new(20, 0, 0)));
+        assert_eq!(check(origin, Coord::new(f32::NAN, 0.0, 0.0), east, 1.0), Err(RaycastError::NotFinite));
+        assert_eq!(check(origin, start, Coord::new(0.0, f32::INFINITY, 0.0), 1.0), Err(RaycastError::NotFinite));
+        assert_eq!(check(origin, start, east, f32::NAN), Err(RaycastError::NotFinite));
+        assert_eq!(check(origin, start, Coord::new(0.0, 0.0, 0.0), 1.0), Err(RaycastError::ZeroDirection));
+        assert_eq!(check(origin, start, east, -1.0), Err(RaycastError::NegativeDistance));
+        assert_eq!(check(origin, Coord::new(0.0, 2.0, 0.0), east, 1.0), Err(RaycastError::StartNotInVoxel));
+        let outside = VoxelCoord::new(0, 30, 0);
+        assert_eq!(check(outside, Coord::new(0.0, 30.0, 0.0), east, 1.0), Err(RaycastError::StartOutOfBounds));
+
+        // slightly outside the start voxel is fine
+        assert!(check(origin, Coord::new(0.5001, 0.0, 0.0), east, 1.0).is_ok());
+    }
+
+    #[test]
+    fn transformed_chunks() {
+        let mut world = World::new();
+        let (a, b) = (world.create_entity().build(), world.create_entity().build());
+
+        // a is turned a quarter turn about y, so its z axis points east, with its first voxel at (10, 0, 0)
+        let mut chunk_a = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk_a[VoxelCoord::new(0, 0, 0)] = TestVoxel::Rock;
+        let transform_a = GlobalTransform(
+            Matrix4::from_translation(Coord::new(10.0, 0.0, 0.0)) * Matrix4::from_angle_y(Deg(90.0)),
+        );
+        // b is half size, with its first voxel at (4, 0, 0)
+        let mut chunk_b = Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0));
+        chunk_b[VoxelCoord::new(1, 0, 0)] = TestVoxel::Rock;
+        let transform_b = GlobalTransform(Matrix4::from_translation(Coord::new(4.0, 0.0, 0.0)) * Matrix4::from_scale(0.5));
+
+        let origin = Coord::new(0.0, 0.0, 0.0);
+        let east = Coord::new(1.0, 0.0, 0.0);
+        let only_a = || vec![(a, &chunk_a, &transform_a)];
+        let close = |x: Coord, y: Coord| (x - y).magnitude() < 1e-4;
+
+        // the nearest hit wins
+        let both = vec![(a, &chunk_a, &transform_a), (b, &chunk_b, &transform_b)];
+        let hit = transformed_raycast(both, origin, east, f32::INFINITY).unwrap();
+        assert_eq!((hit.entity(), hit.voxel()), (b, VoxelCoord::new(1, 0, 0)));
+        assert!((hit.t() - 4.25).abs() < 1e-4);
+        assert!(close(hit.normal(), -east));
+
+        // normals are rotated back into world space; t is in terms of the world-space direction
+        let hit = transformed_raycast(only_a(), origin, east * 2.0, f32::INFINITY).unwrap();
+        assert_eq!((hit.entity(), hit.voxel()), (a, VoxelCoord::new(0, 0, 0)));
+        assert!((hit.t() - 4.75).abs() < 1e-4 && (hit.distance() - 9.5).abs() < 1e-4);
+        assert!(close(hit.end(), Coord::new(9.5, 0.0, 0.0)) && close(hit.normal(), -east));
+        assert!(transformed_raycast(only_a(), origin, east, 9.0).is_none());
+
+        // starting inside a chunk
+        let hit = transformed_raycast(only_a(), Coord::new(20.0, 0.0, 0.0), -east, f32::INFINITY).unwrap();
+        assert!((hit.t() - 9.5).abs() < 1e-4 && close(hit.normal(), east));
+        assert!(transformed_raycast(only_a(), Coord::new(20.0, 0.0, 0.0), east, f32::INFINITY).is_none());
     }
 }
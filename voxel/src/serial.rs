@@ -0,0 +1,114 @@
+//! Serde support for chunks, with the `serialize` feature.
+//!
+//! A chunk is written as its coordinate, a palette of the different voxels in it, and runs of palette indices
+//! (in storage order, i.e. z changing fastest and x slowest), so a chunk of air over rock is a few dozen
+//! numbers rather than 4096 voxels.
+
+use super::{canonicalize_chunk, Chunk, Voxel, VoxelCoord, CHUNK_SIZE};
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::{Serialize, Serializer};
+use std::iter::repeat;
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Chunk")]
+struct Packed<V> {
+    coord: (i16, i16, i16),
+    palette: Vec<V>,
+    /// Palette indices, and how many voxels in a row have them.
+    runs: Vec<(u16, u16)>,
+}
+
+impl<V: Voxel + Serialize> Serialize for Chunk<V> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut palette: Vec<V> = Vec::new();
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+        for voxel in self.voxels.iter().flat_map(|plane| plane.iter().flat_map(|row| row.iter())) {
+            let index = match palette.iter().position(|v| v == voxel) {
+                Some(index) => index,
+                None => {
+                    palette.push(*voxel);
+                    palette.len() - 1
+                }
+            } as u16;
+            if let Some(&mut (last, ref mut length)) = runs.last_mut() {
+                if last == index {
+                    *length += 1;
+                    continue;
+                }
+            }
+            runs.push((index, 1));
+        }
+        Packed {
+            coord: (self.coord.x, self.coord.y, self.coord.z),
+            palette,
+            runs,
+        }.serialize(serializer)
+    }
+}
+
+impl<'de, V: Voxel + Deserialize<'de>> Deserialize<'de> for Chunk<V> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let packed = Packed::<V>::deserialize(deserializer)?;
+        let coord = VoxelCoord::new(packed.coord.0, packed.coord.1, packed.coord.2);
+        if coord != canonicalize_chunk(coord) {
+            return Err(de::Error::custom("improper chunk coordinate"));
+        }
+        let count: usize = packed.runs.iter().map(|&(_, length)| length as usize).sum();
+        if count != CHUNK_SIZE * CHUNK_SIZE * CHUNK_SIZE {
+            return Err(de::Error::custom("wrong number of voxels in chunk"));
+        }
+
+        let mut chunk = Chunk::empty(coord);
+        let indices = packed
+            .runs
+            .iter()
+            .flat_map(|&(index, length)| repeat(index).take(length as usize));
+        let voxels = chunk
+            .voxels
+            .iter_mut()
+            .flat_map(|plane| plane.iter_mut().flat_map(|row| row.iter_mut()));
+        for (voxel, index) in voxels.zip(indices) {
+            *voxel = *packed
+                .palette
+                .get(index as usize)
+                .ok_or_else(|| de::Error::custom("voxel missing from chunk palette"))?;
+        }
+        Ok(chunk)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+    use TestVoxel;
+
+    #[test]
+    fn round_trip() {
+        let mut chunk = Chunk::empty(VoxelCoord::new(16, -32, 0));
+        chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 7, 15), TestVoxel::Rock);
+        chunk[VoxelCoord::new(3, 8, 3)] = TestVoxel::Grass;
+
+        let json = serde_json::to_string(&chunk).unwrap();
+        assert!(json.len() < 600, "{}", json);
+        let back: Chunk<TestVoxel> = serde_json::from_str(&json).unwrap();
+        assert_eq!(back.coord, chunk.coord);
+        assert!(back.voxels == chunk.voxels);
+    }
+
+    #[test]
+    fn bad_chunks() {
+        let bad = [
+            // not on the chunk grid
+            r#"{"coord":[1,0,0],"palette":["Air"],"runs":[[0,4096]]}"#,
+            // too few voxels
+            r#"{"coord":[0,0,0],"palette":["Air"],"runs":[[0,4095]]}"#,
+            // index past the palette
+            r#"{"coord":[0,0,0],"palette":["Air"],"runs":[[0,4000],[1,96]]}"#,
+        ];
+        for json in &bad {
+            assert!(serde_json::from_str::<Chunk<TestVoxel>>(json).is_err(), "{}", json);
+        }
+    }
+}
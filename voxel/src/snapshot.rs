@@ -0,0 +1,366 @@
+//! Snapshots of parts of the world, to put back later; e.g. to reset a minigame arena, or revert in an editor.
+//!
+//! A `Snapshot` copies the voxels in a box (or in whole chunks, e.g. every loaded one) out of the loaded chunks.
+//! Restoring it stamps them back through `ChunkDeltas`, so it lands like any other edit: chunks are re-meshed
+//! and re-lit, autosaved, journaled and so on. Chunks that weren't loaded when the snapshot was taken are left
+//! alone, as are ones that aren't loaded when it's restored.
+//!
+//! Snapshots can be kept by name in the `Snapshots` resource, or saved to disk with `Snapshot::save`.
+
+use super::{chunks_in_box, Chunk, ChunkAccess, Voxel, VoxelCoord, VoxelId, CHUNK_SIZE};
+use bytes::{invalid, put_u16, put_u32, Reader};
+use delta::{ChunkDeltas, DeltaId, DeltaSource};
+use persist::{pack_voxels, unpack_voxel_runs, unpack_voxels};
+use structure::{MergePolicy, Rotation, Structure};
+
+use fnv::FnvHashMap;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 4] = b"MVSS";
+/// Version 1 snapshots packed voxels by id, and version 2 ones never packed them as runs; both can still be read.
+const VERSION: u8 = 3;
+/// The most voxels `Snapshot::to_structure` will flatten a snapshot into.
+pub const MAX_STRUCTURE_VOXELS: usize = 1 << 24;
+
+/// A copy of some of the world's voxels; see the module docs.
+#[derive(Clone, Debug)]
+pub struct Snapshot<V: Voxel> {
+    name: String,
+    /// The copied parts of each chunk, by their minimum corners.
+    pieces: Vec<(VoxelCoord, Structure<V>)>,
+}
+impl<V: Voxel> Snapshot<V> {
+    /// Copy the voxels in the box from `min` to `max` (inclusive), in chunks that are loaded.
+    pub fn capture_box<C: ChunkAccess<V>>(name: &str, chunks: &C, min: VoxelCoord, max: VoxelCoord) -> Self {
+        let last = CHUNK_SIZE as i16 - 1;
+        let mut pieces = Vec::new();
+        for chunk_coord in chunks_in_box(min, max) {
+            if let Some(chunk) = chunks.get_chunk(chunk_coord) {
+                let end = chunk_coord + VoxelCoord::new(last, last, last);
+                let lo = VoxelCoord::new(min.x.max(chunk_coord.x), min.y.max(chunk_coord.y), min.z.max(chunk_coord.z));
+                let hi = VoxelCoord::new(max.x.min(end.x), max.y.min(end.y), max.z.min(end.z));
+                pieces.push((lo, copy(chunk, lo, hi)));
+            }
+        }
+        Snapshot {
+            name: name.to_string(),
+            pieces,
+        }
+    }
+
+    /// Copy whole chunks; e.g. every loaded chunk, with `(&chunks).join()`.
+    pub fn capture_chunks<'a, I: IntoIterator<Item = &'a Chunk<V>>>(name: &str, chunks: I) -> Self {
+        let last = CHUNK_SIZE as i16 - 1;
+        let pieces = chunks
+            .into_iter()
+            .map(|chunk| {
+                let end = chunk.coord + VoxelCoord::new(last, last, last);
+                (chunk.coord, copy(chunk, chunk.coord, end))
+            })
+            .collect();
+        Snapshot {
+            name: name.to_string(),
+            pieces,
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// How many chunks the snapshot has voxels from.
+    pub fn chunk_count(&self) -> usize {
+        self.pieces.len()
+    }
+
+    /// The copy of the voxel at `coord`, if the snapshot has it.
+    pub fn get(&self, coord: VoxelCoord) -> Option<V> {
+        self.pieces
+            .iter()
+            .find(|&&(origin, ref structure)| structure.contains(coord - origin))
+            .map(|&(origin, ref structure)| structure[coord - origin])
+    }
+
+    /// The smallest box (min and max, inclusive) holding every voxel the snapshot has, or None if it's empty.
+    pub fn bounds(&self) -> Option<(VoxelCoord, VoxelCoord)> {
+        let one = VoxelCoord::new(1, 1, 1);
+        self.pieces.iter().fold(None, |bounds, &(origin, ref structure)| {
+            let end = origin + (structure.size() - one);
+            Some(match bounds {
+                None => (origin, end),
+                Some((min, max)) => (
+                    VoxelCoord::new(min.x.min(origin.x), min.y.min(origin.y), min.z.min(origin.z)),
+                    VoxelCoord::new(max.x.max(end.x), max.y.max(end.y), max.z.max(end.z)),
+                ),
+            })
+        })
+    }
+
+    /// The snapshot as one structure filling its `bounds`, to stamp somewhere else; parts of the box from chunks
+    /// that weren't loaded are empty. None if the snapshot's empty, or its pieces are so far apart that the box
+    /// would be more than `MAX_STRUCTURE_VOXELS` (or too big for a `VoxelCoord`).
+    pub fn to_structure(&self) -> Option<Structure<V>> {
+        let (min, max) = self.bounds()?;
+        let side = |lo: i16, hi: i16| i32::from(hi) - i32::from(lo) + 1;
+        let (x, y, z) = (side(min.x, max.x), side(min.y, max.y), side(min.z, max.z));
+        let longest = i32::from(i16::max_value());
+        if x > longest || y > longest || z > longest || x as usize * y as usize * z as usize > MAX_STRUCTURE_VOXELS {
+            return None;
+        }
+        let mut flat = Structure::empty(VoxelCoord::new(x as i16, y as i16, z as i16));
+        for &(origin, ref structure) in &self.pieces {
+            for coord in structure.coords() {
+                flat[origin - min + coord] = structure[coord];
+            }
+        }
+        Some(flat)
+    }
+
+    /// Put the copied voxels back, through `deltas`; one edit per chunk, with the ids returned.
+    pub fn restore(&self, deltas: &ChunkDeltas<V>) -> Vec<DeltaId> {
+        self.pieces
+            .iter()
+            .map(|&(origin, ref structure)| {
+                deltas
+                    .writer()
+                    .source(DeltaSource::System("snapshot"))
+                    .defer_stamp(origin, structure, Rotation::None, MergePolicy::ReplaceAll)
+            })
+            .collect()
+    }
+}
+impl<V: VoxelId> Snapshot<V> {
+    /// Write the snapshot out: a header, the name, and each chunk's copy, with its voxels packed as in `persist`.
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if self.name.len() > u16::max_value() as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "snapshot name is too long"));
+        }
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+        put_u16(&mut bytes, self.name.len() as u16);
+        bytes.extend_from_slice(self.name.as_bytes());
+        put_u32(&mut bytes, self.pieces.len() as u32);
+        for &(origin, ref structure) in &self.pieces {
+            let size = structure.size();
+            for &c in &[origin.x, origin.y, origin.z, size.x, size.y, size.z] {
+                put_u16(&mut bytes, c as u16);
+            }
+            let packed = pack_voxels(structure.coords().map(|coord| structure[coord]));
+            put_u32(&mut bytes, packed.len() as u32);
+            bytes.extend(packed);
+        }
+        writer.write_all(&bytes)
+    }
+
+    /// Read a snapshot written by `write`.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let mut reader = Reader::new(&bytes);
+        if reader.take(4)? != MAGIC {
+            return Err(invalid("not a voxel snapshot"));
+        }
+        let version = reader.take(1)?[0];
+        if version == 0 || version > VERSION {
+            return Err(invalid(format!("unsupported snapshot version {}", version)));
+        }
+        let name_length = reader.u16()? as usize;
+        let name = String::from_utf8(reader.take(name_length)?.to_vec())
+            .map_err(|_| invalid("snapshot name isn't UTF-8"))?;
+
+        let count = reader.u32()?;
+        let mut pieces = Vec::new();
+        for _ in 0..count {
+            let mut c = [0; 6];
+            for c in &mut c {
+                *c = reader.u16()? as i16;
+            }
+            let (origin, size) = (VoxelCoord::new(c[0], c[1], c[2]), VoxelCoord::new(c[3], c[4], c[5]));
+            if size.x <= 0 || size.y <= 0 || size.z <= 0 || size.x > CHUNK_SIZE as i16 || size.y > CHUNK_SIZE as i16
+                || size.z > CHUNK_SIZE as i16
+            {
+                return Err(invalid(format!("bad snapshot piece size {:?}", size)));
+            }
+            let packed_length = reader.u32()? as usize;
+            let mut structure = Structure::empty(size);
+            let count = size.x as usize * size.y as usize * size.z as usize;
+            let packed = reader.take(packed_length)?;
+            let voxels = if version == 1 {
+                unpack_voxel_runs(packed, count)?
+            } else {
+                unpack_voxels(packed, count)?
+            };
+            for (coord, voxel) in structure.coords().zip(voxels) {
+                structure[coord] = voxel;
+            }
+            pieces.push((origin, structure));
+        }
+        Ok(Snapshot { name, pieces })
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Snapshot::read(&mut BufReader::new(File::open(path)?))
+    }
+}
+
+/// The part of `chunk` from `min` to `max` (inclusive, in world coordinates).
+fn copy<V: Voxel>(chunk: &Chunk<V>, min: VoxelCoord, max: VoxelCoord) -> Structure<V> {
+    let mut structure = Structure::empty(max - min + VoxelCoord::new(1, 1, 1));
+    for coord in structure.coords() {
+        structure[coord] = chunk[min + coord - chunk.coord];
+    }
+    structure
+}
+
+/// Snapshots kept in memory by name; a resource.
+pub struct Snapshots<V: Voxel> {
+    snapshots: FnvHashMap<String, Snapshot<V>>,
+}
+impl<V: Voxel> Default for Snapshots<V> {
+    fn default() -> Self {
+        Snapshots {
+            snapshots: FnvHashMap::default(),
+        }
+    }
+}
+impl<V: Voxel> Snapshots<V> {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Keep `snapshot` under its name, returning the one it replaces, if any.
+    pub fn insert(&mut self, snapshot: Snapshot<V>) -> Option<Snapshot<V>> {
+        self.snapshots.insert(snapshot.name.clone(), snapshot)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Snapshot<V>> {
+        self.snapshots.get(name)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Snapshot<V>> {
+        self.snapshots.remove(name)
+    }
+
+    /// Restore the snapshot called `name`, if there is one; see `Snapshot::restore`.
+    pub fn restore(&self, name: &str, deltas: &ChunkDeltas<V>) -> Option<Vec<DeltaId>> {
+        self.get(name).map(|snapshot| snapshot.restore(deltas))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use delta::ChunkDeltaSystem;
+    use specs::prelude::*;
+    use tracker::ChunkTrackerSystem;
+    use {ChunkTracker, TestVoxel};
+
+    #[test]
+    fn capture_write_read() {
+        let mut chunks = ::std::collections::HashMap::new();
+        for &x in &[0, 16] {
+            let mut chunk = Chunk::empty(VoxelCoord::new(x, 0, 0));
+            chunk.fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 1, 15), TestVoxel::Rock);
+            chunks.insert(chunk.coord, chunk);
+        }
+        // (the chunk at x = -16 isn't loaded)
+        let snapshot = Snapshot::capture_box("arena", &chunks, VoxelCoord::new(-4, 0, 0), VoxelCoord::new(19, 2, 3));
+        assert_eq!(snapshot.chunk_count(), 2);
+        assert_eq!(snapshot.get(VoxelCoord::new(18, 1, 3)), Some(TestVoxel::Rock));
+        assert_eq!(snapshot.get(VoxelCoord::new(18, 2, 3)), Some(TestVoxel::Air));
+        assert_eq!(snapshot.get(VoxelCoord::new(20, 1, 3)), None);
+        assert_eq!(snapshot.get(VoxelCoord::new(-2, 1, 3)), None);
+
+        let mut bytes = Vec::new();
+        snapshot.write(&mut bytes).unwrap();
+        let back = Snapshot::<TestVoxel>::read(&mut &bytes[..]).unwrap();
+        assert_eq!(back.name(), "arena");
+        assert_eq!(back.chunk_count(), 2);
+        assert_eq!(back.get(VoxelCoord::new(18, 1, 3)), Some(TestVoxel::Rock));
+        assert_eq!(back.get(VoxelCoord::new(0, 2, 0)), Some(TestVoxel::Air));
+        assert!(Snapshot::<TestVoxel>::read(&mut &bytes[..bytes.len() - 1]).is_err());
+
+        assert_eq!(
+            back.bounds(),
+            Some((VoxelCoord::new(0, 0, 0), VoxelCoord::new(19, 2, 3)))
+        );
+        let flat = back.to_structure().unwrap();
+        assert_eq!(flat.size(), VoxelCoord::new(20, 3, 4));
+        assert_eq!(flat[VoxelCoord::new(18, 1, 3)], TestVoxel::Rock);
+        assert_eq!(flat[VoxelCoord::new(18, 2, 3)], TestVoxel::Air);
+
+        let whole = Snapshot::capture_chunks("everything", chunks.values());
+        assert_eq!(whole.chunk_count(), 2);
+        assert_eq!(whole.get(VoxelCoord::new(31, 1, 15)), Some(TestVoxel::Rock));
+    }
+
+    #[test]
+    fn too_big_to_flatten() {
+        let snapshot = |coords: &[(i16, i16, i16)]| {
+            let chunks: Vec<_> = coords
+                .iter()
+                .map(|&(x, y, z)| Chunk::<TestVoxel>::empty(VoxelCoord::new(x, y, z)))
+                .collect();
+            Snapshot::capture_chunks("far apart", &chunks)
+        };
+        assert!(snapshot(&[(0, 0, 0), (0, 32, 0)]).to_structure().is_some());
+        // wider than a VoxelCoord can say
+        assert!(snapshot(&[(-32768, 0, 0), (32752, 0, 0)]).to_structure().is_none());
+        // too many voxels
+        assert!(snapshot(&[(0, 0, 0), (0, 4096, 4096)]).to_structure().is_none());
+    }
+
+    #[test]
+    fn restore() {
+        let mut world = World::new();
+        world.register::<Chunk<TestVoxel>>();
+        world.add_resource(ChunkTracker::new());
+        world.add_resource(ChunkDeltas::<TestVoxel>::new());
+        world.add_resource(Snapshots::<TestVoxel>::new());
+        let mut dispatcher = DispatcherBuilder::new()
+            .with(ChunkTrackerSystem::<TestVoxel>::new(), "chunk_tracker", &[])
+            .with(ChunkDeltaSystem::<TestVoxel>::new(), "chunk_deltas", &["chunk_tracker"])
+            .build();
+        dispatcher.setup(&mut world.res);
+        world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(VoxelCoord::new(0, 0, 0)))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+
+        let (min, max) = (VoxelCoord::new(2, 2, 2), VoxelCoord::new(5, 5, 5));
+        {
+            let tracker = world.read_resource::<ChunkTracker>();
+            let chunks = world.read_storage::<Chunk<TestVoxel>>();
+            let snapshot = Snapshot::capture_box("before", &tracker.chunks(&chunks), min, max);
+            world.write_resource::<Snapshots<TestVoxel>>().insert(snapshot);
+        }
+        world
+            .read_resource::<ChunkDeltas<TestVoxel>>()
+            .defer_fill_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(7, 7, 7), TestVoxel::Rock);
+        dispatcher.dispatch(&mut world.res);
+
+        {
+            let snapshots = world.read_resource::<Snapshots<TestVoxel>>();
+            let deltas = world.read_resource::<ChunkDeltas<TestVoxel>>();
+            assert_eq!(snapshots.restore("before", &deltas).map(|ids| ids.len()), Some(1));
+            assert!(snapshots.restore("after", &deltas).is_none());
+        }
+        dispatcher.dispatch(&mut world.res);
+
+        let tracker = world.read_resource::<ChunkTracker>();
+        let chunks = world.read_storage::<Chunk<TestVoxel>>();
+        let chunks = tracker.chunks(&chunks);
+        assert_eq!(chunks.get_voxel(VoxelCoord::new(3, 3, 3)), Some(TestVoxel::Air));
+        assert_eq!(chunks.get_voxel(VoxelCoord::new(5, 5, 6)), Some(TestVoxel::Rock));
+        assert_eq!(chunks.get_voxel(VoxelCoord::new(1, 1, 1)), Some(TestVoxel::Rock));
+    }
+}
@@ -0,0 +1,280 @@
+//! Voxel templates (trees, dungeons, prefabs...) that can be stamped into the world.
+//!
+//! See `ChunkDeltas::defer_stamp`, and `generate::WithStructures` for placing them as the world is generated.
+
+use super::{voxels_in_box, Chunk, Voxel, VoxelCoord, CHUNK_SIZE};
+
+use std::ops::{Index, IndexMut};
+use std::sync::Arc;
+
+/// A box of voxels, not aligned to the chunk grid. May be any size.
+#[derive(Clone, Debug)]
+pub struct Structure<V: Voxel> {
+    size: VoxelCoord,
+    voxels: Vec<V>,
+}
+impl<V: Voxel> Structure<V> {
+    /// A structure of the given size, filled with empty voxels.
+    pub fn empty(size: VoxelCoord) -> Self {
+        assert!(size.x > 0 && size.y > 0 && size.z > 0, "improper structure size");
+        let len = size.x as usize * size.y as usize * size.z as usize;
+        Structure {
+            size,
+            voxels: vec![V::default(); len],
+        }
+    }
+
+    pub fn size(&self) -> VoxelCoord {
+        self.size
+    }
+
+    /// Whether `coord` is inside the structure.
+    pub fn contains(&self, coord: VoxelCoord) -> bool {
+        0 <= coord.x && coord.x < self.size.x && 0 <= coord.y && coord.y < self.size.y && 0 <= coord.z
+            && coord.z < self.size.z
+    }
+
+    /// Iterate over every coordinate in the structure.
+    pub fn coords(&self) -> impl Iterator<Item = VoxelCoord> {
+        voxels_in_box(VoxelCoord::new(0, 0, 0), self.size - VoxelCoord::new(1, 1, 1))
+    }
+
+    /// A copy of the structure turned by `rotation`, as `Placement` would stamp it.
+    pub fn rotated(&self, rotation: Rotation) -> Self {
+        let mut rotated = Structure::empty(rotation.rotate_size(self.size));
+        for coord in self.coords() {
+            rotated[rotation.rotate(coord, self.size)] = self[coord];
+        }
+        rotated
+    }
+
+    /// A copy of the structure flipped along `axis`, e.g. `Axis::X` to swap its -x and +x ends.
+    pub fn mirrored(&self, axis: Axis) -> Self {
+        let mut mirrored = Structure::empty(self.size);
+        let last = self.size - VoxelCoord::new(1, 1, 1);
+        for coord in self.coords() {
+            let flipped = match axis {
+                Axis::X => VoxelCoord::new(last.x - coord.x, coord.y, coord.z),
+                Axis::Y => VoxelCoord::new(coord.x, last.y - coord.y, coord.z),
+                Axis::Z => VoxelCoord::new(coord.x, coord.y, last.z - coord.z),
+            };
+            mirrored[flipped] = self[coord];
+        }
+        mirrored
+    }
+
+    #[inline(always)]
+    fn offset(&self, coord: VoxelCoord) -> usize {
+        assert!(self.contains(coord), "coordinate outside structure: {:?}", coord);
+        (coord.x as usize * self.size.y as usize + coord.y as usize) * self.size.z as usize + coord.z as usize
+    }
+}
+impl<V: Voxel> Index<VoxelCoord> for Structure<V> {
+    type Output = V;
+
+    #[inline(always)]
+    fn index(&self, index: VoxelCoord) -> &V {
+        &self.voxels[self.offset(index)]
+    }
+}
+impl<V: Voxel> IndexMut<VoxelCoord> for Structure<V> {
+    #[inline(always)]
+    fn index_mut(&mut self, index: VoxelCoord) -> &mut V {
+        let offset = self.offset(index);
+        &mut self.voxels[offset]
+    }
+}
+
+/// One of the world's axes; e.g. the one `Structure::mirrored` flips along.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+/// A rotation about the y (up) axis, in quarter turns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Rotation {
+    None,
+    Quarter,
+    Half,
+    ThreeQuarter,
+}
+impl Rotation {
+    /// The size of a box of size `size` after rotation.
+    pub fn rotate_size(self, size: VoxelCoord) -> VoxelCoord {
+        match self {
+            Rotation::None | Rotation::Half => size,
+            Rotation::Quarter | Rotation::ThreeQuarter => VoxelCoord::new(size.z, size.y, size.x),
+        }
+    }
+
+    /// Map a coordinate within a box of size `size` to its coordinate within the rotated box.
+    pub fn rotate(self, coord: VoxelCoord, size: VoxelCoord) -> VoxelCoord {
+        let VoxelCoord { x, y, z } = coord;
+        match self {
+            Rotation::None => coord,
+            Rotation::Quarter => VoxelCoord::new(size.z - 1 - z, y, x),
+            Rotation::Half => VoxelCoord::new(size.x - 1 - x, y, size.z - 1 - z),
+            Rotation::ThreeQuarter => VoxelCoord::new(z, y, size.x - 1 - x),
+        }
+    }
+
+    /// The inverse of `rotate`: map a coordinate within the rotated box back to the original box
+    /// of size `size`.
+    pub fn unrotate(self, coord: VoxelCoord, size: VoxelCoord) -> VoxelCoord {
+        let VoxelCoord { x, y, z } = coord;
+        match self {
+            Rotation::None => coord,
+            Rotation::Quarter => VoxelCoord::new(z, y, size.z - 1 - x),
+            Rotation::Half => VoxelCoord::new(size.x - 1 - x, y, size.z - 1 - z),
+            Rotation::ThreeQuarter => VoxelCoord::new(size.x - 1 - z, y, x),
+        }
+    }
+}
+
+/// How a stamped structure combines with the voxels already in the world.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum MergePolicy {
+    /// Overwrite every voxel in the structure's box.
+    ReplaceAll,
+    /// Leave the world alone wherever the structure is empty (`V::default()`).
+    SkipAir,
+    /// As `SkipAir`, and also leave the world's empty voxels alone; i.e. only stamp the structure where it
+    /// overlaps what's already there.
+    Overlap,
+}
+
+/// A structure put somewhere in the world: its minimum corner at `origin`, after rotating it.
+#[derive(Clone, Debug)]
+pub struct Placement<V: Voxel> {
+    pub origin: VoxelCoord,
+    pub structure: Arc<Structure<V>>,
+    pub rotation: Rotation,
+    pub policy: MergePolicy,
+}
+impl<V: Voxel> Placement<V> {
+    pub fn new(
+        origin: VoxelCoord,
+        structure: Arc<Structure<V>>,
+        rotation: Rotation,
+        policy: MergePolicy,
+    ) -> Self {
+        Placement {
+            origin,
+            structure,
+            rotation,
+            policy,
+        }
+    }
+
+    /// The box the structure covers, inclusive.
+    pub fn bounds(&self) -> (VoxelCoord, VoxelCoord) {
+        let size = self.rotation.rotate_size(self.structure.size());
+        (self.origin, self.origin + size - VoxelCoord::new(1, 1, 1))
+    }
+
+    /// The voxel the structure puts at `coord`, which must be inside `bounds`, over `current`; None if it leaves
+    /// the voxel there alone.
+    pub fn voxel(&self, coord: VoxelCoord, current: V) -> Option<V> {
+        let local = self.rotation.unrotate(coord - self.origin, self.structure.size());
+        let voxel = self.structure[local];
+        let skip = match self.policy {
+            MergePolicy::ReplaceAll => false,
+            MergePolicy::SkipAir => voxel == V::default(),
+            MergePolicy::Overlap => voxel == V::default() || current == V::default(),
+        };
+        if skip {
+            None
+        } else {
+            Some(voxel)
+        }
+    }
+
+    /// Stamp the part of the structure inside `chunk` into it, if any.
+    pub fn stamp_chunk(&self, chunk: &mut Chunk<V>) {
+        let (min, max) = self.bounds();
+        let chunk_max = chunk.coord + VoxelCoord::new(1, 1, 1) * (CHUNK_SIZE as i16 - 1);
+        let lo = VoxelCoord::new(
+            min.x.max(chunk.coord.x),
+            min.y.max(chunk.coord.y),
+            min.z.max(chunk.coord.z),
+        );
+        let hi = VoxelCoord::new(max.x.min(chunk_max.x), max.y.min(chunk_max.y), max.z.min(chunk_max.z));
+        if lo.x > hi.x || lo.y > hi.y || lo.z > hi.z {
+            return;
+        }
+        for coord in voxels_in_box(lo, hi) {
+            let local = coord - chunk.coord;
+            if let Some(voxel) = self.voxel(coord, chunk[local]) {
+                chunk[local] = voxel;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use TestVoxel;
+
+    #[test]
+    fn rotation_round_trip() {
+        let size = VoxelCoord::new(2, 3, 5);
+        for &rotation in &[Rotation::None, Rotation::Quarter, Rotation::Half, Rotation::ThreeQuarter] {
+            let rotated_size = rotation.rotate_size(size);
+            let structure = Structure::<TestVoxel>::empty(size);
+            for coord in structure.coords() {
+                let rotated = rotation.rotate(coord, size);
+                assert!(Structure::<TestVoxel>::empty(rotated_size).contains(rotated));
+                assert_eq!(rotation.unrotate(rotated, size), coord);
+            }
+        }
+    }
+
+    #[test]
+    fn stamp_across_chunks() {
+        // an L, sticking out of the chunk at 0,0,0 into the one at 16,0,0
+        let mut structure = Structure::empty(VoxelCoord::new(3, 2, 1));
+        structure[VoxelCoord::new(0, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(1, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(2, 0, 0)] = TestVoxel::Rock;
+        structure[VoxelCoord::new(0, 1, 0)] = TestVoxel::Grass;
+        let placement = Placement::new(
+            VoxelCoord::new(14, 3, 5),
+            Arc::new(structure),
+            Rotation::None,
+            MergePolicy::SkipAir,
+        );
+        assert_eq!(placement.bounds(), (VoxelCoord::new(14, 3, 5), VoxelCoord::new(16, 4, 5)));
+
+        let mut left = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        left[VoxelCoord::new(15, 4, 5)] = TestVoxel::Grass;
+        placement.stamp_chunk(&mut left);
+        assert_eq!(left[VoxelCoord::new(14, 3, 5)], TestVoxel::Rock);
+        assert_eq!(left[VoxelCoord::new(15, 3, 5)], TestVoxel::Rock);
+        assert_eq!(left[VoxelCoord::new(14, 4, 5)], TestVoxel::Grass);
+        // (the structure's air there is skipped)
+        assert_eq!(left[VoxelCoord::new(15, 4, 5)], TestVoxel::Grass);
+
+        let mut right = Chunk::empty(VoxelCoord::new(16, 0, 0));
+        placement.stamp_chunk(&mut right);
+        assert_eq!(right[VoxelCoord::new(0, 3, 5)], TestVoxel::Rock);
+        assert_eq!(right[VoxelCoord::new(0, 4, 5)], TestVoxel::Air);
+
+        // chunks it doesn't touch are left alone
+        let mut far = Chunk::empty(VoxelCoord::new(32, 0, 0));
+        placement.stamp_chunk(&mut far);
+        let all = voxels_in_box(VoxelCoord::new(0, 0, 0), VoxelCoord::new(15, 15, 15));
+        assert!(all.map(|v| far[v]).all(|v| v == TestVoxel::Air));
+
+        // only over what's there
+        let overlap = Placement { policy: MergePolicy::Overlap, ..placement };
+        let mut ground = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        ground[VoxelCoord::new(15, 3, 5)] = TestVoxel::Grass;
+        overlap.stamp_chunk(&mut ground);
+        assert_eq!(ground[VoxelCoord::new(15, 3, 5)], TestVoxel::Rock);
+        assert_eq!(ground[VoxelCoord::new(14, 3, 5)], TestVoxel::Air);
+    }
+}
@@ -1,12 +1,56 @@
 //! Implements a system to allow lookups of chunks by coordinate.
 
 use super::{canonicalize_chunk, Chunk, Voxel, VoxelCoord};
+use biome::ChunkBiomes;
+use light::{ChunkLight, LightAccess};
 
+use amethyst::core::transform::GlobalTransform;
+use cgmath::Matrix4;
 use fnv::FnvHashMap;
 use specs::prelude::*;
 use specs::world::Index;
 use specs::storage::MaskedStorage;
+use specs::Storage;
+use std::collections::HashMap;
+use std::hash::BuildHasher;
 use std::marker::PhantomData;
+use std::ops::{Deref, DerefMut};
+
+/// Anything chunks can be looked up in by coordinate: the ECS (see `ChunkTracker::chunks`),
+/// a plain `HashMap` in tests or on a headless server, ...
+pub trait ChunkAccess<V: Voxel> {
+    /// The chunk containing `coord`, if it's loaded.
+    fn get_chunk(&self, coord: VoxelCoord) -> Option<&Chunk<V>>;
+
+    /// The voxel at `coord`, if its chunk is loaded.
+    fn get_voxel(&self, coord: VoxelCoord) -> Option<V> {
+        self.get_chunk(coord).map(|chunk| chunk[coord - chunk.coord])
+    }
+
+    /// The entity of the chunk containing `coord`, if chunks here are entities and it's loaded.
+    fn get_chunk_ent(&self, _coord: VoxelCoord) -> Option<Entity> {
+        None
+    }
+
+    /// The canonical coordinates of the lowest and highest chunks in a box containing every loaded chunk,
+    /// if that's cheap to know. Queries use this to skip over regions that can't contain anything.
+    fn loaded_bounds(&self) -> Option<(VoxelCoord, VoxelCoord)> {
+        None
+    }
+
+    /// The y coordinate of the highest non-transparent voxel in the column at `(x, z)` in loaded chunks,
+    /// if there's a heightmap to answer that. `surface_below` uses it to avoid walking down the column.
+    fn surface_height(&self, _x: i16, _z: i16) -> Option<i16> {
+        None
+    }
+}
+
+/// Chunks keyed by their canonical coordinates.
+impl<V: Voxel, S: BuildHasher> ChunkAccess<V> for HashMap<VoxelCoord, Chunk<V>, S> {
+    fn get_chunk(&self, coord: VoxelCoord) -> Option<&Chunk<V>> {
+        self.get(&canonicalize_chunk(coord))
+    }
+}
 
 /// A global table of chunks, to allow easy lookup of neighbors.
 /// Doesn't track chunk movement; if you reassign a chunk location nothing will happen.
@@ -15,6 +59,8 @@ pub struct ChunkTracker {
     // bidirectional mapping
     coord_to_ent: FnvHashMap<VoxelCoord, Entity>,
     idx_to_coord: FnvHashMap<Index, VoxelCoord>,
+    /// lowest and highest loaded chunk coordinates
+    bounds: Option<(VoxelCoord, VoxelCoord)>,
 }
 impl ChunkTracker {
     pub fn new() -> Self {
@@ -28,6 +74,20 @@ impl ChunkTracker {
             .map(Clone::clone)
     }
 
+    /// The canonical coordinates of the lowest and highest chunks in the smallest box containing
+    /// every tracked chunk; None if there aren't any.
+    pub fn loaded_bounds(&self) -> Option<(VoxelCoord, VoxelCoord)> {
+        self.bounds
+    }
+
+    /// Recompute `bounds` from scratch, after chunks on the edge are removed.
+    fn recompute_bounds(&mut self) {
+        self.bounds = None;
+        for &coord in self.coord_to_ent.keys() {
+            self.bounds = Some(grow(self.bounds, coord));
+        }
+    }
+
     pub fn get_chunk<'a, V: Voxel>(
         &self,
         chunk_storage: &'a ReadStorage<Chunk<V>>,
@@ -36,9 +96,130 @@ impl ChunkTracker {
         self.get_chunk_ent(coord).and_then(|ent| chunk_storage.get(ent))
     }
 
+    /// Look up chunks in `storage` (a `ReadStorage` or `WriteStorage`) through this tracker, as a `ChunkAccess`.
+    pub fn chunks<'a, 'e, V, D>(&'a self, storage: &'a Storage<'e, Chunk<V>, D>) -> TrackedChunks<'a, 'e, V, D>
+    where
+        V: Voxel,
+        D: Deref<Target = MaskedStorage<Chunk<V>>>,
+    {
+        TrackedChunks {
+            tracker: self,
+            storage,
+        }
+    }
+
+    /// Create an entity for `chunk`, with a `GlobalTransform` at its coordinates, unless there's already a
+    /// chunk there; returns the entity. The tracker only finds it once the `ChunkTrackerSystem` next runs,
+    /// so don't insert the same chunk twice in between.
+    pub fn insert_chunk<V: Voxel>(
+        &self,
+        entities: &Entities,
+        chunks: &mut WriteStorage<Chunk<V>>,
+        transforms: &mut WriteStorage<GlobalTransform>,
+        chunk: Chunk<V>,
+    ) -> Option<Entity> {
+        if self.get_chunk_ent(chunk.coord).is_some() {
+            return None;
+        }
+        let ent = entities.create();
+        let transform = GlobalTransform(Matrix4::from_translation(chunk.coord.cast().unwrap()));
+        // (the entity was just created, so these can't fail)
+        transforms.insert(ent, transform).unwrap();
+        chunks.insert(ent, chunk).unwrap();
+        Some(ent)
+    }
+
+    /// The light of the chunk containing `coord`, if it's lit; see `ChunkLight`.
+    pub fn get_light<'a>(
+        &self,
+        light_storage: &'a ReadStorage<ChunkLight>,
+        coord: VoxelCoord,
+    ) -> Option<&'a ChunkLight> {
+        self.get_chunk_ent(coord).and_then(|ent| light_storage.get(ent))
+    }
+
+    /// Look up and light chunks' `ChunkLight`s in `storage` through this tracker, as a `LightAccess`.
+    pub fn light<'a, 'e, D>(&'a self, storage: &'a mut Storage<'e, ChunkLight, D>) -> TrackedLight<'a, 'e, D>
+    where
+        D: DerefMut<Target = MaskedStorage<ChunkLight>>,
+    {
+        TrackedLight {
+            tracker: self,
+            storage,
+        }
+    }
+}
+
+/// Chunks in the ECS; see `ChunkTracker::chunks`.
+pub struct TrackedChunks<'a, 'e: 'a, V: Voxel, D: 'a> {
+    tracker: &'a ChunkTracker,
+    storage: &'a Storage<'e, Chunk<V>, D>,
+}
+impl<'a, 'e, V, D> ChunkAccess<V> for TrackedChunks<'a, 'e, V, D>
+where
+    V: Voxel,
+    D: Deref<Target = MaskedStorage<Chunk<V>>>,
+{
+    fn get_chunk(&self, coord: VoxelCoord) -> Option<&Chunk<V>> {
+        self.tracker
+            .get_chunk_ent(coord)
+            .and_then(|ent| self.storage.get(ent))
+    }
+
+    fn get_chunk_ent(&self, coord: VoxelCoord) -> Option<Entity> {
+        self.tracker.get_chunk_ent(coord)
+    }
+
+    fn loaded_bounds(&self) -> Option<(VoxelCoord, VoxelCoord)> {
+        self.tracker.loaded_bounds()
+    }
+}
+
+/// Chunks' light in the ECS; see `ChunkTracker::light`.
+pub struct TrackedLight<'a, 'e: 'a, D: 'a> {
+    tracker: &'a ChunkTracker,
+    storage: &'a mut Storage<'e, ChunkLight, D>,
 }
+impl<'a, 'e, D> LightAccess for TrackedLight<'a, 'e, D>
+where
+    D: DerefMut<Target = MaskedStorage<ChunkLight>>,
+{
+    fn get_light(&self, coord: VoxelCoord) -> Option<&ChunkLight> {
+        self.tracker
+            .get_chunk_ent(coord)
+            .and_then(|ent| self.storage.get(ent))
+    }
+
+    fn get_light_mut(&mut self, coord: VoxelCoord) -> Option<&mut ChunkLight> {
+        match self.tracker.get_chunk_ent(coord) {
+            Some(ent) => self.storage.get_mut(ent),
+            None => None,
+        }
+    }
 
-/// A system that registers new chunks in the ChunkTracker.
+    /// Adds a `ChunkLight` to the chunk's entity.
+    fn insert_light(&mut self, coord: VoxelCoord) -> bool {
+        let ent = match self.tracker.get_chunk_ent(coord) {
+            Some(ent) => ent,
+            None => return false,
+        };
+        self.storage.get(ent).is_some() || self.storage.insert(ent, ChunkLight::dark()).is_ok()
+    }
+}
+
+/// `bounds` grown to include `coord`.
+fn grow(bounds: Option<(VoxelCoord, VoxelCoord)>, coord: VoxelCoord) -> (VoxelCoord, VoxelCoord) {
+    match bounds {
+        None => (coord, coord),
+        Some((min, max)) => (
+            VoxelCoord::new(min.x.min(coord.x), min.y.min(coord.y), min.z.min(coord.z)),
+            VoxelCoord::new(max.x.max(coord.x), max.y.max(coord.y), max.z.max(coord.z)),
+        ),
+    }
+}
+
+/// A system that registers new chunks in the ChunkTracker, and removes their `ChunkLight` and `ChunkBiomes`
+/// when they're removed.
 pub struct ChunkTrackerSystem<V: Voxel> {
     ids: Option<(ReaderId<InsertedFlag>, ReaderId<RemovedFlag>)>,
     _phantom: PhantomData<V>,
@@ -55,6 +236,8 @@ impl<'a, V: Voxel> System<'a> for ChunkTrackerSystem<V> {
     type SystemData = (
         Entities<'a>,
         ReadStorage<'a, Chunk<V>>,
+        WriteStorage<'a, ChunkLight>,
+        WriteStorage<'a, ChunkBiomes>,
         Write<'a, ChunkTracker>,
     );
 
@@ -64,9 +247,10 @@ impl<'a, V: Voxel> System<'a> for ChunkTrackerSystem<V> {
         self.ids = Some((chunks.track_inserted(), chunks.track_removed()));
     }
 
-    fn run(&mut self, (entities, chunks, mut tracker): Self::SystemData) {
+    fn run(&mut self, (entities, chunks, mut light, mut biomes, mut tracker): Self::SystemData) {
         let &mut (ref mut inserted_ids, ref mut removed_ids) = self.ids.as_mut().unwrap();
 
+        let mut shrunk = false;
         for removed in chunks.removed().read(removed_ids) {
             let idx = **removed;
             let coord = *tracker
@@ -79,6 +263,19 @@ impl<'a, V: Voxel> System<'a> for ChunkTrackerSystem<V> {
 
             tracker.idx_to_coord.remove(&idx);
             tracker.coord_to_ent.remove(&coord);
+            // (if the entity's gone, so is its light)
+            let ent = entities.entity(idx);
+            if entities.is_alive(ent) {
+                light.remove(ent);
+                biomes.remove(ent);
+            }
+
+            if let Some((min, max)) = tracker.bounds {
+                shrunk |= (0..3).any(|i| coord[i] == min[i] || coord[i] == max[i]);
+            }
+        }
+        if shrunk {
+            tracker.recompute_bounds();
         }
         for inserted in chunks.inserted().read(inserted_ids) {
             let idx = **inserted;
@@ -90,6 +287,7 @@ impl<'a, V: Voxel> System<'a> for ChunkTrackerSystem<V> {
 
             tracker.idx_to_coord.insert(idx, coord);
             tracker.coord_to_ent.insert(coord, ent);
+            tracker.bounds = Some(grow(tracker.bounds, coord));
         }
     }
 }
@@ -160,12 +358,38 @@ mod tests {
             assert_eq!(tracker.get_chunk_ent(VoxelCoord::new(0, 0, 0)), Some(ent));
         }
 
+        // bounds grow as chunks are added, and shrink as they're removed
+        let far = VoxelCoord::new(-32, 16, 48);
+        let far_ent = world
+            .create_entity()
+            .with(Chunk::<TestVoxel>::empty(far))
+            .build();
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(
+            world.read_resource::<ChunkTracker>().loaded_bounds(),
+            Some((VoxelCoord::new(-32, 0, 0), VoxelCoord::new(0, 16, 48)))
+        );
+        world.delete_entity(far_ent).unwrap();
+        dispatcher.dispatch(&mut world.res);
+        assert_eq!(world.read_resource::<ChunkTracker>().loaded_bounds(), Some((coord, coord)));
+
+        // light and biomes go with the chunk
+        world.write_storage::<ChunkLight>().insert(ent, ChunkLight::dark()).unwrap();
+        world.write_storage::<ChunkBiomes>().insert(ent, ChunkBiomes::uniform(2)).unwrap();
+        world.write_storage::<Chunk<TestVoxel>>().remove(ent);
+        dispatcher.dispatch(&mut world.res);
+        assert!(world.read_storage::<ChunkLight>().get(ent).is_none());
+        assert!(world.read_storage::<ChunkBiomes>().get(ent).is_none());
+        world.write_storage::<Chunk<TestVoxel>>().insert(ent, Chunk::empty(coord)).unwrap();
+        dispatcher.dispatch(&mut world.res);
+
         // remove entity
         world.delete_entity(ent).unwrap();
         dispatcher.dispatch(&mut world.res);
         {
             let tracker = world.read_resource::<ChunkTracker>();
             assert_eq!(tracker.get_chunk_ent(VoxelCoord::new(0, 0, 0)), None);
+            assert_eq!(tracker.loaded_bounds(), None);
         }
     }
 }
@@ -0,0 +1,169 @@
+//! Exporting boxes of the world as MagicaVoxel `.vox` files, for sharing builds, or for looking at what a
+//! generator made in another program.
+//!
+//! Voxels get their colors from `Voxel::color` (made opaque, since `.vox` palettes don't do transparency),
+//! and empty voxels (`V::default()`) are left out. A model can be at most 256 voxels along each side and use
+//! at most 255 colors. `.vox` models have z up, so world y becomes z, and world z runs backwards along y (so
+//! that the model isn't mirrored).
+
+use super::{voxels_in_box, ChunkAccess, Voxel, VoxelCoord};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+/// The most voxels a model can have along each side.
+pub const MAX_SIZE: i32 = 256;
+
+const VERSION: u32 = 150;
+
+/// Write the voxels in the box from `min` to `max` (inclusive) as a `.vox` model, returning how many voxels
+/// were written. Unloaded chunks count as empty.
+pub fn export_vox<V: Voxel, C: ChunkAccess<V>, W: Write>(
+    chunks: &C,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    writer: &mut W,
+) -> io::Result<usize> {
+    let size = [
+        i32::from(max.x) - i32::from(min.x) + 1,
+        i32::from(max.y) - i32::from(min.y) + 1,
+        i32::from(max.z) - i32::from(min.z) + 1,
+    ];
+    if size.iter().any(|&side| side <= 0 || side > MAX_SIZE) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("can't export a {:?} box; .vox models are 1 to {} voxels a side", size, MAX_SIZE),
+        ));
+    }
+
+    let mut palette: Vec<[u8; 4]> = Vec::new();
+    let mut voxels = Vec::new();
+    for coord in voxels_in_box(min, max) {
+        let voxel = match chunks.get_voxel(coord) {
+            Some(voxel) if voxel != V::default() => voxel,
+            _ => continue,
+        };
+        let color = rgba(voxel.color());
+        let index = match palette.iter().position(|&c| c == color) {
+            Some(index) => index,
+            None if palette.len() < 255 => {
+                palette.push(color);
+                palette.len() - 1
+            }
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "too many colors for a .vox palette",
+                ))
+            }
+        };
+        voxels.extend_from_slice(&[
+            (coord.x - min.x) as u8,
+            (max.z - coord.z) as u8,
+            (coord.y - min.y) as u8,
+            // (palette indices start at 1)
+            index as u8 + 1,
+        ]);
+    }
+    let count = voxels.len() / 4;
+
+    let mut size_content = Vec::with_capacity(12);
+    // (.vox y is world z, and .vox z is world y)
+    for &side in &[size[0], size[2], size[1]] {
+        push_u32(&mut size_content, side as u32);
+    }
+    let mut xyzi_content = Vec::with_capacity(4 + voxels.len());
+    push_u32(&mut xyzi_content, count as u32);
+    xyzi_content.extend(voxels);
+    let mut rgba_content = vec![0; 256 * 4];
+    for (i, color) in palette.iter().enumerate() {
+        rgba_content[i * 4..i * 4 + 4].copy_from_slice(color);
+    }
+
+    let mut children = Vec::new();
+    push_chunk(&mut children, b"SIZE", &size_content, &[]);
+    push_chunk(&mut children, b"XYZI", &xyzi_content, &[]);
+    push_chunk(&mut children, b"RGBA", &rgba_content, &[]);
+    let mut bytes = b"VOX ".to_vec();
+    push_u32(&mut bytes, VERSION);
+    push_chunk(&mut bytes, b"MAIN", &[], &children);
+    writer.write_all(&bytes)?;
+    Ok(count)
+}
+
+/// Export the box from `min` to `max` to a `.vox` file at `path`; see `export_vox`.
+pub fn save_vox<V: Voxel, C: ChunkAccess<V>, P: AsRef<Path>>(
+    chunks: &C,
+    min: VoxelCoord,
+    max: VoxelCoord,
+    path: P,
+) -> io::Result<usize> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    let count = export_vox(chunks, min, max, &mut writer)?;
+    writer.flush()?;
+    Ok(count)
+}
+
+/// A color as bytes, opaque.
+fn rgba(color: [f32; 4]) -> [u8; 4] {
+    let byte = |c: f32| (c.max(0.0).min(1.0) * 255.0).round() as u8;
+    [byte(color[0]), byte(color[1]), byte(color[2]), 255]
+}
+
+fn push_u32(bytes: &mut Vec<u8>, value: u32) {
+    for i in 0..4 {
+        bytes.push((value >> (i * 8)) as u8);
+    }
+}
+
+/// A `.vox` chunk: its id, the sizes of its content and children, and then them.
+fn push_chunk(bytes: &mut Vec<u8>, id: &[u8; 4], content: &[u8], children: &[u8]) {
+    bytes.extend_from_slice(id);
+    push_u32(bytes, content.len() as u32);
+    push_u32(bytes, children.len() as u32);
+    bytes.extend_from_slice(content);
+    bytes.extend_from_slice(children);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use {Chunk, TestVoxel};
+
+    #[test]
+    fn export() {
+        let mut chunks = HashMap::new();
+        let mut chunk = Chunk::empty(VoxelCoord::new(0, 0, 0));
+        chunk[VoxelCoord::new(1, 0, 0)] = TestVoxel::Rock;
+        chunk[VoxelCoord::new(1, 2, 3)] = TestVoxel::Grass;
+        chunk[VoxelCoord::new(2, 0, 0)] = TestVoxel::Rock;
+        chunks.insert(chunk.coord, chunk);
+
+        let mut bytes = Vec::new();
+        let count = export_vox(&chunks, VoxelCoord::new(1, 0, 0), VoxelCoord::new(4, 2, 3), &mut bytes).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(&bytes[0..8], b"VOX \x96\x00\x00\x00");
+        assert_eq!(&bytes[8..12], b"MAIN");
+        let u32_at = |i: usize| (0..4).fold(0, |acc, j| acc | u32::from(bytes[i + j]) << (j * 8));
+        assert_eq!(u32_at(16) as usize, bytes.len() - 20);
+
+        // SIZE: 4 wide, 4 deep (world z), 3 high (world y)
+        assert_eq!(&bytes[20..24], b"SIZE");
+        assert_eq!((u32_at(32), u32_at(36), u32_at(40)), (4, 4, 3));
+        // XYZI: z is flipped into y, and y is up
+        assert_eq!(&bytes[44..48], b"XYZI");
+        assert_eq!(u32_at(56), 3);
+        let voxels: Vec<_> = bytes[60..72].chunks(4).map(|v| v.to_vec()).collect();
+        assert_eq!(voxels, vec![vec![0, 3, 0, 1], vec![0, 0, 2, 2], vec![1, 3, 0, 1]]);
+        // RGBA: grass is clamped to bright green
+        assert_eq!(&bytes[72..76], b"RGBA");
+        assert_eq!(&bytes[84..88], &rgba(TestVoxel::Rock.color()));
+        assert_eq!(&bytes[88..92], &[0, 255, 0, 255]);
+        assert_eq!(bytes.len(), 84 + 256 * 4);
+
+        let big = export_vox(&chunks, VoxelCoord::new(0, 0, 0), VoxelCoord::new(256, 0, 0), &mut Vec::new());
+        assert_eq!(big.unwrap_err().kind(), io::ErrorKind::InvalidInput);
+    }
+}